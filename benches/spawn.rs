@@ -0,0 +1,47 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate coio;
+
+use std::time::Instant;
+
+use coio::Scheduler;
+
+const ITER_COUNT: usize = 100_000;
+
+// Spawns and joins coroutines one after another, which is the steady state of a server
+// spawning a coroutine per request: Every spawn can reuse the stack of the previous one.
+fn run_test(pool_capacity: usize) -> u64 {
+    Scheduler::new()
+        .stack_pool_capacity(pool_capacity)
+        .run(|| {
+            let beg = Instant::now();
+
+            for _ in 0..ITER_COUNT {
+                Scheduler::spawn(|| {}).join().unwrap();
+            }
+
+            let dur = beg.elapsed();
+            dur.as_secs() * 1_000_000_000 + dur.subsec_nanos() as u64
+        })
+        .unwrap()
+}
+
+// Run this benchmark with
+//   cargo bench --bench spawn
+fn main() {
+    for &(name, capacity) in &[("without stack pool", 0), ("with stack pool", 1024)] {
+        let dur = run_test(capacity);
+
+        println!("{}: {} spawns in {} ms => {} ns/spawn",
+                 name,
+                 ITER_COUNT,
+                 dur / 1_000_000,
+                 dur / ITER_COUNT as u64);
+    }
+}
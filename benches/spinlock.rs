@@ -28,7 +28,53 @@ fn rdiv(a: usize, b: usize) -> usize {
     (a + (b / 2)) / b
 }
 
-fn run_test(thread_count: usize) -> Vec<Result> {
+trait Lock: Send + Sync + 'static {
+    fn new() -> Self;
+
+    // Increments the counter and returns its previous value
+    fn increment(&self) -> usize;
+}
+
+impl Lock for Spinlock<usize> {
+    fn new() -> Self {
+        Spinlock::new(0)
+    }
+
+    fn increment(&self) -> usize {
+        let mut inner = self.lock();
+        let n = *inner;
+        *inner = n + 1;
+        n
+    }
+}
+
+impl Lock for TicketSpinlock<usize> {
+    fn new() -> Self {
+        TicketSpinlock::new(0)
+    }
+
+    fn increment(&self) -> usize {
+        let mut inner = self.lock();
+        let n = *inner;
+        *inner = n + 1;
+        n
+    }
+}
+
+impl Lock for QueueSpinlock<usize> {
+    fn new() -> Self {
+        QueueSpinlock::new(0)
+    }
+
+    fn increment(&self) -> usize {
+        let mut inner = self.lock();
+        let n = *inner;
+        *inner = n + 1;
+        n
+    }
+}
+
+fn run_test<L: Lock>(thread_count: usize) -> Vec<Result> {
     const ITER_COUNT: usize = 10_000_000;
     const EMPTY: Result = Result {
         duration: 0,
@@ -37,7 +83,7 @@ fn run_test(thread_count: usize) -> Vec<Result> {
 
     let total_count = thread_count * ITER_COUNT;
     let barriers = Arc::new(Barrier::new(thread_count));
-    let lock = Arc::new(Spinlock::new(0usize));
+    let lock = Arc::new(L::new());
     let results = Arc::new(Mutex::new(Vec::new()));
     let mut threads = Vec::with_capacity(thread_count);
 
@@ -55,10 +101,7 @@ fn run_test(thread_count: usize) -> Vec<Result> {
             let mut cnt = 0usize;
 
             loop {
-                let mut inner = lock.lock();
-                let n = *inner;
-
-                *inner = n + 1;
+                let n = lock.increment();
                 cnt += 1;
 
                 if n >= total_count {
@@ -87,14 +130,24 @@ fn run_test(thread_count: usize) -> Vec<Result> {
 // Run this test with
 //   cargo bench --bench spinlock -- --csv
 // to get a parsable output.
+// The lock which is tested can be chosen by passing
+// --ticket or --queue, otherwise the Spinlock is used.
 // The first column will contain the thread count for that data plot and
 // the second column will contain the ns/iter.
 // You can feed that data into Excel for instance and create a boxplot graph.
 fn main() {
     let csv = std::env::args().any(|arg| arg == "--csv");
+    let ticket = std::env::args().any(|arg| arg == "--ticket");
+    let queue = std::env::args().any(|arg| arg == "--queue");
 
     for i in 1..(num_cpus::get() + 1) {
-        let results = run_test(i);
+        let results = if ticket {
+            run_test::<TicketSpinlock<usize>>(i)
+        } else if queue {
+            run_test::<QueueSpinlock<usize>>(i)
+        } else {
+            run_test::<Spinlock<usize>>(i)
+        };
 
         if csv {
             for r in results.iter() {
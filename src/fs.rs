@@ -0,0 +1,265 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Filesystem access which doesn't block the workers
+//!
+//! Files can't be polled for readiness, which is why every operation is run on the thread pool
+//! of `Scheduler::spawn_blocking()` while only the calling coroutine is parked. Each call
+//! hands a job to another thread, so small reads and writes should be buffered, e.g. using
+//! `coio::io::BufReader`. Outside of a coroutine all operations are done on the current thread.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use blocking;
+
+/// A file whose operations are run on the blocking pool
+///
+/// The data of every `read()` and `write()` is copied between the coroutine and the pool.
+pub struct File {
+    inner: Arc<fs::File>,
+}
+
+impl File {
+    /// Opens a file in read-only mode, see `std::fs::File::open()`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// Opens a file in write-only mode, creating or truncating it, see `std::fs::File::create()`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+
+    /// Wraps a file opened using `std::fs`
+    pub fn from_std(file: fs::File) -> File {
+        File { inner: Arc::new(file) }
+    }
+
+    /// Flushes all data and metadata to disk
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.with_file(|file| file.sync_all())
+    }
+
+    /// Flushes all data to disk, but not necessarily the metadata
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.with_file(|file| file.sync_data())
+    }
+
+    /// Truncates or extends the file to `size` bytes
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.with_file(move |file| file.set_len(size))
+    }
+
+    /// Queries the metadata of the file
+    pub fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.with_file(|file| file.metadata())
+    }
+
+    fn with_file<F, T>(&self, f: F) -> T
+        where F: FnOnce(&fs::File) -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let file = self.inner.clone();
+        blocking(move || f(&file))
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len();
+        let data = try!(self.with_file(move |mut file| {
+            let mut data = vec![0; len];
+            file.read(&mut data).map(|len| {
+                data.truncate(len);
+                data
+            })
+        }));
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = buf.to_vec();
+        self.with_file(move |mut file| file.write(&data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_file(|mut file| file.flush())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.with_file(move |mut file| file.seek(pos))
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File({:?})", self.inner)
+    }
+}
+
+/// Options for opening a `File`, see `std::fs::OpenOptions`
+#[derive(Clone, Debug)]
+pub struct OpenOptions(fs::OpenOptions);
+
+impl OpenOptions {
+    /// Creates a blank set of options
+    pub fn new() -> OpenOptions {
+        OpenOptions(fs::OpenOptions::new())
+    }
+
+    /// Sets the option for read access
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.0.read(read);
+        self
+    }
+
+    /// Sets the option for write access
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.0.write(write);
+        self
+    }
+
+    /// Sets the option for appending to the file
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.0.append(append);
+        self
+    }
+
+    /// Sets the option for truncating the file on opening
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.0.truncate(truncate);
+        self
+    }
+
+    /// Sets the option for creating the file if it doesn't exist
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.0.create(create);
+        self
+    }
+
+    /// Opens the file at `path` on the blocking pool
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<File> {
+        let options = self.0.clone();
+        let path = path.as_ref().to_path_buf();
+
+        blocking(move || options.open(path)).map(File::from_std)
+    }
+}
+
+/// Queries the metadata of the file at `path`, following symlinks
+pub fn metadata<P: AsRef<Path>>(path: P) -> io::Result<fs::Metadata> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || fs::metadata(path))
+}
+
+/// Lists the entries of the directory at `path`
+///
+/// Unlike `std::fs::read_dir()` the whole directory is read at once, since iterating it
+/// would have to hand a job to the blocking pool for every entry.
+pub fn read_dir<P: AsRef<Path>>(path: P) -> io::Result<Vec<fs::DirEntry>> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || fs::read_dir(path).and_then(|entries| entries.collect()))
+}
+
+/// Renames the file or directory `from` to `to`, replacing `to` if it exists
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let from = from.as_ref().to_path_buf();
+    let to = to.as_ref().to_path_buf();
+    blocking(move || fs::rename(from, to))
+}
+
+/// Removes the file at `path`
+pub fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || fs::remove_file(path))
+}
+
+/// Returns the absolute form of `path` with all symlinks resolved
+pub fn canonicalize<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+    let path = path.as_ref().to_path_buf();
+    blocking(move || fs::canonicalize(path))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn fs_file_roundtrip() {
+        Scheduler::new()
+            .run(|| {
+                let path = ::std::env::temp_dir().join("coio-test-fs-file-roundtrip");
+
+                {
+                    let mut file = File::create(&path).unwrap();
+                    file.write_all(b"hello coio").unwrap();
+                    file.sync_all().unwrap();
+                    assert_eq!(file.metadata().unwrap().len(), 10);
+                }
+
+                let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+                file.seek(SeekFrom::Start(6)).unwrap();
+
+                let mut buf = String::new();
+                file.read_to_string(&mut buf).unwrap();
+                assert_eq!(buf, "coio");
+
+                file.set_len(5).unwrap();
+                file.seek(SeekFrom::Start(0)).unwrap();
+                buf.clear();
+                file.read_to_string(&mut buf).unwrap();
+                assert_eq!(buf, "hello");
+
+                let _ = ::std::fs::remove_file(&path);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn fs_metadata_operations() {
+        Scheduler::new()
+            .run(|| {
+                let dir = ::std::env::temp_dir().join("coio-test-fs-metadata");
+                let _ = ::std::fs::remove_dir_all(&dir);
+                ::std::fs::create_dir(&dir).unwrap();
+
+                File::create(dir.join("a")).unwrap().write_all(b"abc").unwrap();
+                assert_eq!(metadata(dir.join("a")).unwrap().len(), 3);
+
+                rename(dir.join("a"), dir.join("b")).unwrap();
+                assert!(metadata(dir.join("a")).is_err());
+
+                let entries = read_dir(&dir).unwrap();
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].file_name().to_str(), Some("b"));
+
+                let canonical = canonicalize(dir.join(".").join("b")).unwrap();
+                assert_eq!(canonical, canonicalize(&dir).unwrap().join("b"));
+
+                remove_file(dir.join("b")).unwrap();
+                assert!(read_dir(&dir).unwrap().is_empty());
+
+                ::std::fs::remove_dir(&dir).unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,674 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! I/O utilities for coroutines
+//!
+//! The buffered types work like their counterparts in `std::io`, but never lose data if an
+//! operation fails with a recoverable error, e.g. `ErrorKind::TimedOut` after a read or write
+//! timeout has been set on the underlying stream. The failed call can thus simply be retried.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod splice;
+mod stdio;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::splice::{splice, Splicer};
+pub use self::stdio::{stderr, stdin, stdout, Stderr, Stdin, Stdout};
+pub use net::{EventedWrite, GenericEvented as PollEvented};
+pub use scheduler::ReadyMode;
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+use scheduler::Scheduler;
+
+/// Default size of the buffers, 8KB
+pub const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Number of bytes after which `copy()` yields, 64KB
+pub const COPY_YIELD_BYTES: usize = 64 * 1024;
+
+/// Copies all data from `reader` to `writer` and returns the number of bytes copied
+///
+/// Unlike `std::io::copy()` it yields to other coroutines every `COPY_YIELD_BYTES`, since
+/// a fast pair of streams would otherwise never block and occupy its Processor until EOF.
+pub fn copy<R: ?Sized, W: ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+    where R: Read,
+          W: Write
+{
+    copy_yielding(reader, writer, COPY_YIELD_BYTES)
+}
+
+/// Like `copy()`, but yields every `yield_bytes` bytes instead
+pub fn copy_yielding<R: ?Sized, W: ?Sized>(reader: &mut R,
+                                          writer: &mut W,
+                                          yield_bytes: usize)
+                                          -> io::Result<u64>
+    where R: Read,
+          W: Write
+{
+    assert!(yield_bytes > 0, "yield_bytes must not be zero");
+
+    // Coroutine stacks are small, which is why the buffer lives on the heap
+    let mut buf = vec![0; DEFAULT_BUF_SIZE];
+    let mut copied = 0u64;
+    let mut since_yield = 0;
+
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        try!(writer.write_all(&buf[..len]));
+        copied += len as u64;
+        since_yield += len;
+
+        if since_yield >= yield_bytes {
+            since_yield = 0;
+            Scheduler::sched();
+        }
+    }
+}
+
+/// Adds buffering to a reader
+///
+/// `read_until()` and `read_line()` keep an incomplete line in the buffer if the underlying
+/// reader fails, instead of handing it out together with the error as `std::io::BufReader`
+/// does. If a line doesn't fit into the buffer, the buffer grows.
+pub struct BufReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R: Read> BufReader<R> {
+    /// Creates a `BufReader` with a buffer of `DEFAULT_BUF_SIZE` bytes
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a `BufReader` with a buffer of `capacity` bytes
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R> {
+        assert!(capacity > 0, "capacity must not be zero");
+
+        BufReader {
+            inner: inner,
+            buf: vec![0; capacity],
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    /// Reads into `out` until `byte` or EOF is reached and returns the number of bytes read
+    ///
+    /// The delimiter is included in `out`, unless EOF is reached before it. On errors nothing
+    /// is appended to `out` and all data read so far stays buffered.
+    pub fn read_until(&mut self, byte: u8, out: &mut Vec<u8>) -> io::Result<usize> {
+        let mut searched = 0;
+
+        loop {
+            let start = self.pos + searched;
+
+            if let Some(i) = self.buf[start..self.cap].iter().position(|&b| b == byte) {
+                let end = start + i + 1;
+                out.extend_from_slice(&self.buf[self.pos..end]);
+
+                let len = end - self.pos;
+                self.pos = end;
+                return Ok(len);
+            }
+
+            searched = self.cap - self.pos;
+
+            // Make room for more data, moving the incomplete line to the front of the buffer
+            if self.pos > 0 {
+                let len = self.buf.len();
+                self.buf.drain(..self.pos);
+                self.buf.resize(len, 0);
+                self.cap -= self.pos;
+                self.pos = 0;
+            }
+
+            if self.cap == self.buf.len() {
+                let len = self.buf.len();
+                self.buf.resize(len * 2, 0);
+            }
+
+            match self.inner.read(&mut self.buf[self.cap..]) {
+                Ok(0) => {
+                    out.extend_from_slice(&self.buf[self.pos..self.cap]);
+
+                    let len = self.cap - self.pos;
+                    self.pos = self.cap;
+                    return Ok(len);
+                }
+                Ok(len) => self.cap += len,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads a line including the trailing newline into `out`, see `read_until()`
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the line isn't valid UTF-8, in which case the
+    /// line is consumed.
+    pub fn read_line(&mut self, out: &mut String) -> io::Result<usize> {
+        let mut line = Vec::new();
+        let len = try!(self.read_until(b'\n', &mut line));
+
+        match str::from_utf8(&line) {
+            Ok(line) => {
+                out.push_str(line);
+                Ok(len)
+            }
+            Err(..) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "stream did not contain valid UTF-8"))
+            }
+        }
+    }
+
+    /// Returns the currently buffered data
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.cap]
+    }
+
+    /// Gets a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader, e.g. to set a read timeout
+    ///
+    /// Reading from it directly skips the buffered data.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Unwraps the underlying reader, dropping all buffered data
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads bypass the buffer if it's empty
+        if self.pos == self.cap && buf.len() >= self.buf.len() {
+            return self.inner.read(buf);
+        }
+
+        let len = {
+            let mut data = try!(self.fill_buf());
+            try!(data.read(buf))
+        };
+
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.cap {
+            self.cap = try!(self.inner.read(&mut self.buf));
+            self.pos = 0;
+        }
+
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = ::std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for BufReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "BufReader {{ inner: {:?}, buffered: {}/{} }}",
+               self.inner,
+               self.cap - self.pos,
+               self.buf.len())
+    }
+}
+
+/// Adds buffering to a writer
+///
+/// If writing the buffered data fails, only the part which has actually been written is
+/// removed from the buffer, so that a later `flush()` continues where the failed one stopped.
+/// The buffer is flushed when the `BufWriter` is dropped, ignoring any errors.
+pub struct BufWriter<W: Write> {
+    inner: Option<W>,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl<W: Write> BufWriter<W> {
+    /// Creates a `BufWriter` with a buffer of `DEFAULT_BUF_SIZE` bytes
+    pub fn new(inner: W) -> BufWriter<W> {
+        BufWriter::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a `BufWriter` with a buffer of `capacity` bytes
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W> {
+        BufWriter {
+            inner: Some(inner),
+            buf: Vec::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    // Writes the buffered data to the underlying writer, keeping what couldn't be written
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let mut ret = Ok(());
+
+        while written < self.buf.len() {
+            match self.inner.as_mut().unwrap().write(&self.buf[written..]) {
+                Ok(0) => {
+                    ret = Err(io::Error::new(io::ErrorKind::WriteZero,
+                                             "failed to write the buffered data"));
+                    break;
+                }
+                Ok(len) => written += len,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    ret = Err(err);
+                    break;
+                }
+            }
+        }
+
+        self.buf.drain(..written);
+        ret
+    }
+
+    /// Returns the data which hasn't been written yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Gets a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Gets a mutable reference to the underlying writer, e.g. to set a write timeout
+    ///
+    /// Writing to it directly bypasses the buffered data.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Flushes the buffer and unwraps the underlying writer
+    ///
+    /// If flushing fails the `BufWriter` is handed back together with the error.
+    pub fn into_inner(mut self) -> Result<W, IntoInnerError<BufWriter<W>>> {
+        match self.flush_buf() {
+            Ok(()) => Ok(self.inner.take().unwrap()),
+            Err(err) => Err(IntoInnerError(self, err)),
+        }
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + buf.len() > self.capacity {
+            try!(self.flush_buf());
+        }
+
+        if buf.len() >= self.capacity {
+            self.get_mut().write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.flush_buf());
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for BufWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "BufWriter {{ inner: {:?}, buffered: {}/{} }}",
+               self.get_ref(),
+               self.buf.len(),
+               self.capacity)
+    }
+}
+
+/// The error returned by `BufWriter::into_inner()`, which still holds the `BufWriter`
+#[derive(Debug)]
+pub struct IntoInnerError<W>(W, io::Error);
+
+impl<W> IntoInnerError<W> {
+    /// Returns the error which occurred while flushing
+    pub fn error(&self) -> &io::Error {
+        &self.1
+    }
+
+    /// Returns the `BufWriter`, whose buffer still holds the data which couldn't be written
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W> From<IntoInnerError<W>> for io::Error {
+    fn from(err: IntoInnerError<W>) -> io::Error {
+        err.1
+    }
+}
+
+// Flushes pending writes before reading from a `BufStream`
+struct FlushingReader<S: Write>(BufWriter<S>);
+
+impl<S: Read + Write> Read for FlushingReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.0.flush_buf());
+        self.0.get_mut().read(buf)
+    }
+}
+
+/// Adds buffering to both directions of a stream
+///
+/// Buffered writes are flushed before the stream is read from. Otherwise a request might
+/// never be sent while the coroutine is already waiting for the response.
+pub struct BufStream<S: Read + Write> {
+    inner: BufReader<FlushingReader<S>>,
+}
+
+impl<S: Read + Write> BufStream<S> {
+    /// Creates a `BufStream` with buffers of `DEFAULT_BUF_SIZE` bytes
+    pub fn new(inner: S) -> BufStream<S> {
+        BufStream::with_capacities(DEFAULT_BUF_SIZE, DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a `BufStream` with buffers of the given sizes
+    pub fn with_capacities(reader_capacity: usize,
+                           writer_capacity: usize,
+                           inner: S)
+                           -> BufStream<S> {
+        let writer = BufWriter::with_capacity(writer_capacity, inner);
+        BufStream { inner: BufReader::with_capacity(reader_capacity, FlushingReader(writer)) }
+    }
+
+    /// See `BufReader::read_until()`
+    pub fn read_until(&mut self, byte: u8, out: &mut Vec<u8>) -> io::Result<usize> {
+        self.inner.read_until(byte, out)
+    }
+
+    /// See `BufReader::read_line()`
+    pub fn read_line(&mut self, out: &mut String) -> io::Result<usize> {
+        self.inner.read_line(out)
+    }
+
+    /// Gets a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref().0.get_ref()
+    }
+
+    /// Gets a mutable reference to the underlying stream, e.g. to set timeouts
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut().0.get_mut()
+    }
+
+    /// Flushes the write buffer and unwraps the underlying stream, dropping all buffered input
+    pub fn into_inner(self) -> io::Result<S> {
+        self.inner.into_inner().0.into_inner().map_err(io::Error::from)
+    }
+}
+
+impl<S: Read + Write> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read + Write> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().0.flush()
+    }
+}
+
+impl<S: Read + Write + fmt::Debug> fmt::Debug for BufStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BufStream({:?})", self.get_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+
+    use super::{copy_yielding, BufReader, BufStream, BufWriter};
+
+    // Replays a script of reads, where `None` stands for a timeout
+    struct ScriptedReader(VecDeque<Option<&'static [u8]>>);
+
+    impl Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(data)) => {
+                    let len = ::std::cmp::min(buf.len(), data.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+
+                    if len < data.len() {
+                        self.0.push_front(Some(&data[len..]));
+                    }
+
+                    Ok(len)
+                }
+                Some(None) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                None => Ok(0),
+            }
+        }
+    }
+
+    // Accepts `budget` bytes before every failure
+    struct FlakyWriter {
+        data: Vec<u8>,
+        budget: usize,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+            }
+
+            let len = ::std::cmp::min(self.budget, buf.len());
+            self.budget -= len;
+            self.data.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buf_reader_read_line_survives_timeout() {
+        let script = vec![Some(&b"hel"[..]), None, Some(&b"lo\nwor"[..]), Some(&b"ld"[..])];
+        let mut reader = BufReader::with_capacity(4, ScriptedReader(script.into_iter().collect()));
+
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap_err().kind(),
+                   io::ErrorKind::TimedOut);
+        assert_eq!(line, "");
+        assert_eq!(reader.buffer(), b"hel");
+
+        assert_eq!(reader.read_line(&mut line).unwrap(), 6);
+        assert_eq!(line, "hello\n");
+
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 5);
+        assert_eq!(line, "world");
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn buf_writer_keeps_unwritten_data() {
+        let mut writer = BufWriter::with_capacity(16,
+                                                  FlakyWriter {
+                                                      data: Vec::new(),
+                                                      budget: 3,
+                                                  });
+
+        writer.write_all(b"abcdef").unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(writer.buffer(), b"def");
+
+        writer.get_mut().budget = 3;
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref().data, b"abcdef");
+    }
+
+    #[test]
+    fn copy_yields_while_copying() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        use scheduler::Scheduler;
+
+        Scheduler::new()
+            .run(|| {
+                let ran = Arc::new(AtomicBool::new(false));
+                let other = {
+                    let ran = ran.clone();
+                    Scheduler::spawn(move || ran.store(true, Ordering::SeqCst))
+                };
+
+                // Reads 16 bytes, one at a time
+                struct Bytes(usize);
+
+                impl Read for Bytes {
+                    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                        if self.0 == 0 {
+                            return Ok(0);
+                        }
+
+                        self.0 -= 1;
+                        buf[0] = b'x';
+                        Ok(1)
+                    }
+                }
+
+                let mut out = Vec::new();
+                assert_eq!(copy_yielding(&mut Bytes(16), &mut out, 4).unwrap(), 16);
+                assert_eq!(out.len(), 16);
+
+                // The other coroutine got the chance to run while copying
+                assert!(ran.load(Ordering::SeqCst));
+
+                other.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn buf_stream_flushes_before_reading() {
+        struct Echo(Vec<u8>);
+
+        impl Read for Echo {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = ::std::cmp::min(buf.len(), self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0.drain(..len);
+                Ok(len)
+            }
+        }
+
+        impl Write for Echo {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut stream = BufStream::new(Echo(Vec::new()));
+        stream.write_all(b"ping\n").unwrap();
+        assert!(stream.get_ref().0.is_empty());
+
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "ping\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn poll_evented_custom_type() {
+        use std::time::Duration;
+
+        use mio::EventSet;
+        use mio::unix;
+
+        use scheduler::Scheduler;
+        use super::PollEvented;
+
+        Scheduler::new()
+            .run(|| {
+                let (reader, mut writer) = unix::pipe().unwrap();
+                let mut reader = PollEvented::new(reader, EventSet::readable()).unwrap();
+
+                let err = reader.wait_readable_timeout(Duration::from_millis(20)).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+                let writer = Scheduler::spawn(move || {
+                    writer.write_all(b"abc").unwrap();
+                });
+
+                reader.wait_readable().unwrap();
+
+                let mut buf = [0u8; 8];
+                let len = reader.get_mut().read(&mut buf).unwrap();
+                assert_eq!(&buf[..len], b"abc");
+
+                writer.join().unwrap();
+            })
+            .unwrap();
+    }
+}
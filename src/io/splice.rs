@@ -0,0 +1,352 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Zero-copy forwarding between file descriptors using Linux' `splice(2)`
+
+use std::cmp;
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+
+use libc::{self, c_uint};
+use mio::Evented;
+
+use net::{self, GenericEvented};
+
+const SPLICE_F_MOVE: c_uint = 1;
+const SPLICE_F_NONBLOCK: c_uint = 2;
+
+mod ffi {
+    use libc::{c_int, c_uint, loff_t, size_t, ssize_t};
+
+    extern "C" {
+        pub fn splice(fd_in: c_int,
+                      off_in: *mut loff_t,
+                      fd_out: c_int,
+                      off_out: *mut loff_t,
+                      len: size_t,
+                      flags: c_uint)
+                      -> ssize_t;
+    }
+}
+
+// The intermediate pipe, since one end of every splice() has to be a pipe
+struct Pipe {
+    reader: RawFd,
+    writer: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Pipe {
+                reader: fds[0],
+                writer: fds[1],
+            })
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.reader);
+            libc::close(self.writer);
+        }
+    }
+}
+
+fn splice_fd(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        ffi::splice(from,
+                    ptr::null_mut(),
+                    to,
+                    ptr::null_mut(),
+                    len,
+                    SPLICE_F_MOVE | SPLICE_F_NONBLOCK)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Forwards up to `len` bytes from `from` to `to` without copying them into userspace
+///
+/// Returns the number of bytes forwarded, which is less than `len` if `from` has reached EOF
+/// or an error occurred after some data had already been forwarded. The calling coroutine is
+/// parked while `from` has no data or `to` is full. Read and write timeouts of the objects
+/// don't apply.
+///
+/// Every call creates and closes a pipe of its own, which takes two extra syscalls. Use a
+/// `Splicer` to forward data repeatedly. It also keeps data which has been read from `from`
+/// but not written yet and reports an error that follows partial progress on the next call.
+/// Since there is no next call here, such an error is returned right away instead.
+///
+/// Only supported on Linux. At least one of the file descriptors has to be a socket or
+/// a pipe, others fail with `EINVAL`.
+pub fn splice<A, B>(from: &GenericEvented<A>,
+                    to: &GenericEvented<B>,
+                    len: usize)
+                    -> io::Result<usize>
+    where A: Evented + Debug + AsRawFd,
+          B: Evented + Debug + AsRawFd
+{
+    let mut splicer = try!(Splicer::new());
+    let total = try!(splicer.splice(from, to, len));
+
+    // Neither the error nor the data left in the pipe would survive the temporary Splicer
+    match splicer.error.take() {
+        Some(err) => Err(err),
+        None => Ok(total + try!(splicer.flush(from, to))),
+    }
+}
+
+/// Forwards data between a pair of file descriptors using `splice()`, reusing its pipe
+pub struct Splicer {
+    pipe: Pipe,
+
+    // Bytes in the pipe which haven't been written to the destination yet
+    pending: usize,
+
+    // An error which occurred after the last call had already forwarded some data
+    error: Option<io::Error>,
+}
+
+impl Splicer {
+    pub fn new() -> io::Result<Splicer> {
+        Ok(Splicer {
+            pipe: try!(Pipe::new()),
+            pending: 0,
+            error: None,
+        })
+    }
+
+    /// Forwards up to `len` bytes from `from` to `to`, see `splice()`
+    ///
+    /// If an error occurs after some data has been forwarded, the number of bytes is returned
+    /// first and the error by the next call. The `Splicer` should always be used with the
+    /// same pair of objects, since data which couldn't be written yet is kept for the next call.
+    pub fn splice<A, B>(&mut self,
+                        from: &GenericEvented<A>,
+                        to: &GenericEvented<B>,
+                        len: usize)
+                        -> io::Result<usize>
+        where A: Evented + Debug + AsRawFd,
+              B: Evented + Debug + AsRawFd
+    {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        let mut total = 0;
+
+        while total < len {
+            match self.forward(from, to, len - total) {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(err) => {
+                    if total == 0 {
+                        return Err(err);
+                    }
+
+                    self.error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        Ok(total)
+    }
+
+    // Writes the data which is left in the pipe to `to`
+    fn flush<A, B>(&mut self, from: &GenericEvented<A>, to: &GenericEvented<B>) -> io::Result<usize>
+        where A: Evented + Debug + AsRawFd,
+              B: Evented + Debug + AsRawFd
+    {
+        let mut total = 0;
+
+        while self.pending > 0 {
+            let pending = self.pending;
+
+            match try!(self.forward(from, to, pending)) {
+                0 => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write the spliced data"))
+                }
+                n => total += n,
+            }
+        }
+
+        Ok(total)
+    }
+
+    // Fills the pipe if it's empty and moves up to `len` bytes of it to `to`
+    fn forward<A, B>(&mut self,
+                     from: &GenericEvented<A>,
+                     to: &GenericEvented<B>,
+                     len: usize)
+                     -> io::Result<usize>
+        where A: Evented + Debug + AsRawFd,
+              B: Evented + Debug + AsRawFd
+    {
+        while self.pending == 0 {
+            match splice_fd(from.as_raw_fd(), self.pipe.writer, len) {
+                Ok(0) => return Ok(0),
+                Ok(n) => self.pending = n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    try!(from.wait_readable());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let len = cmp::min(len, self.pending);
+
+        // Like `sendfile()`, splicing into a socket whose peer has gone away raises `SIGPIPE`
+        loop {
+            match net::without_sigpipe(|| splice_fd(self.pipe.reader, to.as_raw_fd(), len)) {
+                Ok(n) => {
+                    self.pending -= n;
+                    return Ok(n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    try!(to.wait_writable());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use net::{TcpListener, TcpStream, UnixStream};
+    use scheduler::Scheduler;
+
+    use super::{splice, Splicer};
+
+    #[test]
+    fn splice_between_streams() {
+        Scheduler::new()
+            .run(|| {
+                let (mut client, upstream) = UnixStream::pair().unwrap();
+                let (downstream, mut server) = UnixStream::pair().unwrap();
+
+                client.write_all(b"zero copy").unwrap();
+                drop(client);
+
+                assert_eq!(splice(&upstream, &downstream, 4).unwrap(), 4);
+                assert_eq!(splice(&upstream, &downstream, 1024).unwrap(), 5);
+                drop(downstream);
+
+                let mut buf = Vec::new();
+                server.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf[..], b"zero copy");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn splicer_reuses_pipe_and_reports_errors() {
+        Scheduler::new()
+            .run(|| {
+                let (mut client, upstream) = UnixStream::pair().unwrap();
+                let (downstream, server) = UnixStream::pair().unwrap();
+                let mut splicer = Splicer::new().unwrap();
+
+                client.write_all(b"abcdef").unwrap();
+                assert_eq!(splicer.splice(&upstream, &downstream, 3).unwrap(), 3);
+                assert_eq!(splicer.splice(&upstream, &downstream, 3).unwrap(), 3);
+
+                // An error which followed partial progress is returned by the next call
+                splicer.error = Some(io::Error::new(io::ErrorKind::Other, "failure"));
+                assert_eq!(splicer.splice(&upstream, &downstream, 3).unwrap_err().kind(),
+                           io::ErrorKind::Other);
+
+                client.write_all(b"g").unwrap();
+                drop(server);
+                let err = splicer.splice(&upstream, &downstream, 1).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn splice_to_closed_peer() {
+        Scheduler::new()
+            .run(|| {
+                let (mut client, upstream) = UnixStream::pair().unwrap();
+
+                let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+                let downstream = TcpStream::connect(acceptor.local_addr().unwrap()).unwrap();
+                drop(acceptor.accept().unwrap());
+
+                // The first writes may still succeed before the peer has reset the connection
+                client.write_all(&[0u8; 1024]).unwrap();
+                let mut result = splice(&upstream, &downstream, 1024);
+
+                while result.is_ok() {
+                    ::sleep_ms(10);
+                    client.write_all(&[0u8; 1024]).unwrap();
+                    result = splice(&upstream, &downstream, 1024);
+                }
+
+                let err = result.unwrap_err();
+                assert!(err.kind() == io::ErrorKind::BrokenPipe ||
+                        err.kind() == io::ErrorKind::ConnectionReset,
+                        "unexpected error: {:?}",
+                        err);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn splice_reports_error_after_short_write() {
+        Scheduler::new()
+            .run(|| {
+                let (mut client, upstream) = UnixStream::pair().unwrap();
+                let (downstream, mut server) = UnixStream::pair().unwrap();
+                let len = 4 * 1024 * 1024;
+
+                // More data than the sockets can buffer, so that the writes to `downstream`
+                // stay short until the reader goes away after the first chunk
+                let writer = Scheduler::spawn(move || {
+                    let _ = client.write_all(&vec![0u8; len]);
+                });
+
+                let reader = Scheduler::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    server.read_exact(&mut buf).unwrap();
+                });
+
+                let err = splice(&upstream, &downstream, len).unwrap_err();
+                assert!(err.kind() == io::ErrorKind::BrokenPipe ||
+                        err.kind() == io::ErrorKind::ConnectionReset,
+                        "unexpected error: {:?}",
+                        err);
+
+                reader.join().unwrap();
+                drop(upstream);
+                writer.join().unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Standard streams which don't block the workers
+//!
+//! The standard streams are shared with the parent process and usually are terminals, which is
+//! why they aren't switched into non-blocking mode. Instead every operation is run on the
+//! thread pool of `Scheduler::spawn_blocking()`, while only the calling coroutine is parked.
+//! Since this is comparatively expensive, small writes should be buffered, e.g. using
+//! `coio::io::BufWriter`. Outside of a coroutine all operations are done on the current thread.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use blocking;
+
+/// A handle to the standard input of the process, see `stdin()`
+pub struct Stdin(());
+
+/// Returns a handle to the standard input of the process
+pub fn stdin() -> Stdin {
+    Stdin(())
+}
+
+impl Stdin {
+    /// Reads a line including the trailing newline into `buf`
+    pub fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        let line = try!(blocking(|| {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line).map(|_| line)
+        }));
+
+        buf.push_str(&line);
+        Ok(line.len())
+    }
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len();
+        let data = try!(blocking(move || {
+            let mut data = vec![0; len];
+            io::stdin().read(&mut data).map(|len| {
+                data.truncate(len);
+                data
+            })
+        }));
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl fmt::Debug for Stdin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Stdin { .. }")
+    }
+}
+
+/// A handle to the standard output of the process, see `stdout()`
+pub struct Stdout(());
+
+/// Returns a handle to the standard output of the process
+pub fn stdout() -> Stdout {
+    Stdout(())
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = buf.to_vec();
+        blocking(move || io::stdout().write(&data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        blocking(|| io::stdout().flush())
+    }
+}
+
+impl fmt::Debug for Stdout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Stdout { .. }")
+    }
+}
+
+/// A handle to the standard error of the process, see `stderr()`
+pub struct Stderr(());
+
+/// Returns a handle to the standard error of the process
+pub fn stderr() -> Stderr {
+    Stderr(())
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = buf.to_vec();
+        blocking(move || io::stderr().write(&data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        blocking(|| io::stderr().flush())
+    }
+}
+
+impl fmt::Debug for Stderr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Stderr { .. }")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn stdio_from_coroutine() {
+        Scheduler::new()
+            .run(|| {
+                assert_eq!(stdout().write(b"").unwrap(), 0);
+                stdout().flush().unwrap();
+                stderr().flush().unwrap();
+            })
+            .unwrap();
+
+        // Outside of a coroutine the streams are used directly
+        stdout().flush().unwrap();
+    }
+}
@@ -6,15 +6,135 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::any::Any;
 use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use sync::mono_barrier::MonoBarrier;
 
+/// The error returned by `JoinHandle::join_timeout()` if the coroutine didn't finish in time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for Timeout {
+    fn description(&self) -> &str {
+        "coroutine didn't finish in time"
+    }
+}
+
+/// What the panic hook of the `Scheduler` recorded about a panicking coroutine
+#[doc(hidden)]
+pub struct CapturedPanic {
+    // The address of the payload, which tells the panic apart from others the coroutine has
+    // caught itself in the meantime
+    pub payload: usize,
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub backtrace: Option<String>,
+}
+
+/// Describes where a coroutine panicked, see `JoinHandle::join_report()`
+pub struct PanicReport {
+    payload: Box<Any + Send + 'static>,
+    name: Option<String>,
+    location: Option<String>,
+    backtrace: Option<String>,
+}
+
+impl PanicReport {
+    fn new(payload: Box<Any + Send + 'static>, captured: Option<CapturedPanic>) -> PanicReport {
+        let captured = captured.unwrap_or(CapturedPanic {
+            payload: 0,
+            name: None,
+            location: None,
+            backtrace: None,
+        });
+
+        PanicReport {
+            payload: payload,
+            name: captured.name,
+            location: captured.location,
+            backtrace: captured.backtrace,
+        }
+    }
+
+    /// Returns the name of the coroutine
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(String::as_str)
+    }
+
+    /// Returns the message passed to `panic!()`, if the payload is a string
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&'static str>() {
+            Some(msg) => Some(*msg),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+
+    /// Returns the source location of the panic as `file:line`
+    ///
+    /// This is `None` if the coroutine didn't panic by itself, e.g. if it has been aborted.
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_ref().map(String::as_str)
+    }
+
+    /// Returns the backtrace of the panicking coroutine
+    ///
+    /// Only captured if coio is built with the `backtrace` feature.
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_ref().map(String::as_str)
+    }
+
+    /// Returns the payload, which `JoinHandle::join()` would have returned
+    pub fn into_payload(self) -> Box<Any + Send + 'static> {
+        self.payload
+    }
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "coroutine `{}` panicked at '{}'",
+                    self.name().unwrap_or("<unnamed>"),
+                    self.message().unwrap_or("Box<Any>")));
+
+        if let Some(location) = self.location() {
+            try!(write!(f, ", {}", location));
+        }
+
+        if let Some(backtrace) = self.backtrace() {
+            try!(write!(f, "\n{}", backtrace));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PanicReport")
+         .field("name", &self.name)
+         .field("message", &self.message())
+         .field("location", &self.location)
+         .field("backtrace", &self.backtrace.is_some())
+         .finish()
+    }
+}
+
 struct JoinHandleInner<T> {
     barrier: MonoBarrier,
     data: UnsafeCell<Option<thread::Result<T>>>,
+    panic: UnsafeCell<Option<CapturedPanic>>,
 }
 
 unsafe impl<T: Send> Send for JoinHandleInner<T> {}
@@ -25,6 +145,7 @@ impl<T> JoinHandleInner<T> {
         JoinHandleInner {
             barrier: MonoBarrier::new(),
             data: UnsafeCell::new(None),
+            panic: UnsafeCell::new(None),
         }
     }
 }
@@ -34,6 +155,12 @@ pub struct JoinHandleSender<T> {
 }
 
 impl<T> JoinHandleSender<T> {
+    /// Attaches the details of a panic to the result, must be called before `push()`
+    pub fn capture_panic(&self, captured: CapturedPanic) {
+        let panic = unsafe { &mut *self.inner.panic.get() };
+        *panic = Some(captured);
+    }
+
     pub fn push(self, result: thread::Result<T>) {
         let data = unsafe { &mut *self.inner.data.get() };
         *data = Some(result);
@@ -47,7 +174,51 @@ pub struct JoinHandleReceiver<T> {
 }
 
 impl<T> JoinHandleReceiver<T> {
+    /// Returns true if the result has been pushed, no matter whether it was received already
+    pub fn is_finished(&self) -> bool {
+        self.received || self.inner.barrier.is_ready()
+    }
+
+    /// Returns the result if it has been pushed already, without blocking
+    pub fn try_pop(&mut self) -> Option<thread::Result<T>> {
+        if self.received || !self.inner.barrier.is_ready() {
+            return None;
+        }
+
+        // Returns immediately, since the barrier is ready
+        self.inner.barrier.wait().unwrap();
+        let data = unsafe { &mut *self.inner.data.get() };
+        self.received = true;
+        data.take()
+    }
+
+    /// Waits at most `timeout` for the result to be pushed
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Result<thread::Result<T>, Timeout> {
+        assert!(!self.received, "result has already been received");
+
+        if !self.inner.barrier.wait_timeout(timeout).unwrap() {
+            return Err(Timeout);
+        }
+
+        let data = unsafe { &mut *self.inner.data.get() };
+        self.received = true;
+        Ok(data.take().unwrap())
+    }
+
+    /// Like `pop()`, but describes a panic using its `PanicReport`
+    pub fn pop_report(self) -> Result<T, PanicReport> {
+        let inner = self.inner.clone();
+
+        self.pop().map_err(|payload| {
+            // The sender has pushed the result and is thus done with the panic as well
+            let captured = unsafe { (*inner.panic.get()).take() };
+            PanicReport::new(payload, captured)
+        })
+    }
+
     pub fn pop(mut self) -> thread::Result<T> {
+        assert!(!self.received, "result has already been received");
+
         self.inner.barrier.wait().unwrap();
         let data = unsafe { &mut *self.inner.data.get() };
         self.received = true;
@@ -55,6 +226,13 @@ impl<T> JoinHandleReceiver<T> {
     }
 }
 
+/// Identifies a panic payload for `CapturedPanic`
+#[doc(hidden)]
+#[inline]
+pub fn payload_address(payload: &(Any + Send)) -> usize {
+    payload as *const (Any + Send) as *const () as usize
+}
+
 pub fn handle_pair<T>() -> (JoinHandleSender<T>, JoinHandleReceiver<T>) {
     let inner = Arc::new(JoinHandleInner::new());
     let sender = JoinHandleSender { inner: inner.clone() };
@@ -69,6 +247,8 @@ pub fn handle_pair<T>() -> (JoinHandleSender<T>, JoinHandleReceiver<T>) {
 mod test {
     use super::*;
 
+    use std::time::Duration;
+
     use scheduler::Scheduler;
 
     #[test]
@@ -110,6 +290,43 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn test_join_handle_try_pop() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, mut rx) = handle_pair();
+
+                assert!(!rx.is_finished());
+                assert!(rx.try_pop().is_none());
+
+                tx.push(Ok(1));
+
+                assert!(rx.is_finished());
+                assert_eq!(rx.try_pop().unwrap().unwrap(), 1);
+                assert!(rx.is_finished());
+                assert!(rx.try_pop().is_none());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_handle_pop_timeout() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, mut rx) = handle_pair();
+
+                assert_eq!(rx.pop_timeout(Duration::from_millis(10)).unwrap_err(), Timeout);
+
+                Scheduler::spawn(move || {
+                    Scheduler::instance().unwrap().sleep_ms(10).unwrap();
+                    tx.push(Ok(1));
+                });
+
+                assert_eq!(rx.pop_timeout(Duration::from_secs(10)).unwrap().unwrap(), 1);
+            })
+            .unwrap();
+    }
+
     #[test]
     fn test_join_handle_basic3() {
         Scheduler::new()
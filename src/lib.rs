@@ -24,30 +24,63 @@ extern crate log;
 
 extern crate context;
 extern crate deque;
+extern crate libc;
 extern crate mio;
 extern crate rand;
 extern crate slab;
 extern crate linked_hash_map;
+#[cfg(feature = "backtrace")]
+extern crate backtrace;
 
 #[cfg(test)]
 extern crate env_logger;
 
+// Logs a message of the I/O path at the trace level. If tracing has been enabled for the
+// current coroutine the message is logged at the debug level instead and prefixed with the
+// coroutine, so that a single coroutine can be followed without enabling tracing globally.
+// Both levels imply that debug messages are enabled, otherwise the coroutine isn't looked up.
+macro_rules! io_trace {
+    ($($arg:tt)*) => (
+        if log_enabled!(::log::LogLevel::Debug) {
+            match ::traced_coroutine() {
+                Some(coro) => debug!("{}: {}", coro, format_args!($($arg)*)),
+                None => trace!($($arg)*),
+            }
+        }
+    )
+}
+
+pub mod fs;
+pub mod io;
 pub mod join_handle;
 pub mod net;
 pub mod options;
+pub mod os;
 pub mod promise;
 pub mod scheduler;
+pub mod scope;
 pub mod sync;
+#[macro_use]
+pub mod task_local;
 
-pub use options::Options;
+// Uses task_local!() and thus has to come after it
+pub mod coroutine;
+
+pub use coroutine::Builder;
+pub use options::{Options, Priority, ResumeContext};
 pub use promise::Promise;
+pub use runtime::cancel::Cancelled;
 pub use scheduler::{Scheduler, JoinHandle};
 
-mod coroutine;
 mod runtime;
 
+use runtime::Processor;
+use runtime::cancel;
+
+use std::io;
+use std::panic;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(debug_assertions)]
 use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
@@ -62,6 +95,9 @@ pub fn spawn<F, T>(f: F) -> JoinHandle<T>
 }
 
 /// Spawn a new Coroutine with options
+///
+/// `Builder` offers the same `Options` through chained methods and reports failures to create
+/// the coroutine as an error.
 #[inline]
 pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
     where F: FnOnce() -> T + Send + 'static,
@@ -70,19 +106,77 @@ pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
     Scheduler::spawn_opts(f, opts)
 }
 
+// Upper bound for a single sleep in spawn_at(), after which the system clock is checked again
+const SPAWN_AT_RECHECK_MS: u64 = 1_000;
+
+/// Spawn a new Coroutine which starts running `f` at the wall-clock time `when`
+///
+/// `f` is started right away if `when` lies in the past. The system clock might be adjusted
+/// while waiting, which is why the remaining time is recomputed at least once per second:
+/// If the clock jumps forward `f` starts at most a second after the clock has passed `when`,
+/// and if it jumps back the wait is prolonged accordingly. Cancelling the returned handle
+/// starts `f` immediately, so that it can react to `is_cancelled()`.
+pub fn spawn_at<F, T>(when: SystemTime, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    Scheduler::spawn(move || {
+        let recheck = Duration::from_millis(SPAWN_AT_RECHECK_MS);
+
+        while !is_cancelled() {
+            let remaining = match when.duration_since(SystemTime::now()) {
+                Ok(remaining) if remaining > Duration::new(0, 0) => remaining,
+                _ => break,
+            };
+
+            sleep(if remaining < recheck {
+                remaining
+            } else {
+                recheck
+            });
+        }
+
+        f()
+    })
+}
+
+// Runs `f` using `Scheduler::spawn_blocking()` and waits for its result,
+// or runs it right away outside of a coroutine. Panics of `f` are propagated.
+fn blocking<F, T>(f: F) -> T
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    if Scheduler::instance().is_none() {
+        return f();
+    }
+
+    match Scheduler::spawn_blocking(f).join() {
+        Ok(ret) => ret,
+        Err(err) => panic::resume_unwind(err),
+    }
+}
+
 /// Give up the CPU
+///
+/// Unwinds the current coroutine afterwards if it has been aborted.
 #[inline]
 pub fn sched() {
-    Scheduler::sched()
+    Scheduler::sched();
+    cancel::unwind_if_aborted();
 }
 
 /// Put the current coroutine to sleep for the specific amount of time
+///
+/// See `sleep()`.
 #[inline]
 pub fn sleep_ms(ms: u64) {
     sleep(Duration::from_millis(ms))
 }
 
 /// Put the current coroutine to sleep for the specific amount of time
+///
+/// Returns early if the current coroutine is cancelled.
+/// Outside of a coroutine this blocks the calling thread using `std::thread::sleep()`.
 #[inline]
 pub fn sleep(dur: Duration) {
     match Scheduler::instance() {
@@ -91,45 +185,152 @@ pub fn sleep(dur: Duration) {
     }
 }
 
-/// Coroutine configuration. Provides detailed control over
-/// the properties and behavior of new coroutines.
-pub struct Builder {
-    opts: Options,
+/// Put the current coroutine to sleep until the specific point in time
+///
+/// Returns immediately if the `deadline` already lies in the past and never wakes up before it,
+/// but returns early if the current coroutine is cancelled.
+/// Outside of a coroutine this blocks the calling thread using `std::thread::sleep()`.
+#[inline]
+pub fn sleep_until(deadline: Instant) {
+    match Scheduler::instance() {
+        Some(s) => s.sleep_until(deadline).unwrap(),
+        None => {
+            let now = Instant::now();
+
+            if deadline > now {
+                thread::sleep(deadline.duration_since(now));
+            }
+        }
+    }
 }
 
-impl Builder {
-    /// Generates the base configuration for spawning a coroutine,
-    // from which configuration methods can be chained.
-    #[inline]
-    pub fn new() -> Builder {
-        Builder { opts: Options::new() }
+/// Yields the current coroutine if it has used up its time slice
+///
+/// This is a cheap check which CPU-bound loops should call regularly, so that they don't keep
+/// other coroutines on the same worker from running. It only ever yields if preemption has been
+/// enabled using `Scheduler::preempt_interval()`.
+#[inline]
+pub fn preempt_point() {
+    let preempt = Processor::current().map_or(false, |p| p.preemption_requested());
+
+    if preempt {
+        Scheduler::sched();
     }
+}
+
+/// Returns the priority of the current coroutine
+///
+/// Outside of a coroutine `Priority::Normal` is returned.
+#[inline]
+pub fn current_priority() -> Priority {
+    Processor::current()
+        .and_then(|mut p| p.current().map(|coro| coro.priority()))
+        .unwrap_or(Priority::Normal)
+}
 
-    /// Sets the size of the stack for the new coroutine.
-    #[inline]
-    pub fn stack_size(mut self, stack_size: usize) -> Builder {
-        self.opts.stack_size = stack_size;
-        self
+/// Sets the priority of the current coroutine
+///
+/// The new priority takes effect the next time the coroutine is enqueued,
+/// e.g. after calling `sched()` or after it has been woken up.
+#[inline]
+pub fn set_current_priority(priority: Priority) {
+    if let Some(mut p) = Processor::current() {
+        if let Some(coro) = p.current() {
+            coro.set_priority(priority);
+        }
     }
+}
 
-    /// Names the coroutine-to-be. Currently the name
-    // is used for identification only in panic messages.
-    #[inline]
-    pub fn name(mut self, name: String) -> Builder {
-        self.opts.name = Some(name);
-        self
+/// Returns true if the current coroutine has been cancelled using `JoinHandle::cancel()`
+#[inline]
+pub fn is_cancelled() -> bool {
+    Processor::current()
+        .and_then(|mut p| {
+            p.current().and_then(|coro| coro.cancel_token().map(|t| t.is_cancelled()))
+        })
+        .unwrap_or(false)
+}
+
+/// Returns a `Cancelled` error if the current coroutine has been cancelled
+///
+/// This allows long running computations to stop at well defined points:
+///
+/// ```ignore
+/// for item in items {
+///     try!(coio::check_cancel());
+///     process(item);
+/// }
+/// ```
+#[inline]
+pub fn check_cancel() -> io::Result<()> {
+    if is_cancelled() {
+        cancel::unwind_if_aborted();
+        Err(cancel::cancelled_error())
+    } else {
+        Ok(())
     }
+}
 
-    /// Spawn a new coroutine
-    #[inline]
-    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
-        where F: FnOnce() -> T + Send + 'static,
-              T: Send + 'static
-    {
-        Scheduler::spawn_opts(f, self.opts)
+/// Returns true if verbose I/O tracing is enabled for the current coroutine
+#[inline]
+pub fn current_tracing() -> bool {
+    Processor::current()
+        .and_then(|mut p| p.current().map(|coro| coro.is_tracing()))
+        .unwrap_or(false)
+}
+
+/// Enables or disables verbose I/O tracing for the current coroutine
+///
+/// While enabled, the I/O operations of the coroutine are logged at the debug level
+/// instead of the trace level, each message prefixed with the coroutine's name.
+/// This allows following a single connection using e.g. `RUST_LOG=coio=debug`.
+#[inline]
+pub fn set_current_tracing(enabled: bool) {
+    if let Some(mut p) = Processor::current() {
+        if let Some(coro) = p.current() {
+            coro.set_tracing(enabled);
+        }
     }
 }
 
+// Used by `io_trace!()`
+fn traced_coroutine() -> Option<String> {
+    Processor::current().and_then(|mut p| {
+        p.current().and_then(|coro| {
+            if coro.is_tracing() {
+                Some(format!("{:?}", coro))
+            } else {
+                None
+            }
+        })
+    })
+}
+
+/// An RAII guard which changes the priority of the current coroutine
+/// and restores the previous one when dropped.
+///
+/// Guards can be nested, since every one of them restores the priority it replaced.
+#[must_use]
+pub struct PriorityGuard {
+    previous: Priority,
+}
+
+impl PriorityGuard {
+    /// Sets the priority of the current coroutine to `priority`
+    pub fn new(priority: Priority) -> PriorityGuard {
+        let previous = current_priority();
+        set_current_priority(priority);
+        PriorityGuard { previous: previous }
+    }
+}
+
+impl !Send for PriorityGuard {}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        set_current_priority(self.previous);
+    }
+}
 
 #[cfg(debug_assertions)]
 static GLOBAL_WORK_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -165,6 +366,8 @@ fn global_work_count_get() -> usize {
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, Instant, SystemTime};
+
     use super::*;
 
     #[test]
@@ -175,4 +378,191 @@ mod test {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_sleep_outside_coroutine() {
+        let start = Instant::now();
+        sleep(Duration::from_millis(10));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_priority_guard() {
+        Scheduler::new()
+            .run(|| {
+                assert_eq!(current_priority(), Priority::Normal);
+
+                {
+                    let _outer = PriorityGuard::new(Priority::High);
+                    assert_eq!(current_priority(), Priority::High);
+
+                    {
+                        let _inner = PriorityGuard::new(Priority::Normal);
+                        assert_eq!(current_priority(), Priority::Normal);
+                    }
+
+                    assert_eq!(current_priority(), Priority::High);
+                }
+
+                assert_eq!(current_priority(), Priority::Normal);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_current_tracing() {
+        Scheduler::new()
+            .run(|| {
+                assert!(!current_tracing());
+                set_current_tracing(true);
+                assert!(current_tracing());
+
+                // Tracing is a per-coroutine setting
+                Scheduler::spawn(|| assert!(!current_tracing())).join().unwrap();
+
+                let traced = Builder::new().trace(true).spawn(|| current_tracing()).unwrap();
+                assert!(traced.join().unwrap());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_on_resume() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use runtime::Processor;
+
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                // Emulates a processor-local resource cached by the coroutine
+                let cached = Arc::new(AtomicUsize::new(!0));
+                let first_resumes = Arc::new(AtomicUsize::new(0));
+
+                let handle = {
+                    let cached = cached.clone();
+                    let first_resumes = first_resumes.clone();
+
+                    Builder::new()
+                        .on_resume(move |ctx| {
+                            if ctx.last_processor_id.is_none() {
+                                first_resumes.fetch_add(1, Ordering::SeqCst);
+                            }
+
+                            if ctx.migrated() || ctx.last_processor_id.is_none() {
+                                cached.store(ctx.processor_id, Ordering::SeqCst);
+                            }
+                        })
+                        .spawn(move || {
+                            for _ in 0..1000 {
+                                let id = Processor::current().unwrap().id();
+                                assert_eq!(cached.load(Ordering::SeqCst), id);
+                                Scheduler::sched();
+                            }
+                        })
+                        .unwrap()
+                };
+
+                handle.join().unwrap();
+                assert_eq!(first_resumes.load(Ordering::SeqCst), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_preempt_point() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        Scheduler::new()
+            .with_workers(1)
+            .preempt_interval(Duration::from_millis(10))
+            .run(|| {
+                let stop = Arc::new(AtomicBool::new(false));
+
+                let spinner = {
+                    let stop = stop.clone();
+                    spawn(move || {
+                        while !stop.load(Ordering::SeqCst) {
+                            preempt_point();
+                        }
+                    })
+                };
+
+                // Only runs again, once the spinner on the single worker has been preempted
+                sleep_ms(1);
+                stop.store(true, Ordering::SeqCst);
+
+                spinner.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_builder_reports_stack_failure() {
+        Scheduler::new()
+            .run(|| {
+                let handle = Builder::new()
+                                 .name("named".to_owned())
+                                 .spawn(|| 1);
+                assert_eq!(handle.unwrap().join().unwrap(), 1);
+
+                // Far more address space than can ever be mapped
+                assert!(Builder::new().stack_size(!0 >> 1).spawn(|| ()).is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cancel_sleeping() {
+        Scheduler::new()
+            .run(|| {
+                let handle = spawn(|| {
+                    assert!(!is_cancelled());
+                    sleep(Duration::from_secs(3600));
+                    assert!(check_cancel().is_err());
+                    is_cancelled()
+                });
+
+                // Let the coroutine fall asleep
+                sched();
+
+                let start = Instant::now();
+                handle.cancel();
+                assert!(handle.is_cancelled());
+                assert!(handle.join().unwrap());
+                assert!(start.elapsed() < Duration::from_secs(60));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_spawn_at() {
+        Scheduler::new()
+            .run(|| {
+                let when = SystemTime::now() + Duration::from_millis(100);
+                let started = spawn_at(when, SystemTime::now).join().unwrap();
+                assert!(started >= when);
+
+                // Points in time in the past start right away
+                let start = Instant::now();
+                spawn_at(when, || {}).join().unwrap();
+                assert!(start.elapsed() < Duration::from_secs(1));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sleep_until() {
+        Scheduler::new()
+            .run(|| {
+                let deadline = Instant::now() + Duration::from_millis(100);
+                sleep_until(deadline);
+                assert!(Instant::now() >= deadline);
+
+                // Deadlines in the past return immediately
+                sleep_until(deadline);
+            })
+            .unwrap();
+    }
 }
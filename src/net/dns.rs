@@ -0,0 +1,69 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Name resolution which doesn't block the workers
+//!
+//! The system resolver is blocking, which is why the lookups are run on the thread pool of
+//! `Scheduler::spawn_blocking()`. Only the calling coroutine is parked in the meantime.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use blocking;
+
+/// Resolves `host` to all of its addresses, whose ports are set to 0
+///
+/// Outside of a coroutine the lookup is done on the current thread.
+pub fn lookup_host(host: &str) -> io::Result<Vec<SocketAddr>> {
+    resolve(host, 0)
+}
+
+/// Resolves `host` to all of its addresses, combined with `port`
+///
+/// The returned addresses can be passed to `TcpStream::connect()` or `UdpSocket::bind()` without
+/// another blocking lookup.
+pub fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = host.to_owned();
+    blocking(move || blocking_resolve(&host, port))
+}
+
+fn blocking_resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    (host, port).to_socket_addrs().map(|addrs| addrs.collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_dns_resolve_localhost() {
+        Scheduler::new()
+            .run(|| {
+                let addrs = resolve("localhost", 8080).unwrap();
+                assert!(!addrs.is_empty());
+                assert!(addrs.iter().all(|addr| addr.port() == 8080));
+
+                let addrs = lookup_host("127.0.0.1").unwrap();
+                assert_eq!(addrs, vec!["127.0.0.1:0".parse::<SocketAddr>().unwrap()]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dns_resolve_invalid() {
+        Scheduler::new()
+            .run(|| {
+                assert!(resolve("this-host-does-not-exist.invalid", 80).is_err());
+            })
+            .unwrap();
+    }
+}
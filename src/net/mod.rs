@@ -8,57 +8,231 @@
 
 //! Asynchronous network library
 
+pub mod dns;
 pub mod tcp;
+pub mod tls;
 pub mod udp;
 
+#[cfg(unix)]
+pub mod raw;
 #[cfg(unix)]
 pub mod unix;
 
+#[cfg(unix)]
+mod sockopt;
+
 pub use self::tcp::{TcpListener, TcpStream, Shutdown};
 pub use self::udp::UdpSocket;
+pub use scheduler::{ReadyMode, ReadyType};
 
 #[cfg(unix)]
-pub use self::unix::{UnixListener, UnixStream, UnixSocket};
+pub use self::raw::RawSocket;
+#[cfg(unix)]
+pub use self::tcp::TcpBuilder;
+#[cfg(unix)]
+pub use self::unix::{UCred, UnixAddr, UnixDatagram, UnixListener, UnixStream, UnixSocket};
+
+// For `coio::io::splice()`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[doc(hidden)]
+pub use self::sockopt::without_sigpipe;
 
 use std::fmt::Debug;
 use std::io::{self, Read, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use mio::{Evented, EventSet, Token};
 
-use scheduler::{ReadyStates, ReadyType, Scheduler};
+use runtime::cancel;
+use scheduler::{ReadyMode, ReadyStates, ReadyType, Scheduler};
 
 
+/// A mio `Evented` I/O object which is registered with the Scheduler
+///
+/// All sockets of this crate are built on top of it. It's also exported as
+/// `coio::io::PollEvented` to integrate other `Evented` types, e.g. serial ports:
+/// Their non-blocking operations are retried after parking the coroutine with
+/// `wait_readable()` or `wait_writable()` whenever they fail with `ErrorKind::WouldBlock`.
+/// If `E` implements `Read` or `EventedWrite`, this is done by the `Read` and `Write`
+/// implementations already.
 #[derive(Debug)]
-#[doc(hidden)]
 pub struct GenericEvented<E: Evented + Debug> {
     inner: E,
     ready_states: ReadyStates,
     token: Token,
+
+    // Timeouts of read() and write() in milliseconds, 0 means no timeout
+    read_timeout_ms: AtomicUsize,
+    write_timeout_ms: AtomicUsize,
 }
 
 impl<E: Evented + Debug> GenericEvented<E> {
-    #[doc(hidden)]
+    /// Registers `inner` with the Scheduler for the events in `interest`
+    ///
+    /// Fails if called outside of a coroutine. The registration is edge triggered.
     pub fn new(inner: E, interest: EventSet) -> io::Result<GenericEvented<E>> {
+        GenericEvented::with_mode(inner, interest, ReadyMode::Single)
+    }
+
+    /// Registers `inner` with the Scheduler, distributing its readiness events
+    /// among waiting coroutines according to `mode`.
+    pub fn with_mode(inner: E, interest: EventSet, mode: ReadyMode) -> io::Result<GenericEvented<E>> {
         let scheduler = try!(Scheduler::instance_or_err());
-        let (token, ready_states) = try!(scheduler.register(&inner, interest));
+        let (token, ready_states) = try!(scheduler.register_with_mode(&inner, interest, mode));
 
         Ok(GenericEvented {
             inner: inner,
             ready_states: ready_states,
             token: token,
+
+            read_timeout_ms: AtomicUsize::new(0),
+            write_timeout_ms: AtomicUsize::new(0),
         })
     }
+
+    /// Parks the current coroutine until the object becomes readable
+    ///
+    /// Returns immediately if a readiness event arrived since it has last been waited for.
+    /// Since events are edge triggered, the object has to be read from until it fails with
+    /// `ErrorKind::WouldBlock` before waiting again, or this might block forever.
+    pub fn wait_readable(&self) -> io::Result<()> {
+        self.ready_states.wait(ReadyType::Readable)
+    }
+
+    /// Parks the current coroutine until the object becomes writable, see `wait_readable()`
+    pub fn wait_writable(&self) -> io::Result<()> {
+        self.ready_states.wait(ReadyType::Writable)
+    }
+
+    /// Like `wait_readable()`, but fails with `ErrorKind::TimedOut` after `timeout`
+    pub fn wait_readable_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.ready_states.wait_timeout(ReadyType::Readable, Some(timeout))
+    }
+
+    /// Like `wait_writable()`, but fails with `ErrorKind::TimedOut` after `timeout`
+    pub fn wait_writable_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.ready_states.wait_timeout(ReadyType::Writable, Some(timeout))
+    }
+
+    /// Gets a reference to the wrapped I/O object
+    pub fn get_ref(&self) -> &E {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the wrapped I/O object
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    // Parks until the source is ready for `ready_type`, or fails with `TimedOut` after `deadline`
+    fn wait_until(&self, ready_type: ReadyType, deadline: Option<Instant>) -> io::Result<()> {
+        wait_until(&self.ready_states, ready_type, deadline)
+    }
+}
+
+fn wait_until(ready_states: &ReadyStates,
+              ready_type: ReadyType,
+              deadline: Option<Instant>)
+              -> io::Result<()> {
+    let timeout = deadline.map(|deadline| {
+        let now = Instant::now();
+
+        if deadline > now {
+            deadline.duration_since(now)
+        } else {
+            Duration::new(0, 0)
+        }
+    });
+
+    ready_states.wait_timeout(ready_type, timeout)
+}
+
+// Runs the non-blocking operation `op` of the source registered as `token`, parking the
+// coroutine until the source is ready for `ready_type` whenever it fails with `WouldBlock`.
+// `NotConnected` is retried as well, since sockets return it while still connecting.
+// Waiting fails with `TimedOut` once `deadline` has passed.
+//
+// This takes the parts of the `GenericEvented` instead of `&self`, so that `op` may borrow
+// the wrapped object mutably.
+fn retry_io<F, R>(token: Token,
+                  ready_states: &ReadyStates,
+                  name: &str,
+                  ready_type: ReadyType,
+                  deadline: Option<Instant>,
+                  mut op: F)
+                  -> io::Result<R>
+    where F: FnMut() -> io::Result<R>,
+          R: Debug
+{
+    let mut sync_guard = SyncGuard::new();
+
+    loop {
+        match op() {
+            Ok(ret) => {
+                io_trace!("GenericEvented({:?}): {}() => Ok({:?})", token, name, ret);
+                return Ok(ret);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                io_trace!("GenericEvented({:?}): {}() => WouldBlock", token, name);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
+                io_trace!("GenericEvented({:?}): {}() => NotConnected", token, name);
+            }
+            Err(err) => {
+                io_trace!("GenericEvented({:?}): {}() => Err(..)", token, name);
+                return Err(err);
+            }
+        }
+
+        io_trace!("GenericEvented({:?}): wait({:?})", token, ready_type);
+        try!(sync_guard.waited(wait_until(ready_states, ready_type, deadline)));
+    }
+}
+
+// Stores a timeout set by `set_read_timeout()` or `set_write_timeout()`.
+// Rejects a zero duration just like std does, since it would be indistinguishable from `None`.
+fn store_timeout(slot: &AtomicUsize, timeout: Option<Duration>) -> io::Result<()> {
+    let ms = match timeout {
+        Some(d) if d.as_secs() == 0 && d.subsec_nanos() == 0 => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "cannot set a 0 duration timeout"));
+        }
+        // Rounded up, so that sub-millisecond timeouts don't turn into "no timeout"
+        Some(d) => d.as_secs() as usize * 1_000 + (d.subsec_nanos() as usize + 999_999) / 1_000_000,
+        None => 0,
+    };
+
+    slot.store(ms, Ordering::Relaxed);
+    Ok(())
+}
+
+fn load_timeout(slot: &AtomicUsize) -> Option<Duration> {
+    match slot.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+// Returns the point in time at which an operation with the timeout in `slot` fails
+#[inline]
+fn timeout_deadline(slot: &AtomicUsize) -> Option<Instant> {
+    load_timeout(slot).map(|timeout| Instant::now() + timeout)
 }
 
 impl<E: Evented + Debug> Drop for GenericEvented<E> {
     fn drop(&mut self) {
         let scheduler = Scheduler::instance().unwrap();
-        scheduler.deregister(&self.inner, self.token).unwrap();
+
+        if let Err(err) = scheduler.deregister(&self.inner, self.token, &self.ready_states) {
+            panic!("failed to deregister {:?}: {}", self.inner, err);
+        }
     }
 }
 
@@ -78,86 +252,165 @@ impl<E: Evented + Debug> DerefMut for GenericEvented<E> {
 
 impl<E: Evented + Debug + Read> Read for GenericEvented<E> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+        self.read_with_deadline(buf, deadline)
+    }
+}
 
-        loop {
-            match self.inner.read(buf) {
-                Ok(len) => {
-                    trace!("GenericEvented({:?}): read() => Ok({})", self.token, len);
-                    return Ok(len);
+impl<E: Evented + Debug + Read> GenericEvented<E> {
+    /// Reads exactly `buf.len()` bytes, failing with `ErrorKind::TimedOut` if that takes
+    /// longer than `timeout` in total
+    ///
+    /// This bounds the time spent waiting for a complete message, while the read timeout
+    /// (which is ignored here) only bounds the time between two chunks of it. Just like with
+    /// `read_exact()` the contents of `buf` are unspecified if an error is returned.
+    pub fn read_exact_timeout(&mut self, mut buf: &mut [u8], timeout: Duration) -> io::Result<()> {
+        let deadline = Some(Instant::now() + timeout);
+
+        while !buf.is_empty() {
+            match self.read_with_deadline(buf, deadline) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "failed to fill whole buffer"));
                 }
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    trace!("GenericEvented({:?}): read() => WouldBlock", self.token);
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
-                    trace!("GenericEvented({:?}): read() => NotConnected", self.token);
-                }
-                Err(err) => {
-                    trace!("GenericEvented({:?}): read() => Err(..)", self.token);
-                    return Err(err);
+                Ok(len) => {
+                    let tmp = buf;
+                    buf = &mut tmp[len..];
                 }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
             }
+        }
 
-            trace!("GenericEvented({:?}): wait(Readable)", self.token);
-            self.ready_states.wait(ReadyType::Readable);
-            sync_guard.disarm();
+        Ok(())
+    }
+
+    fn read_with_deadline(&mut self,
+                          buf: &mut [u8],
+                          deadline: Option<Instant>)
+                          -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry_io(self.token,
+                 &self.ready_states,
+                 "read",
+                 ReadyType::Readable,
+                 deadline,
+                 || inner.read(buf))
+    }
+}
+
+/// An I/O object which can be written to through a `GenericEvented`
+///
+/// Sockets override `nosignal_write()`, so that writing to a connection whose peer has gone away
+/// fails with `BrokenPipe` instead of raising a `SIGPIPE`, which would terminate the process.
+/// Other types can use the default methods, which simply call `write()`.
+pub trait EventedWrite: Write {
+    fn nosignal_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    /// Writes several buffers at once. By default only the first non-empty one is written.
+    fn nosignal_write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.nosignal_write(buf),
+            None => Ok(0),
         }
     }
 }
 
-impl<E: Evented + Debug + Write> Write for GenericEvented<E> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut sync_guard = SyncGuard::new();
+// Stops writes to `io` from raising `SIGPIPE` on platforms where sockets don't have to be
+// written using `MSG_NOSIGNAL`. Has to be called for every new socket.
+#[cfg(unix)]
+fn disable_sigpipe<E: AsRawFd>(io: &E) -> io::Result<()> {
+    sockopt::set_nosigpipe(io.as_raw_fd())
+}
 
-        loop {
-            match self.inner.write(buf) {
-                Ok(len) => {
-                    trace!("GenericEvented({:?}): write() => Ok({})", self.token, len);
-                    return Ok(len);
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    trace!("GenericEvented({:?}): write() => WouldBlock", self.token);
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
-                    trace!("GenericEvented({:?}): write() => NotConnected", self.token);
-                }
-                Err(err) => {
-                    trace!("GenericEvented({:?}): write() => Err(..)", self.token);
-                    return Err(err);
-                }
-            }
+#[cfg(not(unix))]
+fn disable_sigpipe<E>(_: &E) -> io::Result<()> {
+    Ok(())
+}
 
-            trace!("GenericEvented({:?}): wait(Writable)", self.token);
-            self.ready_states.wait(ReadyType::Writable);
-            sync_guard.disarm();
-        }
+impl<E: Evented + Debug + EventedWrite> Write for GenericEvented<E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+        self.write_with_deadline(buf, deadline)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+        let inner = &mut self.inner;
+        retry_io(self.token,
+                 &self.ready_states,
+                 "flush",
+                 ReadyType::Writable,
+                 deadline,
+                 || inner.flush())
+    }
+}
 
-        loop {
-            match self.inner.flush() {
-                Ok(()) => {
-                    trace!("GenericEvented({:?}): write() => Ok(())", self.token);
-                    return Ok(());
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    trace!("GenericEvented({:?}): flush() => WouldBlock", self.token);
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
-                    trace!("GenericEvented({:?}): flush() => NotConnected", self.token);
-                }
-                Err(err) => {
-                    trace!("GenericEvented({:?}): flush() => Err(..)", self.token);
-                    return Err(err);
+impl<E: Evented + Debug + EventedWrite> GenericEvented<E> {
+    /// Writes all of `buf`, failing with `ErrorKind::TimedOut` if that takes longer
+    /// than `timeout` in total
+    ///
+    /// The write timeout is ignored here, see `read_exact_timeout()`. If an error is
+    /// returned, an unspecified part of `buf` has been written.
+    pub fn write_all_timeout(&mut self, mut buf: &[u8], timeout: Duration) -> io::Result<()> {
+        let deadline = Some(Instant::now() + timeout);
+
+        while !buf.is_empty() {
+            match self.write_with_deadline(buf, deadline) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write whole buffer"));
                 }
+                Ok(len) => buf = &buf[len..],
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
             }
-
-            trace!("GenericEvented({:?}): wait(Writable)", self.token);
-            self.ready_states.wait(ReadyType::Writable);
-            sync_guard.disarm();
         }
+
+        Ok(())
+    }
+
+    fn write_with_deadline(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry_io(self.token,
+                 &self.ready_states,
+                 "write",
+                 ReadyType::Writable,
+                 deadline,
+                 || inner.nosignal_write(buf))
+    }
+}
+
+#[cfg(unix)]
+impl<E: Evented + Debug + Read + AsRawFd> GenericEvented<E> {
+    /// Like `read()`, but reads into several buffers at once, filling them in order
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+        let fd = self.inner.as_raw_fd();
+        retry_io(self.token,
+                 &self.ready_states,
+                 "read_vectored",
+                 ReadyType::Readable,
+                 deadline,
+                 || sockopt::readv(fd, bufs))
+    }
+}
+
+impl<E: Evented + Debug + EventedWrite> GenericEvented<E> {
+    /// Like `write()`, but gathers the data from several buffers in a single call
+    ///
+    /// Just like `write()` it might write only a part of the data.
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+        let inner = &mut self.inner;
+        retry_io(self.token,
+                 &self.ready_states,
+                 "write_vectored",
+                 ReadyType::Writable,
+                 deadline,
+                 || inner.nosignal_write_vectored(bufs))
     }
 }
 
@@ -168,12 +421,112 @@ impl<E: Evented + Debug + AsRawFd> AsRawFd for GenericEvented<E> {
     }
 }
 
+/// A plain C type which socket options can be read into, see `GenericEvented::get_opt()`
+///
+/// It's implemented for the integer types, `libc::linger` and `libc::timeval`. Implementing it
+/// for other types is unsafe, since they must be valid for any bit pattern the kernel writes.
+#[cfg(unix)]
+pub unsafe trait SockOptValue: Copy {}
+
+#[cfg(unix)]
+unsafe impl SockOptValue for u8 {}
+#[cfg(unix)]
+unsafe impl SockOptValue for i32 {}
+#[cfg(unix)]
+unsafe impl SockOptValue for u32 {}
+#[cfg(unix)]
+unsafe impl SockOptValue for i64 {}
+#[cfg(unix)]
+unsafe impl SockOptValue for u64 {}
+#[cfg(unix)]
+unsafe impl SockOptValue for ::libc::linger {}
+#[cfg(unix)]
+unsafe impl SockOptValue for ::libc::timeval {}
+
+#[cfg(unix)]
+impl<E: Evented + Debug + AsRawFd> GenericEvented<E> {
+    /// Sets a socket option which isn't wrapped by this crate
+    ///
+    /// `level` and `name` are the raw constants, e.g. `libc::SOL_SOCKET` and `libc::SO_RCVBUF`,
+    /// and `value` has to be of the C type the option expects, which usually is `c_int`.
+    pub fn set_opt<T: SockOptValue>(&self, level: i32, name: i32, value: T) -> io::Result<()> {
+        sockopt::set(self.as_raw_fd(), level, name, value)
+    }
+
+    /// Gets a socket option which isn't wrapped by this crate, see `set_opt()`
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the size of the option doesn't match `T`.
+    pub fn get_opt<T: SockOptValue>(&self, level: i32, name: i32) -> io::Result<T> {
+        sockopt::get(self.as_raw_fd(), level, name)
+    }
+}
+
+/// An I/O source which can be awaited using `select()`
+pub trait Selectable {
+    #[doc(hidden)]
+    fn ready_states(&self) -> &ReadyStates;
+}
+
+impl<E: Evented + Debug> Selectable for GenericEvented<E> {
+    fn ready_states(&self) -> &ReadyStates {
+        &self.ready_states
+    }
+}
+
+/// Blocks the current coroutine until any of the `sources` is ready
+/// and returns the index of the one which fired.
+///
+/// A source which is already ready at the time of the call is returned immediately.
+/// `ReadyType::Error` and `ReadyType::Hup` events wake up readers and writers as well,
+/// so that the following read or write will report the failure.
+///
+/// Returns a `Cancelled` error if the coroutine is cancelled while waiting.
+///
+/// # Panics
+///
+/// Panics if `sources` is empty or if called outside of a coroutine.
+pub fn select(sources: &[(&Selectable, ReadyType)]) -> io::Result<usize> {
+    let states: Vec<(&ReadyStates, ReadyType)> = sources.iter()
+                                                       .map(|&(s, t)| (s.ready_states(), t))
+                                                       .collect();
+    ReadyStates::select(&states)
+}
 
+/// Waits until at least one of the `sources` is ready and returns the indices of all sources
+/// which are ready by then, in ascending order
+///
+/// Unlike `select()` this allows a single coroutine to serve many mostly idle connections,
+/// by handling every returned source until it would block and polling again afterwards.
+/// The readiness of all returned sources is consumed, just as it is with `select()`.
+///
+/// `None` waits without a timeout, otherwise an `ErrorKind::TimedOut` error is returned
+/// if no source becomes ready in time.
+///
+/// # Panics
+///
+/// Panics if `sources` is empty or if called outside of a coroutine.
+pub fn poll(sources: &[(&Selectable, ReadyType)],
+            timeout: Option<Duration>)
+            -> io::Result<Vec<usize>> {
+    let states: Vec<(&ReadyStates, ReadyType)> = sources.iter()
+                                                       .map(|&(s, t)| (s.ready_states(), t))
+                                                       .collect();
+    ReadyStates::poll(&states, timeout)
+}
+
+
+// Yields once at the end of an I/O operation which never had to wait, so that a coroutine
+// doing a lot of I/O can't starve all others.
+//
+// Aborted coroutines are only unwound while no guard is armed, since the guard would otherwise
+// yield from within its destructor while the stack is unwound.
 struct SyncGuard(bool);
 
 impl SyncGuard {
+    /// Unwinds the current coroutine if it has been aborted and arms the guard otherwise
     #[inline]
     pub fn new() -> SyncGuard {
+        cancel::unwind_if_aborted();
         SyncGuard(true)
     }
 
@@ -181,11 +534,25 @@ impl SyncGuard {
     pub fn disarm(&mut self) {
         self.0 = false;
     }
+
+    /// Disarms the guard after the coroutine has waited for readiness
+    ///
+    /// If the wait failed, because the coroutine has been aborted, it's unwound right away.
+    #[inline]
+    pub fn waited(&mut self, ret: io::Result<()>) -> io::Result<()> {
+        self.disarm();
+
+        if ret.is_err() {
+            cancel::unwind_if_aborted();
+        }
+
+        ret
+    }
 }
 
 impl Drop for SyncGuard {
     fn drop(&mut self) {
-        if self.0 {
+        if self.0 && !thread::panicking() {
             Scheduler::sched();
         }
     }
@@ -210,3 +577,63 @@ fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
                        "could not resolve to any addresses")
     }))
 }
+
+
+#[cfg(test)]
+mod test {
+    use mio::EventSet;
+    use mio::udp::UdpSocket as MioUdpSocket;
+
+    use scheduler::Scheduler;
+    use super::GenericEvented;
+
+    #[test]
+    fn generic_evented_double_deregister() {
+        Scheduler::new()
+            .run(|| {
+                let evented = GenericEvented::new(MioUdpSocket::v4().unwrap(), EventSet::readable())
+                                  .unwrap();
+                let scheduler = Scheduler::instance().unwrap();
+
+                scheduler.deregister(&evented.inner, evented.token, &evented.ready_states)
+                         .unwrap();
+
+                // The token might be reused by a new registration right away
+                let other = GenericEvented::new(MioUdpSocket::v4().unwrap(), EventSet::readable())
+                                .unwrap();
+
+                let err = scheduler.deregister(&evented.inner, evented.token, &evented.ready_states)
+                                   .unwrap_err();
+                assert!(err.to_string().contains("double deregistration"));
+
+                // The other registration must still be intact
+                scheduler.deregister(&other.inner, other.token, &other.ready_states).unwrap();
+
+                ::std::mem::forget(evented);
+                ::std::mem::forget(other);
+            })
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generic_evented_sockopt_escape_hatch() {
+        use libc;
+        use net::UdpSocket;
+
+        Scheduler::new()
+            .run(|| {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+                socket.set_opt(libc::SOL_SOCKET, libc::SO_BROADCAST, 1 as libc::c_int).unwrap();
+                let value: libc::c_int = socket.get_opt(libc::SOL_SOCKET, libc::SO_BROADCAST)
+                                               .unwrap();
+                assert!(value != 0);
+
+                // The option is an int, not a 64 bit value
+                let err = socket.get_opt::<u64>(libc::SOL_SOCKET, libc::SO_BROADCAST).unwrap_err();
+                assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidInput);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Raw IP sockets, e.g. for sending ICMP probes
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+use libc;
+use mio::{EventSet, Evented, PollOpt, Selector, Token};
+use mio::unix::EventedFd;
+
+use scheduler::ReadyType;
+use super::{each_addr, load_timeout, sockopt, store_timeout, timeout_deadline, GenericEvented,
+            SyncGuard};
+
+/// The protocol number of ICMP
+pub const IPPROTO_ICMP: i32 = 1;
+
+/// The protocol number of ICMPv6
+pub const IPPROTO_ICMPV6: i32 = 58;
+
+macro_rules! create_raw_socket {
+    ($inner:expr) => (RawSocket::new($inner, EventSet::readable() | EventSet::writable()));
+}
+
+/// A raw IP socket, which mio doesn't provide
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct MioRawSocket(RawFd);
+
+impl Evented for MioRawSocket {
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        EventedFd(&self.0).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        EventedFd(&self.0).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.0).deregister(selector)
+    }
+}
+
+impl AsRawFd for MioRawSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl FromRawFd for MioRawSocket {
+    unsafe fn from_raw_fd(fd: RawFd) -> MioRawSocket {
+        MioRawSocket(fd)
+    }
+}
+
+impl Drop for MioRawSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A socket sending and receiving IP payloads of a single protocol, e.g. ICMP
+///
+/// Just like `UdpSocket` all methods take `&self`, so that one socket can be shared by many
+/// coroutines, for instance one per probe. Received datagrams are distributed among them.
+pub type RawSocket = GenericEvented<MioRawSocket>;
+
+impl RawSocket {
+    /// Creates a raw IPv4 socket for `protocol`, which usually requires root privileges
+    ///
+    /// Received datagrams include the IPv4 header.
+    pub fn v4(protocol: i32) -> io::Result<RawSocket> {
+        RawSocket::open(libc::AF_INET, libc::SOCK_RAW, protocol)
+    }
+
+    /// Creates a raw IPv6 socket for `protocol`, which usually requires root privileges
+    pub fn v6(protocol: i32) -> io::Result<RawSocket> {
+        RawSocket::open(libc::AF_INET6, libc::SOCK_RAW, protocol)
+    }
+
+    /// Creates an unprivileged ICMP socket for sending echo requests
+    ///
+    /// The kernel fills in the identifier of outgoing echo requests and only delivers the
+    /// matching replies, without the IP header. This is supported on macOS and, depending
+    /// on `net.ipv4.ping_group_range`, on Linux.
+    pub fn icmp_v4() -> io::Result<RawSocket> {
+        RawSocket::open(libc::AF_INET, libc::SOCK_DGRAM, IPPROTO_ICMP)
+    }
+
+    /// Creates an unprivileged ICMPv6 socket, see `icmp_v4()`
+    pub fn icmp_v6() -> io::Result<RawSocket> {
+        RawSocket::open(libc::AF_INET6, libc::SOCK_DGRAM, IPPROTO_ICMPV6)
+    }
+
+    fn open(family: libc::c_int, ty: libc::c_int, protocol: i32) -> io::Result<RawSocket> {
+        let fd = try!(sockopt::socket_with_protocol(family, ty, protocol));
+        create_raw_socket!(MioRawSocket(fd))
+    }
+
+    /// Binds the socket to a local address, e.g. to pick the source of outgoing probes
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let fd = self.as_raw_fd();
+        each_addr(addr, |addr| sockopt::bind(fd, addr))
+    }
+
+    /// Sets the value of the `IP_TTL` option, e.g. for traceroute probes
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_ttl(self.as_raw_fd(), ttl)
+    }
+
+    /// Gets the value of the `IP_TTL` option
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::ttl(self.as_raw_fd())
+    }
+
+    /// Sets the timeout of `recv_from()`, see `UdpSocket::set_read_timeout()`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.read_timeout_ms, timeout)
+    }
+
+    /// Gets the timeout of `recv_from()`
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(load_timeout(&self.read_timeout_ms))
+    }
+
+    /// Sends `buf` as the payload of an IP datagram to `addr`, whose port is ignored
+    pub fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+
+        loop {
+            match sockopt::send_to(self.as_raw_fd(), buf, addr) {
+                Ok(len) => {
+                    io_trace!("RawSocket({:?}): send_to() => Ok({})", self.token, len);
+                    self.ready_states.pass_on(ReadyType::Writable);
+                    return Ok(len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("RawSocket({:?}): send_to() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("RawSocket({:?}): send_to() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("RawSocket({:?}): wait(Writable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Writable, deadline)));
+        }
+    }
+
+    /// Receives a datagram together with the address of its sender
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+
+        loop {
+            match sockopt::recv_from(self.as_raw_fd(), buf) {
+                Ok(t) => {
+                    io_trace!("RawSocket({:?}): recv_from() => Ok(..)", self.token);
+                    self.ready_states.pass_on(ReadyType::Readable);
+                    return Ok(t);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("RawSocket({:?}): recv_from() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("RawSocket({:?}): recv_from() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("RawSocket({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Readable, deadline)));
+        }
+    }
+}
+
+impl FromRawFd for RawSocket {
+    unsafe fn from_raw_fd(fd: RawFd) -> RawSocket {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
+        create_raw_socket!(MioRawSocket(fd)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use scheduler::Scheduler;
+    use super::RawSocket;
+
+    // The Internet checksum of RFC 1071
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = data.chunks(2).fold(0u32, |sum, chunk| {
+            let word = (chunk[0] as u32) << 8 | chunk.get(1).map_or(0, |&b| b as u32);
+            sum + word
+        });
+
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+
+    #[test]
+    fn test_icmp_ping_localhost() {
+        Scheduler::new()
+            .run(|| {
+                let socket = match RawSocket::icmp_v4() {
+                    Ok(socket) => socket,
+                    // Unprivileged ICMP sockets might not be permitted on this machine
+                    Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+                    Err(err) => panic!("failed to create ICMP socket: {}", err),
+                };
+
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+                // Echo request: type 8, code 0, checksum, identifier, sequence number, payload
+                let mut request = vec![8, 0, 0, 0, 0, 0, 0, 1, b'c', b'o', b'i', b'o'];
+                let sum = checksum(&request);
+                request[2] = (sum >> 8) as u8;
+                request[3] = sum as u8;
+
+                let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+                socket.send_to(&request, &addr).unwrap();
+
+                let mut buf = [0u8; 64];
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+
+                // Echo reply with our payload
+                assert_eq!(from.ip(), addr.ip());
+                assert_eq!(buf[0], 0);
+                assert_eq!(&buf[8..len], b"coio");
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,954 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Socket options and raw socket calls which aren't exposed by mio
+
+use std::cmp;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use libc::{self, c_int, c_void, socklen_t};
+
+#[inline]
+fn cvt(ret: c_int) -> io::Result<c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Puts the file descriptor into non-blocking mode
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = try!(cvt(libc::fcntl(fd, libc::F_GETFL)));
+        cvt(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)).map(|_| ())
+    }
+}
+
+/// Creates a non-blocking, close-on-exec socket
+#[inline]
+pub fn socket(family: c_int, ty: c_int) -> io::Result<RawFd> {
+    socket_with_protocol(family, ty, 0)
+}
+
+/// Like `socket()`, but for a specific protocol
+pub fn socket_with_protocol(family: c_int, ty: c_int, protocol: c_int) -> io::Result<RawFd> {
+    let fd = try!(cvt(unsafe { libc::socket(family, ty, protocol) }));
+
+    let ret = cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })
+                  .and_then(|_| set_nonblocking(fd));
+
+    match ret {
+        Ok(..) => Ok(fd),
+        Err(err) => {
+            unsafe { libc::close(fd) };
+            Err(err)
+        }
+    }
+}
+
+// Converts `addr` into its C representation
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, socklen_t) {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+
+        let len = match *addr {
+            SocketAddr::V4(ref a) => {
+                let sa = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sa.sin_family = libc::AF_INET as libc::sa_family_t;
+                sa.sin_port = a.port().to_be();
+                sa.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(ref a) => {
+                let sa = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sa.sin6_port = a.port().to_be();
+                sa.sin6_flowinfo = a.flowinfo().to_be();
+                sa.sin6_scope_id = a.scope_id();
+
+                for (i, segment) in a.ip().segments().iter().enumerate() {
+                    sa.sin6_addr.s6_addr[i * 2] = (segment >> 8) as u8;
+                    sa.sin6_addr.s6_addr[i * 2 + 1] = *segment as u8;
+                }
+
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, len as socklen_t)
+    }
+}
+
+// Returns the offset of `sun_path` within `sockaddr_un`
+fn sun_path_offset() -> usize {
+    let addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_path.as_ptr() as usize - &addr as *const _ as usize
+}
+
+// Whether `bytes` name an address in Linux' abstract namespace, i.e. start with a null byte
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn is_abstract(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&0)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[inline]
+fn is_abstract(_bytes: &[u8]) -> bool {
+    false
+}
+
+// Converts `path` into the C representation of a Unix domain socket address
+//
+// On Linux a leading null byte denotes an address in the abstract namespace. Such a name
+// may contain further null bytes and isn't null terminated.
+fn sockaddr_un(path: &Path) -> io::Result<(libc::sockaddr_un, socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+
+    if is_abstract(bytes) {
+        if bytes.len() > addr.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "abstract name must not be longer than SUN_LEN"));
+        }
+
+        for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+            *dst = *src as libc::c_char;
+        }
+
+        return Ok((addr, (sun_path_offset() + bytes.len()) as socklen_t));
+    }
+
+    if bytes.contains(&0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "paths may not contain interior null bytes"));
+    }
+
+    // One byte is left for the terminating null byte
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "path must be shorter than SUN_LEN"));
+    }
+
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    Ok((addr, (sun_path_offset() + bytes.len() + 1) as socklen_t))
+}
+
+// Extracts the `sun_path` of a Unix domain socket address of length `len`
+//
+// The result is empty for unnamed sockets and starts with a null byte for abstract addresses.
+fn sun_path(addr: &libc::sockaddr_un, len: socklen_t) -> Vec<u8> {
+    let path_len = (len as usize).saturating_sub(sun_path_offset());
+    let bytes: Vec<u8> = addr.sun_path[..path_len].iter().map(|&c| c as u8).collect();
+
+    if is_abstract(&bytes) {
+        bytes
+    } else {
+        bytes.into_iter().take_while(|&c| c != 0).collect()
+    }
+}
+
+/// Returns the local address of a Unix domain socket, see `sun_path()`
+pub fn unix_local_addr(fd: RawFd) -> io::Result<Vec<u8>> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_un>() as socklen_t;
+
+    let ret = unsafe {
+        libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+
+    try!(cvt(ret));
+    Ok(sun_path(&addr, len))
+}
+
+/// Returns the address of the peer of a Unix domain socket, see `sun_path()`
+pub fn unix_peer_addr(fd: RawFd) -> io::Result<Vec<u8>> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_un>() as socklen_t;
+
+    let ret = unsafe {
+        libc::getpeername(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+
+    try!(cvt(ret));
+    Ok(sun_path(&addr, len))
+}
+
+/// Binds a Unix domain socket to `path`
+pub fn bind_unix(fd: RawFd, path: &Path) -> io::Result<()> {
+    let (addr, len) = try!(sockaddr_un(path));
+    let ret = unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) };
+    cvt(ret).map(|_| ())
+}
+
+/// Connects a Unix domain socket to `path`
+pub fn connect_unix(fd: RawFd, path: &Path) -> io::Result<()> {
+    let (addr, len) = try!(sockaddr_un(path));
+    let ret = unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len) };
+    cvt(ret).map(|_| ())
+}
+
+/// Sends a datagram to the Unix domain socket bound to `path`
+pub fn send_to_unix(fd: RawFd, buf: &[u8], path: &Path) -> io::Result<usize> {
+    let (addr, len) = try!(sockaddr_un(path));
+
+    let ret = unsafe {
+        libc::sendto(fd,
+                     buf.as_ptr() as *const c_void,
+                     buf.len(),
+                     0,
+                     &addr as *const _ as *const libc::sockaddr,
+                     len)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Receives a datagram on a Unix domain socket, together with the path of the sender
+///
+/// The path is `None` if the sender isn't bound to a path, e.g. because it's unbound or
+/// bound to an address in Linux' abstract namespace.
+pub fn recv_from_unix(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_un>() as socklen_t;
+
+    let ret = unsafe {
+        libc::recvfrom(fd,
+                       buf.as_mut_ptr() as *mut c_void,
+                       buf.len(),
+                       0,
+                       &mut addr as *mut _ as *mut libc::sockaddr,
+                       &mut len)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let bytes = sun_path(&addr, len);
+
+    let path = if bytes.is_empty() || is_abstract(&bytes) {
+        None
+    } else {
+        Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+    };
+
+    Ok((ret as usize, path))
+}
+
+// Alignment of control messages, see CMSG_ALIGN()
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[inline]
+fn cmsg_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+#[inline]
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+// CMSG_SPACE() and CMSG_LEN() of a control message carrying `len` bytes of data
+#[inline]
+fn cmsg_space(len: usize) -> usize {
+    cmsg_align(mem::size_of::<libc::cmsghdr>()) + cmsg_align(len)
+}
+
+#[inline]
+fn cmsg_len(len: usize) -> usize {
+    cmsg_align(mem::size_of::<libc::cmsghdr>()) + len
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEND_FLAGS: c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SEND_FLAGS: c_int = 0;
+
+/// Sends `buf` together with the file descriptors `fds` using `SCM_RIGHTS`
+pub fn send_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let data_len = fds.len() * mem::size_of::<RawFd>();
+
+    // A buffer of usizes is aligned sufficiently for cmsghdr
+    let words = (cmsg_space(data_len) + mem::size_of::<usize>() - 1) / mem::size_of::<usize>();
+    let mut control = vec![0usize; words];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    let ret = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = cmsg_space(data_len) as _;
+
+            let cmsg = &mut *(msg.msg_control as *mut libc::cmsghdr);
+            cmsg.cmsg_len = cmsg_len(data_len) as _;
+            cmsg.cmsg_level = libc::SOL_SOCKET;
+            cmsg.cmsg_type = libc::SCM_RIGHTS;
+
+            let data = (cmsg as *mut _ as *mut u8)
+                           .offset(cmsg_align(mem::size_of::<libc::cmsghdr>()) as isize);
+            ::std::ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, data, data_len);
+        }
+
+        libc::sendmsg(fd, &msg, SEND_FLAGS)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+// Makes received file descriptors close-on-exec atomically, where the platform supports it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: c_int = 0;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn set_cloexec_received(_: &[RawFd]) {}
+
+// Leaves a window in which a concurrent fork() inherits the file descriptors
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_cloexec_received(fds: &[RawFd]) {
+    for &fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+}
+
+/// Receives data into `buf` and up to `fds.len()` file descriptors sent with `SCM_RIGHTS`
+///
+/// Returns the number of bytes and the number of file descriptors received. The file
+/// descriptors are set to close-on-exec. Any file descriptors which didn't fit into `fds`
+/// are closed.
+pub fn recv_fds(fd: RawFd, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+    let data_len = fds.len() * mem::size_of::<RawFd>();
+    let words = (cmsg_space(data_len) + mem::size_of::<usize>() - 1) / mem::size_of::<usize>();
+    let mut control = vec![0usize; words];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = cmsg_space(data_len) as _;
+
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, RECV_FLAGS) };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut count = 0;
+    let header_len = cmsg_align(mem::size_of::<libc::cmsghdr>());
+
+    // All received file descriptors are in a single SCM_RIGHTS message,
+    // since the control buffer has only room for one
+    if msg.msg_controllen as usize >= header_len {
+        let cmsg = unsafe { &*(msg.msg_control as *const libc::cmsghdr) };
+
+        if cmsg.cmsg_level == libc::SOL_SOCKET && cmsg.cmsg_type == libc::SCM_RIGHTS {
+            // The padding of cmsg_space() might leave room for more than `fds.len()`
+            let received = (cmsg.cmsg_len as usize - header_len) / mem::size_of::<RawFd>();
+            count = cmp::min(received, fds.len());
+
+            unsafe {
+                let data = (cmsg as *const _ as *const u8).offset(header_len as isize);
+                let data = data as *const RawFd;
+                ::std::ptr::copy_nonoverlapping(data, fds.as_mut_ptr(), count);
+
+                for i in count..received {
+                    libc::close(*data.offset(i as isize));
+                }
+            }
+
+            set_cloexec_received(&fds[..count]);
+        }
+    }
+
+    Ok((ret as usize, count))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ucred {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// Returns the uid, gid and pid of the peer of a Unix domain socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_cred(fd: RawFd) -> io::Result<(u32, u32, Option<i32>)> {
+    get::<ucred>(fd, libc::SOL_SOCKET, libc::SO_PEERCRED)
+        .map(|cred| (cred.uid as u32, cred.gid as u32, Some(cred.pid as i32)))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+extern "C" {
+    fn getpeereid(socket: c_int, euid: *mut libc::uid_t, egid: *mut libc::gid_t) -> c_int;
+}
+
+/// Returns the uid, gid and pid of the peer of a Unix domain socket
+///
+/// The pid isn't available on BSDs.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+pub fn peer_cred(fd: RawFd) -> io::Result<(u32, u32, Option<i32>)> {
+    let mut uid = 0;
+    let mut gid = 0;
+
+    try!(cvt(unsafe { getpeereid(fd, &mut uid, &mut gid) }));
+    Ok((uid as u32, gid as u32, None))
+}
+
+/// Creates a pair of connected, non-blocking Unix domain sockets
+pub fn socketpair(ty: c_int) -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+    try!(cvt(unsafe { libc::socketpair(libc::AF_UNIX, ty, 0, fds.as_mut_ptr()) }));
+
+    for &fd in &fds {
+        let ret = cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })
+                      .and_then(|_| set_nonblocking(fd));
+
+        if let Err(err) = ret {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+
+            return Err(err);
+        }
+    }
+
+    Ok((fds[0], fds[1]))
+}
+
+// Converts a C socket address back into a `SocketAddr`
+fn socket_addr(storage: &libc::sockaddr_storage, len: socklen_t) -> io::Result<SocketAddr> {
+    let len = len as usize;
+
+    match storage.ss_family as c_int {
+        libc::AF_INET if len >= mem::size_of::<libc::sockaddr_in>() => {
+            let sa = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sa.sin_port))))
+        }
+        libc::AF_INET6 if len >= mem::size_of::<libc::sockaddr_in6>() => {
+            let sa = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let b = &sa.sin6_addr.s6_addr;
+            let segment = |i: usize| (b[i * 2] as u16) << 8 | b[i * 2 + 1] as u16;
+            let ip = Ipv6Addr::new(segment(0),
+                                   segment(1),
+                                   segment(2),
+                                   segment(3),
+                                   segment(4),
+                                   segment(5),
+                                   segment(6),
+                                   segment(7));
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                u16::from_be(sa.sin6_port),
+                                                u32::from_be(sa.sin6_flowinfo),
+                                                sa.sin6_scope_id)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address")),
+    }
+}
+
+/// Sends a datagram to `addr`
+pub fn send_to(fd: RawFd, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+    let (storage, len) = sockaddr(addr);
+
+    let ret = unsafe {
+        libc::sendto(fd,
+                     buf.as_ptr() as *const c_void,
+                     buf.len(),
+                     0,
+                     &storage as *const _ as *const libc::sockaddr,
+                     len)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Receives a datagram together with the address of its sender
+pub fn recv_from(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+
+    let ret = unsafe {
+        libc::recvfrom(fd,
+                       buf.as_mut_ptr() as *mut c_void,
+                       buf.len(),
+                       0,
+                       &mut storage as *mut _ as *mut libc::sockaddr,
+                       &mut len)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        socket_addr(&storage, len).map(|addr| (ret as usize, addr))
+    }
+}
+
+pub fn bind(fd: RawFd, addr: &SocketAddr) -> io::Result<()> {
+    let (storage, len) = sockaddr(addr);
+    let ret = unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) };
+    cvt(ret).map(|_| ())
+}
+
+pub fn listen(fd: RawFd, backlog: i32) -> io::Result<()> {
+    cvt(unsafe { libc::listen(fd, backlog) }).map(|_| ())
+}
+
+/// Starts connecting the non-blocking socket to `addr`
+///
+/// A connection which is still in progress counts as success. Errors happening after that
+/// are reported by the first read or write, just like with `mio::tcp::TcpStream::connect()`.
+pub fn connect(fd: RawFd, addr: &SocketAddr) -> io::Result<()> {
+    let (storage, len) = sockaddr(addr);
+    let ret = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) };
+
+    match cvt(ret) {
+        Err(ref err) if err.raw_os_error() == Some(libc::EINPROGRESS) => Ok(()),
+        ret => ret.map(|_| ()),
+    }
+}
+
+pub fn set<T>(fd: RawFd, level: c_int, name: c_int, value: T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         name,
+                         &value as *const T as *const c_void,
+                         mem::size_of::<T>() as socklen_t)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the value of a socket option
+///
+/// Fails with `ErrorKind::InvalidInput` if the option isn't of type `T`.
+pub fn get<T: Copy>(fd: RawFd, level: c_int, name: c_int) -> io::Result<T> {
+    let (value, len) = try!(get_with_len(fd, level, name));
+
+    if len == mem::size_of::<T>() {
+        Ok(value)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "socket option has a different size than the requested type"))
+    }
+}
+
+// Returns the value of the option and the length reported by the kernel
+fn get_with_len<T: Copy>(fd: RawFd, level: c_int, name: c_int) -> io::Result<(T, usize)> {
+    unsafe {
+        let mut value: T = mem::zeroed();
+        let mut len = mem::size_of::<T>() as socklen_t;
+
+        let ret = libc::getsockopt(fd,
+                                   level,
+                                   name,
+                                   &mut value as *mut T as *mut c_void,
+                                   &mut len);
+
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok((value, len as usize))
+        }
+    }
+}
+
+/// Writes `buf` to the socket without raising `SIGPIPE` if the peer has gone away
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send_nosignal(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::send(fd,
+                   buf.as_ptr() as *const c_void,
+                   buf.len(),
+                   libc::MSG_NOSIGNAL)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+// Upper bound for the number of buffers passed to the kernel at once,
+// which is the smallest IOV_MAX of all supported platforms
+const MAX_IOVECS: usize = 1024;
+
+/// Reads into several buffers at once, filling them in order
+pub fn readv(fd: RawFd, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+    let iovs: Vec<libc::iovec> = bufs.iter_mut()
+                                     .take(MAX_IOVECS)
+                                     .map(|buf| {
+                                         libc::iovec {
+                                             iov_base: buf.as_mut_ptr() as *mut c_void,
+                                             iov_len: buf.len(),
+                                         }
+                                     })
+                                     .collect();
+
+    let ret = unsafe { libc::readv(fd, iovs.as_ptr(), iovs.len() as c_int) };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+fn write_iovecs(bufs: &[&[u8]]) -> Vec<libc::iovec> {
+    bufs.iter()
+        .take(MAX_IOVECS)
+        .map(|buf| {
+            libc::iovec {
+                iov_base: buf.as_ptr() as *mut c_void,
+                iov_len: buf.len(),
+            }
+        })
+        .collect()
+}
+
+/// Writes several buffers at once, in order
+pub fn writev(fd: RawFd, bufs: &[&[u8]]) -> io::Result<usize> {
+    let iovs = write_iovecs(bufs);
+    let ret = unsafe { libc::writev(fd, iovs.as_ptr(), iovs.len() as c_int) };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Like `writev()`, but without raising `SIGPIPE` if the peer has gone away
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn sendv_nosignal(fd: RawFd, bufs: &[&[u8]]) -> io::Result<usize> {
+    let mut iovs = write_iovecs(bufs);
+
+    let ret = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = iovs.as_mut_ptr();
+        msg.msg_iovlen = iovs.len() as _;
+
+        libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Like `writev()`, for platforms where `SIGPIPE` is disabled using `set_nosigpipe()`
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[inline]
+pub fn sendv_nosignal(fd: RawFd, bufs: &[&[u8]]) -> io::Result<usize> {
+    writev(fd, bufs)
+}
+
+/// Copies up to `len` bytes starting at `offset` from `file` to the socket within the kernel
+///
+/// Returns the number of bytes sent, which might be less than `len`. There is no flag like
+/// `MSG_NOSIGNAL` for `sendfile()`, so it's called using `without_sigpipe()`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn sendfile(sock: RawFd, file: RawFd, offset: u64, len: usize) -> io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+
+    without_sigpipe(|| {
+        let ret = unsafe { libc::sendfile(sock, file, &mut offset, len) };
+
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    })
+}
+
+/// Runs `f` with `SIGPIPE` blocked for the calling thread
+///
+/// This is for writes like `sendfile()` and `splice()`, which lack a flag like `MSG_NOSIGNAL`.
+/// A signal raised by `f` is consumed before `SIGPIPE` is unblocked again.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn without_sigpipe<F, T>(f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T>
+{
+    unsafe {
+        let mut sigpipe: libc::sigset_t = mem::zeroed();
+        let mut previous: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut sigpipe);
+        libc::sigaddset(&mut sigpipe, libc::SIGPIPE);
+
+        // A signal which was pending already doesn't belong to us
+        let pending_before = sigpipe_pending();
+
+        let err = libc::pthread_sigmask(libc::SIG_BLOCK, &sigpipe, &mut previous);
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+
+        let result = f();
+
+        if !pending_before && sigpipe_pending() {
+            let mut signal = 0;
+            sigwait(&sigpipe, &mut signal);
+        }
+
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, ::std::ptr::null_mut());
+        result
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern "C" {
+    fn sigwait(set: *const libc::sigset_t, sig: *mut c_int) -> c_int;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sigpipe_pending() -> bool {
+    let mut pending: libc::sigset_t = mem::zeroed();
+    libc::sigpending(&mut pending) == 0 && libc::sigismember(&pending, libc::SIGPIPE) == 1
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+mod bsd {
+    use libc::{c_int, c_void, off_t};
+
+    extern "C" {
+        #[cfg(target_os = "freebsd")]
+        pub fn sendfile(fd: c_int,
+                        s: c_int,
+                        offset: off_t,
+                        nbytes: ::libc::size_t,
+                        hdtr: *mut c_void,
+                        sbytes: *mut off_t,
+                        flags: c_int)
+                        -> c_int;
+
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        pub fn sendfile(fd: c_int,
+                        s: c_int,
+                        offset: off_t,
+                        len: *mut off_t,
+                        hdtr: *mut c_void,
+                        flags: c_int)
+                        -> c_int;
+    }
+}
+
+// Returns the return value of `sendfile()` and the number of bytes sent
+#[cfg(target_os = "freebsd")]
+unsafe fn bsd_sendfile(sock: RawFd, file: RawFd, offset: u64, len: usize) -> (c_int, libc::off_t) {
+    let mut sent = 0;
+    let ret = bsd::sendfile(file,
+                            sock,
+                            offset as libc::off_t,
+                            len,
+                            ::std::ptr::null_mut(),
+                            &mut sent,
+                            0);
+    (ret, sent)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+unsafe fn bsd_sendfile(sock: RawFd, file: RawFd, offset: u64, len: usize) -> (c_int, libc::off_t) {
+    let mut sent = len as libc::off_t;
+    let ret = bsd::sendfile(file,
+                            sock,
+                            offset as libc::off_t,
+                            &mut sent,
+                            ::std::ptr::null_mut(),
+                            0);
+    (ret, sent)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+pub fn sendfile(sock: RawFd, file: RawFd, offset: u64, len: usize) -> io::Result<usize> {
+    // A length of 0 means "until the end of the file" on BSDs
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let (ret, sent) = unsafe { bsd_sendfile(sock, file, offset, len) };
+
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+
+        // The BSDs report a partial send as EAGAIN
+        if err.kind() == io::ErrorKind::WouldBlock && sent > 0 {
+            Ok(sent as usize)
+        } else {
+            Err(err)
+        }
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+/// Sends data on a connected socket
+pub fn send(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let ret = unsafe { libc::send(fd, buf.as_ptr() as *const c_void, buf.len(), 0) };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Receives data from a connected socket
+pub fn recv(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Receives data from the socket without removing it from the receive queue
+pub fn peek(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::recv(fd,
+                   buf.as_mut_ptr() as *mut c_void,
+                   buf.len(),
+                   libc::MSG_PEEK)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Stops writes to the socket from raising `SIGPIPE` on platforms without `MSG_NOSIGNAL`
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+pub fn set_nosigpipe(fd: RawFd) -> io::Result<()> {
+    set(fd, libc::SOL_SOCKET, libc::SO_NOSIGPIPE, 1 as c_int)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd")))]
+pub fn set_nosigpipe(_: RawFd) -> io::Result<()> {
+    Ok(())
+}
+
+// The option holding the idle time before keepalive probes are sent, which libc doesn't export
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TCP_KEEPIDLE: c_int = 4;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: c_int = 0x10; // TCP_KEEPALIVE
+#[cfg(target_os = "freebsd")]
+const TCP_KEEPIDLE: c_int = 256;
+
+/// Returns the idle time in seconds after which keepalive probes are sent
+#[cfg(any(target_os = "linux",
+          target_os = "android",
+          target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd"))]
+pub fn keepalive_idle(fd: RawFd) -> io::Result<u32> {
+    get::<c_int>(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE).map(|v| v as u32)
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd")))]
+pub fn keepalive_idle(_: RawFd) -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "reading the keepalive time is not supported on this platform"))
+}
+
+// Returns the address family of an IP socket
+fn family(fd: RawFd) -> io::Result<c_int> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+
+    let ret = unsafe {
+        libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+
+    try!(cvt(ret));
+    Ok(storage.ss_family as c_int)
+}
+
+// The unicast hop limit of IPv6 sockets, which libc doesn't export
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPV6_UNICAST_HOPS: c_int = 16;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const IPV6_UNICAST_HOPS: c_int = 4;
+
+// Returns the level and name of the TTL option, which for IPv6 is the unicast hop limit
+fn ttl_opt(fd: RawFd) -> io::Result<(c_int, c_int)> {
+    match try!(family(fd)) {
+        libc::AF_INET6 => Ok((libc::IPPROTO_IPV6, IPV6_UNICAST_HOPS)),
+        _ => Ok((libc::IPPROTO_IP, libc::IP_TTL)),
+    }
+}
+
+pub fn set_ttl(fd: RawFd, ttl: u32) -> io::Result<()> {
+    let (level, name) = try!(ttl_opt(fd));
+    set(fd, level, name, ttl as c_int)
+}
+
+pub fn ttl(fd: RawFd) -> io::Result<u32> {
+    let (level, name) = try!(ttl_opt(fd));
+    get::<c_int>(fd, level, name).map(|v| v as u32)
+}
@@ -10,30 +10,53 @@
 
 pub use mio::tcp::Shutdown;
 
-use std::io;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::iter::Iterator;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+#[cfg(unix)]
+use libc;
 
 use mio::EventSet;
 use mio::tcp::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
 
-use scheduler::ReadyType;
-use super::{each_addr, GenericEvented, SyncGuard};
+use scheduler::{ReadyType, Scheduler};
+use sync::{Condvar, Mutex};
+use super::{disable_sigpipe, each_addr, load_timeout, retry_io, store_timeout, timeout_deadline,
+            EventedWrite, GenericEvented, SyncGuard};
+
+#[cfg(unix)]
+use super::dns;
+#[cfg(unix)]
+use super::sockopt;
 
 macro_rules! create_tcp_listener {
     ($inner:expr) => (TcpListener::new($inner, EventSet::readable()));
 }
 
 macro_rules! create_tcp_stream {
-    ($inner:expr) => (TcpStream::new($inner, EventSet::readable() | EventSet::writable()));
+    ($inner:expr) => ({
+        let inner = $inner;
+        disable_sigpipe(&inner)
+            .and_then(move |_| TcpStream::new(inner, EventSet::readable() | EventSet::writable()))
+    });
 }
 
 pub type TcpListener = GenericEvented<MioTcpListener>;
 
 impl TcpListener {
+    /// Creates a listener bound to `addr` with a backlog of 1024 and `SO_REUSEADDR` set
+    ///
+    /// Use `TcpBuilder` to configure the backlog or other options before listening.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
         each_addr(addr, |addr| {
             let inner = try!(MioTcpListener::bind(addr));
@@ -41,27 +64,79 @@ impl TcpListener {
         })
     }
 
+    /// Binds `shards` listeners with `SO_REUSEPORT` to the same address
+    ///
+    /// The kernel distributes incoming connections among the listeners, so that every one of
+    /// them can be served by its own accept loop instead of all workers contending for a
+    /// single listener. Usually one shard per worker is used, see `Scheduler::worker_count()`:
+    ///
+    /// ```no_run
+    /// # use coio::Scheduler;
+    /// # use coio::net::TcpListener;
+    /// Scheduler::new().with_workers(4).run(|| {
+    ///     let shards = Scheduler::instance().unwrap().worker_count();
+    ///
+    ///     for listener in TcpListener::bind_sharded("0.0.0.0:8080", shards).unwrap() {
+    ///         Scheduler::spawn(move || {
+    ///             for stream in listener.incoming() {
+    ///                 // ...
+    ///             }
+    ///         });
+    ///     }
+    /// }).unwrap();
+    /// ```
+    ///
+    /// If the port of `addr` is 0 all listeners share the port picked for the first one.
+    #[cfg(unix)]
+    pub fn bind_sharded<A: ToSocketAddrs>(addr: A, shards: usize) -> io::Result<Vec<TcpListener>> {
+        assert!(shards >= 1, "Must have at least one shard");
+
+        each_addr(addr, |addr| {
+            let mut listeners = Vec::with_capacity(shards);
+            let mut addr = *addr;
+
+            for _ in 0..shards {
+                let builder = match addr {
+                    SocketAddr::V4(..) => try!(TcpBuilder::new_v4()),
+                    SocketAddr::V6(..) => try!(TcpBuilder::new_v6()),
+                };
+
+                try!(builder.reuse_address(true));
+                try!(builder.reuse_port(true));
+                try!(builder.bind(addr));
+
+                let listener = try!(builder.listen(1024));
+                addr = try!(listener.local_addr());
+                listeners.push(listener);
+            }
+
+            Ok(listeners)
+        })
+    }
+
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
         let mut sync_guard = SyncGuard::new();
 
         loop {
             match self.inner.accept() {
                 Ok(None) => {
-                    trace!("TcpListener({:?}): accept() => WouldBlock", self.token);
+                    io_trace!("TcpListener({:?}): accept() => WouldBlock", self.token);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("TcpListener({:?}): accept() => WouldBlock", self.token);
                 }
                 Ok(Some((stream, addr))) => {
-                    trace!("TcpListener({:?}): accept() => Ok(..)", self.token);
+                    io_trace!("TcpListener({:?}): accept() => Ok(..)", self.token);
                     return create_tcp_stream!(stream).map(|stream| (stream, addr));
                 }
                 Err(err) => {
-                    trace!("TcpListener({:?}): accept() => Err(..)", self.token);
+                    io_trace!("TcpListener({:?}): accept() => Err(..)", self.token);
                     return Err(err);
                 }
             }
 
-            trace!("TcpListener({:?}): wait(Readable)", self.token);
-            self.ready_states.wait(ReadyType::Readable);
-            sync_guard.disarm();
+            io_trace!("TcpListener({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.ready_states.wait(ReadyType::Readable)));
         }
     }
 
@@ -70,20 +145,49 @@ impl TcpListener {
         create_tcp_listener!(inner)
     }
 
+    /// Adopts a listener created by the standard library, e.g. through socket activation
+    ///
+    /// The socket is put into non-blocking mode and registered with the running Scheduler.
+    #[cfg(unix)]
+    pub fn from_std(listener: ::std::net::TcpListener) -> io::Result<TcpListener> {
+        try!(sockopt::set_nonblocking(listener.as_raw_fd()));
+        let inner = unsafe { MioTcpListener::from_raw_fd(listener.into_raw_fd()) };
+        create_tcp_listener!(inner)
+    }
+
+    /// Returns an iterator over the connections being received on this listener
+    ///
+    /// The iterator never returns `None` and never yields `WouldBlock`, since the coroutine is
+    /// parked until the next connection arrives. Other errors, like running out of file
+    /// descriptors, are yielded as they are and iterating may continue afterwards.
     pub fn incoming(&self) -> Incoming {
         Incoming(self)
     }
+
+    /// Sets the value of the `IP_TTL` option for this socket
+    #[cfg(unix)]
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_ttl(self.as_raw_fd(), ttl)
+    }
+
+    /// Gets the value of the `IP_TTL` option for this socket
+    #[cfg(unix)]
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::ttl(self.as_raw_fd())
+    }
 }
 
 #[cfg(unix)]
 impl FromRawFd for TcpListener {
     unsafe fn from_raw_fd(fd: RawFd) -> TcpListener {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_tcp_listener!(inner).unwrap()
     }
 }
 
 
+/// An infinite iterator over the connections of a `TcpListener`, see `TcpListener::incoming()`
 pub struct Incoming<'a>(&'a TcpListener);
 
 impl<'a> Iterator for Incoming<'a> {
@@ -96,7 +200,23 @@ impl<'a> Iterator for Incoming<'a> {
 
 pub type TcpStream = GenericEvented<MioTcpStream>;
 
+impl EventedWrite for MioTcpStream {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn nosignal_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        sockopt::send_nosignal(self.as_raw_fd(), buf)
+    }
+
+    #[cfg(unix)]
+    fn nosignal_write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        sockopt::sendv_nosignal(self.as_raw_fd(), bufs)
+    }
+}
+
 impl TcpStream {
+    /// Opens a connection to `addr`
+    ///
+    /// Host names are resolved using the blocking `ToSocketAddrs`, which stalls the whole
+    /// worker. Use `net::dns::resolve()` to look them up beforehand instead.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
         each_addr(addr, |addr| {
             let inner = try!(MioTcpStream::connect(addr));
@@ -108,12 +228,610 @@ impl TcpStream {
         let inner = try!(self.inner.try_clone());
         create_tcp_stream!(inner)
     }
+
+    /// Opens a connection to `host` using the "Happy Eyeballs" algorithm of RFC 8305
+    ///
+    /// The host name is resolved using `net::dns::resolve()`, so that the worker isn't blocked,
+    /// and its addresses are tried alternating between IPv6 and IPv4, each in its own
+    /// coroutine. A new attempt is started every 250ms or as soon as all previous ones have
+    /// failed, and the first connection which is established wins. Unlike `connect()` this waits
+    /// until the connection is established. If all attempts fail, the last error is returned.
+    #[cfg(unix)]
+    pub fn connect_happy_eyeballs(host: &str, port: u16) -> io::Result<TcpStream> {
+        let addrs = try!(dns::resolve(host, port));
+        TcpStream::connect_happy_eyeballs_addrs(&addrs)
+    }
+
+    /// Like `connect_happy_eyeballs()`, but with addresses which have already been resolved
+    #[cfg(unix)]
+    pub fn connect_happy_eyeballs_addrs(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let addrs = interleave_families(addrs.to_vec());
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "could not resolve to any addresses"));
+        }
+
+        let shared = Arc::new((Mutex::new(Attempts {
+            winner: None,
+            failed: 0,
+            last_error: None,
+        }),
+                               Condvar::new()));
+        let mut handles = Vec::with_capacity(addrs.len());
+        let delay = Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS);
+
+        let result = {
+            let &(ref attempts, ref cond) = &*shared;
+
+            for (started, addr) in addrs.iter().cloned().enumerate() {
+                let shared = shared.clone();
+
+                handles.push(Scheduler::spawn(move || {
+                    let ret = TcpStream::connect(addr).and_then(|s| s.wait_connected().map(|_| s));
+
+                    let &(ref attempts, ref cond) = &*shared;
+                    let mut attempts = attempts.lock().unwrap();
+
+                    match ret {
+                        Ok(stream) => {
+                            // Losing connections are simply closed
+                            if attempts.winner.is_none() {
+                                attempts.winner = Some(stream);
+                            }
+                        }
+                        Err(err) => {
+                            attempts.failed += 1;
+                            attempts.last_error = Some(err);
+                        }
+                    }
+
+                    cond.notify_all();
+                }));
+
+                // Wait for a winner, the failure of all running attempts, or the next delay
+                let deadline = Instant::now() + delay;
+                let mut guard = attempts.lock().unwrap();
+
+                while guard.winner.is_none() && guard.failed <= started {
+                    let now = Instant::now();
+
+                    if now >= deadline {
+                        break;
+                    }
+
+                    guard = cond.wait_timeout(guard, deadline.duration_since(now)).unwrap().0;
+                }
+
+                if guard.winner.is_some() {
+                    break;
+                }
+            }
+
+            let mut guard = attempts.lock().unwrap();
+
+            while guard.winner.is_none() && guard.failed < handles.len() {
+                guard = cond.wait(guard).unwrap();
+            }
+
+            match guard.winner.take() {
+                Some(stream) => Ok(stream),
+                None => Err(guard.last_error.take().expect("all attempts failed without an error")),
+            }
+        };
+
+        for handle in handles {
+            handle.cancel();
+        }
+
+        result
+    }
+
+    // Parks until a connection started by `connect()` is established or has failed
+    #[cfg(unix)]
+    fn wait_connected(&self) -> io::Result<()> {
+        try!(self.wait_until(ReadyType::Writable, None));
+
+        match try!(sockopt::get::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR)) {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// Adopts a connected stream created by the standard library or another library
+    ///
+    /// The socket is put into non-blocking mode and registered with the running Scheduler.
+    #[cfg(unix)]
+    pub fn from_std(stream: ::std::net::TcpStream) -> io::Result<TcpStream> {
+        try!(sockopt::set_nonblocking(stream.as_raw_fd()));
+        let inner = unsafe { MioTcpStream::from_raw_fd(stream.into_raw_fd()) };
+        create_tcp_stream!(inner)
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option for this socket
+    ///
+    /// Newly created streams keep the operating system's default, which usually
+    /// means that Nagle's algorithm is enabled.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option for this socket
+    #[cfg(unix)]
+    pub fn nodelay(&self) -> io::Result<bool> {
+        sockopt::get::<libc::c_int>(self.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_NODELAY)
+            .map(|v| v != 0)
+    }
+
+    /// Enables `SO_KEEPALIVE` with the given idle time, or disables it for `None`
+    ///
+    /// The duration is truncated to whole seconds.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.inner.set_keepalive(keepalive.map(|d| d.as_secs() as u32))
+    }
+
+    /// Gets the idle time after which keepalive probes are sent, or `None` if disabled
+    #[cfg(unix)]
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        let fd = self.as_raw_fd();
+        let enabled = try!(sockopt::get::<libc::c_int>(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE));
+
+        if enabled == 0 {
+            return Ok(None);
+        }
+
+        sockopt::keepalive_idle(fd).map(|secs| Some(Duration::from_secs(secs as u64)))
+    }
+
+    /// Sets the value of the `SO_LINGER` option for this socket
+    ///
+    /// The duration is truncated to whole seconds.
+    #[cfg(unix)]
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        let linger = libc::linger {
+            l_onoff: linger.is_some() as libc::c_int,
+            l_linger: linger.map(|d| d.as_secs() as libc::c_int).unwrap_or(0),
+        };
+
+        sockopt::set(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, linger)
+    }
+
+    /// Gets the value of the `SO_LINGER` option for this socket
+    #[cfg(unix)]
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        let linger = try!(sockopt::get::<libc::linger>(self.as_raw_fd(),
+                                                       libc::SOL_SOCKET,
+                                                       libc::SO_LINGER));
+
+        if linger.l_onoff == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(Duration::from_secs(linger.l_linger as u64)))
+        }
+    }
+
+    /// Sets the value of the `IP_TTL` option for this socket
+    #[cfg(unix)]
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_ttl(self.as_raw_fd(), ttl)
+    }
+
+    /// Gets the value of the `IP_TTL` option for this socket
+    #[cfg(unix)]
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::ttl(self.as_raw_fd())
+    }
+
+    /// Receives data like `read()`, but without removing it from the socket's receive queue
+    ///
+    /// Parks the coroutine until data is available, so that the next `read()` returns at least
+    /// the data which has been peeked. The read timeout applies as well.
+    #[cfg(unix)]
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+        retry_io(self.token,
+                 &self.ready_states,
+                 "peek",
+                 ReadyType::Readable,
+                 deadline,
+                 || sockopt::peek(self.as_raw_fd(), buf))
+    }
+
+    /// Sends up to `len` bytes of `file`, starting at `offset`, without copying them
+    /// through userspace
+    ///
+    /// Returns the number of bytes sent, which might be less than `len`, just like `write()`
+    /// does. The coroutine is parked while the socket isn't writable and the write timeout
+    /// applies. The file position of `file` is left untouched.
+    ///
+    /// Just like `write()` it fails with `BrokenPipe` instead of raising `SIGPIPE` if the peer
+    /// has gone away.
+    #[cfg(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd"))]
+    pub fn send_file(&mut self, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+        let fd = self.as_raw_fd();
+        retry_io(self.token,
+                 &self.ready_states,
+                 "send_file",
+                 ReadyType::Writable,
+                 deadline,
+                 || sockopt::sendfile(fd, file.as_raw_fd(), offset, len))
+    }
+
+    /// Sets the timeout of `read()`, `None` waits indefinitely
+    ///
+    /// A read which is parked for longer than this fails with `ErrorKind::TimedOut`.
+    /// The timeout is measured in whole milliseconds and applies to every call separately.
+    /// Passing a zero duration fails with `ErrorKind::InvalidInput`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.read_timeout_ms, timeout)
+    }
+
+    /// Gets the timeout of `read()`
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(load_timeout(&self.read_timeout_ms))
+    }
+
+    /// Sets the timeout of `write()` and `flush()`, `None` waits indefinitely
+    ///
+    /// See `set_read_timeout()` for details.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.write_timeout_ms, timeout)
+    }
+
+    /// Gets the timeout of `write()` and `flush()`
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(load_timeout(&self.write_timeout_ms))
+    }
+
+    /// Splits the stream into a read and a write half, which can be used
+    /// independently from each other, e.g. in two different coroutines.
+    ///
+    /// The underlying socket is deregistered and closed once both halves are dropped.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        let shared = Arc::new(SplitStream(self));
+        (ReadHalf(shared.clone()), WriteHalf(shared))
+    }
+}
+
+/// Reads from a `TcpStream` through a shared reference, just like `std::net::TcpStream` allows
+///
+/// Reading and writing don't share any state, so that one coroutine may read while another one
+/// writes, e.g. after cloning an `Arc<TcpStream>`.
+impl<'a> Read for &'a TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+        retry_io(self.token,
+                 &self.ready_states,
+                 "read",
+                 ReadyType::Readable,
+                 deadline,
+                 || (&self.inner).read(buf))
+    }
+}
+
+/// Writes to a `TcpStream` through a shared reference, see `Read for &TcpStream`
+impl<'a> Write for &'a TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+        retry_io(self.token,
+                 &self.ready_states,
+                 "write",
+                 ReadyType::Writable,
+                 deadline,
+                 || shared_nosignal_write(&self.inner, buf))
+    }
+
+    // TCP sockets don't buffer anything in userspace
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The shared counterpart of `EventedWrite::nosignal_write()` for `MioTcpStream`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn shared_nosignal_write(stream: &MioTcpStream, buf: &[u8]) -> io::Result<usize> {
+    sockopt::send_nosignal(stream.as_raw_fd(), buf)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn shared_nosignal_write(mut stream: &MioTcpStream, buf: &[u8]) -> io::Result<usize> {
+    stream.write(buf)
+}
+
+/// The "Connection Attempt Delay" of RFC 8305
+#[cfg(unix)]
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+// The state shared between the attempts of `TcpStream::connect_happy_eyeballs()`
+#[cfg(unix)]
+struct Attempts {
+    winner: Option<TcpStream>,
+    failed: usize,
+    last_error: Option<io::Error>,
+}
+
+// Orders the addresses alternating between both families, starting with IPv6 (RFC 8305, 4.)
+#[cfg(unix)]
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| {
+        match *addr {
+            SocketAddr::V6(..) => true,
+            SocketAddr::V4(..) => false,
+        }
+    });
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => {
+                ordered.extend(a);
+                ordered.extend(b);
+            }
+        }
+    }
+
+    ordered
+}
+
+/// Puts the halves of a `TcpStream`, which has been split using `TcpStream::split()`, back together.
+///
+/// Returns a `ReuniteError` containing both halves if they don't belong to the same stream.
+pub fn reunite(read: ReadHalf, write: WriteHalf) -> Result<TcpStream, ReuniteError> {
+    if &*read.0 as *const SplitStream != &*write.0 as *const SplitStream {
+        return Err(ReuniteError(read, write));
+    }
+
+    drop(write);
+
+    match Arc::try_unwrap(read.0) {
+        Ok(shared) => Ok(shared.0),
+        Err(..) => unreachable!("both halves of a TcpStream have been dropped"),
+    }
+}
+
+// Both halves only access the stream through shared references, see `Read for &TcpStream`.
+// The read half only ever reads from it, while the write half only ever writes to or shuts down
+// the stream, and `GenericEvented` keeps the waiters for both directions apart.
+struct SplitStream(TcpStream);
+
+unsafe impl Send for SplitStream {}
+unsafe impl Sync for SplitStream {}
+
+impl SplitStream {
+    #[inline]
+    fn get_ref(&self) -> &TcpStream {
+        &self.0
+    }
+}
+
+/// The reading half of a `TcpStream`, created by `TcpStream::split()`
+pub struct ReadHalf(Arc<SplitStream>);
+
+impl ReadHalf {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.get_ref().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.get_ref().local_addr()
+    }
+
+    /// Sets the timeout of `read()`, see `TcpStream::set_read_timeout()`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.get_ref().set_read_timeout(timeout)
+    }
+
+    /// Gets the timeout of `read()`
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.0.get_ref().read_timeout()
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self.0.get_ref();
+        stream.read(buf)
+    }
+}
+
+impl fmt::Debug for ReadHalf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadHalf({:?})", self.0.get_ref())
+    }
+}
+
+/// The writing half of a `TcpStream`, created by `TcpStream::split()`
+pub struct WriteHalf(Arc<SplitStream>);
+
+impl WriteHalf {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.get_ref().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.get_ref().local_addr()
+    }
+
+    /// Shuts down the writing direction of the stream, signaling EOF to the peer.
+    ///
+    /// The read half stays usable.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        self.0.get_ref().shutdown(Shutdown::Write)
+    }
+
+    /// Sets the timeout of `write()` and `flush()`, see `TcpStream::set_write_timeout()`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.get_ref().set_write_timeout(timeout)
+    }
+
+    /// Gets the timeout of `write()` and `flush()`
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.0.get_ref().write_timeout()
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.0.get_ref();
+        stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut stream = self.0.get_ref();
+        stream.flush()
+    }
+}
+
+impl fmt::Debug for WriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WriteHalf({:?})", self.0.get_ref())
+    }
+}
+
+/// Error returned by `reunite()` if the halves belong to different streams
+#[derive(Debug)]
+pub struct ReuniteError(pub ReadHalf, pub WriteHalf);
+
+impl fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for ReuniteError {
+    fn description(&self) -> &str {
+        "tried to reunite halves which are not from the same TcpStream"
+    }
 }
 
 #[cfg(unix)]
 impl FromRawFd for TcpStream {
     unsafe fn from_raw_fd(fd: RawFd) -> TcpStream {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_tcp_stream!(inner).unwrap()
     }
 }
+
+/// A builder for TCP sockets, which allows setting options before they are bound
+///
+/// It is turned into a `TcpListener` using `listen()` or into a `TcpStream` using `connect()`,
+/// after which all other methods fail with `ErrorKind::InvalidInput`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct TcpBuilder {
+    fd: Cell<Option<RawFd>>,
+}
+
+#[cfg(unix)]
+impl TcpBuilder {
+    /// Creates a new IPv4 socket
+    pub fn new_v4() -> io::Result<TcpBuilder> {
+        TcpBuilder::new(libc::AF_INET)
+    }
+
+    /// Creates a new IPv6 socket
+    pub fn new_v6() -> io::Result<TcpBuilder> {
+        TcpBuilder::new(libc::AF_INET6)
+    }
+
+    fn new(family: libc::c_int) -> io::Result<TcpBuilder> {
+        sockopt::socket(family, libc::SOCK_STREAM).map(|fd| TcpBuilder { fd: Cell::new(Some(fd)) })
+    }
+
+    fn fd(&self) -> io::Result<RawFd> {
+        self.fd.get().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "socket has already been turned into a listener or stream")
+        })
+    }
+
+    /// Sets the value of the `SO_REUSEADDR` option for this socket
+    pub fn reuse_address(&self, reuse: bool) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(sockopt::set(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, reuse as libc::c_int));
+        Ok(self)
+    }
+
+    /// Sets the value of the `SO_REUSEPORT` option for this socket
+    ///
+    /// This allows several processes or workers to each accept connections on the same port.
+    pub fn reuse_port(&self, reuse: bool) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(sockopt::set(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, reuse as libc::c_int));
+        Ok(self)
+    }
+
+    /// Sets the value of the `IPV6_V6ONLY` option for this socket
+    ///
+    /// If disabled, an IPv6 socket accepts IPv4 connections as well.
+    pub fn only_v6(&self, only_v6: bool) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(sockopt::set(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, only_v6 as libc::c_int));
+        Ok(self)
+    }
+
+    /// Sets the value of the `SO_RCVBUF` option for this socket
+    ///
+    /// Sockets accepted by a listener inherit its buffer sizes. Since the TCP window scale is
+    /// negotiated during the handshake, large buffers have to be set before `listen()`.
+    pub fn recv_buffer_size(&self, size: usize) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(sockopt::set(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int));
+        Ok(self)
+    }
+
+    /// Sets the value of the `SO_SNDBUF` option for this socket
+    pub fn send_buffer_size(&self, size: usize) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(sockopt::set(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int));
+        Ok(self)
+    }
+
+    /// Binds the socket to the given address
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<&TcpBuilder> {
+        let fd = try!(self.fd());
+        try!(each_addr(addr, |addr| sockopt::bind(fd, addr)));
+        Ok(self)
+    }
+
+    /// Starts listening on the bound socket and registers it with the Scheduler
+    pub fn listen(&self, backlog: i32) -> io::Result<TcpListener> {
+        let fd = try!(self.fd());
+        try!(sockopt::listen(fd, backlog));
+        self.fd.set(None);
+
+        let inner = unsafe { MioTcpListener::from_raw_fd(fd) };
+        create_tcp_listener!(inner)
+    }
+
+    /// Connects the socket to the given address and registers it with the Scheduler
+    ///
+    /// Just like `TcpStream::connect()` this doesn't wait for the connection to be established.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        let fd = try!(self.fd());
+        try!(each_addr(addr, |addr| sockopt::connect(fd, addr)));
+        self.fd.set(None);
+
+        let inner = unsafe { MioTcpStream::from_raw_fd(fd) };
+        create_tcp_stream!(inner)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TcpBuilder {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.get() {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! TLS on top of coio streams
+//!
+//! coio doesn't depend on a TLS implementation. Instead `TlsStream` drives any `Session`,
+//! which is a buffer-oriented TLS state machine in the style of rustls: Encrypted records are
+//! moved between the session and the underlying stream with `read_tls()` and `write_tls()`,
+//! while the plaintext is accessed through the session's `Read` and `Write` implementations.
+//!
+//! Since coio streams park the calling coroutine instead of returning `WouldBlock`, the
+//! handshake and all following reads and writes only ever block the current coroutine.
+//!
+//! Libraries with a stream-oriented API, like the `openssl` crate, work without this module:
+//! Their streams can wrap a coio stream directly for the same reason.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// A TLS state machine, e.g. a thin wrapper around a rustls `ClientSession` or `ServerSession`
+pub trait Session: Read + Write {
+    /// Reads encrypted TLS records from `rd`
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize>;
+
+    /// Writes pending encrypted TLS records to `wr`
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize>;
+
+    /// Processes the records received by `read_tls()`
+    ///
+    /// Protocol errors should be reported as `ErrorKind::InvalidData`.
+    fn process_new_packets(&mut self) -> io::Result<()>;
+
+    /// Returns true if the session needs more records from the peer
+    fn wants_read(&self) -> bool;
+
+    /// Returns true if the session has records to send to the peer
+    fn wants_write(&self) -> bool;
+
+    /// Returns true until the handshake has completed
+    fn is_handshaking(&self) -> bool;
+}
+
+/// A TLS connection on top of the stream `S`
+pub struct TlsStream<S: Read + Write, T: Session> {
+    io: S,
+    session: T,
+    eof: bool,
+}
+
+impl<S: Read + Write, T: Session> TlsStream<S, T> {
+    /// Wraps `io` without performing the handshake yet
+    ///
+    /// The handshake is done on the first read or write, or explicitly using `handshake()`.
+    pub fn new(io: S, session: T) -> TlsStream<S, T> {
+        TlsStream {
+            io: io,
+            session: session,
+            eof: false,
+        }
+    }
+
+    /// Wraps `io` and completes the handshake, parking the coroutine while doing so
+    pub fn connect(io: S, session: T) -> io::Result<TlsStream<S, T>> {
+        let mut stream = TlsStream::new(io, session);
+        try!(stream.handshake());
+        Ok(stream)
+    }
+
+    /// Completes the handshake, if it hasn't been completed yet
+    pub fn handshake(&mut self) -> io::Result<()> {
+        while self.session.is_handshaking() {
+            try!(self.complete_io());
+        }
+
+        // Flush the last handshake records, e.g. the client's Finished message
+        self.flush_tls()
+    }
+
+    // Writes all pending records to the underlying stream
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if try!(self.session.write_tls(&mut self.io)) == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write TLS records to the stream"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Moves records in both directions until the session has made progress
+    fn complete_io(&mut self) -> io::Result<()> {
+        try!(self.flush_tls());
+
+        if self.session.wants_read() {
+            if try!(self.session.read_tls(&mut self.io)) == 0 {
+                self.eof = true;
+
+                if self.session.is_handshaking() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "connection closed during the TLS handshake"));
+                }
+            }
+
+            try!(self.session.process_new_packets());
+        }
+
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.io
+    }
+
+    pub fn session(&self) -> &T {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut T {
+        &mut self.session
+    }
+
+    /// Returns the underlying stream and the session
+    pub fn into_inner(self) -> (S, T) {
+        (self.io, self.session)
+    }
+}
+
+impl<S: Read + Write, T: Session> Read for TlsStream<S, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.handshake());
+
+        loop {
+            match self.session.read(buf) {
+                Ok(0) if !self.eof && !buf.is_empty() => {}
+                ret => return ret,
+            }
+
+            if !self.session.wants_read() {
+                // The peer closed the connection cleanly
+                return Ok(0);
+            }
+
+            if try!(self.session.read_tls(&mut self.io)) == 0 {
+                self.eof = true;
+            }
+
+            try!(self.session.process_new_packets());
+        }
+    }
+}
+
+impl<S: Read + Write, T: Session> Write for TlsStream<S, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.handshake());
+
+        let len = try!(self.session.write(buf));
+
+        try!(self.flush_tls());
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.session.flush());
+
+        try!(self.flush_tls());
+
+        self.io.flush()
+    }
+}
+
+impl<S: Read + Write + fmt::Debug, T: Session> fmt::Debug for TlsStream<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsStream({:?})", self.io)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use super::*;
+
+    // A fake session which "encrypts" by prefixing every record with its length and
+    // completes its handshake after exchanging one record in each direction
+    struct FakeSession {
+        handshaking: bool,
+        hello_sent: bool,
+        incoming: Vec<u8>,
+        plaintext: Vec<u8>,
+        outgoing: Vec<u8>,
+    }
+
+    impl FakeSession {
+        fn new() -> FakeSession {
+            FakeSession {
+                handshaking: true,
+                hello_sent: false,
+                incoming: Vec::new(),
+                plaintext: Vec::new(),
+                outgoing: vec![1, b'H'],
+            }
+        }
+    }
+
+    impl Read for FakeSession {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = ::std::cmp::min(buf.len(), self.plaintext.len());
+            buf[..len].copy_from_slice(&self.plaintext[..len]);
+            self.plaintext.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeSession {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = ::std::cmp::min(buf.len(), 255);
+            self.outgoing.push(len as u8);
+            self.outgoing.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Session for FakeSession {
+        fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+            let mut buf = [0u8; 64];
+            let len = try!(rd.read(&mut buf));
+            self.incoming.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+            let len = try!(wr.write(&self.outgoing));
+            self.outgoing.drain(..len);
+            self.hello_sent = true;
+            Ok(len)
+        }
+
+        fn process_new_packets(&mut self) -> io::Result<()> {
+            while !self.incoming.is_empty() && self.incoming.len() > self.incoming[0] as usize {
+                let len = self.incoming[0] as usize;
+                let record: Vec<u8> = self.incoming.drain(..len + 1).skip(1).collect();
+
+                if self.handshaking {
+                    if record != b"H" {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad hello"));
+                    }
+                    self.handshaking = false;
+                } else {
+                    self.plaintext.extend_from_slice(&record);
+                }
+            }
+
+            Ok(())
+        }
+
+        fn wants_read(&self) -> bool {
+            self.plaintext.is_empty()
+        }
+
+        fn wants_write(&self) -> bool {
+            !self.outgoing.is_empty()
+        }
+
+        fn is_handshaking(&self) -> bool {
+            self.handshaking || !self.hello_sent
+        }
+    }
+
+    // An in-memory transport with a fixed input
+    struct Transport {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        closed: bool,
+    }
+
+    impl Read for Transport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Transport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.closed {
+                return Ok(0);
+            }
+
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tls_stream_handshake_and_data() {
+        let transport = Transport {
+            input: io::Cursor::new(vec![1, b'H', 3, b'a', b'b', b'c']),
+            output: Vec::new(),
+            closed: false,
+        };
+
+        let mut stream = TlsStream::connect(transport, FakeSession::new()).unwrap();
+        assert!(!stream.session().is_handshaking());
+
+        stream.write_all(b"xy").unwrap();
+        assert_eq!(&stream.get_ref().output[..], &[1, b'H', 2, b'x', b'y'][..]);
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"abc");
+    }
+
+    #[test]
+    fn tls_stream_eof_during_handshake() {
+        let transport = Transport {
+            input: io::Cursor::new(Vec::new()),
+            output: Vec::new(),
+            closed: false,
+        };
+
+        let err = TlsStream::connect(transport, FakeSession::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn tls_stream_write_zero() {
+        let transport = Transport {
+            input: io::Cursor::new(vec![1, b'H']),
+            output: Vec::new(),
+            closed: true,
+        };
+
+        let err = TlsStream::connect(transport, FakeSession::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+}
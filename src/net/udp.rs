@@ -9,21 +9,30 @@
 //! UDP
 
 use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
 
 #[cfg(unix)]
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 
 use mio::EventSet;
 use mio::udp::UdpSocket as MioUdpSocket;
 
 use scheduler::ReadyType;
-use super::{each_addr, GenericEvented, SyncGuard};
+use super::{each_addr, load_timeout, store_timeout, timeout_deadline, GenericEvented, SyncGuard};
+
+#[cfg(unix)]
+use super::sockopt;
 
 macro_rules! create_udp_socket {
     ($inner:expr) => (UdpSocket::new($inner, EventSet::readable() | EventSet::writable()));
 }
 
+/// A UDP socket
+///
+/// All methods take `&self`, so a socket can be shared between multiple coroutines
+/// using an `Arc`. Concurrent calls to `recv_from()` distribute the incoming datagrams
+/// among the receiving coroutines.
 pub type UdpSocket = GenericEvented<MioUdpSocket>;
 
 impl UdpSocket {
@@ -52,63 +61,199 @@ impl UdpSocket {
         })
     }
 
+    /// Adopts a socket created by the standard library or another library
+    ///
+    /// The socket is put into non-blocking mode and registered with the running Scheduler.
+    #[cfg(unix)]
+    pub fn from_std(socket: ::std::net::UdpSocket) -> io::Result<UdpSocket> {
+        try!(sockopt::set_nonblocking(socket.as_raw_fd()));
+        let inner = unsafe { MioUdpSocket::from_raw_fd(socket.into_raw_fd()) };
+        create_udp_socket!(inner)
+    }
+
     pub fn try_clone(&self) -> io::Result<UdpSocket> {
         let inner = try!(self.inner.try_clone());
         create_udp_socket!(inner)
     }
 
+    /// Sets the timeout of `recv_from()`, `None` waits indefinitely
+    ///
+    /// A receive which is parked for longer than this fails with `ErrorKind::TimedOut`.
+    /// The timeout is measured in whole milliseconds and applies to every call separately.
+    /// Passing a zero duration fails with `ErrorKind::InvalidInput`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.read_timeout_ms, timeout)
+    }
+
+    /// Gets the timeout of `recv_from()`
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(load_timeout(&self.read_timeout_ms))
+    }
+
+    /// Sets the timeout of `send_to()`, `None` waits indefinitely
+    ///
+    /// See `set_read_timeout()` for details.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.write_timeout_ms, timeout)
+    }
+
+    /// Gets the timeout of `send_to()`
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(load_timeout(&self.write_timeout_ms))
+    }
+
     pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
         let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
 
         loop {
             match self.inner.send_to(buf, target) {
                 Ok(None) => {
-                    trace!("UdpSocket({:?}): send_to() => WouldBlock", self.token);
+                    io_trace!("UdpSocket({:?}): send_to() => WouldBlock", self.token);
                 }
                 Ok(Some(len)) => {
-                    trace!("UdpSocket({:?}): send_to() => Ok({})", self.token, len);
+                    io_trace!("UdpSocket({:?}): send_to() => Ok({})", self.token, len);
+                    self.ready_states.pass_on(ReadyType::Writable);
                     return Ok(len);
                 }
                 Err(err) => {
-                    trace!("UdpSocket({:?}): send_to() => Err(..)", self.token);
+                    io_trace!("UdpSocket({:?}): send_to() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UdpSocket({:?}): wait(Writable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Writable, deadline)));
+        }
+    }
+
+    /// Connects the socket to a single peer
+    ///
+    /// Afterwards `send()` and `recv()` can be used, only datagrams from that peer are received,
+    /// and ICMP errors like "port unreachable" are reported by the following calls.
+    #[cfg(unix)]
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let fd = self.as_raw_fd();
+        each_addr(addr, |addr| sockopt::connect(fd, addr))
+    }
+
+    /// Sends a datagram to the peer set by `connect()`
+    #[cfg(unix)]
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+
+        loop {
+            match sockopt::send(self.as_raw_fd(), buf) {
+                Ok(len) => {
+                    io_trace!("UdpSocket({:?}): send() => Ok({})", self.token, len);
+                    self.ready_states.pass_on(ReadyType::Writable);
+                    return Ok(len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UdpSocket({:?}): send() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UdpSocket({:?}): send() => Err(..)", self.token);
                     return Err(err);
                 }
             }
 
-            trace!("UdpSocket({:?}): wait(Writable)", self.token);
-            self.ready_states.wait(ReadyType::Writable);
-            sync_guard.disarm();
+            io_trace!("UdpSocket({:?}): wait(Writable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Writable, deadline)));
+        }
+    }
+
+    /// Receives a datagram from the peer set by `connect()`
+    #[cfg(unix)]
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+
+        loop {
+            match sockopt::recv(self.as_raw_fd(), buf) {
+                Ok(len) => {
+                    io_trace!("UdpSocket({:?}): recv() => Ok({})", self.token, len);
+                    self.ready_states.pass_on(ReadyType::Readable);
+                    return Ok(len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UdpSocket({:?}): recv() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UdpSocket({:?}): recv() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UdpSocket({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Readable, deadline)));
         }
     }
 
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
 
         loop {
             match self.inner.recv_from(buf) {
                 Ok(None) => {
-                    trace!("UdpSocket({:?}): recv_from() => WouldBlock", self.token);
+                    io_trace!("UdpSocket({:?}): recv_from() => WouldBlock", self.token);
                 }
                 Ok(Some(t)) => {
-                    trace!("UdpSocket({:?}): recv_from() => Ok(..)", self.token);
+                    io_trace!("UdpSocket({:?}): recv_from() => Ok(..)", self.token);
+                    self.ready_states.pass_on(ReadyType::Readable);
                     return Ok(t);
                 }
                 Err(err) => {
-                    trace!("UdpSocket({:?}): recv_from() => Err(..)", self.token);
+                    io_trace!("UdpSocket({:?}): recv_from() => Err(..)", self.token);
                     return Err(err);
                 }
             }
 
-            trace!("UdpSocket({:?}): wait(Readable)", self.token);
-            self.ready_states.wait(ReadyType::Readable);
-            sync_guard.disarm();
+            io_trace!("UdpSocket({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Readable, deadline)));
         }
     }
 }
 
+// Multicast
+impl UdpSocket {
+    /// Joins the IPv4 multicast group `multiaddr` on the default interface
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr) -> io::Result<()> {
+        self.inner.join_multicast(&IpAddr::V4(*multiaddr))
+    }
+
+    /// Leaves the IPv4 multicast group `multiaddr`
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr) -> io::Result<()> {
+        self.inner.leave_multicast(&IpAddr::V4(*multiaddr))
+    }
+
+    /// Joins the IPv6 multicast group `multiaddr` on the default interface
+    pub fn join_multicast_v6(&self, multiaddr: &Ipv6Addr) -> io::Result<()> {
+        self.inner.join_multicast(&IpAddr::V6(*multiaddr))
+    }
+
+    /// Leaves the IPv6 multicast group `multiaddr`
+    pub fn leave_multicast_v6(&self, multiaddr: &Ipv6Addr) -> io::Result<()> {
+        self.inner.leave_multicast(&IpAddr::V6(*multiaddr))
+    }
+
+    /// Sets whether multicast datagrams sent by this socket are looped back to local sockets
+    pub fn set_multicast_loop_v4(&self, on: bool) -> io::Result<()> {
+        self.inner.set_multicast_loop(on)
+    }
+
+    /// Sets the time-to-live of outgoing IPv4 multicast datagrams
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_multicast_time_to_live(ttl as i32)
+    }
+}
+
 #[cfg(unix)]
 impl FromRawFd for UdpSocket {
     unsafe fn from_raw_fd(fd: RawFd) -> UdpSocket {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_udp_socket!(inner).unwrap()
     }
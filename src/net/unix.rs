@@ -8,11 +8,19 @@
 
 //! Unix domain socket
 
+use std::ascii;
+use std::ffi::OsStr;
+use std::fmt;
 use std::io;
-use std::os::unix::io::{FromRawFd, RawFd};
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStderr, ChildStdin, ChildStdout};
+use std::str::FromStr;
 
-use mio::EventSet;
+use libc;
+use mio::{EventSet, Evented, PollOpt, Selector, Token};
+use mio::unix::EventedFd;
 use mio::unix::PipeReader as MioPipeReader;
 use mio::unix::PipeWriter as MioPipeWriter;
 use mio::unix::UnixListener as MioUnixListener;
@@ -20,14 +28,22 @@ use mio::unix::UnixSocket as MioUnixSocket;
 use mio::unix::UnixStream as MioUnixStream;
 
 use scheduler::ReadyType;
-use super::{GenericEvented, SyncGuard};
+use super::{disable_sigpipe, sockopt, timeout_deadline, EventedWrite, GenericEvented, SyncGuard};
 
 macro_rules! create_unix_listener {
     ($inner:expr) => (UnixListener::new($inner, EventSet::readable()));
 }
 
 macro_rules! create_unix_stream {
-    ($inner:expr) => (UnixStream::new($inner, EventSet::readable() | EventSet::writable()));
+    ($inner:expr) => ({
+        let inner = $inner;
+        disable_sigpipe(&inner)
+            .and_then(move |_| UnixStream::new(inner, EventSet::readable() | EventSet::writable()))
+    });
+}
+
+macro_rules! create_unix_datagram {
+    ($inner:expr) => (UnixDatagram::new($inner, EventSet::readable() | EventSet::writable()));
 }
 
 macro_rules! create_pipe_reader {
@@ -38,6 +54,99 @@ macro_rules! create_pipe_writer {
     ($inner:expr) => (PipeWriter::new($inner, EventSet::writable()));
 }
 
+/// The address of a Unix domain socket
+///
+/// It's either unnamed, a path in the filesystem or a name in Linux' abstract namespace.
+/// Abstract addresses are represented as paths starting with a null byte, which is why a
+/// `UnixAddr` can be passed to `UnixListener::bind()` and `UnixStream::connect()` directly.
+/// Unlike paths they vanish once the socket is closed, so that they never need to be cleaned up.
+///
+/// When displayed or parsed abstract addresses are written as `@name`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct UnixAddr(Vec<u8>);
+
+impl UnixAddr {
+    /// Creates the address of a socket bound to `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> UnixAddr {
+        UnixAddr(path.as_ref().as_os_str().as_bytes().to_vec())
+    }
+
+    /// Creates an address in the abstract namespace, which is only supported on Linux
+    pub fn from_abstract(name: &[u8]) -> UnixAddr {
+        let mut bytes = Vec::with_capacity(name.len() + 1);
+        bytes.push(0);
+        bytes.extend_from_slice(name);
+        UnixAddr(bytes)
+    }
+
+    /// Returns true if the socket isn't bound to any address
+    pub fn is_unnamed(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the path of the socket, unless it's unnamed or abstract
+    pub fn as_pathname(&self) -> Option<&Path> {
+        if self.is_unnamed() || self.as_abstract().is_some() {
+            None
+        } else {
+            Some(self.as_ref())
+        }
+    }
+
+    /// Returns the name of an abstract address, without the leading null byte
+    pub fn as_abstract(&self) -> Option<&[u8]> {
+        match self.0.first() {
+            Some(&0) => Some(&self.0[1..]),
+            _ => None,
+        }
+    }
+}
+
+impl AsRef<Path> for UnixAddr {
+    fn as_ref(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.0))
+    }
+}
+
+impl fmt::Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.as_abstract() {
+            try!(f.write_str("@"));
+
+            for c in name.iter().flat_map(|&b| ascii::escape_default(b)) {
+                try!(write!(f, "{}", c as char));
+            }
+
+            Ok(())
+        } else if self.is_unnamed() {
+            f.write_str("(unnamed)")
+        } else {
+            write!(f, "{}", Path::new(OsStr::from_bytes(&self.0)).display())
+        }
+    }
+}
+
+impl fmt::Debug for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UnixAddr({})", self)
+    }
+}
+
+impl FromStr for UnixAddr {
+    type Err = io::Error;
+
+    /// Parses `@name` as an abstract address and everything else as a path
+    fn from_str(s: &str) -> io::Result<UnixAddr> {
+        if s.is_empty() {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "empty Unix socket address"))
+        } else if s.starts_with('@') {
+            Ok(UnixAddr::from_abstract(s[1..].as_bytes()))
+        } else {
+            Ok(UnixAddr::from_path(s))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnixSocket {
     inner: MioUnixSocket,
@@ -81,32 +190,47 @@ impl FromRawFd for UnixSocket {
 pub type UnixListener = GenericEvented<MioUnixListener>;
 
 impl UnixListener {
+    /// Creates a listener bound to `path`
+    ///
+    /// On Linux a path starting with a null byte, e.g. a `UnixAddr::from_abstract()`,
+    /// binds the listener to an address in the abstract namespace instead.
     pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
-        let inner = try!(MioUnixListener::bind(path.as_ref()));
+        let fd = try!(sockopt::socket(libc::AF_UNIX, libc::SOCK_STREAM));
+        let inner = unsafe { MioUnixListener::from_raw_fd(fd) };
+
+        try!(sockopt::bind_unix(fd, path.as_ref()));
+        try!(sockopt::listen(fd, 128));
         create_unix_listener!(inner)
     }
 
+    /// Returns the address this listener is bound to
+    pub fn local_addr(&self) -> io::Result<UnixAddr> {
+        sockopt::unix_local_addr(self.as_raw_fd()).map(UnixAddr)
+    }
+
     pub fn accept(&self) -> io::Result<UnixStream> {
         let mut sync_guard = SyncGuard::new();
 
         loop {
             match self.inner.accept() {
                 Ok(None) => {
-                    trace!("UnixListener({:?}): accept() => WouldBlock", self.token);
+                    io_trace!("UnixListener({:?}): accept() => WouldBlock", self.token);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UnixListener({:?}): accept() => WouldBlock", self.token);
                 }
                 Ok(Some(stream)) => {
-                    trace!("UnixListener({:?}): accept() => Ok(..)", self.token);
+                    io_trace!("UnixListener({:?}): accept() => Ok(..)", self.token);
                     return create_unix_stream!(stream);
                 }
                 Err(err) => {
-                    trace!("UnixListener({:?}): accept() => Err(..)", self.token);
+                    io_trace!("UnixListener({:?}): accept() => Err(..)", self.token);
                     return Err(err);
                 }
             }
 
-            trace!("UnixListener({:?}): wait(Readable)", self.token);
-            self.ready_states.wait(ReadyType::Readable);
-            sync_guard.disarm();
+            io_trace!("UnixListener({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.ready_states.wait(ReadyType::Readable)));
         }
     }
 
@@ -114,10 +238,29 @@ impl UnixListener {
         let inner = try!(self.inner.try_clone());
         create_unix_listener!(inner)
     }
+
+    /// Returns an iterator over the connections being received on this listener
+    ///
+    /// Just like `TcpListener::incoming()` it never returns `None` and never yields `WouldBlock`.
+    pub fn incoming(&self) -> Incoming {
+        Incoming(self)
+    }
+}
+
+/// An infinite iterator over the connections of a `UnixListener`, see `UnixListener::incoming()`
+pub struct Incoming<'a>(&'a UnixListener);
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<UnixStream>;
+
+    fn next(&mut self) -> Option<io::Result<UnixStream>> {
+        Some(self.0.accept())
+    }
 }
 
 impl FromRawFd for UnixListener {
     unsafe fn from_raw_fd(fd: RawFd) -> UnixListener {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_unix_listener!(inner).unwrap()
     }
@@ -125,12 +268,134 @@ impl FromRawFd for UnixListener {
 
 pub type UnixStream = GenericEvented<MioUnixStream>;
 
+impl EventedWrite for MioUnixStream {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn nosignal_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        sockopt::send_nosignal(self.as_raw_fd(), buf)
+    }
+
+    fn nosignal_write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        sockopt::sendv_nosignal(self.as_raw_fd(), bufs)
+    }
+}
+
+/// Credentials of the process on the other end of a `UnixStream`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UCred {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only available on Linux
+    pub pid: Option<i32>,
+}
+
 impl UnixStream {
+    /// Connects to the listener bound to `path`, which may be abstract, see `UnixListener::bind()`
     pub fn connect<P: AsRef<Path>>(path: &P) -> io::Result<UnixStream> {
-        let inner = try!(MioUnixStream::connect(path.as_ref()));
+        let fd = try!(sockopt::socket(libc::AF_UNIX, libc::SOCK_STREAM));
+        let inner = unsafe { MioUnixStream::from_raw_fd(fd) };
+
+        try!(sockopt::connect_unix(fd, path.as_ref()));
         create_unix_stream!(inner)
     }
 
+    /// Returns the address this stream is bound to, which usually is unnamed
+    pub fn local_addr(&self) -> io::Result<UnixAddr> {
+        sockopt::unix_local_addr(self.as_raw_fd()).map(UnixAddr)
+    }
+
+    /// Returns the address of the listener this stream is connected to
+    pub fn peer_addr(&self) -> io::Result<UnixAddr> {
+        sockopt::unix_peer_addr(self.as_raw_fd()).map(UnixAddr)
+    }
+
+    /// Creates a pair of streams connected to each other
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = try!(sockopt::socketpair(libc::SOCK_STREAM));
+        let (a, b) = unsafe { (MioUnixStream::from_raw_fd(a), MioUnixStream::from_raw_fd(b)) };
+        Ok((try!(create_unix_stream!(a)), try!(create_unix_stream!(b))))
+    }
+
+    /// Returns the credentials of the peer, as they were when the connection was established
+    ///
+    /// Uses `SO_PEERCRED` on Linux and `getpeereid()` on BSDs.
+    #[cfg(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd"))]
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        sockopt::peer_cred(self.as_raw_fd()).map(|(uid, gid, pid)| {
+            UCred {
+                uid: uid,
+                gid: gid,
+                pid: pid,
+            }
+        })
+    }
+
+    /// Writes `buf` and passes the file descriptors `fds` to the peer
+    ///
+    /// The file descriptors stay open in this process. Fails with `ErrorKind::InvalidInput` if
+    /// `buf` is empty, since the descriptors are attached to the data and are received together
+    /// with its first byte.
+    pub fn send_fds(&mut self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "file descriptors can't be sent without data"));
+        }
+
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+
+        loop {
+            match sockopt::send_fds(self.as_raw_fd(), buf, fds) {
+                Ok(len) => {
+                    io_trace!("UnixStream({:?}): send_fds() => Ok({})", self.token, len);
+                    return Ok(len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UnixStream({:?}): send_fds() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UnixStream({:?}): send_fds() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UnixStream({:?}): wait(Writable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Writable, deadline)));
+        }
+    }
+
+    /// Reads into `buf` and receives up to `fds.len()` file descriptors passed by the peer
+    ///
+    /// Returns the number of bytes and the number of file descriptors received. The caller
+    /// owns the received descriptors, which can be wrapped using `FromRawFd`, e.g. to adopt a
+    /// listening socket. Descriptors which don't fit into `fds` are closed.
+    pub fn recv_fds(&mut self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+
+        loop {
+            match sockopt::recv_fds(self.as_raw_fd(), buf, fds) {
+                Ok(t) => {
+                    io_trace!("UnixStream({:?}): recv_fds() => Ok({:?})", self.token, t);
+                    return Ok(t);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UnixStream({:?}): recv_fds() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UnixStream({:?}): recv_fds() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UnixStream({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Readable, deadline)));
+        }
+    }
+
     pub fn try_clone(&self) -> io::Result<UnixStream> {
         let inner = try!(self.inner.try_clone());
         create_unix_stream!(inner)
@@ -139,11 +404,13 @@ impl UnixStream {
 
 impl FromRawFd for UnixStream {
     unsafe fn from_raw_fd(fd: RawFd) -> UnixStream {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_unix_stream!(inner).unwrap()
     }
 }
 
+/// Creates an anonymous pipe, whose ends are registered with the Scheduler
 pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
     let (reader, writer) = try!(::mio::unix::pipe());
     let reader = try!(create_pipe_reader!(reader));
@@ -153,18 +420,467 @@ pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
 
 pub type PipeReader = GenericEvented<MioPipeReader>;
 
+impl PipeReader {
+    /// Takes over the captured standard output of a child process
+    pub fn from_child_stdout(stdout: ChildStdout) -> io::Result<PipeReader> {
+        PipeReader::adopt(stdout.into_raw_fd())
+    }
+
+    /// Takes over the captured standard error of a child process
+    pub fn from_child_stderr(stderr: ChildStderr) -> io::Result<PipeReader> {
+        PipeReader::adopt(stderr.into_raw_fd())
+    }
+
+    fn adopt(fd: RawFd) -> io::Result<PipeReader> {
+        let inner = unsafe { MioPipeReader::from_raw_fd(fd) };
+        try!(sockopt::set_nonblocking(fd));
+        create_pipe_reader!(inner)
+    }
+}
+
 impl FromRawFd for PipeReader {
     unsafe fn from_raw_fd(fd: RawFd) -> PipeReader {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_pipe_reader!(inner).unwrap()
     }
 }
 
+/// The writing end of a pipe
+///
+/// Unlike with sockets, writing to a pipe whose reader has been closed raises `SIGPIPE`,
+/// which terminates the process unless the signal is ignored.
 pub type PipeWriter = GenericEvented<MioPipeWriter>;
 
+impl PipeWriter {
+    /// Takes over the captured standard input of a child process
+    pub fn from_child_stdin(stdin: ChildStdin) -> io::Result<PipeWriter> {
+        let fd = stdin.into_raw_fd();
+        let inner = unsafe { MioPipeWriter::from_raw_fd(fd) };
+        try!(sockopt::set_nonblocking(fd));
+        create_pipe_writer!(inner)
+    }
+}
+
+impl EventedWrite for MioPipeWriter {
+    fn nosignal_write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        sockopt::writev(self.as_raw_fd(), bufs)
+    }
+}
+
 impl FromRawFd for PipeWriter {
     unsafe fn from_raw_fd(fd: RawFd) -> PipeWriter {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
         let inner = FromRawFd::from_raw_fd(fd);
         create_pipe_writer!(inner).unwrap()
     }
 }
+
+/// A Unix domain datagram socket, which mio doesn't provide
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct MioUnixDatagram(RawFd);
+
+impl MioUnixDatagram {
+    fn new() -> io::Result<MioUnixDatagram> {
+        sockopt::socket(libc::AF_UNIX, libc::SOCK_DGRAM).map(MioUnixDatagram)
+    }
+}
+
+impl Evented for MioUnixDatagram {
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        EventedFd(&self.0).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        EventedFd(&self.0).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.0).deregister(selector)
+    }
+}
+
+impl AsRawFd for MioUnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl FromRawFd for MioUnixDatagram {
+    unsafe fn from_raw_fd(fd: RawFd) -> MioUnixDatagram {
+        MioUnixDatagram(fd)
+    }
+}
+
+impl Drop for MioUnixDatagram {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A Unix domain datagram socket
+///
+/// Just like `UdpSocket` all methods take `&self`, so that it can be shared between coroutines.
+pub type UnixDatagram = GenericEvented<MioUnixDatagram>;
+
+impl UnixDatagram {
+    /// Creates a socket bound to `path`
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixDatagram> {
+        let inner = try!(MioUnixDatagram::new());
+        try!(sockopt::bind_unix(inner.0, path.as_ref()));
+        create_unix_datagram!(inner)
+    }
+
+    /// Creates a socket which isn't bound to any path
+    pub fn unbound() -> io::Result<UnixDatagram> {
+        let inner = try!(MioUnixDatagram::new());
+        create_unix_datagram!(inner)
+    }
+
+    /// Creates a pair of sockets connected to each other
+    pub fn pair() -> io::Result<(UnixDatagram, UnixDatagram)> {
+        let (a, b) = try!(sockopt::socketpair(libc::SOCK_DGRAM));
+        let (a, b) = (MioUnixDatagram(a), MioUnixDatagram(b));
+        Ok((try!(create_unix_datagram!(a)), try!(create_unix_datagram!(b))))
+    }
+
+    /// Connects the socket to `path`, so that `send()` and `recv()` can be used
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        sockopt::connect_unix(self.as_raw_fd(), path.as_ref())
+    }
+
+    /// Sends a datagram to the socket bound to `path`
+    pub fn send_to<P: AsRef<Path>>(&self, buf: &[u8], path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        self.send_with(|fd| sockopt::send_to_unix(fd, buf, path))
+    }
+
+    /// Sends a datagram to the connected peer
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_with(|fd| sockopt::send(fd, buf))
+    }
+
+    /// Receives a datagram together with the path of its sender, if the sender is bound to one
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.read_timeout_ms);
+
+        loop {
+            match sockopt::recv_from_unix(self.as_raw_fd(), buf) {
+                Ok(t) => {
+                    io_trace!("UnixDatagram({:?}): recv_from() => Ok(..)", self.token);
+                    self.ready_states.pass_on(ReadyType::Readable);
+                    return Ok(t);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UnixDatagram({:?}): recv_from() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UnixDatagram({:?}): recv_from() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UnixDatagram({:?}): wait(Readable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Readable, deadline)));
+        }
+    }
+
+    /// Receives a datagram from the connected peer
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(len, _)| len)
+    }
+
+    fn send_with<F>(&self, mut send: F) -> io::Result<usize>
+        where F: FnMut(RawFd) -> io::Result<usize>
+    {
+        let mut sync_guard = SyncGuard::new();
+        let deadline = timeout_deadline(&self.write_timeout_ms);
+
+        loop {
+            match send(self.as_raw_fd()) {
+                Ok(len) => {
+                    io_trace!("UnixDatagram({:?}): send() => Ok({})", self.token, len);
+                    self.ready_states.pass_on(ReadyType::Writable);
+                    return Ok(len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    io_trace!("UnixDatagram({:?}): send() => WouldBlock", self.token);
+                }
+                Err(err) => {
+                    io_trace!("UnixDatagram({:?}): send() => Err(..)", self.token);
+                    return Err(err);
+                }
+            }
+
+            io_trace!("UnixDatagram({:?}): wait(Writable)", self.token);
+            try!(sync_guard.waited(self.wait_until(ReadyType::Writable, deadline)));
+        }
+    }
+}
+
+impl FromRawFd for UnixDatagram {
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixDatagram {
+        sockopt::set_nonblocking(fd).expect("failed to make the file descriptor non-blocking");
+        let inner = FromRawFd::from_raw_fd(fd);
+        create_unix_datagram!(inner).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use mio::EventSet;
+
+    use net::{self, GenericEvented, ReadyMode, ReadyType};
+    use scheduler::Scheduler;
+    use super::{UnixAddr, UnixDatagram, UnixListener, UnixStream};
+
+    #[test]
+    fn test_unix_incoming() {
+        Scheduler::new()
+            .run(|| {
+                let path = ::std::env::temp_dir().join("coio-test-unix-incoming.sock");
+                let _ = ::std::fs::remove_file(&path);
+
+                let listener = UnixListener::bind(&path).unwrap();
+
+                let client = {
+                    let path = path.clone();
+
+                    Scheduler::spawn(move || {
+                        for _ in 0..3 {
+                            UnixStream::connect(&path).unwrap();
+                        }
+                    })
+                };
+
+                for stream in listener.incoming().take(3) {
+                    stream.unwrap();
+                }
+
+                client.join().unwrap();
+                let _ = ::std::fs::remove_file(&path);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unix_addr_display_parse() {
+        let addr: UnixAddr = "@coio".parse().unwrap();
+        assert_eq!(addr, UnixAddr::from_abstract(b"coio"));
+        assert_eq!(addr.as_abstract(), Some(&b"coio"[..]));
+        assert_eq!(addr.as_pathname(), None);
+        assert_eq!(addr.to_string(), "@coio");
+
+        let addr: UnixAddr = "/tmp/coio.sock".parse().unwrap();
+        assert_eq!(addr.as_pathname(), Some(::std::path::Path::new("/tmp/coio.sock")));
+        assert_eq!(addr.to_string(), "/tmp/coio.sock");
+
+        assert_eq!(UnixAddr::from_abstract(b"a\0b").to_string(), "@a\\x00b");
+        assert!("".parse::<UnixAddr>().is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_unix_abstract() {
+        Scheduler::new()
+            .run(|| {
+                let addr = UnixAddr::from_abstract(b"coio-test-unix-abstract");
+                let listener = UnixListener::bind(&addr).unwrap();
+                assert_eq!(listener.local_addr().unwrap(), addr);
+
+                let client = {
+                    let addr = addr.clone();
+
+                    Scheduler::spawn(move || {
+                        let mut stream = UnixStream::connect(&addr).unwrap();
+                        assert_eq!(stream.peer_addr().unwrap(), addr);
+                        assert!(stream.local_addr().unwrap().is_unnamed());
+                        stream.write_all(b"abstract").unwrap();
+                    })
+                };
+
+                let mut stream = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf[..], b"abstract");
+
+                client.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unix_datagram() {
+        Scheduler::new()
+            .run(|| {
+                let path = ::std::env::temp_dir().join("coio-test-unix-datagram.sock");
+                let _ = ::std::fs::remove_file(&path);
+
+                let server = UnixDatagram::bind(&path).unwrap();
+                let client = UnixDatagram::unbound().unwrap();
+
+                client.send_to(b"<13>hello", &path).unwrap();
+
+                let mut buf = [0u8; 64];
+                let (len, sender) = server.recv_from(&mut buf).unwrap();
+                assert_eq!(&buf[..len], b"<13>hello");
+                assert_eq!(sender, None);
+
+                let (a, b) = UnixDatagram::pair().unwrap();
+                let reader = Scheduler::spawn(move || {
+                    let mut buf = [0u8; 64];
+                    let len = b.recv(&mut buf).unwrap();
+                    buf[..len].to_vec()
+                });
+
+                a.send(b"ping").unwrap();
+                assert_eq!(&reader.join().unwrap()[..], b"ping");
+
+                let _ = ::std::fs::remove_file(&path);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unix_pass_fds() {
+        use std::io::Read;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        Scheduler::new()
+            .run(|| {
+                let (mut a, mut b) = UnixStream::pair().unwrap();
+                let (mut reader, mut writer) = net::unix::pipe().unwrap();
+
+                let receiver = Scheduler::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    let mut fds = [-1; 2];
+                    let (len, count) = b.recv_fds(&mut buf, &mut fds).unwrap();
+
+                    assert_eq!(&buf[..len], b"fd");
+                    assert_eq!(count, 1);
+
+                    // The received descriptor refers to the same pipe
+                    let mut writer = unsafe { net::unix::PipeWriter::from_raw_fd(fds[0]) };
+                    writer.write_all(b"through the pipe").unwrap();
+                });
+
+                a.send_fds(b"fd", &[writer.as_raw_fd()]).unwrap();
+                receiver.join().unwrap();
+                writer.flush().unwrap();
+                drop(writer);
+
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).unwrap();
+                assert_eq!(&data[..], b"through the pipe");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unix_pass_more_fds_than_requested() {
+        use std::io;
+        use std::os::unix::io::AsRawFd;
+
+        use libc;
+
+        Scheduler::new()
+            .run(|| {
+                let (mut a, mut b) = UnixStream::pair().unwrap();
+                let (_reader, writer) = net::unix::pipe().unwrap();
+
+                let err = a.send_fds(b"", &[writer.as_raw_fd()]).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+                // The padding of the control buffer leaves room for a second descriptor,
+                // which must neither be written past `fds` nor be leaked
+                let fd = writer.as_raw_fd();
+                a.send_fds(b"fd", &[fd, fd]).unwrap();
+
+                let mut buf = [0u8; 16];
+                let mut fds = [-1; 1];
+                let (len, count) = b.recv_fds(&mut buf, &mut fds).unwrap();
+
+                assert_eq!(&buf[..len], b"fd");
+                assert_eq!(count, 1);
+                assert!(fds[0] != fd);
+                unsafe { libc::close(fds[0]) };
+            })
+            .unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_unix_peer_cred() {
+        use libc;
+
+        Scheduler::new()
+            .run(|| {
+                let (a, _b) = UnixStream::pair().unwrap();
+                let cred = a.peer_cred().unwrap();
+
+                assert_eq!(cred.uid, unsafe { libc::getuid() } as u32);
+                assert_eq!(cred.gid, unsafe { libc::getgid() } as u32);
+
+                if cfg!(target_os = "linux") {
+                    assert_eq!(cred.pid, Some(unsafe { libc::getpid() } as i32));
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_broadcast_readiness() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let (reader, writer) = ::mio::unix::pipe().unwrap();
+                let reader = GenericEvented::with_mode(reader,
+                                                       EventSet::readable(),
+                                                       ReadyMode::Broadcast)
+                                 .unwrap();
+                let reader = Arc::new(reader);
+                let mut writer = GenericEvented::new(writer, EventSet::writable()).unwrap();
+                let woken = Arc::new(AtomicUsize::new(0));
+
+                let mut handles = Vec::new();
+
+                for _ in 0..4 {
+                    let reader = reader.clone();
+                    let woken = woken.clone();
+
+                    handles.push(Scheduler::spawn(move || {
+                        net::select(&[(&*reader, ReadyType::Readable)]).unwrap();
+                        woken.fetch_add(1, Ordering::SeqCst);
+                    }));
+                }
+
+                // Let all coroutines park on the pipe
+                Scheduler::sched();
+
+                writer.write_all(b"x").unwrap();
+
+                for h in handles {
+                    h.join().unwrap();
+                }
+
+                assert_eq!(woken.load(Ordering::SeqCst), 4);
+            })
+            .unwrap();
+    }
+}
@@ -9,12 +9,57 @@
 //! Coroutine options
 
 use std::default::Default;
+use std::fmt;
+use std::sync::Arc;
+
+/// Scheduling priority of a coroutine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// The default priority
+    Normal,
+    /// Coroutines with a high priority are resumed before all normal ones on the same Processor
+    ///
+    /// They are also fetched from the global queue, e.g. after being woken up by I/O, before any
+    /// normal coroutine waiting there.
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+/// Information passed to the `on_resume` hook of a coroutine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeContext {
+    /// The id of the Processor which is about to resume the coroutine
+    pub processor_id: usize,
+    /// The id of the Processor the coroutine has been running on before,
+    /// or `None` if it is resumed for the first time
+    pub last_processor_id: Option<usize>,
+}
+
+impl ResumeContext {
+    /// Returns true if the coroutine has been moved to another Processor since it last ran
+    #[inline]
+    pub fn migrated(&self) -> bool {
+        self.last_processor_id.map_or(false, |id| id != self.processor_id)
+    }
+}
+
+/// Hook invoked every time a coroutine is resumed
+pub type ResumeHook = Arc<Fn(&ResumeContext) + Send + Sync + 'static>;
 
 /// Coroutine options
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Options {
     pub stack_size: usize,
     pub name: Option<String>,
+    pub priority: Priority,
+    pub trace: bool,
+    pub on_resume: Option<ResumeHook>,
+    pub guard_page: bool,
 }
 
 /// Default coroutine stack size, 128KB
@@ -25,6 +70,10 @@ impl Options {
         Options {
             stack_size: DEFAULT_STACK,
             name: None,
+            priority: Priority::Normal,
+            trace: false,
+            on_resume: None,
+            guard_page: true,
         }
     }
 
@@ -37,6 +86,52 @@ impl Options {
         self.name = Some(name);
         self
     }
+
+    pub fn priority(&mut self, priority: Priority) -> &mut Options {
+        self.priority = priority;
+        self
+    }
+
+    /// Enables verbose I/O tracing for the coroutine (see `coio::set_current_tracing()`)
+    pub fn trace(&mut self, trace: bool) -> &mut Options {
+        self.trace = trace;
+        self
+    }
+
+    /// Places an inaccessible guard page below the stack of the coroutine, which is the default
+    ///
+    /// A stack overflow then faults with a message naming the coroutine, instead of silently
+    /// overwriting the memory next to the stack. Without a guard page the stack is a plain heap
+    /// allocation, which is cheaper to create, but isn't pooled for reuse.
+    pub fn guard_page(&mut self, enabled: bool) -> &mut Options {
+        self.guard_page = enabled;
+        self
+    }
+
+    /// Sets a hook which is invoked right before the coroutine is resumed
+    ///
+    /// The hook runs on the worker thread of the resuming Processor, which makes it possible to
+    /// detect migrations (see `ResumeContext::migrated()`) and refresh thread local resources.
+    /// It is called on every resumption and must thus be cheap, must not block and must not panic.
+    pub fn on_resume<F>(&mut self, hook: F) -> &mut Options
+        where F: Fn(&ResumeContext) + Send + Sync + 'static
+    {
+        self.on_resume = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Options")
+         .field("stack_size", &self.stack_size)
+         .field("name", &self.name)
+         .field("priority", &self.priority)
+         .field("trace", &self.trace)
+         .field("on_resume", &self.on_resume.is_some())
+         .field("guard_page", &self.guard_page)
+         .finish()
+    }
 }
 
 impl Default for Options {
@@ -0,0 +1,69 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Operating system specific I/O objects
+//!
+//! Pipes are only available on Unix yet, Windows named pipes aren't supported.
+
+#[cfg(unix)]
+pub use net::unix::{pipe, PipeReader, PipeWriter};
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn os_pipe() {
+        Scheduler::new()
+            .run(|| {
+                let (mut reader, mut writer) = pipe().unwrap();
+
+                let writer = Scheduler::spawn(move || {
+                    writer.write_all(b"through the pipe").unwrap();
+                });
+
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf[..], b"through the pipe");
+
+                writer.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn os_pipe_child_process() {
+        Scheduler::new()
+            .run(|| {
+                let mut child = Command::new("cat")
+                                    .stdin(Stdio::piped())
+                                    .stdout(Stdio::piped())
+                                    .spawn()
+                                    .unwrap();
+
+                let mut stdin = PipeWriter::from_child_stdin(child.stdin.take().unwrap()).unwrap();
+                let mut stdout = PipeReader::from_child_stdout(child.stdout.take().unwrap())
+                                     .unwrap();
+
+                stdin.write_all(b"echo").unwrap();
+                drop(stdin);
+
+                let mut buf = Vec::new();
+                stdout.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf[..], b"echo");
+
+                assert!(child.wait().unwrap().success());
+            })
+            .unwrap();
+    }
+}
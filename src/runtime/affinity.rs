@@ -0,0 +1,73 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pinning of threads to CPUs
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+use std::mem;
+
+#[cfg(target_os = "linux")]
+use libc::{self, c_int, pid_t, size_t};
+
+// Mirrors the glibc definition of `cpu_set_t` which is able to hold 1024 CPUs
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CpuSet {
+    bits: [u64; 16],
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sched_setaffinity(pid: pid_t, cpusetsize: size_t, mask: *const CpuSet) -> c_int;
+}
+
+/// Restricts the current thread to run on `cpu` only
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    let mut set = CpuSet { bits: [0; 16] };
+
+    if cpu >= set.bits.len() * 64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index out of range"));
+    }
+
+    set.bits[cpu / 64] |= 1 << (cpu % 64);
+
+    // A pid of 0 refers to the calling thread
+    let ret = unsafe { sched_setaffinity(0, mem::size_of::<CpuSet>() as size_t, &set) };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_: usize) -> io::Result<()> {
+    warn!("pinning threads to CPUs is not supported on this platform");
+    Ok(())
+}
+
+/// Returns the number of online CPUs
+#[cfg(target_os = "linux")]
+pub fn cpu_count() -> usize {
+    let ret = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+
+    if ret < 1 {
+        1
+    } else {
+        ret as usize
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_count() -> usize {
+    1
+}
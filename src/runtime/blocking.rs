@@ -0,0 +1,161 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Thread pool for blocking operations
+
+use std::boxed::FnBox;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Default maximum number of threads of the blocking pool
+pub const DEFAULT_MAX_THREADS: usize = 128;
+
+/// Idle threads exit after this amount of time
+const KEEP_ALIVE_MS: u64 = 10_000;
+
+pub type Job = Box<FnBox() + Send + 'static>;
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    is_shutdown: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    condvar: Condvar,
+    max_threads: usize,
+}
+
+/// A lazily growing pool of OS threads, separate from the Processors
+///
+/// Threads are only spawned if there is no idle one and are
+/// terminated again after they have been idle for a while.
+pub struct BlockingPool(Arc<Inner>);
+
+impl BlockingPool {
+    pub fn new(max_threads: usize) -> BlockingPool {
+        assert!(max_threads >= 1, "Must have at least one blocking thread");
+
+        BlockingPool(Arc::new(Inner {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                threads: 0,
+                idle: 0,
+                is_shutdown: false,
+            }),
+            condvar: Condvar::new(),
+            max_threads: max_threads,
+        }))
+    }
+
+    #[inline]
+    pub fn max_threads(&self) -> usize {
+        self.0.max_threads
+    }
+
+    /// Queues `job` and spawns a new thread if all existing ones are busy
+    pub fn execute(&self, job: Job) {
+        let mut state = self.0.state.lock().unwrap();
+        assert!(!state.is_shutdown, "BlockingPool has been shut down");
+
+        state.queue.push_back(job);
+
+        if state.idle > 0 {
+            self.0.condvar.notify_one();
+        } else if state.threads < self.0.max_threads {
+            state.threads += 1;
+
+            let inner = self.0.clone();
+            let spawned = thread::Builder::new()
+                              .name("coio-blocking".to_owned())
+                              .spawn(move || run_worker(inner));
+
+            if let Err(err) = spawned {
+                // The job is picked up by one of the existing threads later on
+                error!("BlockingPool: failed to spawn thread: {}", err);
+                state.threads -= 1;
+            }
+        }
+    }
+
+    /// Stops all threads once they have finished their current job
+    ///
+    /// Jobs which have not been started yet are dropped.
+    pub fn shutdown(&self) {
+        let queue = {
+            let mut state = self.0.state.lock().unwrap();
+            state.is_shutdown = true;
+            mem::replace(&mut state.queue, VecDeque::new())
+        };
+
+        self.0.condvar.notify_all();
+        drop(queue);
+    }
+}
+
+fn run_worker(inner: Arc<Inner>) {
+    trace!("BlockingPool: thread started");
+
+    let keep_alive = Duration::from_millis(KEEP_ALIVE_MS);
+    let mut state = inner.state.lock().unwrap();
+
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            drop(state);
+            job.call_box(());
+            state = inner.state.lock().unwrap();
+            continue;
+        }
+
+        if state.is_shutdown {
+            break;
+        }
+
+        state.idle += 1;
+        let (guard, timeout) = inner.condvar.wait_timeout(state, keep_alive).unwrap();
+        state = guard;
+        state.idle -= 1;
+
+        if timeout.timed_out() && state.queue.is_empty() {
+            break;
+        }
+    }
+
+    state.threads -= 1;
+    trace!("BlockingPool: thread finished");
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn blocking_pool_bounded_threads() {
+        let pool = BlockingPool::new(2);
+        let (tx, rx) = mpsc::channel();
+
+        for i in 0..10 {
+            let tx = tx.clone();
+            pool.execute(Box::new(move || tx.send(i).unwrap()));
+        }
+
+        let mut results = rx.iter().take(10).collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+        assert!(pool.0.state.lock().unwrap().threads <= 2);
+
+        pool.shutdown();
+    }
+}
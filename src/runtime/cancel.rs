@@ -0,0 +1,168 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Cooperative cancellation of coroutines
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use runtime::Processor;
+use runtime::waiter::Waiter;
+use scheduler::Scheduler;
+use sync::spinlock::Spinlock;
+
+/// The source index with which a `Waiter` is fired if its coroutine has been cancelled
+pub const CANCEL_SOURCE: usize = !0;
+
+/// The error returned by blocking operations of a cancelled coroutine
+///
+/// It is usually wrapped in an `io::Error` of the kind `io::ErrorKind::Other`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for Cancelled {
+    fn description(&self) -> &str {
+        "coroutine has been cancelled"
+    }
+}
+
+/// Returns a `Cancelled` error wrapped in an `io::Error`
+#[inline]
+pub fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, Cancelled)
+}
+
+/// Unwinds the current coroutine with a `Cancelled` panic payload if it has been aborted.
+///
+/// Only called at points where the runtime doesn't hold any state on behalf of the coroutine,
+/// e.g. after it has been removed from all wait lists, and never while the coroutine is already
+/// unwinding, since a panic inside of a destructor would abort the process.
+pub fn unwind_if_aborted() {
+    if thread::panicking() {
+        return;
+    }
+
+    let aborted = Processor::current()
+                      .and_then(|mut p| {
+                          p.current().and_then(|coro| coro.cancel_token().map(|t| t.is_aborted()))
+                      })
+                      .unwrap_or(false);
+
+    if aborted {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+struct CancelInner {
+    cancelled: AtomicBool,
+    aborted: AtomicBool,
+    parked_on: Spinlock<Option<Arc<Waiter>>>,
+}
+
+/// Cancellation flag shared between a coroutine and its `JoinHandle`
+#[derive(Clone)]
+pub struct CancelToken(Arc<CancelInner>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken(Arc::new(CancelInner {
+            cancelled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            parked_on: Spinlock::new(None),
+        }))
+    }
+
+    /// Marks the coroutine as cancelled and wakes it up if it's currently parked.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+
+        let waiter = self.0.parked_on.lock().take();
+
+        if let Some(waiter) = waiter {
+            waiter.wake(CANCEL_SOURCE, Scheduler::ready);
+        }
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the coroutine and makes it unwind at the next cancellation point.
+    pub fn abort(&self) {
+        self.0.aborted.store(true, Ordering::SeqCst);
+        self.cancel();
+    }
+
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        self.0.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Registers the `Waiter` of the coroutine which is about to be parked,
+    /// so that `cancel()` is able to wake it up.
+    ///
+    /// Returns false if the coroutine has already been cancelled.
+    pub fn park(&self, waiter: &Arc<Waiter>) -> bool {
+        let mut parked_on = self.0.parked_on.lock();
+
+        if self.is_cancelled() {
+            false
+        } else {
+            *parked_on = Some(waiter.clone());
+            true
+        }
+    }
+
+    /// Removes the `Waiter` registered by `park()`.
+    pub fn unpark(&self) {
+        self.0.parked_on.lock().take();
+    }
+
+    /// Returns a handle which doesn't keep the coroutine's cancellation state alive.
+    pub fn downgrade(&self) -> WeakCancelToken {
+        WeakCancelToken(Arc::downgrade(&self.0))
+    }
+
+    /// Registers `waiter` using `park()` and fires it right away if the coroutine is cancelled.
+    ///
+    /// Has to be called from within a `park_with()` callback, before the `Waiter` is armed.
+    pub fn park_or_fire(&self, waiter: &Arc<Waiter>) {
+        if !self.park(waiter) {
+            waiter.wake(CANCEL_SOURCE, |_| unreachable!("Waiter is not armed yet"));
+        }
+    }
+}
+
+/// A `CancelToken` which is gone once the coroutine and its `JoinHandle` have been dropped
+#[derive(Clone)]
+pub struct WeakCancelToken(Weak<CancelInner>);
+
+impl WeakCancelToken {
+    #[inline]
+    pub fn upgrade(&self) -> Option<CancelToken> {
+        self.0.upgrade().map(CancelToken)
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CancelToken({})", self.is_cancelled())
+    }
+}
@@ -8,5 +8,12 @@
 
 pub use self::processor::Processor;
 
+pub mod affinity;
+pub mod blocking;
+pub mod cancel;
+pub mod coroutine;
 pub mod processor;
+pub mod stack_overflow;
 pub mod stack_pool;
+pub mod timer_wheel;
+pub mod waiter;
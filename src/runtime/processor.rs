@@ -11,21 +11,27 @@
 use std::boxed::FnBox;
 use std::cell::UnsafeCell;
 use std::fmt;
+use std::io;
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::sync::{Arc, Barrier, Weak};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender, SendError};
 use std::thread::{self, Builder};
 
 use rand::{self, Rng};
 
-use coroutine::{Coroutine, State, Handle};
 use scheduler::Scheduler;
-use options::Options;
+use options::{Options, Priority};
+use runtime::affinity;
+use runtime::coroutine::{Coroutine, State, Handle, HandleList};
+use runtime::stack_overflow;
 use runtime::stack_pool::StackPool;
+use runtime::timer_wheel::{self, TimerKey, TimerWheel};
+use runtime::waiter::Waiter;
 
+/// Default size of the local queue of each Processor
 pub const QUEUE_SIZE: usize = 256;
 
 thread_local!(static PROCESSOR: UnsafeCell<Option<Processor>> = UnsafeCell::new(None));
@@ -91,20 +97,29 @@ impl ProcessorHandle {
         self.0.ready(coroutine)
     }
 
+    #[inline]
+    pub fn cancel_timer(&mut self, timer: Timer) {
+        self.0.cancel_timer(timer)
+    }
+
     #[inline]
     pub fn current(&mut self) -> Option<&mut Handle> {
         self.0.current_coroutine()
     }
 
     #[inline]
-    pub fn spawn_opts<F: FnOnce() + Send + 'static>(&mut self, f: F, opts: Options) {
+    pub fn spawn_opts<F: FnOnce() + Send + 'static>(&mut self,
+                                                     f: F,
+                                                     opts: Options)
+                                                     -> io::Result<()> {
         self.spawn_opts_imp(Box::new(f), opts)
     }
 
-    pub fn spawn_opts_imp(&mut self, f: Box<FnBox()>, opts: Options) {
-        let new_coro = Coroutine::spawn_opts_with_pool(f, opts, self.stack_pool());
+    pub fn spawn_opts_imp(&mut self, f: Box<FnBox()>, opts: Options) -> io::Result<()> {
+        let new_coro = try!(Coroutine::spawn_opts_with_pool(f, opts, self.stack_pool()));
         self.ready(new_coro);
         self.scheduler().unpark_processor_maybe(1);
+        Ok(())
     }
 
     /// Obtains the currently running coroutine after setting it's state to Parked.
@@ -216,8 +231,11 @@ pub struct ProcessorInner {
     /// ```
     ///
     /// Both head as well as tail are *only* incremented and *never* decremented.
-    /// All access on the ring buffer will thus happen modulo to the size of the buffer.
-    queue: [*mut Coroutine; QUEUE_SIZE],
+    /// All access on the ring buffer will thus happen modulo to the size of the buffer,
+    /// which is why the size must be a power of two.
+    ///
+    /// If the buffer is full, half of it is moved to the global queue in a single batch.
+    queue: Box<[*mut Coroutine]>,
 
     /// Points to the next element being removed by `queue_pop_front()`
     ///
@@ -230,6 +248,25 @@ pub struct ProcessorInner {
     /// but might be read by foreign ones.
     queue_tail: AtomicUsize,
 
+    /// Queue for coroutines with `Priority::High`, which are resumed before the ones in `queue`
+    ///
+    /// This queue is only ever accessed by the current thread and thus not subject to stealing.
+    priority_queue: HandleList,
+
+    /// Incremented by every call to `resume()`
+    ///
+    /// The event loop compares it between two preemption checks to find coroutines which have been
+    /// running for a whole interval.
+    resume_count: AtomicUsize,
+
+    /// Set by the event loop if the current coroutine should yield at its next `preempt_point()`
+    preempt_requested: AtomicBool,
+
+    /// Timers of the coroutines parked on this Processor, see `add_timer()`
+    ///
+    /// This wheel is only ever accessed by the current thread.
+    timers: TimerWheel,
+
     // NOTE: current_coro is ONLY to be used by resume() and park_with().
     current_coro: Option<Handle>,
     rand_order: RandomProcessorOrder,
@@ -244,8 +281,15 @@ impl Processor {
     pub fn spawn(sched: *mut Scheduler,
                  processor_id: usize,
                  barrier: Arc<Barrier>,
-                 max_stack_memory_limit: usize)
+                 max_stack_memory_limit: usize,
+                 queue_size: usize,
+                 stack_pool_capacity: Option<usize>,
+                 stack_pool_release_pages: bool,
+                 cpu: Option<usize>)
                  -> Machine {
+        assert!(queue_size >= 2 && queue_size.is_power_of_two(),
+                "queue size must be a power of two");
+
         let (tx, rx) = mpsc::channel();
 
         let mut p = Processor(Arc::new(UnsafeCell::new(ProcessorInner {
@@ -259,7 +303,14 @@ impl Processor {
 
             queue_head: AtomicUsize::new(0),
             queue_tail: AtomicUsize::new(0),
-            queue: unsafe { mem::zeroed() },
+            queue: vec![ptr::null_mut(); queue_size].into_boxed_slice(),
+
+            priority_queue: HandleList::new(),
+
+            resume_count: AtomicUsize::new(0),
+            preempt_requested: AtomicBool::new(false),
+
+            timers: TimerWheel::new(),
 
             current_coro: None,
             rand_order: RandomProcessorOrder::new(),
@@ -270,6 +321,9 @@ impl Processor {
                                        Some(max_stack_memory_limit)),
         })));
 
+        p.stack_pool().set_capacity(stack_pool_capacity);
+        p.stack_pool().set_release_pages(stack_pool_release_pages);
+
         {
             let weak_self = WeakProcessor(Arc::downgrade(&p.0));
             let inner = p.deref_mut();
@@ -288,6 +342,18 @@ impl Processor {
                         *proc_opt = Some(p.clone());
                     });
 
+                    if let Some(cpu) = cpu {
+                        if let Err(err) = affinity::pin_current_thread(cpu) {
+                            warn!("Processor#{}: failed to pin to CPU {}: {}",
+                                  processor_id,
+                                  cpu,
+                                  err);
+                        }
+                    }
+
+                    // Lets stack overflows of coroutines be reported, see runtime::stack_overflow
+                    let _alt_stack = stack_overflow::AltStack::new();
+
                     barrier.wait();
                     p.schedule();
                 })
@@ -351,6 +417,28 @@ impl Processor {
         self.id
     }
 
+    /// Returns the number of coroutines resumed by this instance so far.
+    ///
+    /// This method *is* thread safe.
+    #[inline]
+    pub fn resume_count(&self) -> usize {
+        self.resume_count.load(Ordering::Relaxed)
+    }
+
+    /// Asks the current coroutine to yield at its next `coio::preempt_point()`.
+    ///
+    /// This method *is* thread safe.
+    #[inline]
+    pub fn request_preemption(&self) {
+        self.preempt_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the current coroutine has used up its time slice.
+    #[inline]
+    pub fn preemption_requested(&self) -> bool {
+        self.preempt_requested.load(Ordering::Relaxed)
+    }
+
     /// Returns the handle through which messages can be sent to this instance.
     pub fn handle(&self) -> ProcMessageSender {
         ProcMessageSender {
@@ -359,9 +447,69 @@ impl Processor {
         }
     }
 
-    /// Enqueue a coroutine to be resumed as soon as possible (making it the head of the queue)
+    /// Enqueue a coroutine to be resumed as soon as possible
+    ///
+    /// Coroutines with a high priority are put in front of all others.
     pub fn ready(&mut self, coro: Handle) {
-        self.queue_push_back(coro);
+        if coro.priority() == Priority::High {
+            self.thread_assert();
+            trace!("{:?}: pushing {:?} to priority queue", self, coro);
+            self.priority_queue.push_back(coro);
+        } else {
+            self.queue_push_back(coro);
+        }
+    }
+
+    /// Wakes up `waiter` with `TIMER_EXPIRED` once `delay_ms` have elapsed
+    ///
+    /// The timer is expired by this Processor in between two coroutines or once it has been
+    /// parked for long enough. If another source wakes up the `Waiter` first, the timer
+    /// should be removed with `cancel_timer()`.
+    pub fn add_timer(&mut self, delay_ms: u64, waiter: Arc<Waiter>) -> Timer {
+        self.thread_assert();
+
+        Timer {
+            processor: self.id,
+            key: self.timers.insert(delay_ms, waiter),
+        }
+    }
+
+    /// Removes a timer returned by `add_timer()` if it hasn't expired yet
+    ///
+    /// The coroutine might have been resumed on another Processor than the one it armed the
+    /// timer on, in which case the owning Processor is asked to remove the timer instead.
+    /// Until it has done so, the timer can still expire, which the `Waiter` ignores.
+    pub fn cancel_timer(&mut self, timer: Timer) {
+        if timer.processor == self.id {
+            self.timers.remove(timer.key);
+            return;
+        }
+
+        let machines = self.scheduler().get_machines();
+        let msg = ProcMessage::CancelTimer(timer.key);
+
+        // The Processor has already shut down if sending fails, taking its timers with it.
+        let _ = machines[timer.processor].processor_handle.send(msg);
+    }
+
+    fn expire_timers(&mut self) {
+        if self.timers.is_empty() {
+            return;
+        }
+
+        let mut expired = Vec::new();
+        self.timers.advance(&mut expired);
+
+        if expired.is_empty() {
+            return;
+        }
+
+        self.scheduler().count_timer_events(expired.len());
+
+        for waiter in expired {
+            trace!("{:?}: timer expired for {:?}", self, waiter);
+            waiter.wake(timer_wheel::TIMER_EXPIRED, |coro| self.ready(coro));
+        }
     }
 
     /// Suspends the current running coroutine, equivalent to `Scheduler::sched`
@@ -393,6 +541,21 @@ impl Processor {
     #[inline(always)]
     fn thread_assert(&self) {}
 
+    /// Returns the number of coroutines in the local queue
+    ///
+    /// # Safety
+    ///
+    /// This method *is* thread safe, but the result is only an approximation.
+    #[inline]
+    pub fn queue_len(&self) -> usize {
+        let h = self.queue_head.load(Ordering::Relaxed);
+        let t = self.queue_tail.load(Ordering::Relaxed);
+        let n = t.wrapping_sub(h);
+
+        // Foreign threads might observe an inconsistent head and tail
+        if n > self.queue.len() { 0 } else { n }
+    }
+
     fn queue_empty(&self) -> bool {
         self.queue_head.load(Ordering::Relaxed) == self.queue_tail.load(Ordering::Relaxed)
     }
@@ -408,7 +571,7 @@ impl Processor {
                 return None;
             }
 
-            let coro = unsafe { *self.queue.get_unchecked(h % QUEUE_SIZE) };
+            let coro = unsafe { *self.queue.get_unchecked(h % self.queue.len()) };
 
             if self.queue_head.compare_and_swap(h, h.wrapping_add(1), Ordering::Release) == h {
                 let hdl = Some(unsafe { Handle::from_raw(coro) });
@@ -428,8 +591,10 @@ impl Processor {
             let h = self.queue_head.load(Ordering::Acquire);
             let t = self.queue_tail.load(Ordering::Relaxed);
 
-            if t.wrapping_sub(h) < QUEUE_SIZE {
-                unsafe { *self.queue.get_unchecked_mut(t % QUEUE_SIZE) = coro };
+            let size = self.queue.len();
+
+            if t.wrapping_sub(h) < size {
+                unsafe { *self.queue.get_unchecked_mut(t % size) = coro };
                 self.queue_tail.store(t.wrapping_add(1), Ordering::Release);
                 return;
             }
@@ -443,10 +608,14 @@ impl Processor {
     /// Only to be called by queue_push_back()
     #[cold]
     fn queue_push_back_slow(&mut self, coro: *mut Coroutine, h: usize, t: usize) -> bool {
-        let mut batch: [*mut Coroutine; QUEUE_SIZE / 2 + 1] = unsafe { mem::uninitialized() };
+        let size = self.queue.len();
         let n = t.wrapping_sub(h) / 2;
 
-        assert!(n == QUEUE_SIZE / 2, "queue is not full");
+        assert!(n == size / 2, "queue is not full");
+
+        let mut batch: Vec<*mut Coroutine> = Vec::with_capacity(n + 1);
+
+        unsafe { batch.set_len(n + 1) };
 
         {
             let src = self.queue.as_ptr();
@@ -454,7 +623,7 @@ impl Processor {
 
             for i in 0..n {
                 unsafe {
-                    let src = src.offset((h.wrapping_add(i) % QUEUE_SIZE) as isize);
+                    let src = src.offset((h.wrapping_add(i) % size) as isize);
                     let dst = dst.offset(i as isize);
                     ptr::copy_nonoverlapping(src, dst, 1);
                 }
@@ -474,7 +643,10 @@ impl Processor {
     /// Steals half of the local queue from self and puts it into batch.
     ///
     /// This is the only queue* method accessing foreign ones.
-    fn queue_grab(&mut self, batch: &mut [*mut Coroutine; QUEUE_SIZE], batch_tail: usize) -> usize {
+    fn queue_grab(&mut self, batch: &mut [*mut Coroutine], batch_tail: usize) -> usize {
+        let size = self.queue.len();
+        let batch_size = batch.len();
+
         loop {
             let h = self.queue_head.load(Ordering::Acquire); // synchronize with other consumers
             let t = self.queue_tail.load(Ordering::Acquire); // synchronize with the producer
@@ -484,7 +656,7 @@ impl Processor {
                 return 0;
             }
 
-            if n > QUEUE_SIZE / 2 {
+            if n > size / 2 {
                 // read inconsistent h and t
                 continue;
             }
@@ -495,8 +667,8 @@ impl Processor {
 
                 for i in 0..n {
                     unsafe {
-                        let src = src.offset((h.wrapping_add(i) % QUEUE_SIZE) as isize);
-                        let dst = dst.offset((batch_tail.wrapping_add(i) % QUEUE_SIZE) as isize);
+                        let src = src.offset((h.wrapping_add(i) % size) as isize);
+                        let dst = dst.offset((batch_tail.wrapping_add(i) % batch_size) as isize);
                         ptr::copy_nonoverlapping(src, dst, 1);
                     }
                 }
@@ -520,12 +692,13 @@ impl Processor {
         trace!("{:?}: stole {} Coroutines from {:?}", self, n, from);
 
         let n = n - 1;
-        let coro = unsafe { *self.queue.get_unchecked(t.wrapping_add(n) % QUEUE_SIZE) };
+        let size = self.queue.len();
+        let coro = unsafe { *self.queue.get_unchecked(t.wrapping_add(n) % size) };
 
         if n != 0 {
             // synchronize with consumers
             let h = self.queue_head.load(Ordering::Acquire);
-            assert!(t.wrapping_sub(h).wrapping_add(n) < QUEUE_SIZE,
+            assert!(t.wrapping_sub(h).wrapping_add(n) < size,
                     "queue overflow");
             // makes the item available for consumption
             self.queue_tail.store(t.wrapping_add(n), Ordering::Release);
@@ -557,18 +730,36 @@ impl Processor {
             return None;
         }
 
+        // High priority coroutines go to our priority_queue, which is never stolen from,
+        // but we only take our fair share of them, so that other Processors can help out.
+        if let Some(hdl) = queue.priority.pop_front() {
+            let n = queue.priority.len() / scheduler.get_machines().len();
+            let mut batch = queue.priority.split_off_front(n);
+            let size = queue.len();
+            scheduler.set_global_queue_size(size);
+            drop(queue);
+
+            trace!("{:?}: got {} high priority Coroutines from global",
+                   self,
+                   batch.len() + 1);
+            self.priority_queue.append(&mut batch);
+
+            return Some(hdl);
+        }
+
+        let size = self.queue.len();
         let mut n = (queue.len() / scheduler.get_machines().len()) + 1;
 
-        if n > QUEUE_SIZE / 2 {
-            n = QUEUE_SIZE / 2;
+        if n > size / 2 {
+            n = size / 2;
         }
 
-        let hdl = queue.pop_front();
+        let hdl = queue.normal.pop_front();
 
         let cnt = if hdl.is_some() {
             let h = self.queue_head.load(Ordering::Acquire);
             let t = self.queue_tail.load(Ordering::Relaxed);
-            let max = (QUEUE_SIZE - t.wrapping_sub(h) + 1) / 2;
+            let max = (size - t.wrapping_sub(h) + 1) / 2;
             let dst = self.queue.as_mut_ptr();
 
             if n > max {
@@ -576,7 +767,7 @@ impl Processor {
             }
 
             let queue = {
-                let q = queue.split_off_front(n);
+                let q = queue.normal.split_off_front(n);
                 scheduler.set_global_queue_size(queue.len());
                 drop(queue);
                 q
@@ -586,7 +777,7 @@ impl Processor {
 
             for (i, hdl) in queue.into_iter().enumerate() {
                 unsafe {
-                    let dst = dst.offset((t.wrapping_add(i) % QUEUE_SIZE) as isize);
+                    let dst = dst.offset((t.wrapping_add(i) % size) as isize);
                     *dst = Handle::into_raw(hdl);
                 }
             }
@@ -617,6 +808,9 @@ impl Processor {
                         self.should_finish = true;
                         return None;
                     }
+                    ProcMessage::CancelTimer(key) => {
+                        self.timers.remove(key);
+                    }
                 }
             }
         }
@@ -663,6 +857,13 @@ impl Processor {
         while self.should_finish == false {
             // TODO: Ensure that coroutines from foreign queues are fetched once in a while.
 
+            self.expire_timers();
+
+            // Run high priority tasks first
+            if run_next.is_none() {
+                run_next = self.priority_queue.pop_front();
+            }
+
             // Run tasks in local queue
             if run_next.is_none() {
                 run_next = self.queue_pop_front();
@@ -679,7 +880,10 @@ impl Processor {
             } else {
                 if !scheduler.is_shutting_down() {
                     trace!("{:?}: parking", self);
-                    scheduler.park_processor(|| {
+
+                    // Wake up in time for the next timer, since nobody else will expire it
+                    let timeout = self.timers.next_timeout();
+                    scheduler.park_processor(timeout, || {
                         run_next = self.fetch_foreign_coroutines();
                         run_next.is_none()
                     });
@@ -698,24 +902,35 @@ impl Processor {
         trace!("{:?}: dropping run_next", self);
         drop(run_next);
 
+        trace!("{:?}: dropping timers", self);
+        self.timers.clear();
+
         trace!("{:?}: dropping local coroutines", self);
+        while let Some(_coro) = self.priority_queue.pop_front() {}
+
         while self.queue_head.load(Ordering::Relaxed) != self.queue_tail.load(Ordering::Relaxed) {
             // pop from tail of local queue
             let t = self.queue_tail.fetch_sub(1, Ordering::Relaxed) - 1;
-            let _coro = unsafe { Handle::from_raw(*self.queue.get_unchecked(t % QUEUE_SIZE)) };
+            let _coro = unsafe {
+                Handle::from_raw(*self.queue.get_unchecked(t % self.queue.len()))
+            };
         }
 
         trace!("{:?}: local scheduler end", self);
     }
 
-    fn resume(&mut self, coro: Handle) -> Option<Handle> {
+    fn resume(&mut self, mut coro: Handle) -> Option<Handle> {
         self.thread_assert();
 
         assert!(coro.is_finished() == false,
                 "Cannot resume a finished coroutine");
 
         trace!("{:?}: resuming {:?}", self, coro);
+        self.resume_count.fetch_add(1, Ordering::Relaxed);
+        self.preempt_requested.store(false, Ordering::Relaxed);
+
         let data = {
+            coro.prepare_resume(self.id);
             self.current_coro = Some(coro);
 
             if let Some(ref mut c) = self.current_coro {
@@ -734,11 +949,13 @@ impl Processor {
                     // we want to ensure that it's not immediately resumed.
                     // Thus we fetch foreign coroutines first and then put the
                     // suspended one into the local queue as the last one.
-                    if self.queue_empty() {
+                    // The same goes for a preempted coroutine, which has hogged this
+                    // Processor for so long that foreign ones got no chance to run.
+                    if self.queue_empty() || self.preemption_requested() {
                         hdl = self.fetch_foreign_coroutines()
                     }
 
-                    self.queue_push_back(coro);
+                    self.ready(coro);
                 }
                 State::Parked => {
                     assert!(data != 0, "Coroutine parked with data == 0");
@@ -833,6 +1050,16 @@ impl WeakProcessor {
 pub enum ProcMessage {
     /// Ask the processor to shutdown, which will going to force unwind all pending coroutines.
     Shutdown(Arc<Barrier>),
+
+    /// Remove a timer of this processor, see `Processor::cancel_timer()`.
+    CancelTimer(TimerKey),
+}
+
+/// A timer armed with `Processor::add_timer()`
+#[derive(Clone, Copy, Debug)]
+pub struct Timer {
+    processor: usize,
+    key: TimerKey,
 }
 
 // The following idea stems from Go:
@@ -919,7 +1146,7 @@ mod test {
     use std::sync::{Arc, Mutex};
     use std::sync::atomic::{AtomicUsize, Ordering};
 
-    use options::Options;
+    use options::{Options, Priority};
     use scheduler::Scheduler;
     use super::RandomProcessorOrder;
 
@@ -957,28 +1184,79 @@ mod test {
     }
 
     #[test]
-    #[ignore]
+    fn processor_priority_order() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let results = Arc::new(Mutex::new(Vec::new()));
+
+                {
+                    let results = results.clone();
+                    Scheduler::spawn(move || results.lock().unwrap().push(1));
+                }
+
+                {
+                    let results = results.clone();
+                    let mut opts = Options::new();
+                    opts.priority(Priority::High);
+                    Scheduler::spawn_opts(move || results.lock().unwrap().push(2), opts);
+                }
+
+                Scheduler::sched();
+
+                let results = results.lock().unwrap();
+                assert_eq!(results.deref(), &vec![2, 1]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn processor_no_lost_wakeups() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                // Processors constantly run out of work and park, while new work arrives.
+                // A lost wakeup would leave a coroutine in a queue and hang this test.
+                for _ in 0..1000 {
+                    let handles = (0..4)
+                                      .map(|_| Scheduler::spawn(|| Scheduler::sched()))
+                                      .collect::<Vec<_>>();
+
+                    for h in handles {
+                        h.join().unwrap();
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
     fn processor_queue_overflow() {
         Scheduler::new()
+            .with_workers(4)
+            .with_local_queue_size(16)
             .run(move || {
                 let counter = Arc::new(AtomicUsize::new(0));
                 let mut opts = Options::new();
 
                 opts.stack_size(32 * 1024);
 
-                for _ in 0..300 {
-                    let counter = counter.clone();
-                    let f = move || {
-                        counter.fetch_add(1, Ordering::SeqCst);
-                    };
-                    Scheduler::spawn_opts(f, opts.clone());
-                }
-
-                for _ in 0..2 {
-                    Scheduler::sched();
+                // Spawning never blocks, even though the local queue overflows many times
+                let handles = (0..10000)
+                                  .map(|_| {
+                                      let counter = counter.clone();
+                                      let f = move || {
+                                          counter.fetch_add(1, Ordering::SeqCst);
+                                      };
+                                      Scheduler::spawn_opts(f, opts.clone())
+                                  })
+                                  .collect::<Vec<_>>();
+
+                for h in handles {
+                    h.join().unwrap();
                 }
 
-                assert_eq!(counter.load(Ordering::SeqCst), 300);
+                assert_eq!(counter.load(Ordering::SeqCst), 10000);
             })
             .unwrap();
     }
@@ -0,0 +1,246 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reporting of coroutines which overflowed their stack
+//!
+//! The standard library only recognizes faults on the guard pages of threads, so a faulting
+//! guard page of a coroutine would end in a bare segmentation fault. The handler installed here
+//! names the coroutine first and leaves every other fault to the previous handler.
+
+#[cfg(target_os = "linux")]
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::ptr;
+#[cfg(target_os = "linux")]
+use std::sync::{Once, ONCE_INIT};
+
+#[cfg(target_os = "linux")]
+use libc::{self, c_int, c_void};
+
+#[cfg(target_os = "linux")]
+use runtime::Processor;
+
+// The leading members of the kernel's `siginfo_t` for SIGSEGV and SIGBUS
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SigInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    addr: usize,
+}
+
+#[cfg(target_os = "linux")]
+static INIT: Once = ONCE_INIT;
+
+#[cfg(target_os = "linux")]
+static mut PREVIOUS_SIGSEGV: Option<libc::sigaction> = None;
+
+#[cfg(target_os = "linux")]
+static mut PREVIOUS_SIGBUS: Option<libc::sigaction> = None;
+
+/// Installs the handler for SIGSEGV and SIGBUS, once per process
+///
+/// It runs on the alternate signal stack of the faulting thread, see `AltStack`.
+#[cfg(target_os = "linux")]
+pub fn init() {
+    INIT.call_once(|| unsafe {
+        PREVIOUS_SIGSEGV = install(libc::SIGSEGV);
+        PREVIOUS_SIGBUS = install(libc::SIGBUS);
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn init() {}
+
+/// The alternate signal stack of a Processor thread
+///
+/// The handler can't run on the stack which just overflowed. The standard library installs an
+/// alternate stack for the threads it spawns only under some conditions, so every Processor makes
+/// sure to have one for as long as it runs.
+#[cfg(target_os = "linux")]
+pub struct AltStack {
+    // Only set if the stack has been installed by us
+    stack: Option<Vec<u8>>,
+}
+
+#[cfg(target_os = "linux")]
+impl AltStack {
+    pub fn new() -> AltStack {
+        unsafe {
+            let mut current: libc::stack_t = mem::zeroed();
+            libc::sigaltstack(ptr::null(), &mut current);
+
+            if current.ss_flags & libc::SS_DISABLE == 0 {
+                return AltStack { stack: None };
+            }
+
+            let mut stack = vec![0u8; libc::SIGSTKSZ];
+            let new = libc::stack_t {
+                ss_sp: stack.as_mut_ptr() as *mut c_void,
+                ss_flags: 0,
+                ss_size: stack.len(),
+            };
+
+            if libc::sigaltstack(&new, ptr::null_mut()) != 0 {
+                warn!("failed to install alternate signal stack: {}",
+                      io::Error::last_os_error());
+                return AltStack { stack: None };
+            }
+
+            AltStack { stack: Some(stack) }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        if self.stack.is_some() {
+            unsafe {
+                let disable = libc::stack_t {
+                    ss_sp: ptr::null_mut(),
+                    ss_flags: libc::SS_DISABLE,
+                    ss_size: libc::SIGSTKSZ,
+                };
+                libc::sigaltstack(&disable, ptr::null_mut());
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct AltStack;
+
+#[cfg(not(target_os = "linux"))]
+impl AltStack {
+    pub fn new() -> AltStack {
+        AltStack
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn install(signum: c_int) -> Option<libc::sigaction> {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handler as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    let mut previous: libc::sigaction = mem::zeroed();
+
+    if libc::sigaction(signum, &action, &mut previous) == 0 {
+        Some(previous)
+    } else {
+        warn!("failed to install stack overflow handler: {}",
+              io::Error::last_os_error());
+        None
+    }
+}
+
+// A message assembled without allocating, since the handler may only call
+// async-signal-safe functions. Whatever doesn't fit is cut off.
+#[cfg(target_os = "linux")]
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Message {
+    fn new() -> Message {
+        Message {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = cmp::min(bytes.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_usize(&mut self, mut value: usize, radix: usize) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+
+        loop {
+            start -= 1;
+            digits[start] = b"0123456789abcdef"[value % radix];
+            value /= radix;
+
+            if value == 0 {
+                break;
+            }
+        }
+
+        self.push(&digits[start..]);
+    }
+
+    fn write_stderr(&self) {
+        unsafe { libc::write(2, self.buf.as_ptr() as *const c_void, self.len) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn handler(signum: c_int, info: *mut SigInfo, _: *mut c_void) {
+    let addr = unsafe { (*info).addr };
+
+    let overflowed = Processor::current().and_then(|mut p| {
+        p.current().and_then(|coro| {
+            if !coro.guard_page_contains(addr) {
+                return None;
+            }
+
+            // Mirrors the Debug output of `Coroutine`
+            let mut msg = Message::new();
+            msg.push(b"Coroutine(");
+
+            match coro.name() {
+                Some(name) => msg.push(name.as_bytes()),
+                None => {
+                    let ptr: *const _ = &**coro;
+                    msg.push(b"0x");
+                    msg.push_usize(ptr as usize, 16);
+                }
+            }
+
+            msg.push(b") with a stack of ");
+            msg.push_usize(coro.stack_size(), 10);
+            msg.push(b" bytes has overflowed its stack, aborting\n");
+            Some(msg)
+        })
+    });
+
+    if let Some(msg) = overflowed {
+        msg.write_stderr();
+        unsafe { libc::abort() };
+    }
+
+    // Not ours: Restore the previous handler and let the faulting instruction run into it again
+    unsafe {
+        let previous = if signum == libc::SIGSEGV {
+            PREVIOUS_SIGSEGV.as_ref()
+        } else {
+            PREVIOUS_SIGBUS.as_ref()
+        };
+
+        match previous {
+            Some(previous) => {
+                libc::sigaction(signum, previous, ptr::null_mut());
+            }
+            None => {
+                libc::signal(signum, libc::SIG_DFL);
+            }
+        }
+    }
+}
@@ -8,37 +8,163 @@
 
 //! Stack pool
 
+use std::io;
 use std::ops::{Deref, DerefMut};
 
+#[cfg(target_os = "linux")]
+use libc;
 use linked_hash_map::LinkedHashMap;
 
-use context::stack::ProtectedFixedSizeStack;
+use context::stack::{self, FixedSizeStack, ProtectedFixedSizeStack};
+
+enum RawStack {
+    // Sits on top of an inaccessible guard page
+    Protected(ProtectedFixedSizeStack),
+    Unprotected(FixedSizeStack),
+}
 
 /// Stack representation
 pub struct Stack {
-    inner: ProtectedFixedSizeStack,
+    inner: RawStack,
     size: usize,
+
+    // The pages have been handed back to the kernel and read as zeroes again
+    released: bool,
 }
 
 impl Stack {
-    fn new(s: ProtectedFixedSizeStack, size: usize) -> Stack {
+    fn new(s: RawStack, size: usize) -> Stack {
         Stack {
             inner: s,
             size: size,
+            released: false,
+        }
+    }
+
+    /// Returns true if an overflow of the stack faults instead of overwriting other memory
+    #[inline]
+    pub fn has_guard_page(&self) -> bool {
+        match self.inner {
+            RawStack::Protected(..) => true,
+            RawStack::Unprotected(..) => false,
+        }
+    }
+
+    /// Returns true if `addr` lies within the guard page below the stack
+    pub fn guard_page_contains(&self, addr: usize) -> bool {
+        if !self.has_guard_page() {
+            return false;
         }
+
+        let bottom = self.bottom() as usize;
+        addr < bottom && addr >= bottom.saturating_sub(page_size())
+    }
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+impl Stack {
+    /// Zeroes the stack, so that no data leaks into the next coroutine
+    ///
+    /// On Linux the pages are simply handed back to the kernel, which is much cheaper than
+    /// overwriting them. Otherwise only the part above the lowest byte which isn't zero yet is
+    /// overwritten: Stacks grow downwards and are all zeroes below their high-water mark.
+    fn clear(&mut self) {
+        if self.discard_pages() {
+            return;
+        }
+
+        let bottom = self.bottom() as *mut u8;
+        let len = self.len();
+
+        unsafe {
+            let untouched = (0..len).take_while(|&i| *bottom.offset(i as isize) == 0).count();
+            ::std::ptr::write_bytes(bottom.offset(untouched as isize), 0, len - untouched);
+        }
+    }
+
+    /// Lets the kernel reclaim the memory of the stack while it is kept in the pool
+    ///
+    /// Private anonymous pages are zero-filled on the next access after `MADV_DONTNEED`,
+    /// so a released stack doesn't have to be `clear()`ed before it is reused.
+    fn release(&mut self) {
+        if self.discard_pages() {
+            self.released = true;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn discard_pages(&mut self) -> bool {
+        let bottom = self.bottom() as *mut libc::c_void;
+        let len = self.len();
+
+        if unsafe { libc::madvise(bottom, len, libc::MADV_DONTNEED) } == 0 {
+            true
+        } else {
+            warn!("failed to release stack pages: {}", io::Error::last_os_error());
+            false
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn discard_pages(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "stack-watermark")]
+const STACK_PAINT_BYTE: u8 = 0xa5;
+
+#[cfg(feature = "stack-watermark")]
+impl Stack {
+    /// Fills the whole stack with a known pattern so that its peak usage can be measured later
+    pub fn paint(&mut self) {
+        let bottom = self.bottom() as *mut u8;
+        let len = self.len();
+
+        unsafe { ::std::ptr::write_bytes(bottom, STACK_PAINT_BYTE, len) };
+    }
+
+    /// Returns the number of bytes which have been touched since the last call to `paint()`
+    ///
+    /// Stacks grow downwards, so the first byte above `bottom()` which doesn't
+    /// match the pattern anymore marks the high-water mark.
+    pub fn used(&self) -> usize {
+        let bottom = self.bottom() as *const u8;
+        let len = self.len();
+
+        let untouched = (0..len)
+                            .take_while(|&i| unsafe { *bottom.offset(i as isize) } == STACK_PAINT_BYTE)
+                            .count();
+
+        len - untouched
     }
 }
 
 impl Deref for Stack {
-    type Target = ProtectedFixedSizeStack;
-    fn deref(&self) -> &ProtectedFixedSizeStack {
-        &self.inner
+    type Target = stack::Stack;
+    fn deref(&self) -> &stack::Stack {
+        match self.inner {
+            RawStack::Protected(ref s) => &**s,
+            RawStack::Unprotected(ref s) => &**s,
+        }
     }
 }
 
 impl DerefMut for Stack {
-    fn deref_mut(&mut self) -> &mut ProtectedFixedSizeStack {
-        &mut self.inner
+    fn deref_mut(&mut self) -> &mut stack::Stack {
+        match self.inner {
+            RawStack::Protected(ref mut s) => &mut **s,
+            RawStack::Unprotected(ref mut s) => &mut **s,
+        }
     }
 }
 
@@ -49,6 +175,10 @@ pub struct StackPool {
     total_size: usize,
     higher_water_mark: Option<usize>,
     lower_water_mark: Option<usize>,
+
+    stack_count: usize,
+    capacity: Option<usize>,
+    release_pages: bool,
 }
 
 impl StackPool {
@@ -63,30 +193,91 @@ impl StackPool {
             total_size: 0,
             higher_water_mark: hwm,
             lower_water_mark: lwm,
+
+            stack_count: 0,
+            capacity: None,
+            release_pages: false,
+        }
+    }
+
+    /// Hand the memory of pooled stacks back to the kernel using `madvise(MADV_DONTNEED)`
+    ///
+    /// This keeps the address space mapped, so reusing a stack still avoids `mmap()`, but
+    /// idle stacks no longer occupy physical memory. Only supported on Linux.
+    pub fn set_release_pages(&mut self, enabled: bool) {
+        self.release_pages = enabled;
+    }
+
+    /// Limit the number of stacks kept in the pool
+    ///
+    /// Stacks given back while the pool is full are freed right away. `None` means that the pool
+    /// is only bounded by its water marks.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+
+        if let Some(capacity) = capacity {
+            while self.stack_count > capacity {
+                if !self.pop_lru() {
+                    break;
+                }
+            }
         }
     }
 
     /// Allocate stack by directly creation
-    pub fn raw_allocate(size: usize) -> Stack {
+    ///
+    /// Without a guard page the stack is allocated on the heap instead.
+    pub fn try_raw_allocate(size: usize, guard_page: bool) -> io::Result<Stack> {
         trace!("allocating {} bytes from raw", size);
-        Stack::new(ProtectedFixedSizeStack::new(size).expect("failed to acquire stack"),
-                   size)
+
+        let stack = if guard_page {
+            ProtectedFixedSizeStack::new(size).map(RawStack::Protected)
+        } else {
+            FixedSizeStack::new(size).map(RawStack::Unprotected)
+        };
+
+        match stack {
+            Ok(stack) => Ok(Stack::new(stack, size)),
+            Err(err) => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   format!("failed to acquire stack of {} bytes: {:?}", size, err)))
+            }
+        }
     }
 
     /// Create a stack from pool, create if we don't have stack in pool
     pub fn allocate(&mut self, size: usize) -> Stack {
+        self.try_allocate(size, true).expect("failed to acquire stack")
+    }
+
+    /// Like `allocate()`, but returns an error if a new stack couldn't be mapped
+    ///
+    /// Only stacks with a guard page are pooled, since the others are cheap heap allocations.
+    pub fn try_allocate(&mut self, size: usize, guard_page: bool) -> io::Result<Stack> {
+        if !guard_page {
+            return StackPool::try_raw_allocate(size, false);
+        }
+
         let stack = match self.inner.get_refresh(&size) {
             Some(cached) => {
                 match cached.pop() {
-                    Some(stack) => {
+                    Some(mut stack) => {
                         trace!("allocating {} bytes stack from pool", size);
                         self.total_size -= size;
+                        self.stack_count -= 1;
+
+                        // The previous owner might have left secrets on it
+                        if !stack.released {
+                            stack.clear();
+                        }
+
+                        stack.released = false;
                         stack
                     }
-                    None => StackPool::raw_allocate(size),
+                    None => try!(StackPool::try_raw_allocate(size, true)),
                 }
             }
-            None => StackPool::raw_allocate(size),
+            None => try!(StackPool::try_raw_allocate(size, true)),
         };
 
         self.try_shrink();
@@ -95,13 +286,26 @@ impl StackPool {
                self.total_size,
                self.inner.len());
 
-        stack
+        Ok(stack)
     }
 
     /// Deallocate stack into pool
-    pub fn deallocate(&mut self, stack: Stack) {
+    pub fn deallocate(&mut self, mut stack: Stack) {
         let size = stack.size;
 
+        if !stack.has_guard_page() {
+            return;
+        }
+
+        if self.capacity.map_or(false, |capacity| self.stack_count >= capacity) {
+            trace!("pool is full, freeing {} bytes stack", size);
+            return;
+        }
+
+        if self.release_pages {
+            stack.release();
+        }
+
         let raw_inner: *mut LinkedHashMap<usize, Vec<Stack>> = &mut self.inner;
 
         match self.inner.get_refresh(&size) {
@@ -118,6 +322,7 @@ impl StackPool {
         }
 
         self.total_size += size;
+        self.stack_count += 1;
         self.try_shrink();
         trace!("deallocated, total size: {} bytes, buckets: {}",
                self.total_size,
@@ -148,6 +353,7 @@ impl StackPool {
                 Some((size, mut cached)) => {
                     while let Some(..) = cached.pop() {
                         self.total_size -= size;
+                        self.stack_count -= 1;
 
                         if self.total_size <= lower_bound {
                             // We still have some stacks inside, put it back
@@ -170,10 +376,35 @@ impl StackPool {
                old_size - self.total_size);
     }
 
+    // Frees one stack of the least recently used size, returns false if the pool is empty
+    fn pop_lru(&mut self) -> bool {
+        match self.inner.pop_back() {
+            Some((size, mut cached)) => {
+                if cached.pop().is_some() {
+                    self.total_size -= size;
+                    self.stack_count -= 1;
+                }
+
+                if !cached.is_empty() {
+                    self.inner.insert(size, cached);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+
     #[inline]
     pub fn total_size(&self) -> usize {
         self.total_size
     }
+
+    /// Number of stacks currently kept in the pool
+    #[inline]
+    pub fn stack_count(&self) -> usize {
+        self.stack_count
+    }
 }
 
 #[cfg(test)]
@@ -224,4 +455,90 @@ mod test {
         pool.deallocate(stack3);
         assert_eq!(pool.total_size(), 2048);
     }
+
+    #[test]
+    fn stack_pool_capacity() {
+        let mut pool = StackPool::new(None, None);
+        pool.set_capacity(Some(2));
+
+        let stack1 = pool.allocate(1024);
+        let stack2 = pool.allocate(1024);
+        let stack3 = pool.allocate(1024);
+
+        pool.deallocate(stack1);
+        pool.deallocate(stack2);
+        pool.deallocate(stack3);
+        assert_eq!(pool.stack_count(), 2);
+        assert_eq!(pool.total_size(), 2048);
+
+        pool.set_capacity(Some(1));
+        assert_eq!(pool.stack_count(), 1);
+        assert_eq!(pool.total_size(), 1024);
+    }
+
+    #[test]
+    fn stack_pool_reuse_is_zeroed() {
+        let mut pool = StackPool::new(None, None);
+
+        let stack = pool.allocate(4096);
+        let (bottom, len) = (stack.bottom() as *mut u8, stack.len());
+        unsafe { ::std::ptr::write_bytes(bottom, 0xff, len) };
+        pool.deallocate(stack);
+
+        let stack = pool.allocate(4096);
+        assert_eq!(stack.bottom() as *mut u8, bottom);
+        assert!((0..len).all(|i| unsafe { *bottom.offset(i as isize) } == 0));
+    }
+
+    #[test]
+    fn stack_pool_reuse_is_zeroed_above_watermark() {
+        let mut pool = StackPool::new(None, None);
+
+        // Only the upper half has been used, like a coroutine with a shallow call stack would
+        let stack = pool.allocate(8192);
+        let (bottom, len) = (stack.bottom() as *mut u8, stack.len());
+        unsafe { ::std::ptr::write_bytes(bottom.offset(len as isize / 2), 0xff, len / 2) };
+        pool.deallocate(stack);
+
+        let stack = pool.allocate(8192);
+        assert_eq!(stack.bottom() as *mut u8, bottom);
+        assert!((0..len).all(|i| unsafe { *bottom.offset(i as isize) } == 0));
+    }
+
+    #[test]
+    fn stack_pool_guard_page() {
+        let mut pool = StackPool::new(None, None);
+
+        let stack = pool.allocate(4096);
+        let bottom = stack.bottom() as usize;
+        assert!(stack.has_guard_page());
+        assert!(stack.guard_page_contains(bottom - 1));
+        assert!(!stack.guard_page_contains(bottom));
+
+        // Unprotected stacks are never pooled
+        let unprotected = pool.try_allocate(4096, false).unwrap();
+        assert!(!unprotected.has_guard_page());
+        assert!(!unprotected.guard_page_contains(unprotected.bottom() as usize - 1));
+        pool.deallocate(unprotected);
+        assert_eq!(pool.stack_count(), 0);
+
+        pool.deallocate(stack);
+        assert_eq!(pool.stack_count(), 1);
+    }
+
+    #[test]
+    fn stack_pool_release_pages_is_zeroed() {
+        let mut pool = StackPool::new(None, None);
+        pool.set_release_pages(true);
+
+        let stack = pool.allocate(4096);
+        let (bottom, len) = (stack.bottom() as *mut u8, stack.len());
+        unsafe { ::std::ptr::write_bytes(bottom, 0xff, len) };
+        pool.deallocate(stack);
+        assert_eq!(pool.stack_count(), 1);
+
+        let stack = pool.allocate(4096);
+        assert_eq!(stack.bottom() as *mut u8, bottom);
+        assert!((0..len).all(|i| unsafe { *bottom.offset(i as isize) } == 0));
+    }
 }
@@ -0,0 +1,447 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hierarchical timer wheel of a Processor
+//!
+//! Every Processor keeps the timers of the coroutines parked on it in a wheel of its own, so that
+//! arming a timer neither takes a lock nor a round-trip through the event loop.
+//! The wheel has a resolution of one millisecond and `LEVELS` levels of `SLOTS` slots each,
+//! where a single slot covers a whole rotation of the level below it. Timers are moved down one
+//! or more levels as soon as the wheel reaches their slot, until they expire from the lowest one.
+//!
+//! A timer whose coroutine has been woken up by another source is removed using the `TimerKey`
+//! returned by `insert()`, see `Processor::cancel_timer()`.
+
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use runtime::waiter::Waiter;
+
+/// The source index with which an expired timer wakes up its `Waiter`
+///
+/// It's placed right below `CANCEL_SOURCE`, so that it doesn't collide with the sources of
+/// `ReadyStates::select()`.
+pub const TIMER_EXPIRED: usize = !0 - 1;
+
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = (SLOTS - 1) as u64;
+
+// Covers 64^4 ms (about 4.6 hours). Timers beyond that wait in `TimerWheel::overflow`,
+// which is addressed as the level `LEVELS`.
+const LEVELS: usize = 4;
+
+/// Identifies a timer in a `TimerWheel`
+///
+/// Keys of timers which have expired or have been removed never match another timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerKey {
+    index: usize,
+    generation: usize,
+}
+
+struct Entry {
+    deadline: u64,
+    waiter: Arc<Waiter>,
+
+    // Where the index of this entry is stored: `level`, `slot` and the position in that list
+    level: usize,
+    slot: usize,
+    position: usize,
+}
+
+struct Node {
+    // Incremented whenever the node is freed, which invalidates all keys handed out for it
+    generation: usize,
+    entry: Option<Entry>,
+}
+
+pub struct TimerWheel {
+    start: Instant,
+
+    // The next tick to be processed, in milliseconds since `start`
+    elapsed: u64,
+
+    nodes: Vec<Node>,
+    free_nodes: Vec<usize>,
+
+    // The indices of the nodes of all timers by level and slot
+    levels: Vec<Vec<Vec<usize>>>,
+
+    // Timers beyond the last level, which are placed again with every rotation of it
+    overflow: Vec<usize>,
+
+    // Number of pending timers
+    len: usize,
+}
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        TimerWheel {
+            start: Instant::now(),
+            elapsed: 0,
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            levels: (0..LEVELS).map(|_| (0..SLOTS).map(|_| Vec::new()).collect()).collect(),
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Arms a timer which wakes up `waiter` with `TIMER_EXPIRED` after `delay_ms`
+    pub fn insert(&mut self, delay_ms: u64, waiter: Arc<Waiter>) -> TimerKey {
+        // The current tick has already partially elapsed. Rounding up ensures that the
+        // timer never expires early.
+        let deadline = self.now().saturating_add(delay_ms).saturating_add(1);
+        self.insert_at(deadline, waiter)
+    }
+
+    /// Removes the timer identified by `key`, unless it has already expired
+    ///
+    /// Returns true if the timer was still pending.
+    pub fn remove(&mut self, key: TimerKey) -> bool {
+        let pending = match self.nodes.get(key.index) {
+            Some(node) => node.generation == key.generation && node.entry.is_some(),
+            None => false,
+        };
+
+        if pending {
+            self.unlink(key.index);
+            self.free(key.index);
+        }
+
+        pending
+    }
+
+    /// Moves the wheel up to the current time and appends the `Waiter` of every expired timer
+    /// to `expired`
+    pub fn advance(&mut self, expired: &mut Vec<Arc<Waiter>>) {
+        let now = self.now();
+        self.advance_to(now, expired);
+    }
+
+    /// Returns the time until `advance()` has to be called next, or `None` if there are no timers
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.next_expiration().map(|tick| {
+            let now = self.now();
+
+            if tick > now {
+                Duration::from_millis(tick - now)
+            } else {
+                Duration::from_millis(0)
+            }
+        })
+    }
+
+    /// Drops all pending timers
+    pub fn clear(&mut self) {
+        for level in &mut self.levels {
+            for slot in level.iter_mut() {
+                slot.clear();
+            }
+        }
+
+        self.overflow.clear();
+        self.nodes.clear();
+        self.free_nodes.clear();
+        self.len = 0;
+    }
+
+    fn now(&self) -> u64 {
+        let d = self.start.elapsed();
+        d.as_secs() * 1_000 + d.subsec_nanos() as u64 / 1_000_000
+    }
+
+    fn insert_at(&mut self, deadline: u64, waiter: Arc<Waiter>) -> TimerKey {
+        let entry = Entry {
+            deadline: deadline,
+            waiter: waiter,
+            level: 0,
+            slot: 0,
+            position: 0,
+        };
+
+        let index = match self.free_nodes.pop() {
+            Some(index) => {
+                self.nodes[index].entry = Some(entry);
+                index
+            }
+            None => {
+                self.nodes.push(Node {
+                    generation: 0,
+                    entry: Some(entry),
+                });
+                self.nodes.len() - 1
+            }
+        };
+
+        self.len += 1;
+        self.place(index);
+
+        TimerKey {
+            index: index,
+            generation: self.nodes[index].generation,
+        }
+    }
+
+    fn free(&mut self, index: usize) -> Entry {
+        let node = &mut self.nodes[index];
+        node.generation = node.generation.wrapping_add(1);
+        self.free_nodes.push(index);
+        self.len -= 1;
+        node.entry.take().expect("freed an empty timer node")
+    }
+
+    fn list(&mut self, level: usize, slot: usize) -> &mut Vec<usize> {
+        if level < LEVELS {
+            &mut self.levels[level][slot]
+        } else {
+            &mut self.overflow
+        }
+    }
+
+    fn place(&mut self, index: usize) {
+        let elapsed = self.elapsed;
+
+        let (level, slot) = {
+            let entry = self.nodes[index].entry.as_mut().unwrap();
+
+            if entry.deadline < elapsed {
+                entry.deadline = elapsed;
+            }
+
+            let level = cmp::min(level_for(elapsed, entry.deadline), LEVELS);
+            let slot = if level < LEVELS {
+                slot_for(entry.deadline, level)
+            } else {
+                0
+            };
+
+            (level, slot)
+        };
+
+        let position = {
+            let list = self.list(level, slot);
+            list.push(index);
+            list.len() - 1
+        };
+
+        let entry = self.nodes[index].entry.as_mut().unwrap();
+        entry.level = level;
+        entry.slot = slot;
+        entry.position = position;
+    }
+
+    // Removes the index of a pending timer from the list it's stored in
+    fn unlink(&mut self, index: usize) {
+        let (level, slot, position) = {
+            let entry = self.nodes[index].entry.as_ref().unwrap();
+            (entry.level, entry.slot, entry.position)
+        };
+
+        let moved = {
+            let list = self.list(level, slot);
+            list.swap_remove(position);
+            list.get(position).cloned()
+        };
+
+        if let Some(moved) = moved {
+            self.nodes[moved].entry.as_mut().unwrap().position = position;
+        }
+    }
+
+    fn advance_to(&mut self, now: u64, expired: &mut Vec<Arc<Waiter>>) {
+        while self.elapsed <= now {
+            match self.next_expiration() {
+                Some(tick) if tick <= now => {
+                    self.elapsed = tick;
+                    self.process(tick, expired);
+                    self.elapsed = tick + 1;
+                }
+                _ => self.elapsed = now + 1,
+            }
+        }
+    }
+
+    // Returns the earliest tick at which a slot has to be moved down or expired
+    fn next_expiration(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut next = None;
+
+        for (level, slots) in self.levels.iter().enumerate() {
+            let shift = level * SLOT_BITS;
+            let rotation = self.elapsed >> (shift + SLOT_BITS) << (shift + SLOT_BITS);
+            let current = slot_for(self.elapsed, level);
+
+            // A slot below the current one would belong to the next rotation, but a timer
+            // is always placed on the highest level at which it differs from `elapsed`.
+            if let Some(slot) = (current..SLOTS).find(|&slot| !slots[slot].is_empty()) {
+                let tick = rotation + ((slot as u64) << shift);
+                next = Some(next.map_or(tick, |next| cmp::min(next, tick)));
+            }
+        }
+
+        if !self.overflow.is_empty() {
+            let span = 1u64 << (LEVELS * SLOT_BITS);
+            let tick = (self.elapsed + span - 1) / span * span;
+            next = Some(next.map_or(tick, |next| cmp::min(next, tick)));
+        }
+
+        next
+    }
+
+    fn process(&mut self, tick: u64, expired: &mut Vec<Arc<Waiter>>) {
+        // The higher levels are moved down first, since their timers might end up
+        // in a slot further below, which is due at the same tick.
+        if tick & ((1u64 << (LEVELS * SLOT_BITS)) - 1) == 0 {
+            let indices = mem::replace(&mut self.overflow, Vec::new());
+            self.cascade(indices);
+        }
+
+        for level in (1..LEVELS).rev() {
+            if tick & ((1u64 << (level * SLOT_BITS)) - 1) == 0 {
+                let slot = slot_for(tick, level);
+                let indices = mem::replace(&mut self.levels[level][slot], Vec::new());
+                self.cascade(indices);
+            }
+        }
+
+        let slot = slot_for(tick, 0);
+        let indices = mem::replace(&mut self.levels[0][slot], Vec::new());
+
+        for index in indices {
+            let entry = self.free(index);
+            expired.push(entry.waiter);
+        }
+    }
+
+    fn cascade(&mut self, indices: Vec<usize>) {
+        for index in indices {
+            // Timers are removed by `Processor::cancel_timer()` only after their coroutine
+            // has been resumed, which might not have happened yet.
+            let fired = self.nodes[index].entry.as_ref().unwrap().waiter.fired().is_some();
+
+            if fired {
+                self.free(index);
+            } else {
+                self.place(index);
+            }
+        }
+    }
+}
+
+// Timers are placed on the level of the most significant bit in which they differ from `elapsed`
+#[inline]
+fn level_for(elapsed: u64, deadline: u64) -> usize {
+    let masked = (elapsed ^ deadline) | SLOT_MASK;
+    let significant = 63 - masked.leading_zeros() as usize;
+    significant / SLOT_BITS
+}
+
+#[inline]
+fn slot_for(tick: u64, level: usize) -> usize {
+    ((tick >> (level * SLOT_BITS)) & SLOT_MASK) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use runtime::waiter::Waiter;
+
+    fn expire(wheel: &mut TimerWheel, now: u64, waiters: &[Arc<Waiter>]) -> Vec<usize> {
+        let mut expired = Vec::new();
+        wheel.advance_to(now, &mut expired);
+
+        let mut indices = expired.iter()
+                                 .map(|w| waiters.iter().position(|x| x.same(w)).unwrap())
+                                 .collect::<Vec<_>>();
+        indices.sort();
+        indices
+    }
+
+    #[test]
+    fn timer_wheel_expires_on_every_level() {
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 300_000, 20_000_000];
+        let waiters = deadlines.iter().map(|_| Arc::new(Waiter::new())).collect::<Vec<_>>();
+
+        let mut wheel = TimerWheel::new();
+        for (&deadline, waiter) in deadlines.iter().zip(&waiters) {
+            wheel.insert_at(deadline, waiter.clone());
+        }
+        assert_eq!(wheel.len, deadlines.len());
+
+        assert_eq!(expire(&mut wheel, 0, &waiters), vec![]);
+        assert_eq!(expire(&mut wheel, 1, &waiters), vec![0]);
+        assert_eq!(expire(&mut wheel, 63, &waiters), vec![1]);
+        assert_eq!(expire(&mut wheel, 64, &waiters), vec![2]);
+        assert_eq!(expire(&mut wheel, 4094, &waiters), vec![3]);
+        assert_eq!(expire(&mut wheel, 4096, &waiters), vec![4, 5]);
+        assert_eq!(expire(&mut wheel, 299_999, &waiters), vec![]);
+        assert_eq!(expire(&mut wheel, 300_000, &waiters), vec![6]);
+        assert_eq!(expire(&mut wheel, 19_999_999, &waiters), vec![]);
+        assert_eq!(expire(&mut wheel, 20_000_000, &waiters), vec![7]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn timer_wheel_next_expiration() {
+        let mut wheel = TimerWheel::new();
+        assert_eq!(wheel.next_expiration(), None);
+
+        wheel.insert_at(100, Arc::new(Waiter::new()));
+        // The timer is moved down to the lowest level first
+        assert_eq!(wheel.next_expiration(), Some(64));
+
+        wheel.advance_to(64, &mut Vec::new());
+        assert_eq!(wheel.next_expiration(), Some(100));
+
+        wheel.insert_at(70, Arc::new(Waiter::new()));
+        assert_eq!(wheel.next_expiration(), Some(70));
+    }
+
+    #[test]
+    fn timer_wheel_remove() {
+        let waiters = (0..3).map(|_| Arc::new(Waiter::new())).collect::<Vec<_>>();
+
+        let mut wheel = TimerWheel::new();
+        let first = wheel.insert_at(10, waiters[0].clone());
+        let second = wheel.insert_at(10, waiters[1].clone());
+        wheel.insert_at(1_000, waiters[2].clone());
+
+        // Removing the first entry of a slot moves the last one into its place
+        assert!(wheel.remove(first));
+        assert!(!wheel.remove(first));
+        assert_eq!(wheel.len, 2);
+        assert_eq!(Arc::strong_count(&waiters[0]), 1);
+
+        assert!(wheel.remove(second));
+        assert_eq!(wheel.next_expiration(), Some(960));
+
+        // The node is reused, but the old key doesn't match the new timer
+        let third = wheel.insert_at(20, waiters[0].clone());
+        assert!(!wheel.remove(second));
+
+        assert_eq!(expire(&mut wheel, 20, &waiters), vec![0]);
+        assert!(!wheel.remove(third));
+        assert_eq!(expire(&mut wheel, 1_000, &waiters), vec![2]);
+        assert!(wheel.is_empty());
+    }
+}
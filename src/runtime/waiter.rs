@@ -0,0 +1,115 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Wakeup arbitration for parked coroutines
+
+use std::fmt;
+
+use runtime::coroutine::Handle;
+use sync::spinlock::Spinlock;
+
+struct WaiterState {
+    coro: Option<Handle>,
+    fired: Option<usize>,
+}
+
+/// A parked coroutine which might be woken up by one of multiple sources.
+///
+/// Every source is identified by an index. The first source calling `wake()` wins and
+/// its index is recorded, while the calls of all other sources are ignored.
+/// This allows a coroutine to be registered at several places at once
+/// without ever being readied more than once.
+///
+/// The usual lifecycle is:
+///
+/// 1. Create a `Waiter` and register it (together with a source index) at all sources.
+/// 2. Park the coroutine and `arm()` the `Waiter` with the coroutine's `Handle`.
+/// 3. After the coroutine has been resumed check `fired()` and unregister it from
+///    all sources which didn't win.
+pub struct Waiter(Spinlock<WaiterState>);
+
+impl Waiter {
+    pub fn new() -> Waiter {
+        Waiter(Spinlock::new(WaiterState {
+            coro: None,
+            fired: None,
+        }))
+    }
+
+    /// Stores the parked coroutine in the `Waiter`.
+    ///
+    /// If a source has already fired the `Handle` is given back
+    /// and it's the callers responsibility to ready it.
+    pub fn arm(&self, coro: Handle) -> Option<Handle> {
+        let mut inner = self.0.lock();
+
+        if inner.fired.is_some() {
+            Some(coro)
+        } else {
+            inner.coro = Some(coro);
+            None
+        }
+    }
+
+    /// Tries to wake up the coroutine on behalf of `source`.
+    ///
+    /// Returns `false` if another source has already won the race. Otherwise `source` is
+    /// recorded as the winner and, if the `Waiter` is already armed, `ready` is called with
+    /// the parked coroutine.
+    pub fn wake<F>(&self, source: usize, ready: F) -> bool
+        where F: FnOnce(Handle)
+    {
+        let coro = {
+            let mut inner = self.0.lock();
+
+            if inner.fired.is_some() {
+                return false;
+            }
+
+            inner.fired = Some(source);
+            inner.coro.take()
+        };
+
+        if let Some(coro) = coro {
+            ready(coro);
+        }
+
+        true
+    }
+
+    /// Returns the index of the source which has woken up the coroutine.
+    pub fn fired(&self) -> Option<usize> {
+        self.0.lock().fired
+    }
+
+    /// Returns true if both arguments refer to the same `Waiter`.
+    #[inline]
+    pub fn same(&self, other: &Waiter) -> bool {
+        self as *const Waiter == other as *const Waiter
+    }
+}
+
+impl fmt::Debug for Waiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Waiter({:p})", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn waiter_first_source_wins() {
+        let waiter = Waiter::new();
+
+        assert!(waiter.wake(1, |_| panic!("Waiter is not armed yet")));
+        assert!(!waiter.wake(0, |_| panic!("source 0 must not win")));
+        assert_eq!(waiter.fired(), Some(1));
+    }
+}
@@ -9,38 +9,108 @@
 //! Global coroutine scheduler
 
 use std::cell::UnsafeCell;
-use std::fmt::Debug;
+use std::cmp;
+use std::fmt::{self, Debug};
 use std::io::{self, Write};
 use std::mem;
 use std::panic;
-use std::ptr;
 use std::sync::{Arc, Barrier, Condvar, Mutex, MutexGuard};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use mio::{Evented, EventLoop, EventSet, Handler, NotifyError, PollOpt, Sender, TimerError, Token};
 use slab::Slab;
 
-use coroutine::{Coroutine, Handle, HandleList};
-use join_handle::{self, JoinHandleReceiver};
-use options::Options;
+use join_handle::{self, CapturedPanic, JoinHandleReceiver, PanicReport};
+use options::{Options, Priority};
+use runtime::affinity;
+use runtime::blocking::{self, BlockingPool};
+use runtime::cancel::{self, CancelToken};
+use runtime::coroutine::{Coroutine, Handle, HandleList};
 use runtime::processor::{self, Machine, Processor, ProcMessage};
+use runtime::stack_overflow;
+use runtime::timer_wheel::TIMER_EXPIRED;
+use runtime::waiter::Waiter;
+use scope::{self, Scope};
 use sync::spinlock::Spinlock;
 
 
 /// A handle that could join the coroutine
 pub struct JoinHandle<T> {
     result: JoinHandleReceiver<T>,
+    cancel_token: CancelToken,
 }
 
 unsafe impl<T: Send> Send for JoinHandle<T> {}
 
 impl<T> JoinHandle<T> {
     /// Await completion of the coroutine and return it's result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result has already been taken using `try_join()`.
     pub fn join(self) -> thread::Result<T> {
         self.result.pop()
     }
+
+    /// Like `join()`, but a panic is described by a `PanicReport`
+    ///
+    /// Besides the payload it contains the name of the coroutine and where it panicked, so that
+    /// the joiner is able to log more than just the panic message.
+    pub fn join_report(self) -> Result<T, PanicReport> {
+        self.result.pop_report()
+    }
+
+    /// Like `join()`, but gives up once `timeout` has elapsed while the coroutine is still running.
+    ///
+    /// The timeout is measured using the timer of the `Scheduler` if called from a coroutine.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result has already been taken.
+    pub fn join_timeout(&mut self,
+                        timeout: Duration)
+                        -> Result<thread::Result<T>, join_handle::Timeout> {
+        self.result.pop_timeout(timeout)
+    }
+
+    /// Returns true if the coroutine has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        self.result.is_finished()
+    }
+
+    /// Returns the result of the coroutine if it has finished, without blocking.
+    ///
+    /// The result can only be taken once, so subsequent calls return `None`.
+    pub fn try_join(&mut self) -> Option<thread::Result<T>> {
+        self.result.try_pop()
+    }
+
+    /// Requests the coroutine to stop its work.
+    ///
+    /// Cancellation is cooperative: The coroutine can observe it using `coio::is_cancelled()`.
+    /// If it is currently waiting for I/O or sleeping it is woken up right away
+    /// and the blocking operation returns a `Cancelled` error (or returns early for sleeps).
+    pub fn cancel(&self) {
+        self.cancel_token.cancel();
+    }
+
+    /// Returns true if `cancel()` or `abort()` has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token.is_cancelled()
+    }
+
+    /// Forces the coroutine to stop, by unwinding its stack at the next cancellation point.
+    ///
+    /// This cancels the coroutine just like `cancel()`, but instead of returning a `Cancelled`
+    /// error blocking I/O operations unwind the coroutine, as do sleeps, `coio::sched()` and
+    /// `coio::check_cancel()`. Joining it afterwards returns an error whose payload is
+    /// `Cancelled`. A coroutine which is parked on a synchronization primitive, like a `Mutex`
+    /// or a channel, is only unwound once it has been woken up by that primitive.
+    pub fn abort(&self) {
+        self.cancel_token.abort();
+    }
 }
 
 
@@ -51,14 +121,16 @@ type DeregisterCallback<'a> = &'a mut FnMut(&mut EventLoop<Scheduler>);
 pub struct RegisterMessage {
     cb: RegisterCallback<'static>,
     coro: Handle,
+    mode: ReadyMode,
 }
 
 impl RegisterMessage {
     #[inline]
-    fn new(coro: Handle, cb: RegisterCallback) -> RegisterMessage {
+    fn new(coro: Handle, cb: RegisterCallback, mode: ReadyMode) -> RegisterMessage {
         RegisterMessage {
             cb: unsafe { mem::transmute(cb) },
             coro: coro,
+            mode: mode,
         }
     }
 }
@@ -68,51 +140,92 @@ pub struct DeregisterMessage {
     cb: DeregisterCallback<'static>,
     coro: Handle,
     token: Token,
+    ready_states: ReadyStates,
 }
 
 impl DeregisterMessage {
     #[inline]
-    fn new(coro: Handle, cb: DeregisterCallback, token: Token) -> DeregisterMessage {
+    fn new(coro: Handle,
+           cb: DeregisterCallback,
+           token: Token,
+           ready_states: ReadyStates)
+           -> DeregisterMessage {
         DeregisterMessage {
             cb: unsafe { mem::transmute(cb) },
             coro: coro,
             token: token,
+            ready_states: ready_states,
         }
     }
 }
 
-#[doc(hidden)]
-pub struct TimerMessage {
-    coro: Handle,
-    delay: u64,
-    result: *mut Result<(), TimerError>,
-}
-
-impl TimerMessage {
-    #[inline]
-    fn new(coro: Handle, delay: u64, result: &mut Result<(), TimerError>) -> TimerMessage {
-        TimerMessage {
-            coro: coro,
-            delay: delay,
-            result: result,
-        }
-    }
-}
+/// The source index with which the closure passed to `Scheduler::park_with_timeout()`
+/// should wake up the parked coroutine
+pub const PARK_WOKEN: usize = 2;
 
 #[doc(hidden)]
 pub enum Message {
     Register(RegisterMessage),
     Deregister(DeregisterMessage),
-    Timer(TimerMessage),
+    Drain(Instant),
+    Ready(Handle),
     Shutdown,
 }
 
 unsafe impl Send for Message {}
 
-
+/// Readies parked coroutines of the current `Scheduler` from any thread
+///
+/// A coroutine must only be resumed by one of the workers. `Scheduler::ready()` resumes it on
+/// the calling thread if that isn't a worker, so threads outside of the `Scheduler` instead
+/// hand the coroutine over to the event loop, which pushes it into a worker's queue.
 #[doc(hidden)]
+#[derive(Clone)]
+pub struct RemoteReady(Sender<Message>);
+
+impl RemoteReady {
+    /// Creates a `RemoteReady` for the `Scheduler` of the current coroutine.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a coroutine.
+    pub fn current() -> RemoteReady {
+        let scheduler = Scheduler::instance().expect("RemoteReady requires a Scheduler");
+        RemoteReady(scheduler.event_loop_sender.clone().unwrap())
+    }
+
+    pub fn ready(&self, coro: Handle) {
+        if Processor::current().is_some() {
+            Scheduler::ready(coro);
+            return;
+        }
+
+        send_ready(&self.0, coro);
+    }
+}
+
+// Readies `coro` through the event loop, for threads which aren't running a Processor
+fn send_ready(channel: &Sender<Message>, coro: Handle) {
+    let mut msg = Message::Ready(coro);
+
+    loop {
+        match channel.send(msg) {
+            Err(NotifyError::Full(m)) => msg = m,
+            Err(NotifyError::Closed(Some(m))) => {
+                // The Scheduler has been shut down already and the
+                // coroutine must not be unwound on this thread.
+                mem::forget(m);
+                break;
+            }
+            _ => break,
+        }
+    }
+}
+
+
+/// The kind of readiness a coroutine can wait for
 #[repr(usize)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ReadyType {
     Readable = 0,
     Writable,
@@ -126,65 +239,351 @@ impl Into<EventSet> for ReadyType {
     }
 }
 
+/// Determines how a readiness event is distributed among the coroutines waiting for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadyMode {
+    /// Wake up a single waiter per event. This is the right choice for sockets,
+    /// where only one coroutine can consume the data which made it ready.
+    Single,
+    /// Wake up all waiters, e.g. for fan-out notifications through an eventfd.
+    Broadcast,
+}
+
+type WaitEntry = (Arc<Waiter>, usize);
+
+struct ReadyStatesInner {
+    events: EventSet,
+    waiters: [Vec<WaitEntry>; 4],
+    mode: ReadyMode,
+}
+
+impl fmt::Debug for ReadyStatesInner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "ReadyStatesInner {{ events: {:?}, mode: {:?}, waiters: [{}, {}, {}, {}] }}",
+               self.events,
+               self.mode,
+               self.waiters[0].len(),
+               self.waiters[1].len(),
+               self.waiters[2].len(),
+               self.waiters[3].len())
+    }
+}
+
 #[doc(hidden)]
 #[derive(Clone, Debug)]
-pub struct ReadyStates(Arc<Spinlock<(EventSet, [Option<Handle>; 4])>>);
+pub struct ReadyStates(Arc<Spinlock<ReadyStatesInner>>);
 
 impl ReadyStates {
     #[inline]
-    fn new() -> ReadyStates {
-        ReadyStates(Arc::new(Spinlock::new((EventSet::none(), [None, None, None, None]))))
+    fn new(mode: ReadyMode) -> ReadyStates {
+        ReadyStates(Arc::new(Spinlock::new(ReadyStatesInner {
+            events: EventSet::none(),
+            waiters: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            mode: mode,
+        })))
     }
 
+    /// Returns true if both arguments refer to the same registration.
     #[inline]
-    pub fn wait(&self, ready_type: ReadyType) {
-        let event_set: EventSet = ready_type.into();
-        let mut inner = self.0.lock();
+    pub fn same(&self, other: &ReadyStates) -> bool {
+        &*self.0 as *const _ == &*other.0 as *const _
+    }
 
-        if inner.0.contains(event_set) {
-            inner.0.remove(event_set);
-        } else {
-            drop(inner);
+    #[inline]
+    pub fn wait(&self, ready_type: ReadyType) -> io::Result<()> {
+        ReadyStates::select(&[(self, ready_type)]).map(|_| ())
+    }
+
+    /// Like `wait()`, but fails with `ErrorKind::TimedOut` if `timeout` elapses first.
+    #[inline]
+    pub fn wait_timeout(&self, ready_type: ReadyType, timeout: Option<Duration>) -> io::Result<()> {
+        ReadyStates::select_timeout(&[(self, ready_type)], timeout).map(|_| ())
+    }
+
+    /// Blocks the current coroutine until any of the `sources` is ready
+    /// and returns the index of the one which fired.
+    ///
+    /// Sources which are already ready at the time of the call are returned immediately.
+    /// The readiness of the returned source is consumed, just as it is with `wait()`.
+    /// Returns a `Cancelled` error if the coroutine is cancelled before any source is ready.
+    #[inline]
+    pub fn select(sources: &[(&ReadyStates, ReadyType)]) -> io::Result<usize> {
+        ReadyStates::select_timeout(sources, None)
+    }
+
+    /// Like `select()`, but fails with `ErrorKind::TimedOut` if `timeout` elapses first.
+    ///
+    /// `None` waits without a timeout.
+    pub fn select_timeout(sources: &[(&ReadyStates, ReadyType)],
+                          timeout: Option<Duration>)
+                          -> io::Result<usize> {
+        assert!(!sources.is_empty(), "cannot select without any source");
+
+        for (idx, &(states, ready_type)) in sources.iter().enumerate() {
+            if states.take_event(ready_type) {
+                return Ok(idx);
+            }
+        }
+
+        let mut p = Processor::current().expect("cannot wait without processor");
+        let cancel_token = p.current().and_then(|coro| coro.cancel_token().cloned());
 
-            let p = Processor::current().expect("cannot wait without processor");
-            p.park_with(|p, coro| {
+        if cancel_token.as_ref().map_or(false, |t| t.is_cancelled()) {
+            return Err(cancel::cancelled_error());
+        }
+
+        let delay = timeout.map(|d| d.as_secs() * 1_000 + d.subsec_nanos() as u64 / 1_000_000);
+        let waiter = Arc::new(Waiter::new());
+        let mut timer = None;
+
+        p.park_with(|p, coro| {
+            if let Some(ref cancel_token) = cancel_token {
+                cancel_token.park_or_fire(&waiter);
+            }
+
+            for (idx, &(states, ready_type)) in sources.iter().enumerate() {
+                if !states.push_waiter(ready_type, &waiter, idx) {
+                    break;
+                }
+            }
+
+            if let Some(delay) = delay {
+                timer = Some(p.add_timer(delay, waiter.clone()));
+            }
+
+            if let Some(coro) = waiter.arm(coro) {
+                p.ready(coro);
+            }
+        });
+
+        if let Some(ref cancel_token) = cancel_token {
+            cancel_token.unpark();
+        }
+
+        let fired = waiter.fired().expect("Waiter resumed without being fired");
+
+        if fired != TIMER_EXPIRED {
+            cancel_timer(timer);
+        }
+
+        // The winning source has already removed our entry, but all others still hold one.
+        for (idx, &(states, ready_type)) in sources.iter().enumerate() {
+            if idx != fired {
+                states.remove_waiter(ready_type, &waiter);
+            }
+        }
+
+        match fired {
+            TIMER_EXPIRED => Err(io::Error::new(io::ErrorKind::TimedOut, "operation timed out")),
+            cancel::CANCEL_SOURCE => Err(cancel::cancelled_error()),
+            _ => Ok(fired),
+        }
+    }
+
+    /// Blocks the current coroutine until at least one of the `sources` is ready
+    /// and returns the indices of all sources which are ready by then, in ascending order.
+    ///
+    /// Just like with `select()` the readiness of all returned sources is consumed.
+    pub fn poll(sources: &[(&ReadyStates, ReadyType)],
+                timeout: Option<Duration>)
+                -> io::Result<Vec<usize>> {
+        let fired = try!(ReadyStates::select_timeout(sources, timeout));
+
+        let ready = sources.iter()
+                           .enumerate()
+                           .filter(|&(idx, &(states, ready_type))| {
+                               idx == fired || states.take_event(ready_type)
+                           })
+                           .map(|(idx, _)| idx)
+                           .collect();
+
+        Ok(ready)
+    }
+
+    /// Passes the readiness for `ready_type` on to the next waiting coroutine, if there is any.
+    ///
+    /// Since events are edge triggered, a single event might stand for several pending
+    /// datagrams, but only one of the waiters would be woken up for it. Sources which are
+    /// shared between coroutines call this after each successful operation, so that the
+    /// next waiter gets the chance to check for more pending data.
+    pub fn pass_on(&self, ready_type: ReadyType) {
+        loop {
+            let (waiter, idx) = {
                 let mut inner = self.0.lock();
+                let waiters = &mut inner.waiters[ready_type as usize];
 
-                if inner.0.contains(event_set) {
-                    inner.0.remove(event_set);
-                    p.ready(coro);
-                } else {
-                    inner.1[ready_type as usize] = Some(coro);
+                if waiters.is_empty() {
+                    return;
                 }
-            });
+
+                waiters.remove(0)
+            };
+
+            // The Waiter might have been fired by another source in the meantime
+            if waiter.wake(idx, Scheduler::ready) {
+                return;
+            }
         }
     }
 
     #[inline]
     pub fn make_ready(&self, ready_type: ReadyType) {
-        self.0.lock().0.insert(ready_type.into());
+        self.0.lock().events.insert(ready_type.into());
     }
 
-    // WARNING: `handles` has to be uninitialized
+    // Consumes the readiness for `ready_type` if it is set.
     #[inline]
-    fn notify(&self, event_set: EventSet, handles: &mut [Handle; 4]) -> usize {
+    fn take_event(&self, ready_type: ReadyType) -> bool {
+        let event_set: EventSet = ready_type.into();
+        let mut inner = self.0.lock();
+
+        if inner.events.contains(event_set) {
+            inner.events.remove(event_set);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Registers `waiter` for `ready_type` or fires it immediately if the event is already set.
+    // Returns false if the `Waiter` has fired and no further sources need to be registered.
+    fn push_waiter(&self, ready_type: ReadyType, waiter: &Arc<Waiter>, idx: usize) -> bool {
+        let event_set: EventSet = ready_type.into();
+        let mut inner = self.0.lock();
+
+        if inner.events.contains(event_set) {
+            // Only consume the event if we actually won,
+            // since otherwise the readiness would be lost for other waiters.
+            if waiter.wake(idx, |_| unreachable!("Waiter is not armed yet")) {
+                inner.events.remove(event_set);
+            }
+
+            false
+        } else {
+            inner.waiters[ready_type as usize].push((waiter.clone(), idx));
+            true
+        }
+    }
+
+    fn remove_waiter(&self, ready_type: ReadyType, waiter: &Arc<Waiter>) {
+        let mut inner = self.0.lock();
+        inner.waiters[ready_type as usize].retain(|&(ref w, _)| !w.same(waiter));
+    }
+
+    // Wakes up the waiters for all events in `event_set` and pushes them into `ready`.
+    // Events for which no one was waiting are stored for later calls to `wait()`.
+    fn notify(&self, mut event_set: EventSet, ready: &mut HandleList) {
         let mut inner = self.0.lock();
-        let mut handle_count = 0usize;
+        let broadcast = inner.mode == ReadyMode::Broadcast;
+
+        // Errors and hangups have to wake up both readers and writers,
+        // since otherwise they would never learn about the failure of the underlying I/O.
+        if event_set.is_error() || event_set.is_hup() {
+            event_set = event_set | EventSet::readable() | EventSet::writable();
+        }
 
         for i in 0..4usize {
             let event: EventSet = unsafe { mem::transmute(1usize << i) };
 
-            if event_set.contains(event) {
-                if let Some(coro) = inner.1[i].take() {
-                    unsafe { ptr::write(handles.as_mut_ptr().offset(handle_count as isize), coro) };
-                    handle_count += 1;
-                } else {
-                    inner.0.insert(event);
+            if !event_set.contains(event) {
+                continue;
+            }
+
+            let mut woken = false;
+
+            while !inner.waiters[i].is_empty() {
+                let (waiter, idx) = inner.waiters[i].remove(0);
+
+                if waiter.wake(idx, |coro| ready.push_back(coro)) {
+                    woken = true;
+
+                    if !broadcast {
+                        break;
+                    }
                 }
             }
+
+            if !woken {
+                inner.events.insert(event);
+            }
         }
+    }
+}
+
+/// A snapshot of the Scheduler's runtime statistics
+///
+/// All values are gathered without synchronization and are thus only approximations.
+#[derive(Clone, Debug, Default)]
+pub struct SchedulerStats {
+    /// Number of I/O readiness events the event loop has been woken up for
+    pub io_events: usize,
+    /// Number of timers which expired, summed up over all workers
+    pub timer_events: usize,
+    /// Number of messages (registrations, wakeups, ...) the event loop has been woken up for
+    pub notify_events: usize,
+    /// Number of coroutines waiting in the global queue
+    pub global_queue_size: usize,
+    /// Number of workers which are parked, because they ran out of work
+    pub idle_processor_count: usize,
+    /// Number of workers which are currently trying to steal work
+    pub spinning_processor_count: usize,
+    /// Total number of coroutines spawned since the Scheduler has been started
+    pub spawned_coroutines: usize,
+    /// Number of coroutines in the local queue of each worker
+    pub local_queue_sizes: Vec<usize>,
+    /// Largest amount of stack in bytes any finished coroutine has used
+    #[cfg(feature = "stack-watermark")]
+    pub peak_stack_usage: usize,
+}
+
+#[cfg(feature = "backtrace")]
+fn capture_backtrace() -> Option<String> {
+    Some(format!("{:?}", ::backtrace::Backtrace::new()))
+}
+
+#[cfg(not(feature = "backtrace"))]
+fn capture_backtrace() -> Option<String> {
+    None
+}
 
-        handle_count
+// Removes the timer of a coroutine which has been woken up by another source
+fn cancel_timer(timer: Option<processor::Timer>) {
+    if let (Some(timer), Some(mut p)) = (timer, Processor::current()) {
+        p.cancel_timer(timer);
+    }
+}
+
+/// Coroutines which are ready to run, but don't belong to any particular Processor
+///
+/// Coroutines with `Priority::High` are kept apart, so that Processors fetching from the
+/// global queue can pick them before any of the normal ones.
+#[doc(hidden)]
+pub struct GlobalQueue {
+    pub priority: HandleList,
+    pub normal: HandleList,
+}
+
+impl GlobalQueue {
+    fn new() -> GlobalQueue {
+        GlobalQueue {
+            priority: HandleList::new(),
+            normal: HandleList::new(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.priority.len() + self.normal.len()
+    }
+
+    #[inline]
+    pub fn push_back(&mut self, hdl: Handle) {
+        if hdl.priority() == Priority::High {
+            self.priority.push_back(hdl);
+        } else {
+            self.normal.push_back(hdl);
+        }
     }
 }
 
@@ -193,6 +592,12 @@ pub struct Scheduler {
     default_spawn_options: Options,
     expected_worker_count: usize,
     maximum_stack_memory_limit: usize,
+    local_queue_size: usize,
+    stack_pool_capacity: Option<usize>,
+    stack_pool_release_pages: bool,
+    preempt_interval: Option<Duration>,
+    pin_processors: bool,
+    cpu_set: Option<Vec<usize>>,
 
     // Mio event loop handler
     event_loop_sender: Option<Sender<Message>>,
@@ -210,9 +615,27 @@ pub struct Scheduler {
     is_shutting_down: AtomicBool,
     spinning_processor_count: AtomicUsize,
 
+    // Graceful shutdown
+    //   `drain_deadline` and `main_finished` are only accessed by the event loop.
+    is_draining: AtomicBool,
+    drain_deadline: Option<Instant>,
+    main_finished: bool,
+    finished_count: AtomicUsize,
+
     global_queue_size: AtomicUsize,
-    global_queue: Mutex<HandleList>,
+    global_queue: Mutex<GlobalQueue>,
     io_handler_queue: HandleList,
+
+    blocking_pool: BlockingPool,
+
+    // Event loop statistics
+    spawned_count: AtomicUsize,
+    io_event_count: AtomicUsize,
+    timer_event_count: AtomicUsize,
+    notify_event_count: AtomicUsize,
+
+    #[cfg(feature = "stack-watermark")]
+    peak_stack_usage: AtomicUsize,
 }
 
 impl Scheduler {
@@ -222,6 +645,12 @@ impl Scheduler {
             default_spawn_options: Options::default(),
             expected_worker_count: 1,
             maximum_stack_memory_limit: 2 * 1024 * 1024 * 1024, // 2GB
+            local_queue_size: processor::QUEUE_SIZE,
+            stack_pool_capacity: None,
+            stack_pool_release_pages: false,
+            preempt_interval: None,
+            pin_processors: false,
+            cpu_set: None,
 
             event_loop_sender: None,
             slab: Slab::new(1024),
@@ -234,9 +663,25 @@ impl Scheduler {
             is_shutting_down: AtomicBool::new(false),
             spinning_processor_count: AtomicUsize::new(0),
 
+            is_draining: AtomicBool::new(false),
+            drain_deadline: None,
+            main_finished: false,
+            finished_count: AtomicUsize::new(0),
+
             global_queue_size: AtomicUsize::new(0),
-            global_queue: Mutex::new(HandleList::new()),
+            global_queue: Mutex::new(GlobalQueue::new()),
             io_handler_queue: HandleList::new(),
+
+
+            blocking_pool: BlockingPool::new(blocking::DEFAULT_MAX_THREADS),
+
+            spawned_count: AtomicUsize::new(0),
+            io_event_count: AtomicUsize::new(0),
+            timer_event_count: AtomicUsize::new(0),
+            notify_event_count: AtomicUsize::new(0),
+
+            #[cfg(feature = "stack-watermark")]
+            peak_stack_usage: AtomicUsize::new(0),
         }
     }
 
@@ -247,18 +692,154 @@ impl Scheduler {
         self
     }
 
+    /// Set the size of the local run queue of each worker
+    ///
+    /// Coroutines which are spawned or readied are pushed into the local queue of the current
+    /// worker. If that queue is full, half of it is moved to the global queue in a single batch,
+    /// from where idle workers pick them up. Spawning coroutines thus never blocks, but a larger
+    /// queue reduces the contention on the global one. The default is 256.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is not a power of two or smaller than 2.
+    pub fn with_local_queue_size(mut self, size: usize) -> Scheduler {
+        assert!(size >= 2 && size.is_power_of_two(),
+                "Local queue size must be a power of two");
+        self.local_queue_size = size;
+        self
+    }
+
+    /// Pin each worker thread to a CPU
+    ///
+    /// Workers are assigned round-robin to the CPUs given by `cpu_set()`, or to all online CPUs
+    /// by default. Pinning is only supported on Linux and is a no-op with a warning elsewhere.
+    pub fn pin_processors(mut self, pin: bool) -> Scheduler {
+        self.pin_processors = pin;
+        self
+    }
+
+    /// Set the CPUs the workers are pinned to, see `pin_processors()`
+    ///
+    /// If there are more workers than CPUs, the CPUs are assigned to them round-robin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cpus` is empty.
+    pub fn cpu_set(mut self, cpus: Vec<usize>) -> Scheduler {
+        assert!(!cpus.is_empty(), "CPU set must not be empty");
+        self.cpu_set = Some(cpus);
+        self
+    }
+
+    // Returns the CPU the worker `tid` is pinned to, if any
+    fn processor_cpu(&self, tid: usize) -> Option<usize> {
+        if !self.pin_processors {
+            return None;
+        }
+
+        match self.cpu_set {
+            Some(ref cpus) => Some(cpus[tid % cpus.len()]),
+            None => Some(tid % affinity::cpu_count()),
+        }
+    }
+
+    /// Set the maximum number of threads used by `spawn_blocking()`
+    ///
+    /// The threads are spawned on demand and are separate from the workers. The default is 128.
+    pub fn max_blocking_threads(mut self, threads: usize) -> Scheduler {
+        self.blocking_pool = BlockingPool::new(threads);
+        self
+    }
+
+    /// Set the maximum number of stacks each worker keeps for reuse
+    ///
+    /// The stacks of finished coroutines are kept and handed to new coroutines with the same
+    /// stack size, which saves the cost of mapping a fresh one. Reused stacks are zeroed first.
+    /// By default the pool is only bounded by the stack memory limit.
+    pub fn stack_pool_capacity(mut self, capacity: usize) -> Scheduler {
+        self.stack_pool_capacity = Some(capacity);
+        self
+    }
+
+    /// Release the memory of stacks while they are kept in the pool
+    ///
+    /// The pages of pooled stacks are handed back to the kernel using `madvise(MADV_DONTNEED)`,
+    /// which keeps their address space mapped for cheap reuse, but lowers the resident memory
+    /// after a burst of coroutines. Without it the pages are released only once a stack is
+    /// reused, which is how it is zeroed on Linux. Only has an effect on Linux.
+    /// Disabled by default.
+    pub fn stack_pool_release_pages(mut self, enabled: bool) -> Scheduler {
+        self.stack_pool_release_pages = enabled;
+        self
+    }
+
+    /// Preempt coroutines which have been running for longer than `interval`
+    ///
+    /// Coroutines can't be interrupted at arbitrary points. Instead the event loop marks those
+    /// which ran through a whole interval without yielding, and they yield at their next call to
+    /// `coio::preempt_point()`, which compute-heavy loops should call regularly. The worker then
+    /// serves foreign coroutines, e.g. ones woken by I/O, before resuming the preempted one.
+    /// Disabled by default.
+    pub fn preempt_interval(mut self, interval: Duration) -> Scheduler {
+        self.preempt_interval = Some(interval);
+        self
+    }
+
     /// Set the default stack size
     pub fn default_stack_size(mut self, default_stack_size: usize) -> Scheduler {
         self.default_spawn_options.stack_size(default_stack_size);
         self
     }
 
+    /// Returns the number of worker threads
+    #[inline]
+    pub fn worker_count(&self) -> usize {
+        self.expected_worker_count
+    }
+
     #[inline]
     pub fn work_count(&self) -> usize {
         ::global_work_count_get()
     }
 
+    /// Returns a snapshot of the runtime statistics
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            io_events: self.io_event_count.load(Ordering::Relaxed),
+            timer_events: self.timer_event_count.load(Ordering::Relaxed),
+            notify_events: self.notify_event_count.load(Ordering::Relaxed),
+            global_queue_size: self.global_queue_size(),
+            idle_processor_count: self.idle_processor_count(),
+            spinning_processor_count: self.spinning_processor_count(),
+            spawned_coroutines: self.spawned_count(),
+            local_queue_sizes: self.local_queue_sizes(),
+
+            #[cfg(feature = "stack-watermark")]
+            peak_stack_usage: self.peak_stack_usage.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(feature = "stack-watermark")]
+    #[doc(hidden)]
+    pub fn record_stack_usage(&self, used: usize) {
+        let mut peak = self.peak_stack_usage.load(Ordering::Relaxed);
+
+        while used > peak {
+            match self.peak_stack_usage.compare_exchange_weak(peak,
+                                                               used,
+                                                               Ordering::Relaxed,
+                                                               Ordering::Relaxed) {
+                Ok(..) => break,
+                Err(current) => peak = current,
+            }
+        }
+    }
+
     /// Run the scheduler
+    ///
+    /// Returns the result of `f`, or the panic it has been aborted with. If the event loop
+    /// fails irrecoverably all coroutines are shut down and the `io::Error` is returned
+    /// as the error payload instead.
     pub fn run<F, T>(&mut self, f: F) -> thread::Result<T>
         where F: FnOnce() -> T + Send + 'static,
               T: Send + 'static
@@ -269,18 +850,32 @@ impl Scheduler {
         panic::set_hook(Box::new(move |panic_info| {
             if let Some(mut p) = Processor::current() {
                 if let Some(coro) = p.current() {
-                    let mut stderr = io::stderr();
-                    let name = match coro.name() {
-                        Some(name) => name,
-                        None => "<unnamed>",
+                    {
+                        let mut stderr = io::stderr();
+                        let name = match coro.name() {
+                            Some(name) => name,
+                            None => "<unnamed>",
+                        };
+                        let _ = write!(stderr, "Coroutine `{}` running in ", name);
+                    }
+
+                    // Picked up by the spawn wrapper for JoinHandle::join_report()
+                    let captured = CapturedPanic {
+                        payload: join_handle::payload_address(panic_info.payload()),
+                        name: coro.name().map(str::to_owned),
+                        location: panic_info.location()
+                                            .map(|l| format!("{}:{}", l.file(), l.line())),
+                        backtrace: capture_backtrace(),
                     };
-                    let _ = write!(stderr, "Coroutine `{}` running in ", name);
+                    coro.set_captured_panic(captured);
                 }
             }
 
             default_handler(panic_info);
         }));
 
+        stack_overflow::init();
+
         trace!("creating EventLoop");
 
         let mut event_loop = EventLoop::new().unwrap();
@@ -314,9 +909,20 @@ impl Scheduler {
         {
             let barrier = Arc::new(Barrier::new(self.expected_worker_count + 1));
             let mem = self.maximum_stack_memory_limit;
+            let queue_size = self.local_queue_size;
+            let pool_capacity = self.stack_pool_capacity;
+            let release_pages = self.stack_pool_release_pages;
 
             for tid in 0..self.expected_worker_count {
-                machines.push(Processor::spawn(self, tid, barrier.clone(), mem));
+                let cpu = self.processor_cpu(tid);
+                machines.push(Processor::spawn(self,
+                                               tid,
+                                               barrier.clone(),
+                                               mem,
+                                               queue_size,
+                                               pool_capacity,
+                                               release_pages,
+                                               cpu));
             }
 
             // After this Barrier unblocks we know that all Processors a fully spawned and
@@ -327,10 +933,69 @@ impl Scheduler {
 
         trace!("running EventLoop");
 
+        let mut fatal_error = None;
+
+        let preempt_ms = self.preempt_interval.map(|interval| {
+            cmp::max(1, interval.as_secs() * 1_000 + (interval.subsec_nanos() / 1_000_000) as u64)
+        });
+        let mut preempt_check = Instant::now();
+        let mut resume_counts = vec![0; machines.len()];
+
         while event_loop.is_running() {
             thread::sleep(::std::time::Duration::new(0, 500_000));
-            event_loop.run_once(self, None).unwrap();
+
+            // While draining, finished coroutines don't produce any event which would wake
+            // up the event loop, so we have to poll for the drain to complete.
+            // The same goes for the preemption checks.
+            let timeout = if self.drain_deadline.is_some() {
+                Some(10)
+            } else {
+                preempt_ms.map(|ms| ms as usize)
+            };
+
+            match event_loop.run_once(self, timeout) {
+                Ok(..) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                    trace!("EventLoop interrupted => retrying");
+                }
+                Err(err) => {
+                    error!("EventLoop failed => shutting down: {}", err);
+                    fatal_error = Some(err);
+                    break;
+                }
+            }
+
             self.append_io_handler_to_global_queue();
+
+            if let Some(interval) = self.preempt_interval {
+                if preempt_check.elapsed() >= interval {
+                    preempt_check = Instant::now();
+
+                    // A Processor which hasn't resumed anything since the last check is either
+                    // idle, in which case the request is reset by the next resume(), or it is
+                    // still running the same coroutine.
+                    for (m, count) in machines.iter().zip(resume_counts.iter_mut()) {
+                        let current = m.processor.resume_count();
+
+                        if current == *count {
+                            m.processor.request_preemption();
+                        }
+
+                        *count = current;
+                    }
+                }
+            }
+
+            if let Some(deadline) = self.drain_deadline {
+                if self.main_finished && self.live_coroutine_count() == 0 {
+                    trace!("EventLoop drained => shutting down");
+                    event_loop.shutdown();
+                } else if Instant::now() >= deadline {
+                    warn!("EventLoop drain timed out with {} coroutines left => shutting down",
+                          self.live_coroutine_count());
+                    event_loop.shutdown();
+                }
+            }
         }
 
         trace!("EventLoop finished => sending Shutdown");
@@ -353,12 +1018,19 @@ impl Scheduler {
             for m in machines.drain(..) {
                 let _ = m.thread_handle.join();
             }
+
+            // Blocking jobs might still be running, but they'll fail to ready their coroutines
+            self.blocking_pool.shutdown();
         }
 
         // Restore panic handler
         trace!("restoring default panic hook");
         panic::take_hook();
 
+        if let Some(err) = fatal_error {
+            return Err(Box::new(err));
+        }
+
         result.unwrap()
     }
 
@@ -372,6 +1044,16 @@ impl Scheduler {
         Self::instance().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Scheduler missing"))
     }
 
+    /// Run `f` with a `Scope` for spawning coroutines which may borrow from the current stack
+    ///
+    /// All coroutines spawned using the `Scope` have finished when this returns. If one of them
+    /// panicked and its handle hasn't been joined, the panic is propagated.
+    pub fn scope<'env, F, R>(f: F) -> R
+        where F: FnOnce(&Scope<'env>) -> R
+    {
+        scope::scope(f)
+    }
+
     /// Spawn a new coroutine with default options
     pub fn spawn<F, T>(f: F) -> JoinHandle<T>
         where F: FnOnce() -> T + Send + 'static,
@@ -382,21 +1064,154 @@ impl Scheduler {
     }
 
     /// Spawn a new coroutine with options
+    ///
+    /// If the Scheduler is draining after a call to `shutdown_graceful()` the coroutine
+    /// is not spawned at all and joining the returned handle yields an error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack of the coroutine couldn't be allocated, see `coio::Builder::spawn()`
+    /// for a fallible alternative.
     pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
         where F: FnOnce() -> T + Send + 'static,
               T: Send + 'static
+    {
+        match Scheduler::try_spawn_opts(f, opts) {
+            Ok(handle) => handle,
+            Err(err) => panic!("failed to spawn coroutine: {}", err),
+        }
+    }
+
+    /// Like `spawn_opts()`, but returns an error if the coroutine couldn't be created
+    #[doc(hidden)]
+    pub fn try_spawn_opts<F, T>(f: F, opts: Options) -> io::Result<JoinHandle<T>>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        Scheduler::spawn_impl(f, opts, true)
+    }
+
+    // Internal helper coroutines, which coroutines still running during `shutdown_graceful()`
+    // depend on, are spawned with `refuse_draining` set to false.
+    fn spawn_impl<F, T>(f: F, opts: Options, refuse_draining: bool) -> io::Result<JoinHandle<T>>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
     {
         let (tx, rx) = join_handle::handle_pair();
+        let cancel_token = CancelToken::new();
+        let coro_cancel_token = cancel_token.clone();
+
+        let mut processor = Processor::current().expect("Processor required for spawn");
+
+        if refuse_draining && processor.scheduler().is_draining() {
+            trace!("Scheduler is draining => refusing to spawn");
+            let _ = tx.push(Err(Box::new("Scheduler is shutting down")));
+
+            return Ok(JoinHandle {
+                result: rx,
+                cancel_token: cancel_token,
+            });
+        }
+
         let wrapper = move || {
+            if let Some(mut p) = Processor::current() {
+                if let Some(coro) = p.current() {
+                    coro.set_cancel_token(coro_cancel_token);
+                }
+            }
+
             let ret = panic::catch_unwind(panic::AssertUnwindSafe(f));
 
+            let captured = Processor::current().and_then(|mut p| {
+                p.current().and_then(|coro| coro.take_captured_panic())
+            });
+
+            // A panic which the coroutine caught itself may have been captured as well,
+            // while the one it finally died of might not have passed the hook at all.
+            if let (&Err(ref payload), Some(captured)) = (&ret, captured) {
+                if captured.payload == join_handle::payload_address(&**payload) {
+                    tx.capture_panic(captured);
+                }
+            }
+
             // No matter whether it is panicked or not, the result will be sent to the channel
             let _ = tx.push(ret);
+
+            if let Some(p) = Processor::current() {
+                p.scheduler().finished_count.fetch_add(1, Ordering::SeqCst);
+            }
         };
-        let mut processor = Processor::current().expect("Processor required for spawn");
-        processor.spawn_opts(wrapper, opts);
 
-        JoinHandle { result: rx }
+        // Counted before the coroutine becomes runnable, since it might finish right away
+        // and `live_coroutine_count()` would underflow if it was counted afterwards.
+        processor.scheduler().spawned_count.fetch_add(1, Ordering::SeqCst);
+
+        if let Err(err) = processor.spawn_opts(wrapper, opts) {
+            processor.scheduler().spawned_count.fetch_sub(1, Ordering::SeqCst);
+            return Err(err);
+        }
+
+        Ok(JoinHandle {
+            result: rx,
+            cancel_token: cancel_token,
+        })
+    }
+
+    /// Run a blocking function on a dedicated thread pool
+    ///
+    /// The workers are not blocked by `f`, so that all other coroutines continue to run. The
+    /// result is delivered through the returned handle. The size of the pool is configured with
+    /// `max_blocking_threads()` and once all threads are busy, calls are queued.
+    ///
+    /// Unlike `spawn_opts()` this keeps working while the Scheduler is draining.
+    pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let scheduler = Scheduler::instance().expect("Processor required for spawn_blocking");
+        let channel = scheduler.event_loop_sender.clone().unwrap();
+        let opts = scheduler.default_spawn_options.clone();
+
+        // NOTE:
+        //   The result is handed over by a coroutine on a real Processor, since readying the
+        //   joining coroutine directly from the blocking thread would resume it on that thread.
+        //   The blocking thread thus only readies this coroutine through the event loop.
+        //   It is spawned even while draining, since fs and dns calls of the coroutines which
+        //   are allowed to finish end up here.
+        let helper = move || {
+            let waiter = Arc::new(Waiter::new());
+            let result = Arc::new(Spinlock::new(None));
+
+            {
+                let waiter = waiter.clone();
+                let result = result.clone();
+
+                Scheduler::park_with(move |p, coro| {
+                    let job_waiter = waiter.clone();
+
+                    p.scheduler().blocking_pool.execute(Box::new(move || {
+                        let ret = panic::catch_unwind(panic::AssertUnwindSafe(f));
+                        *result.lock() = Some(ret);
+
+                        job_waiter.wake(0, |coro| send_ready(&channel, coro));
+                    }));
+
+                    if let Some(coro) = waiter.arm(coro) {
+                        p.ready(coro);
+                    }
+                });
+            }
+
+            match result.lock().take().expect("blocking job finished without a result") {
+                Ok(ret) => ret,
+                Err(err) => panic::resume_unwind(err),
+            }
+        };
+
+        match Scheduler::spawn_impl(helper, opts, false) {
+            Ok(handle) => handle,
+            Err(err) => panic!("failed to spawn coroutine: {}", err),
+        }
     }
 
     /// Suspend the current coroutine or thread
@@ -416,6 +1231,50 @@ impl Scheduler {
         Processor::current().map(|x| x.park_with(f)).unwrap()
     }
 
+    /// Block the current coroutine until it's woken up by `f` or `delay` has elapsed
+    ///
+    /// `f` is called with the `Waiter` of the parked coroutine and has to arrange for
+    /// `Waiter::wake()` to be called with `PARK_WOKEN` as the source, e.g. by storing it in a
+    /// wait list. Returns `false` if the timer fired first and `true` otherwise.
+    ///
+    /// Whichever source loses the race is ignored by the `Waiter`, so that the coroutine is
+    /// readied exactly once, and a timer which lost is removed before returning. The coroutine
+    /// is woken up early if it's cancelled, in which case `true` is returned as well.
+    pub fn park_with_timeout<F>(delay: Duration, f: F) -> bool
+        where F: FnOnce(&mut Processor, Arc<Waiter>)
+    {
+        let mut p = Processor::current().expect("cannot park without processor");
+        let cancel_token = p.current().and_then(|coro| coro.cancel_token().cloned());
+
+        let delay = delay.as_secs() * 1_000 + delay.subsec_nanos() as u64 / 1_000_000;
+        let waiter = Arc::new(Waiter::new());
+        let mut timer = None;
+
+        p.park_with(|p, coro| {
+            if let Some(ref cancel_token) = cancel_token {
+                cancel_token.park_or_fire(&waiter);
+            }
+
+            timer = Some(p.add_timer(delay, waiter.clone()));
+            f(p, waiter.clone());
+
+            if let Some(coro) = waiter.arm(coro) {
+                p.ready(coro);
+            }
+        });
+
+        if let Some(ref cancel_token) = cancel_token {
+            cancel_token.unpark();
+        }
+
+        if waiter.fired() == Some(TIMER_EXPIRED) {
+            return false;
+        }
+
+        cancel_timer(timer);
+        true
+    }
+
     /// A coroutine is ready for schedule
     #[doc(hidden)]
     pub fn ready(mut coro: Handle) {
@@ -437,9 +1296,23 @@ impl Scheduler {
     pub fn register<E>(&self, fd: &E, interest: EventSet) -> io::Result<(Token, ReadyStates)>
         where E: Evented + Debug
     {
-        trace!("Scheduler: requesting register of {:?} for {:?}",
+        self.register_with_mode(fd, interest, ReadyMode::Single)
+    }
+
+    /// Block the current coroutine and wait for I/O event,
+    /// distributing the readiness events according to `mode`
+    #[doc(hidden)]
+    pub fn register_with_mode<E>(&self,
+                                 fd: &E,
+                                 interest: EventSet,
+                                 mode: ReadyMode)
+                                 -> io::Result<(Token, ReadyStates)>
+        where E: Evented + Debug
+    {
+        trace!("Scheduler: requesting register of {:?} for {:?} ({:?})",
                fd,
-               interest);
+               interest,
+               mode);
 
         let mut ret = Err(io::Error::from_raw_os_error(0));
 
@@ -463,7 +1336,7 @@ impl Scheduler {
 
             Scheduler::park_with(|_, coro| {
                 let channel = self.event_loop_sender.as_ref().unwrap();
-                let mut msg = Message::Register(RegisterMessage::new(coro, cb));
+                let mut msg = Message::Register(RegisterMessage::new(coro, cb, mode));
 
                 while let Err(NotifyError::Full(m)) = channel.send(msg) {
                     msg = m;
@@ -474,24 +1347,30 @@ impl Scheduler {
         ret
     }
 
+    /// Deregisters `fd`, which has been registered under `token` and `ready_states`
+    ///
+    /// The callback is only invoked by the event loop if `token` still belongs to `ready_states`.
+    /// Otherwise the token has already been deregistered (and might even be in use by another
+    /// registration), which is reported as an error instead of removing a foreign registration.
     #[doc(hidden)]
-    pub fn deregister<E>(&self, fd: &E, token: Token) -> io::Result<()>
+    pub fn deregister<E>(&self, fd: &E, token: Token, ready_states: &ReadyStates) -> io::Result<()>
         where E: Evented + Debug
     {
         trace!("Scheduler: requesting deregister of {:?}", fd);
 
-        let mut ret = Ok(());
+        let mut ret = None;
 
         {
             let mut cb = |evloop: &mut EventLoop<Scheduler>| {
                 trace!("Scheduler: deregister of {:?}", fd);
-                ret = evloop.deregister(fd);
+                ret = Some(evloop.deregister(fd));
             };
             let cb = &mut cb as DeregisterCallback;
 
             Scheduler::park_with(|_, coro| {
                 let channel = self.event_loop_sender.as_ref().unwrap();
-                let mut msg = Message::Deregister(DeregisterMessage::new(coro, cb, token));
+                let msg = DeregisterMessage::new(coro, cb, token, ready_states.clone());
+                let mut msg = Message::Deregister(msg);
 
                 loop {
                     match channel.send(msg) {
@@ -502,31 +1381,56 @@ impl Scheduler {
             });
         }
 
-        ret
+        ret.unwrap_or_else(|| {
+            let msg = format!("{:?} of {:?} is not registered (double deregistration?)",
+                              token,
+                              fd);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        })
     }
 
     /// Block the current coroutine until the specific time
+    ///
+    /// Returns early if the coroutine is cancelled. The timer is armed on the current
+    /// Processor, which makes this call cheap enough for many thousands of sleeping coroutines.
+    /// It never fails, the `TimerError` is kept for compatibility only.
     #[doc(hidden)]
     pub fn sleep_ms(&self, delay: u64) -> Result<(), TimerError> {
         trace!("Scheduler: requesting sleep for {}ms", delay);
 
-        let mut ret = Ok(());
+        let mut p = Processor::current().expect("cannot sleep without processor");
+        let cancel_token = p.current().and_then(|coro| coro.cancel_token().cloned());
 
-        {
-            Scheduler::park_with(|_, coro| {
-                let channel = self.event_loop_sender.as_ref().unwrap();
-                let mut msg = Message::Timer(TimerMessage::new(coro, delay, &mut ret));
+        if cancel_token.as_ref().map_or(false, |t| t.is_cancelled()) {
+            cancel::unwind_if_aborted();
+            return Ok(());
+        }
 
-                loop {
-                    match channel.send(msg) {
-                        Err(NotifyError::Full(m)) => msg = m,
-                        _ => break,
-                    }
-                }
-            });
+        let waiter = Arc::new(Waiter::new());
+        let mut timer = None;
+
+        p.park_with(|p, coro| {
+            if let Some(ref cancel_token) = cancel_token {
+                cancel_token.park_or_fire(&waiter);
+            }
+
+            timer = Some(p.add_timer(delay, waiter.clone()));
+
+            if let Some(coro) = waiter.arm(coro) {
+                p.ready(coro);
+            }
+        });
+
+        if let Some(ref cancel_token) = cancel_token {
+            cancel_token.unpark();
         }
 
-        ret
+        if waiter.fired() == Some(cancel::CANCEL_SOURCE) {
+            cancel_timer(timer);
+            cancel::unwind_if_aborted();
+        }
+
+        Ok(())
     }
 
     /// Block the current coroutine until the specific time
@@ -535,13 +1439,27 @@ impl Scheduler {
         self.sleep_ms(delay.as_secs() * 1_000 + delay.subsec_nanos() as u64 / 1_000_000)
     }
 
+    /// Block the current coroutine until the `deadline` is reached, see `coio::sleep_until()`
+    #[doc(hidden)]
+    pub fn sleep_until(&self, deadline: Instant) -> Result<(), TimerError> {
+        let now = Instant::now();
+
+        if deadline <= now {
+            return Ok(());
+        }
+
+        // Round up, so that we never wake up before the deadline
+        let delay = deadline.duration_since(now);
+        self.sleep_ms(delay.as_secs() * 1_000 + (delay.subsec_nanos() as u64 + 999_999) / 1_000_000)
+    }
+
     #[doc(hidden)]
     pub fn get_machines(&'static self) -> &mut [Machine] {
         unsafe { &mut *self.machines.get() }
     }
 
     #[doc(hidden)]
-    pub fn get_global_queue(&self) -> MutexGuard<HandleList> {
+    pub fn get_global_queue(&self) -> MutexGuard<GlobalQueue> {
         self.global_queue.lock().unwrap()
     }
 
@@ -564,7 +1482,11 @@ impl Scheduler {
     {
         let size = {
             let mut queue = self.get_global_queue();
-            queue.extend(iter);
+
+            for hdl in iter {
+                queue.push_back(hdl);
+            }
+
             let size = queue.len();
             self.set_global_queue_size(size);
             size
@@ -578,7 +1500,11 @@ impl Scheduler {
         if !self.io_handler_queue.is_empty() {
             let size = {
                 let mut queue = self.global_queue.lock().unwrap();
-                queue.append(&mut self.io_handler_queue);
+
+                while let Some(hdl) = self.io_handler_queue.pop_front() {
+                    queue.push_back(hdl);
+                }
+
                 let size = queue.len();
                 self.set_global_queue_size(size);
                 size
@@ -588,12 +1514,37 @@ impl Scheduler {
         }
     }
 
-    #[doc(hidden)]
+    /// Returns the number of coroutines waiting in the global queue
     #[inline]
     pub fn global_queue_size(&self) -> usize {
         self.global_queue_size.load(Ordering::Relaxed)
     }
 
+    /// Returns the number of workers which are parked, because they ran out of work
+    #[inline]
+    pub fn idle_processor_count(&self) -> usize {
+        self.idle_processor_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of workers which are currently trying to steal work
+    #[inline]
+    pub fn spinning_processor_count(&self) -> usize {
+        self.spinning_processor_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of coroutines spawned since the Scheduler has been started
+    #[inline]
+    pub fn spawned_count(&self) -> usize {
+        self.spawned_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of coroutines in the local queue of each worker
+    pub fn local_queue_sizes(&self) -> Vec<usize> {
+        // The vector of Machines is constant while the Scheduler is running (see `machines`)
+        let machines = unsafe { &*self.machines.get() };
+        machines.iter().map(|m| m.processor.queue_len()).collect()
+    }
+
     #[doc(hidden)]
     #[inline]
     pub fn set_global_queue_size(&self, size: usize) {
@@ -603,24 +1554,46 @@ impl Scheduler {
     #[doc(hidden)]
     #[inline]
     pub fn inc_spinning(&self) {
-        self.spinning_processor_count.fetch_add(1, Ordering::Relaxed);
+        self.spinning_processor_count.fetch_add(1, Ordering::SeqCst);
     }
 
     #[doc(hidden)]
     #[inline]
     pub fn dec_spinning(&self) {
-        self.spinning_processor_count.fetch_sub(1, Ordering::Relaxed);
+        self.spinning_processor_count.fetch_sub(1, Ordering::SeqCst);
     }
 
     #[doc(hidden)]
-    pub fn park_processor<F: FnOnce() -> bool>(&self, before_wait: F) {
-        self.idle_processor_count.fetch_add(1, Ordering::Relaxed);
+    #[inline]
+    pub fn count_timer_events(&self, count: usize) {
+        self.timer_event_count.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Parks the calling Processor until new work arrives or `timeout` has elapsed
+    #[doc(hidden)]
+    pub fn park_processor<F: FnOnce() -> bool>(&self, timeout: Option<Duration>, before_wait: F) {
+        // NOTE:
+        //   Together with the fence in unpark_processor_maybe() this forms a Dekker-style
+        //   handshake: Either the producer observes our increment and wakes us up, or
+        //   the recheck in before_wait() observes the work the producer has pushed.
+        //   With weaker orderings both sides might miss the other one's write,
+        //   leaving the work in the queue while all Processors are idle.
+        self.idle_processor_count.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
 
         {
             let idle_processor_mutex = self.idle_processor_mutex.lock().unwrap();
 
             if !*idle_processor_mutex && before_wait() {
-                let _ = self.idle_processor_condvar.wait(idle_processor_mutex);
+                match timeout {
+                    Some(timeout) => {
+                        let _ = self.idle_processor_condvar
+                                    .wait_timeout(idle_processor_mutex, timeout);
+                    }
+                    None => {
+                        let _ = self.idle_processor_condvar.wait(idle_processor_mutex);
+                    }
+                }
             }
         }
 
@@ -629,15 +1602,21 @@ impl Scheduler {
 
     #[doc(hidden)]
     pub fn unpark_processors_with_queue_size(&self, size: usize) {
-        self.unpark_processor_maybe(size / (processor::QUEUE_SIZE / 2) + 1);
+        self.unpark_processor_maybe(size / (self.local_queue_size / 2) + 1);
     }
 
     #[doc(hidden)]
     pub fn unpark_processor_maybe(&self, max: usize) {
-        let idle_processor_count = self.idle_processor_count.load(Ordering::Relaxed);
+        // Orders the preceding push into a queue before the check for idle Processors.
+        // See park_processor() for the counterpart.
+        atomic::fence(Ordering::SeqCst);
+
+        let idle_processor_count = self.idle_processor_count.load(Ordering::SeqCst);
 
+        // A spinning Processor is guaranteed to find the work: It either still has to look
+        // into the queues, or it will recheck them in park_processor() before it sleeps.
         if max > 0 && idle_processor_count > 0 &&
-           self.spinning_processor_count.load(Ordering::Relaxed) == 0 {
+           self.spinning_processor_count.load(Ordering::SeqCst) == 0 {
             let cnt = if idle_processor_count < max {
                 idle_processor_count
             } else {
@@ -651,6 +1630,43 @@ impl Scheduler {
         }
     }
 
+    /// Shut down the Scheduler after all running coroutines have finished
+    ///
+    /// Returns immediately. From now on no new coroutines are spawned (see `spawn_opts()`),
+    /// while all coroutines which have been spawned before are allowed to run to completion.
+    /// As soon as they and the main coroutine have finished, the event loop is torn down and
+    /// `run()` returns. If that hasn't happened before `timeout` elapses, the remaining
+    /// coroutines are forcefully unwound, just like during a regular shutdown.
+    pub fn shutdown_graceful(&self, timeout: Duration) {
+        trace!("Scheduler: requesting graceful shutdown within {:?}", timeout);
+
+        self.is_draining.store(true, Ordering::SeqCst);
+
+        let channel = self.event_loop_sender.as_ref().unwrap();
+        let mut msg = Message::Drain(Instant::now() + timeout);
+
+        loop {
+            match channel.send(msg) {
+                Err(NotifyError::Full(m)) => msg = m,
+                _ => break,
+            }
+        }
+    }
+
+    /// Returns true if `shutdown_graceful()` has been called
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.is_draining.load(Ordering::SeqCst)
+    }
+
+    // NOTE:
+    //   `finished_count` is loaded first, so that the subtraction never underflows.
+    //   The main coroutine is not counted, since it's not spawned using spawn_opts().
+    fn live_coroutine_count(&self) -> usize {
+        let finished = self.finished_count.load(Ordering::SeqCst);
+        self.spawned_count.load(Ordering::SeqCst) - finished
+    }
+
     #[doc(hidden)]
     pub fn is_shutting_down(&self) -> bool {
         self.is_shutting_down.load(Ordering::Relaxed)
@@ -665,28 +1681,17 @@ impl Handler for Scheduler {
 
     fn ready(&mut self, _event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
         trace!("Handler: got {:?} for {:?}", events, token);
+        self.io_event_count.fetch_add(1, Ordering::Relaxed);
 
         let ready_states = self.slab.get(token.as_usize()).expect("Token must be registered");
-        let mut handles: [Handle; 4] = unsafe { mem::uninitialized() };
-        let handle_count = ready_states.notify(events, &mut handles);
-
-        for hdl in &handles[..handle_count] {
-            trace!("Handler: got {:?}", hdl);
-            self.io_handler_queue.push_back(unsafe { mem::transmute_copy(hdl) });
-        }
-
-        mem::forget(handles);
-    }
-
-    fn timeout(&mut self, _event_loop: &mut EventLoop<Self>, token: Token) {
-        let coro = unsafe { Handle::from_raw(mem::transmute(token)) };
-        trace!("Handler: timout for {:?}", coro);
-        self.io_handler_queue.push_back(coro);
+        ready_states.notify(events, &mut self.io_handler_queue);
     }
 
     fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Self::Message) {
+        self.notify_event_count.fetch_add(1, Ordering::Relaxed);
+
         match msg {
-            Message::Register(RegisterMessage { cb, coro }) => {
+            Message::Register(RegisterMessage { cb, coro, mode }) => {
                 trace!("Handler: registering for {:?}", coro);
 
                 if self.slab.remaining() == 0 {
@@ -697,7 +1702,7 @@ impl Handler for Scheduler {
 
                 self.slab.insert_with_opt(move |token| {
                     let token = unsafe { mem::transmute(token) };
-                    let ready_states = ReadyStates::new();
+                    let ready_states = ReadyStates::new(mode);
 
                     if (cb)(event_loop, token, ready_states.clone()) {
                         Some(ready_states)
@@ -712,28 +1717,42 @@ impl Handler for Scheduler {
             Message::Deregister(msg) => {
                 trace!("Handler: deregistering for {:?}", msg.coro);
 
-                let _ = self.slab.remove(unsafe { mem::transmute(msg.token) });
-
-                (msg.cb)(event_loop);
+                // NOTE:
+                //   Tokens are reused as soon as they are removed from the slab. A token which
+                //   is deregistered twice might thus belong to another registration by now,
+                //   which is why the ReadyStates are compared and not just the token.
+                let owned = self.slab
+                                .get(msg.token.as_usize())
+                                .map_or(false, |ready_states| ready_states.same(&msg.ready_states));
+
+                if owned {
+                    let _ = self.slab.remove(unsafe { mem::transmute(msg.token) });
+                    (msg.cb)(event_loop);
+                } else {
+                    error!("Handler: {:?} is not registered for {:?} (double deregistration?)",
+                           msg.token,
+                           msg.coro);
+                }
 
                 trace!("Handler: deregistering finished for {:?}", msg.coro);
                 self.io_handler_queue.push_back(msg.coro);
             }
-            Message::Timer(msg) => {
-                trace!("Handler: adding timer for {:?}", msg.coro);
-
-                let coro_ptr = Handle::into_raw(msg.coro);
-                let token = unsafe { mem::transmute(coro_ptr) };
-                let result = unsafe { &mut *msg.result };
-
-                if let Err(err) = event_loop.timeout_ms(token, msg.delay) {
-                    *result = Err(err);
-                    self.io_handler_queue.push_back(unsafe { Handle::from_raw(coro_ptr) });
-                }
+            Message::Ready(coro) => {
+                trace!("Handler: readying {:?}", coro);
+                self.io_handler_queue.push_back(coro);
+            }
+            Message::Drain(deadline) => {
+                trace!("Handler: draining");
+                self.drain_deadline = Some(deadline);
             }
             Message::Shutdown => {
-                trace!("Handler: shutting down");
-                event_loop.shutdown();
+                self.main_finished = true;
+
+                // While draining, the run loop shuts down as soon as all coroutines finished
+                if self.drain_deadline.is_none() {
+                    trace!("Handler: shutting down");
+                    event_loop.shutdown();
+                }
             }
         }
     }
@@ -741,6 +1760,13 @@ impl Handler for Scheduler {
 
 #[cfg(test)]
 mod test {
+    use std::panic;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use runtime::waiter::Waiter;
     use super::*;
 
     #[test]
@@ -753,4 +1779,282 @@ mod test {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_try_join() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let mut handles = (0..10).map(|i| Scheduler::spawn(move || i)).collect::<Vec<_>>();
+                let mut results = Vec::new();
+
+                // Supervisor loop reaping the finished coroutines
+                while !handles.is_empty() {
+                    for h in handles.iter_mut() {
+                        if let Some(r) = h.try_join() {
+                            results.push(r.unwrap());
+                        }
+                    }
+
+                    handles.retain(|h| !h.is_finished());
+                    Scheduler::sched();
+                }
+
+                results.sort();
+                assert_eq!(results, (0..10).collect::<Vec<_>>());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stats_queues() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let before = Scheduler::instance().unwrap().stats().spawned_coroutines;
+
+                let handles = (0..10).map(|_| Scheduler::spawn(|| {})).collect::<Vec<_>>();
+
+                let stats = Scheduler::instance().unwrap().stats();
+                assert_eq!(stats.spawned_coroutines, before + 10);
+                assert_eq!(stats.local_queue_sizes.len(), 2);
+
+                for h in handles {
+                    h.join().unwrap();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stats_timer_events() {
+        Scheduler::new()
+            .run(|| {
+                let scheduler = Scheduler::instance().unwrap();
+                let before = scheduler.stats();
+
+                scheduler.sleep_ms(1).unwrap();
+
+                let after = scheduler.stats();
+                assert!(after.timer_events > before.timer_events);
+
+                // Timers are armed on the Processor without involving the event loop
+                assert_eq!(after.notify_events, before.notify_events);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sleep_many() {
+        Scheduler::new()
+            .with_workers(2)
+            .default_stack_size(32 * 1024)
+            .run(|| {
+                let start = Instant::now();
+
+                let handles = (0..10_000)
+                                  .map(|i| {
+                                      Scheduler::spawn(move || {
+                                          let delay = 10 + i % 20;
+                                          let started = Instant::now();
+                                          Scheduler::instance().unwrap().sleep_ms(delay).unwrap();
+                                          started.elapsed() >= Duration::from_millis(delay)
+                                      })
+                                  })
+                                  .collect::<Vec<_>>();
+
+                for h in handles {
+                    assert!(h.join().unwrap(), "woke up before the timer expired");
+                }
+
+                assert!(start.elapsed() < Duration::from_secs(10));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_graceful() {
+        use std::sync::atomic::ATOMIC_USIZE_INIT;
+
+        static FINISHED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                for _ in 0..10 {
+                    Scheduler::spawn(|| {
+                        Scheduler::instance().unwrap().sleep_ms(50).unwrap();
+                        FINISHED.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+
+                let scheduler = Scheduler::instance().unwrap();
+                scheduler.shutdown_graceful(Duration::from_secs(10));
+                assert!(scheduler.is_draining());
+
+                // No new work is accepted while draining
+                assert!(Scheduler::spawn(|| {}).join().is_err());
+            })
+            .unwrap();
+
+        assert_eq!(FINISHED.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_shutdown_graceful_blocking_io() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                // Coroutines which are allowed to finish may still use blocking I/O
+                let child = Scheduler::spawn(|| {
+                    Scheduler::instance().unwrap().sleep_ms(50).unwrap();
+                    ::fs::metadata(".").unwrap().is_dir()
+                });
+
+                let scheduler = Scheduler::instance().unwrap();
+                scheduler.shutdown_graceful(Duration::from_secs(10));
+
+                assert!(::fs::metadata(".").unwrap().is_dir());
+                assert!(child.join().unwrap());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_graceful_timeout() {
+        let start = Instant::now();
+
+        Scheduler::new()
+            .run(|| {
+                Scheduler::spawn(|| {
+                    loop {
+                        Scheduler::instance().unwrap().sleep_ms(10).unwrap();
+                    }
+                });
+
+                Scheduler::instance().unwrap().shutdown_graceful(Duration::from_millis(100));
+            })
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_blocking() {
+        Scheduler::new()
+            .max_blocking_threads(2)
+            .run(|| {
+                let (tx, rx) = ::std::sync::mpsc::channel();
+
+                let blocking = Scheduler::spawn_blocking(move || {
+                    // Blocks the thread until the coroutine below has been running
+                    rx.recv().unwrap();
+                    thread::current().name().map(|name| name.to_owned())
+                });
+
+                // The single worker is not blocked by the job
+                Scheduler::spawn(move || tx.send(()).unwrap()).join().unwrap();
+
+                assert_eq!(blocking.join().unwrap(), Some("coio-blocking".to_owned()));
+                assert!(Scheduler::spawn_blocking(|| panic!("failure")).join().is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_park_with_timeout() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                // Nobody wakes us up
+                let start = Instant::now();
+                assert!(!Scheduler::park_with_timeout(Duration::from_millis(50), |_, _| {}));
+                assert!(start.elapsed() >= Duration::from_millis(40));
+
+                // Woken up by another coroutine before the timer expires
+                let (tx, rx) = ::std::sync::mpsc::channel();
+
+                let waker = Scheduler::spawn(move || {
+                    let waiter: Arc<Waiter> = rx.recv().unwrap();
+                    waiter.wake(PARK_WOKEN, Scheduler::ready);
+                });
+
+                assert!(Scheduler::park_with_timeout(Duration::from_millis(100), move |_, waiter| {
+                    tx.send(waiter).unwrap();
+                }));
+                waker.join().unwrap();
+
+                // The timer which lost the race must not ready us a second time
+                Scheduler::instance().unwrap().sleep_ms(200).unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pin_processors() {
+        let scheduler = Scheduler::new().with_workers(3);
+        assert_eq!(scheduler.processor_cpu(0), None);
+
+        let scheduler = scheduler.pin_processors(true).cpu_set(vec![2, 5]);
+        assert_eq!(scheduler.processor_cpu(0), Some(2));
+        assert_eq!(scheduler.processor_cpu(1), Some(5));
+        assert_eq!(scheduler.processor_cpu(2), Some(2));
+
+        Scheduler::new()
+            .with_workers(2)
+            .pin_processors(true)
+            .run(|| Scheduler::spawn(|| 1).join().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_report() {
+        Scheduler::new()
+            .run(|| {
+                let mut opts = Options::new();
+                opts.name("worker".to_owned());
+
+                let report = Scheduler::spawn_opts(|| panic!("boom"), opts)
+                                 .join_report()
+                                 .unwrap_err();
+
+                assert_eq!(report.name(), Some("worker"));
+                assert_eq!(report.message(), Some("boom"));
+                assert!(report.location().unwrap().contains("scheduler.rs"));
+
+                assert_eq!(Scheduler::spawn(|| 1).join_report().unwrap(), 1);
+
+                // A panic which was caught must not be reported for a later one bypassing the hook
+                let report = Scheduler::spawn(|| {
+                                 assert!(panic::catch_unwind(|| panic!("caught")).is_err());
+                                 panic::resume_unwind(Box::new("resumed"));
+                             })
+                                 .join_report()
+                                 .unwrap_err();
+
+                assert_eq!(report.message(), Some("resumed"));
+                assert_eq!(report.location(), None);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_global_queue_priority() {
+        let spawn = |name: &str, priority| {
+            let mut opts = Options::new();
+            opts.name(name.to_owned()).priority(priority);
+            Coroutine::spawn_opts(Box::new(|| {}), opts)
+        };
+
+        let mut queue = GlobalQueue::new();
+        queue.push_back(spawn("bulk", Priority::Normal));
+        queue.push_back(spawn("control1", Priority::High));
+        queue.push_back(spawn("control2", Priority::High));
+        assert_eq!(queue.len(), 3);
+
+        // Processors drain the priority list first
+        assert_eq!(queue.priority.pop_front().unwrap().name(), Some("control1"));
+        assert_eq!(queue.priority.pop_front().unwrap().name(), Some("control2"));
+        assert_eq!(queue.normal.pop_front().unwrap().name(), Some("bulk"));
+    }
 }
@@ -0,0 +1,209 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Scoped coroutines which may borrow from the spawning stack frame
+
+use std::boxed::FnBox;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use scheduler::Scheduler;
+use sync::WaitGroup;
+use sync::oneshot;
+
+/// Spawns coroutines which are guaranteed to finish before `Scheduler::scope()` returns
+///
+/// Since the spawned coroutines can't outlive the scope, their closures may borrow everything
+/// which outlives it.
+pub struct Scope<'env> {
+    wait_group: Arc<WaitGroup>,
+    // Number of scoped coroutines which panicked and haven't been joined (yet)
+    unjoined_panics: Arc<AtomicUsize>,
+
+    // Invariant over 'env, just like crossbeam's scope
+    _marker: PhantomData<&'env mut &'env ()>,
+}
+
+// Marks a scoped coroutine as finished, even if it has been dropped without ever running
+struct Done(Arc<WaitGroup>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+impl<'env> Scope<'env> {
+    /// Spawns a new coroutine which may borrow data living as long as the scope
+    pub fn spawn<F, T>(&self, f: F) -> ScopedJoinHandle<T>
+        where F: FnOnce() -> T + Send + 'env,
+              T: Send + 'env
+    {
+        let (tx, rx) = oneshot::channel();
+        let unjoined_panics = self.unjoined_panics.clone();
+
+        self.wait_group.add(1);
+        let done = Done(self.wait_group.clone());
+
+        let job: Box<FnBox() + Send + 'env> = Box::new(move || {
+            let ret = panic::catch_unwind(AssertUnwindSafe(f));
+
+            // Counted before it's sent, so that the handle is either joined afterwards and takes
+            // the panic back or the scope re-raises it, even if the handle is dropped.
+            if ret.is_err() {
+                unjoined_panics.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let _ = tx.send(ret);
+            drop(done);
+        });
+
+        // The scope waits for the coroutine to finish, before anything it borrows goes away
+        let job: Box<FnBox() + Send + 'static> = unsafe { mem::transmute(job) };
+        Scheduler::spawn(move || job.call_box(()));
+
+        ScopedJoinHandle {
+            result: rx,
+            unjoined_panics: self.unjoined_panics.clone(),
+        }
+    }
+}
+
+impl<'env> Drop for Scope<'env> {
+    fn drop(&mut self) {
+        // Waits even if the scope's closure panicked, since the coroutines might still borrow
+        // from the stack frames which are being unwound.
+        self.wait_group.wait();
+    }
+}
+
+/// A handle to join a coroutine spawned by `Scope::spawn()`
+pub struct ScopedJoinHandle<T> {
+    result: oneshot::Receiver<thread::Result<T>>,
+    unjoined_panics: Arc<AtomicUsize>,
+}
+
+unsafe impl<T: Send> Send for ScopedJoinHandle<T> {}
+
+impl<T> ScopedJoinHandle<T> {
+    /// Await completion of the coroutine and return it's result.
+    ///
+    /// If the handle is dropped instead, a panic of the coroutine is raised again by
+    /// `Scheduler::scope()`.
+    pub fn join(self) -> thread::Result<T> {
+        match self.result.recv() {
+            Ok(Ok(ret)) => Ok(ret),
+            Ok(Err(err)) => {
+                self.unjoined_panics.fetch_sub(1, Ordering::SeqCst);
+                Err(err)
+            }
+            Err(..) => Err(Box::new("Scheduler is shutting down")),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn scope<'env, F, R>(f: F) -> R
+    where F: FnOnce(&Scope<'env>) -> R
+{
+    let scope = Scope {
+        wait_group: Arc::new(WaitGroup::new()),
+        unjoined_panics: Arc::new(AtomicUsize::new(0)),
+        _marker: PhantomData,
+    };
+
+    let ret = f(&scope);
+    scope.wait_group.wait();
+
+    if scope.unjoined_panics.load(Ordering::SeqCst) > 0 {
+        panic!("a scoped coroutine panicked");
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use scheduler::Scheduler;
+
+    #[test]
+    fn scope_borrows_stack_data() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let mut data = vec![1, 2, 3, 4];
+                let offset = 10;
+
+                let sum = Scheduler::scope(|s| {
+                    let handles = data.chunks_mut(2)
+                                      .map(|chunk| {
+                                          s.spawn(move || {
+                                              for x in chunk.iter_mut() {
+                                                  *x += offset;
+                                              }
+                                              chunk.iter().fold(0, |acc, x| acc + x)
+                                          })
+                                      })
+                                      .collect::<Vec<_>>();
+
+                    handles.into_iter().map(|h| h.join().unwrap()).fold(0, |acc, x| acc + x)
+                });
+
+                assert_eq!(sum, 50);
+                assert_eq!(data, vec![11, 12, 13, 14]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn scope_waits_for_unjoined() {
+        Scheduler::new()
+            .run(|| {
+                let mut finished = false;
+
+                Scheduler::scope(|s| {
+                    let finished = &mut finished;
+
+                    s.spawn(move || {
+                        Scheduler::sched();
+                        *finished = true;
+                    });
+                });
+
+                assert!(finished);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn scope_reraises_panic_of_dropped_handle() {
+        Scheduler::new()
+            .run(|| {
+                // The coroutine has already panicked by the time its handle is dropped
+                let ret = Scheduler::spawn(|| {
+                              Scheduler::scope(|s| {
+                                  let handle = s.spawn(|| panic!("failure"));
+                                  Scheduler::sched();
+                                  drop(handle);
+                              });
+                          })
+                              .join();
+                assert!(ret.is_err());
+
+                // Joined panics are handed to the caller instead
+                Scheduler::scope(|s| {
+                    assert!(s.spawn(|| panic!("failure")).join().is_err());
+                });
+            })
+            .unwrap();
+    }
+}
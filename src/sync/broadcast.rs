@@ -0,0 +1,371 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Multi-producer, multi-consumer channel delivering every message to all receivers
+
+pub use std::sync::mpsc::SendError;
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+use runtime::coroutine::HandleList;
+use runtime::Processor;
+use scheduler::Scheduler;
+
+use super::spinlock::Spinlock;
+
+/// The error returned by `Receiver::recv()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind and the given number of the oldest messages was skipped.
+    /// The next call returns the oldest message which is still buffered.
+    Lagged(u64),
+    /// All senders have been dropped and every message has been received.
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            RecvError::Closed => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for RecvError {
+    fn description(&self) -> &str {
+        match *self {
+            RecvError::Lagged(..) => "receiver lagged behind",
+            RecvError::Closed => "channel closed",
+        }
+    }
+}
+
+/// The error returned by `Receiver::try_recv()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No new message has been sent yet.
+    Empty,
+    /// See `RecvError::Lagged`.
+    Lagged(u64),
+    /// See `RecvError::Closed`.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for TryRecvError {
+    fn description(&self) -> &str {
+        match *self {
+            TryRecvError::Empty => "channel empty",
+            TryRecvError::Lagged(..) => "receiver lagged behind",
+            TryRecvError::Closed => "channel closed",
+        }
+    }
+}
+
+struct State<T> {
+    // Holds the messages with the positions `head..head + buffer.len()`
+    buffer: VecDeque<T>,
+    head: u64,
+    capacity: usize,
+
+    senders: usize,
+    receivers: usize,
+
+    waiters: HandleList,
+}
+
+impl<T> State<T> {
+    #[inline]
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+/// The sending half of a broadcast channel, see `channel()`
+pub struct Sender<T> {
+    shared: Arc<Spinlock<State<T>>>,
+}
+
+unsafe impl<T: Send> Send for Sender<T> {}
+unsafe impl<T: Send> Sync for Sender<T> {}
+
+impl<T> Sender<T> {
+    /// Sends `t` to all current receivers and returns how many there are
+    ///
+    /// This never parks: If the buffer is full the oldest message is dropped, which is reported
+    /// as `RecvError::Lagged` by all receivers who didn't get it yet.
+    /// If there are no receivers `t` is given back.
+    pub fn send(&self, t: T) -> Result<usize, SendError<T>> {
+        let (receivers, dropped, mut waiters) = {
+            let mut state = self.shared.lock();
+
+            if state.receivers == 0 {
+                return Err(SendError(t));
+            }
+
+            state.buffer.push_back(t);
+
+            let dropped = if state.buffer.len() > state.capacity {
+                state.head += 1;
+                state.buffer.pop_front()
+            } else {
+                None
+            };
+
+            (state.receivers, dropped, mem::replace(&mut state.waiters, HandleList::new()))
+        };
+
+        // Drop the overwritten message outside of the lock
+        drop(dropped);
+
+        while let Some(coro) = waiters.pop_front() {
+            Scheduler::ready(coro);
+        }
+
+        Ok(receivers)
+    }
+
+    /// Creates a new `Receiver` which gets all messages sent after this call
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock();
+        state.receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail(),
+        }
+    }
+
+    /// Returns the number of receivers
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.lock().senders += 1;
+
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut waiters = {
+            let mut state = self.shared.lock();
+            state.senders -= 1;
+
+            if state.senders > 0 {
+                return;
+            }
+
+            mem::replace(&mut state.waiters, HandleList::new())
+        };
+
+        // This was the last Sender, so the receivers have to be woken up to observe the close
+        while let Some(coro) = waiters.pop_front() {
+            trace!("{:?} is awaken by dropping broadcast Sender", coro);
+            Scheduler::ready(coro);
+        }
+    }
+}
+
+/// The receiving half of a broadcast channel, see `channel()`
+///
+/// Every `Receiver` keeps its own position in the channel. Cloning it creates a new
+/// `Receiver` at the same position.
+pub struct Receiver<T> {
+    shared: Arc<Spinlock<State<T>>>,
+    next: u64,
+}
+
+unsafe impl<T: Send> Send for Receiver<T> {}
+
+impl<T: Clone> Receiver<T> {
+    /// Returns the next message if there is one
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.lock();
+        self.take(&state)
+    }
+
+    /// Blocks the current coroutine until the next message has been sent
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            let mut state = self.shared.lock();
+
+            match self.take(&state) {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Lagged(n)) => return Err(RecvError::Lagged(n)),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            match Processor::current() {
+                Some(p) => {
+                    p.park_with(|_, coro| {
+                        state.waiters.push_back(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("broadcast::Receiver will not work in thread environment"),
+            }
+        }
+    }
+
+    fn take(&mut self, state: &State<T>) -> Result<T, TryRecvError> {
+        if self.next < state.head {
+            let lagged = state.head - self.next;
+            self.next = state.head;
+            return Err(TryRecvError::Lagged(lagged));
+        }
+
+        if self.next < state.tail() {
+            let t = state.buffer[(self.next - state.head) as usize].clone();
+            self.next += 1;
+            Ok(t)
+        } else if state.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.shared.lock().receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receivers -= 1;
+    }
+}
+
+/// Create a broadcast channel pair
+///
+/// Every message is cloned for every `Receiver`, which makes it a good fit for fanning out
+/// configuration reloads or shutdown requests to many coroutines. Further receivers are created
+/// by `Sender::subscribe()`. Only the last `capacity` messages are kept, so slow receivers
+/// are never able to block the senders, but miss messages instead.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel needs a capacity of at least 1");
+
+    let shared = Arc::new(Spinlock::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity: capacity,
+
+        senders: 1,
+        receivers: 1,
+
+        waiters: HandleList::new(),
+    }));
+
+    let sender = Sender { shared: shared.clone() };
+    let receiver = Receiver {
+        shared: shared,
+        next: 0,
+    };
+
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn broadcast_every_receiver_gets_every_message() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let (tx, rx) = channel(16);
+
+                let handles = (0..10)
+                                  .map(|_| {
+                                      let mut rx = rx.clone();
+                                      Scheduler::spawn(move || {
+                                          let mut received = Vec::new();
+                                          while let Ok(msg) = rx.recv() {
+                                              received.push(msg);
+                                          }
+                                          received
+                                      })
+                                  })
+                                  .collect::<Vec<_>>();
+                drop(rx);
+
+                for i in 0..10 {
+                    assert_eq!(tx.send(i), Ok(10));
+                }
+                drop(tx);
+
+                for h in handles {
+                    assert_eq!(h.join().unwrap(), (0..10).collect::<Vec<_>>());
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn broadcast_lagged_receiver() {
+        let (tx, mut rx) = channel(2);
+
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Lagged(3)));
+        assert_eq!(rx.try_recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Ok(4));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // A new subscriber only sees what is sent afterwards
+        let mut late = tx.subscribe();
+        tx.send(5).unwrap();
+        assert_eq!(late.try_recv(), Ok(5));
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Ok(5));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Closed));
+    }
+
+    #[test]
+    fn broadcast_send_without_receivers() {
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+}
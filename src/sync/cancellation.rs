@@ -0,0 +1,273 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hierarchical cancellation of groups of coroutines
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use runtime::coroutine::Handle;
+use runtime::Processor;
+use runtime::cancel::{CancelToken, WeakCancelToken};
+use scheduler::RemoteReady;
+
+use super::spinlock::Spinlock;
+
+struct State {
+    children: Vec<Weak<Inner>>,
+    waiters: VecDeque<(Handle, RemoteReady)>,
+    // Weak, so that bound coroutines which have finished don't pile up in long-lived tokens
+    coroutines: Vec<WeakCancelToken>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    state: Spinlock<State>,
+}
+
+/// A token which can be used to cancel a whole tree of coroutines at once
+///
+/// Tokens created using `child()` are cancelled together with their parent, but can also be
+/// cancelled on their own, e.g. the token of a single connection below the one of the server.
+/// Coroutines can either poll `is_cancelled()`, park in `wait_cancelled()`, or `bind()` the
+/// token, so that their blocking I/O operations fail with a `Cancelled` error once it fires,
+/// just as if their `JoinHandle` had been cancelled.
+#[derive(Clone)]
+pub struct CancellationToken(Arc<Inner>);
+
+impl CancellationToken {
+    /// Creates a token which has not been cancelled yet
+    pub fn new() -> CancellationToken {
+        CancellationToken(Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            state: Spinlock::new(State {
+                children: Vec::new(),
+                waiters: VecDeque::new(),
+                coroutines: Vec::new(),
+            }),
+        }))
+    }
+
+    /// Creates a token which is cancelled as soon as this one is
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+
+        {
+            let mut state = self.0.state.lock();
+
+            if !self.is_cancelled() {
+                // Children which have been dropped in the meantime are cleaned up on the way
+                state.children.retain(|c| c.upgrade().is_some());
+                state.children.push(Arc::downgrade(&child.0));
+                return child;
+            }
+        }
+
+        child.cancel();
+        child
+    }
+
+    /// Cancels this token and all of its children
+    pub fn cancel(&self) {
+        let state = {
+            let mut state = self.0.state.lock();
+
+            if self.0.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+
+            mem::replace(&mut *state,
+                         State {
+                             children: Vec::new(),
+                             waiters: VecDeque::new(),
+                             coroutines: Vec::new(),
+                         })
+        };
+
+        for (coro, remote) in state.waiters {
+            remote.ready(coro);
+        }
+
+        for token in state.coroutines.iter().filter_map(WeakCancelToken::upgrade) {
+            token.cancel();
+        }
+
+        for child in state.children {
+            if let Some(child) = child.upgrade() {
+                CancellationToken(child).cancel();
+            }
+        }
+    }
+
+    /// Returns true if this token or one of its parents has been cancelled
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Blocks the current coroutine until the token is cancelled
+    pub fn wait_cancelled(&self) {
+        let mut state = self.0.state.lock();
+
+        if self.is_cancelled() {
+            return;
+        }
+
+        match Processor::current() {
+            Some(p) => {
+                let remote = RemoteReady::current();
+
+                p.park_with(|_, coro| {
+                    state.waiters.push_back((coro, remote));
+                    drop(state); // We _must_ to hold the lock until here
+                });
+            }
+            None => panic!("CancellationToken will not work in thread environment"),
+        }
+    }
+
+    /// Cancels the current coroutine once this token is cancelled
+    ///
+    /// Afterwards its blocking operations return a `Cancelled` error and `coio::is_cancelled()`
+    /// returns true, just like after a call to `JoinHandle::cancel()`.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the current coroutine can't be cancelled,
+    /// because it hasn't been spawned with a `JoinHandle`, e.g. the one started by
+    /// `Scheduler::run()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a coroutine.
+    pub fn bind(&self) -> io::Result<()> {
+        let mut p = Processor::current()
+                        .expect("cannot bind a CancellationToken outside of a coroutine");
+
+        let token = match p.current().and_then(|coro| coro.cancel_token().cloned()) {
+            Some(token) => token,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "the current coroutine can't be cancelled"))
+            }
+        };
+
+        {
+            let mut state = self.0.state.lock();
+
+            if !self.is_cancelled() {
+                // Coroutines which have gone away in the meantime are cleaned up on the way
+                state.coroutines.retain(|c| c.upgrade().is_some());
+                state.coroutines.push(token.downgrade());
+                return Ok(());
+            }
+        }
+
+        token.cancel();
+        Ok(())
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CancellationToken({})", self.is_cancelled())
+    }
+}
+
+unsafe impl Send for CancellationToken {}
+unsafe impl Sync for CancellationToken {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io;
+    use std::time::Duration;
+
+    use net::UdpSocket;
+    use runtime::cancel::Cancelled;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn cancellation_token_children() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        let grandchild = child.child();
+        let sibling = parent.child();
+
+        child.cancel();
+        assert!(child.is_cancelled() && grandchild.is_cancelled());
+        assert!(!parent.is_cancelled() && !sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(parent.child().is_cancelled());
+    }
+
+    #[test]
+    fn cancellation_token_bind() {
+        Scheduler::new()
+            .run(|| {
+                let token = CancellationToken::new();
+
+                // The main coroutine has no JoinHandle which could cancel it
+                assert_eq!(token.bind().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+                for _ in 0..10 {
+                    let token = token.clone();
+                    Scheduler::spawn(move || token.bind().unwrap()).join().unwrap();
+                }
+
+                // Finished coroutines are dropped whenever the next one binds the token
+                assert_eq!(token.0.state.lock().coroutines.len(), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn cancellation_token_wakes_waiters_and_io() {
+        Scheduler::new()
+            .run(|| {
+                let token = CancellationToken::new();
+
+                let waiter = {
+                    let token = token.child();
+                    Scheduler::spawn(move || token.wait_cancelled())
+                };
+                let reader = {
+                    let token = token.child();
+
+                    Scheduler::spawn(move || {
+                        token.bind().unwrap();
+
+                        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                        let mut buf = [0u8; 16];
+                        socket.recv_from(&mut buf).unwrap_err()
+                    })
+                };
+
+                Scheduler::instance().unwrap().sleep(Duration::from_millis(10)).unwrap();
+                token.cancel();
+
+                waiter.join().unwrap();
+
+                let err = reader.join().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::Other);
+                assert!(err.get_ref().unwrap().is::<Cancelled>());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Condition variable for Coroutines
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use runtime::Processor;
+use runtime::waiter::Waiter;
+use scheduler::{self, Scheduler};
+
+use super::mutex::{Guard, LockResult};
+use super::spinlock::Spinlock;
+
+/// Whether a timed wait on a `Condvar` returned because of a timeout
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns true if the wait is known to have timed out.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A condition variable for coroutines, used together with `sync::Mutex`
+///
+/// The mutex is released and the coroutine is put on the wait list in one step, just like
+/// with std's `Condvar`: A notification issued by a coroutine which acquired the mutex after
+/// the waiter released it is never lost.
+pub struct Condvar(Spinlock<VecDeque<Arc<Waiter>>>);
+
+impl Condvar {
+    /// Creates a new condition variable without any waiters.
+    pub fn new() -> Condvar {
+        Condvar(Spinlock::new(VecDeque::new()))
+    }
+
+    /// Releases `guard` and blocks the current coroutine until it is notified,
+    /// after which the mutex is acquired again.
+    ///
+    /// Just like with std's `Condvar` spurious wakeups are possible, which is why the
+    /// condition should be rechecked in a loop.
+    pub fn wait<'a, T>(&self, guard: Guard<'a, T>) -> LockResult<Guard<'a, T>> {
+        let mutex = guard.mutex();
+        let waiter = Arc::new(Waiter::new());
+
+        // Holding the wait list until we're parked makes the release and the park atomic
+        let mut waiters = self.0.lock();
+        drop(guard);
+
+        match Processor::current() {
+            Some(p) => {
+                p.park_with(|_, coro| {
+                    // Nobody can fire the Waiter before it's on the list
+                    let coro = waiter.arm(coro);
+                    debug_assert!(coro.is_none());
+
+                    waiters.push_back(waiter.clone());
+                    drop(waiters); // We _must_ to hold the lock until here
+                });
+            }
+            None => panic!("Condvar will not work in thread environment"),
+        }
+
+        mutex.lock()
+    }
+
+    /// Like `wait()`, but returns after `timeout` has elapsed even without a notification
+    pub fn wait_timeout<'a, T>(&self,
+                               guard: Guard<'a, T>,
+                               timeout: Duration)
+                               -> LockResult<(Guard<'a, T>, WaitTimeoutResult)> {
+        let mutex = guard.mutex();
+        let mut own_waiter = None;
+
+        let waiters = self.0.lock();
+        drop(guard);
+
+        let notified = Scheduler::park_with_timeout(timeout, |_, waiter| {
+            own_waiter = Some(waiter.clone());
+
+            let mut waiters = waiters;
+            waiters.push_back(waiter);
+            drop(waiters); // We _must_ to hold the lock until here
+        });
+
+        // A Waiter which lost against the timer (or a cancellation) is still on the list
+        if let Some(waiter) = own_waiter {
+            let mut waiters = self.0.lock();
+
+            if let Some(pos) = waiters.iter().position(|w| w.same(&waiter)) {
+                waiters.remove(pos);
+            }
+        }
+
+        mutex.lock().map(|guard| (guard, WaitTimeoutResult(!notified)))
+    }
+
+    /// Wakes up the coroutine which has been waiting the longest, if there is any.
+    pub fn notify_one(&self) {
+        let mut waiters = self.0.lock();
+
+        // Skips Waiters which have already been woken up by their timer
+        while let Some(waiter) = waiters.pop_front() {
+            if waiter.wake(scheduler::PARK_WOKEN, Scheduler::ready) {
+                break;
+            }
+        }
+    }
+
+    /// Wakes up all waiting coroutines.
+    pub fn notify_all(&self) {
+        let waiters = {
+            let mut waiters = self.0.lock();
+            waiters.drain(..).collect::<Vec<_>>()
+        };
+
+        for waiter in waiters {
+            waiter.wake(scheduler::PARK_WOKEN, Scheduler::ready);
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+unsafe impl Send for Condvar {}
+unsafe impl Sync for Condvar {}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use scheduler::Scheduler;
+    use sync::mutex::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_condvar_producer_consumer() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let pair = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+                let consumed = Arc::new(AtomicUsize::new(0));
+
+                let mut handlers = Vec::new();
+
+                for _ in 0..4 {
+                    let pair = pair.clone();
+                    let consumed = consumed.clone();
+
+                    handlers.push(Scheduler::spawn(move || {
+                        let &(ref queue, ref cond) = &*pair;
+
+                        for _ in 0..25 {
+                            let mut guard = queue.lock().unwrap();
+
+                            while guard.is_empty() {
+                                guard = cond.wait(guard).unwrap();
+                            }
+
+                            guard.pop().unwrap();
+                            consumed.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }));
+                }
+
+                for i in 0..100 {
+                    let &(ref queue, ref cond) = &*pair;
+                    queue.lock().unwrap().push(i);
+                    cond.notify_one();
+
+                    if i % 10 == 0 {
+                        Scheduler::sched();
+                    }
+                }
+
+                for h in handlers {
+                    h.join().unwrap();
+                }
+
+                assert_eq!(consumed.load(Ordering::SeqCst), 100);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_condvar_wait_timeout() {
+        Scheduler::new()
+            .run(|| {
+                let mutex = Mutex::new(());
+                let cond = Condvar::new();
+
+                let start = Instant::now();
+                let guard = mutex.lock().unwrap();
+                let (guard, result) = cond.wait_timeout(guard, Duration::from_millis(50)).unwrap();
+
+                assert!(result.timed_out());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+                drop(guard);
+
+                // The timed out Waiter must not stay around to swallow the next notification
+                assert!(cond.0.lock().is_empty());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_condvar_wait_timeout_notified() {
+        Scheduler::new()
+            .run(|| {
+                let pair = Arc::new((Mutex::new(false), Condvar::new()));
+
+                let pair2 = pair.clone();
+                let h = Scheduler::spawn(move || {
+                    let &(ref flag, ref cond) = &*pair2;
+                    *flag.lock().unwrap() = true;
+                    cond.notify_all();
+                });
+
+                let &(ref flag, ref cond) = &*pair;
+                let mut guard = flag.lock().unwrap();
+
+                while !*guard {
+                    let (g, result) = cond.wait_timeout(guard, Duration::from_secs(10)).unwrap();
+                    assert!(!result.timed_out());
+                    guard = g;
+                }
+
+                drop(guard);
+                h.join().unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -9,7 +9,7 @@
 use std::sync::Arc;
 use std::thread;
 
-use coroutine::Coroutine;
+use runtime::coroutine::Coroutine;
 use runtime::processor::Processor;
 use scheduler::Scheduler;
 use super::CoreWrapper;
@@ -8,10 +8,27 @@
 
 //! Coroutine synchronization
 
+pub use self::cancellation::CancellationToken;
+pub use self::condvar::Condvar;
 pub use self::mutex::Mutex;
+pub use self::notify::Notify;
+pub use self::rwlock::RwLock;
+pub use self::select::Select;
+pub use self::semaphore::Semaphore;
+pub use self::seqlock::SeqLock;
+pub use self::wait_group::WaitGroup;
 
+pub mod broadcast;
+pub mod cancellation;
+pub mod condvar;
 pub mod mono_barrier;
 pub mod mpsc;
 pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+pub mod rwlock;
+pub mod select;
 pub mod semaphore;
+pub mod seqlock;
 pub mod spinlock;
+pub mod wait_group;
@@ -8,18 +8,22 @@
 
 use std::fmt;
 use std::mem;
-use std::sync::{Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::{Duration, Instant};
 
-use coroutine::{Coroutine, Handle};
+use runtime::coroutine::{Coroutine, Handle};
 use runtime::Processor;
-use scheduler::Scheduler;
+use runtime::waiter::Waiter;
+use scheduler::{self, Scheduler};
 
 enum State {
     Empty,
     Ready,
     Thread,
     Coroutine(Handle),
+    // A coroutine waiting in wait_timeout()
+    Waiter(Arc<Waiter>),
 }
 
 pub struct MonoBarrier {
@@ -82,6 +86,90 @@ impl MonoBarrier {
         }
     }
 
+    /// Like `wait()`, but gives up after `timeout` has elapsed
+    ///
+    /// Returns true if the barrier has been notified and false if the wait timed out.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool, MonoBarrierError> {
+        let mut guard = match self.lock.lock() {
+            Err(_) => return Err(MonoBarrierError::PoisonError),
+            Ok(guard) => guard,
+        };
+
+        match *guard {
+            State::Ready => {
+                *guard = State::Empty;
+                return Ok(true);
+            }
+            State::Empty => {}
+            _ => return Err(MonoBarrierError::Occupied),
+        }
+
+        if Processor::current().is_none() {
+            let deadline = Instant::now() + timeout;
+            *guard = State::Thread;
+
+            loop {
+                if let State::Ready = *guard {
+                    *guard = State::Empty;
+                    return Ok(true);
+                }
+
+                let now = Instant::now();
+
+                if now >= deadline {
+                    *guard = State::Empty;
+                    return Ok(false);
+                }
+
+                guard = match self.cond.wait_timeout(guard, deadline - now) {
+                    Err(_) => return Err(MonoBarrierError::PoisonError),
+                    Ok((guard, _)) => guard,
+                };
+            }
+        }
+
+        let mut own_waiter = None;
+
+        Scheduler::park_with_timeout(timeout, |_, waiter| {
+            own_waiter = Some(waiter.clone());
+            *guard = State::Waiter(waiter);
+            drop(guard); // We _must_ to hold the lock until here
+        });
+
+        let waiter = own_waiter.expect("park_with_timeout() didn't pass a Waiter");
+
+        if waiter.fired() == Some(scheduler::PARK_WOKEN) {
+            return Ok(true);
+        }
+
+        // Timed out or cancelled: notify() must not find our Waiter anymore
+        let mut guard = match self.lock.lock() {
+            Err(_) => return Err(MonoBarrierError::PoisonError),
+            Ok(guard) => guard,
+        };
+
+        let (ours, notified) = match *guard {
+            State::Waiter(ref w) => (w.same(&waiter), false),
+            // notify() lost the race against the timer, but the notification still counts
+            State::Ready => (true, true),
+            _ => (false, false),
+        };
+
+        if ours {
+            *guard = State::Empty;
+        }
+
+        Ok(notified)
+    }
+
+    /// Returns true if `notify()` has been called, but no one has `wait()`ed for it yet
+    pub fn is_ready(&self) -> bool {
+        match *self.lock.lock().unwrap() {
+            State::Ready => true,
+            _ => false,
+        }
+    }
+
     /// Try to notify the waiting executor
     pub fn notify(&self) {
         let mut guard = self.lock.lock().unwrap();
@@ -98,6 +186,12 @@ impl MonoBarrier {
                 *guard = State::Ready;
                 self.cond.notify_one();
             }
+            State::Waiter(waiter) => {
+                // If the timer won the race, the notification is kept for the next wait()
+                if !waiter.wake(scheduler::PARK_WOKEN, Scheduler::ready) {
+                    *guard = State::Ready;
+                }
+            }
         };
     }
 }
@@ -111,6 +205,7 @@ impl fmt::Debug for MonoBarrier {
             State::Ready => write!(f, "MonoBarrier(Ready)"),
             State::Thread => write!(f, "MonoBarrier(Thread)"),
             State::Coroutine(ref coro) => write!(f, "MonoBarrier({:?})", coro),
+            State::Waiter(ref waiter) => write!(f, "MonoBarrier({:?})", waiter),
         }
     }
 }
@@ -7,21 +7,37 @@
 // except according to those terms.
 
 //! Multi-producer, single-consumer FIFO queue communication primitives.
+//!
+//! Unlike the channels of `std::sync::mpsc` these park the waiting coroutine instead of
+//! blocking its worker thread, which could otherwise deadlock the runtime.
+//! `channel()` creates an unbounded channel, while `sync_channel(bound)` creates a bounded one
+//! whose senders are parked while it's full.
 
 pub use std::sync::mpsc::{TrySendError, SendError, TryRecvError, RecvError};
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 
-use coroutine::HandleList;
+use runtime::coroutine::HandleList;
 use runtime::Processor;
+use runtime::waiter::Waiter;
 use scheduler::Scheduler;
 
+use super::select::{self, SelectSource, SelectWaiters};
+
+struct WaitList {
+    coros: HandleList,
+    selectors: SelectWaiters,
+}
+
 #[derive(Clone)]
 pub struct Sender<T> {
     inner: Option<mpsc::Sender<T>>,
 
-    wait_list: Arc<Mutex<HandleList>>,
+    wait_list: Arc<Mutex<WaitList>>,
 }
 
 unsafe impl<T: Send> Send for Sender<T> {}
@@ -30,10 +46,14 @@ impl<T> Sender<T> {
     pub fn send(&self, t: T) -> Result<(), SendError<T>> {
         match self.inner.as_ref().unwrap().send(t) {
             Ok(..) => {
-                let mut wait_list = self.wait_list.lock().unwrap();
-                if let Some(coro) = wait_list.pop_front() {
-                    Scheduler::ready(coro);
-                }
+                let selectors = {
+                    let mut wait_list = self.wait_list.lock().unwrap();
+                    if let Some(coro) = wait_list.coros.pop_front() {
+                        Scheduler::ready(coro);
+                    }
+                    mem::replace(&mut wait_list.selectors, Vec::new())
+                };
+                select::wake_all(selectors);
                 Ok(())
             }
             Err(err) => Err(err),
@@ -51,11 +71,15 @@ impl<T> Drop for Sender<T> {
         // items into this queue, so we have to wake the coroutine up explicitly,
         // who ownes the other end of this channel.
         if Arc::strong_count(&self.wait_list) <= 2 {
-            let mut wait_list = self.wait_list.lock().unwrap();
-            while let Some(hdl) = wait_list.pop_front() {
-                trace!("{:?} is awaken by dropping Sender in wait_list", hdl);
-                Scheduler::ready(hdl);
-            }
+            let selectors = {
+                let mut wait_list = self.wait_list.lock().unwrap();
+                while let Some(hdl) = wait_list.coros.pop_front() {
+                    trace!("{:?} is awaken by dropping Sender in wait_list", hdl);
+                    Scheduler::ready(hdl);
+                }
+                mem::replace(&mut wait_list.selectors, Vec::new())
+            };
+            select::wake_all(selectors);
         }
     }
 }
@@ -63,16 +87,48 @@ impl<T> Drop for Sender<T> {
 pub struct Receiver<T> {
     inner: mpsc::Receiver<T>,
 
-    wait_list: Arc<Mutex<HandleList>>,
+    // An item taken out of `inner` to check for readiness in `Select`
+    peeked: RefCell<Option<T>>,
+
+    wait_list: Arc<Mutex<WaitList>>,
 }
 
 unsafe impl<T: Send> Send for Receiver<T> {}
 
 impl<T> Receiver<T> {
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
-        self.inner.try_recv()
+        match self.peeked.borrow_mut().take() {
+            Some(t) => Ok(t),
+            None => self.inner.try_recv(),
+        }
     }
 
+    /// Returns an iterator which blocks on `recv()` and ends once all `Sender`s are dropped
+    pub fn iter(&self) -> Iter<T> {
+        Iter { rx: self }
+    }
+
+    // Returns true if `try_recv()` is not going to return `TryRecvError::Empty`
+    fn is_ready(&self) -> bool {
+        let mut peeked = self.peeked.borrow_mut();
+
+        if peeked.is_some() {
+            return true;
+        }
+
+        match self.inner.try_recv() {
+            Ok(t) => {
+                *peeked = Some(t);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        }
+    }
+
+    /// Blocks the current coroutine until an item arrives
+    ///
+    /// Once all `Sender`s have been dropped and all items are received `RecvError` is returned.
     pub fn recv(&self) -> Result<T, RecvError> {
         while let Some(processor) = Processor::current() {
             // 1. Try to receive first
@@ -95,7 +151,7 @@ impl<T> Receiver<T> {
                 match r {
                     Err(TryRecvError::Empty) => {
                         // 5.1. Push ourselves into the wait list
-                        wait_list.push_back(coro);
+                        wait_list.coros.push_back(coro);
                     }
                     _ => {
                         // 5.2. Success!
@@ -113,14 +169,87 @@ impl<T> Receiver<T> {
         }
 
         // What? The processor is gone? Then fallback to blocking recv
-        self.inner.recv()
+        match self.peeked.borrow_mut().take() {
+            Some(t) => Ok(t),
+            None => self.inner.recv(),
+        }
+    }
+}
+
+/// An iterator over the items of a `Receiver`, see `Receiver::iter()`
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// An owning iterator over the items of a `Receiver`
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
     }
 }
 
-/// Create a channel pair
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+impl<T> SelectSource for Receiver<T> {
+    fn add_selector(&self, waiter: &Arc<Waiter>, idx: usize) -> bool {
+        let mut wait_list = self.wait_list.lock().unwrap();
+
+        // Checked while holding the wait list, so that no send() can slip in between
+        if self.is_ready() {
+            waiter.wake(idx, Scheduler::ready);
+            false
+        } else {
+            wait_list.selectors.push((waiter.clone(), idx));
+            true
+        }
+    }
+
+    fn remove_selector(&self, waiter: &Arc<Waiter>) {
+        let mut wait_list = self.wait_list.lock().unwrap();
+        wait_list.selectors.retain(|&(ref w, _)| !w.same(waiter));
+    }
+}
+
+/// Create an unbounded channel pair
+///
+/// `Sender::send()` never parks, since the buffer grows as needed, while the receiver is parked
+/// until an item arrives or all `Sender`s have been dropped.
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let (tx, rx) = mpsc::channel();
-    let wait_list = Arc::new(Mutex::new(HandleList::new()));
+    let wait_list = Arc::new(Mutex::new(WaitList {
+        coros: HandleList::new(),
+        selectors: Vec::new(),
+    }));
 
     let sender = Sender {
         inner: Some(tx),
@@ -129,201 +258,299 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
 
     let receiver = Receiver {
         inner: rx,
+        peeked: RefCell::new(None),
         wait_list: wait_list,
     };
 
     (sender, receiver)
 }
 
-#[derive(Clone)]
-pub struct SyncSender<T> {
-    inner: Option<mpsc::SyncSender<T>>,
+struct SyncState<T> {
+    buffer: VecDeque<T>,
+    bound: usize,
 
-    send_wait_list: Arc<Mutex<HandleList>>,
-    recv_wait_list: Arc<Mutex<HandleList>>,
+    senders: usize,
+    receiver_alive: bool,
+
+    send_wait_list: HandleList,
+    recv_wait_list: HandleList,
+    selectors: SelectWaiters,
+}
+
+impl<T> SyncState<T> {
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.buffer.len() >= self.bound
+    }
 }
 
-unsafe impl<T: Send> Send for SyncSender<T> {}
+struct SyncShared<T> {
+    state: Mutex<SyncState<T>>,
+
+    // Used to block threads which are not running inside of a Processor
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> SyncShared<T> {
+    #[inline]
+    fn lock(&self) -> MutexGuard<SyncState<T>> {
+        self.state.lock().unwrap()
+    }
+}
+
+pub struct SyncSender<T> {
+    shared: Arc<SyncShared<T>>,
+}
 
 impl<T> SyncSender<T> {
     pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
-        match self.inner.as_ref().unwrap().try_send(t) {
-            Ok(..) => {
-                let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
-                if let Some(coro) = recv_wait_list.pop_front() {
-                    trace!("{:?} is waken up in SyncSender receive_wait_list, {} \
-                            remains",
-                           coro,
-                           recv_wait_list.len());
-                    Scheduler::ready(coro);
-                }
-                Ok(())
+        let (coro, selectors) = {
+            let mut state = self.shared.lock();
+
+            if !state.receiver_alive {
+                return Err(TrySendError::Disconnected(t));
             }
-            Err(err) => Err(err),
+
+            if state.is_full() {
+                return Err(TrySendError::Full(t));
+            }
+
+            state.buffer.push_back(t);
+            (state.recv_wait_list.pop_front(), mem::replace(&mut state.selectors, Vec::new()))
+        };
+
+        self.shared.not_empty.notify_one();
+        select::wake_all(selectors);
+
+        if let Some(coro) = coro {
+            trace!("{:?} is waken up in SyncSender recv_wait_list", coro);
+            Scheduler::ready(coro);
         }
+
+        Ok(())
     }
 
     pub fn send(&self, mut t: T) -> Result<(), SendError<T>> {
-        while let Some(p) = Processor::current() {
-            let mut r = self.try_send(t);
-
-            match r {
+        loop {
+            match self.try_send(t) {
                 Ok(..) => return Ok(()),
-                Err(TrySendError::Disconnected(e)) => return Err(SendError(e)),
-                Err(TrySendError::Full(t_)) => {
-                    t = t_;
-                }
+                Err(TrySendError::Disconnected(t)) => return Err(SendError(t)),
+                Err(TrySendError::Full(t_)) => t = t_,
             }
 
-            r = Ok(());
-            {
-                let r_ptr = &mut r;
-                p.park_with(move |p, coro| {
-                    let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                    let r = self.try_send(t);
+            match Processor::current() {
+                Some(p) => {
+                    p.park_with(|p, coro| {
+                        let mut state = self.shared.lock();
 
-                    match r {
-                        Err(TrySendError::Full(..)) => {
-                            send_wait_list.push_back(coro);
-                        }
-                        _ => {
+                        // Ensure that the receiver didn't make room while we were parking
+                        if state.receiver_alive && state.is_full() {
+                            state.send_wait_list.push_back(coro);
+                        } else {
                             p.ready(coro);
                         }
-                    }
-
-                    *r_ptr = r;
-                });
-            }
-
-            match r {
-                Ok(..) => return Ok(()),
-                Err(TrySendError::Disconnected(e)) => return Err(SendError(e)),
-                Err(TrySendError::Full(t_)) => {
-                    t = t_;
+                    });
                 }
-            }
-        }
+                None => {
+                    // What? The processor is gone? Then block the thread
+                    let mut state = self.shared.lock();
 
-        match self.inner.as_ref().unwrap().send(t) {
-            Ok(..) => {
-                let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
-                if let Some(coro) = recv_wait_list.pop_front() {
-                    // Wake them up ...
-                    Scheduler::ready(coro);
+                    while state.receiver_alive && state.is_full() {
+                        state = self.shared.not_full.wait(state).unwrap();
+                    }
                 }
-                Ok(())
             }
-            Err(err) => Err(err),
         }
     }
 }
 
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.shared.lock().senders += 1;
+
+        SyncSender { shared: self.shared.clone() }
+    }
+}
+
 impl<T> Drop for SyncSender<T> {
     fn drop(&mut self) {
-        // Drop the inner SyncSender first
-        {
-            self.inner.take();
-        }
+        let (recv_wait_list, selectors) = {
+            let mut state = self.shared.lock();
+            state.senders -= 1;
 
-        // Try to wake up all the pending coroutines if this is the last SyncSender.
-        // Because if this is the last SyncSender, there won't be another one to push
-        // items into this queue, so we have to wake the coroutine up explicitly,
-        // who ownes the other end of this channel.
-        if Arc::strong_count(&self.recv_wait_list) <= 2 {
-            let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
-            while let Some(hdl) = recv_wait_list.pop_front() {
-                trace!("{:?} is awaken by dropping SyncSender in recv_wait_list",
-                       hdl);
-                Scheduler::ready(hdl);
+            if state.senders > 0 {
+                return;
             }
+
+            (mem::replace(&mut state.recv_wait_list, HandleList::new()),
+             mem::replace(&mut state.selectors, Vec::new()))
+        };
+
+        // This was the last SyncSender, so no one is going to push items into this queue anymore.
+        // The receiving side has to be woken up explicitly, so that it can observe the disconnect.
+        self.shared.not_empty.notify_all();
+        select::wake_all(selectors);
+
+        for hdl in recv_wait_list {
+            trace!("{:?} is awaken by dropping SyncSender in recv_wait_list",
+                   hdl);
+            Scheduler::ready(hdl);
         }
     }
 }
 
 pub struct SyncReceiver<T> {
-    inner: Option<mpsc::Receiver<T>>,
-
-    send_wait_list: Arc<Mutex<HandleList>>,
-    recv_wait_list: Arc<Mutex<HandleList>>,
+    shared: Arc<SyncShared<T>>,
 }
 
-unsafe impl<T: Send> Send for SyncReceiver<T> {}
-
 impl<T> SyncReceiver<T> {
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
-        match self.inner.as_ref().unwrap().try_recv() {
-            Ok(t) => {
-                let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                if let Some(coro) = send_wait_list.pop_front() {
-                    trace!("{:?} is waken up in SyncReceiver send_wait_list, {} remains",
-                           coro,
-                           send_wait_list.len());
-                    Scheduler::ready(coro);
-                }
-                Ok(t)
+        let (t, coro) = {
+            let mut state = self.shared.lock();
+
+            match state.buffer.pop_front() {
+                Some(t) => (t, state.send_wait_list.pop_front()),
+                None if state.senders == 0 => return Err(TryRecvError::Disconnected),
+                None => return Err(TryRecvError::Empty),
             }
-            Err(err) => Err(err),
+        };
+
+        self.shared.not_full.notify_one();
+
+        if let Some(coro) = coro {
+            trace!("{:?} is waken up in SyncReceiver send_wait_list", coro);
+            Scheduler::ready(coro);
         }
+
+        Ok(t)
     }
 
+    /// Blocks the current coroutine until an item arrives
+    ///
+    /// Once all `SyncSender`s have been dropped and the buffer is drained `RecvError` is returned.
     pub fn recv(&self) -> Result<T, RecvError> {
-        while let Some(processor) = Processor::current() {
-            let mut r = self.try_recv();
-
-            match r {
-                Ok(v) => return Ok(v),
-                Err(TryRecvError::Empty) => {}
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
                 Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
             }
 
-            processor.park_with(|p, coro| {
-                let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
+            match Processor::current() {
+                Some(p) => {
+                    p.park_with(|p, coro| {
+                        let mut state = self.shared.lock();
 
-                r = self.try_recv();
+                        // Ensure that no one sent items into the queue while we were parking
+                        if state.senders > 0 && state.buffer.is_empty() {
+                            state.recv_wait_list.push_back(coro);
+                        } else {
+                            p.ready(coro);
+                        }
+                    });
+                }
+                None => {
+                    // What? The processor is gone? Then block the thread
+                    let mut state = self.shared.lock();
 
-                match r {
-                    Err(TryRecvError::Empty) => {
-                        recv_wait_list.push_back(coro);
-                    }
-                    _ => {
-                        p.ready(coro);
+                    while state.senders > 0 && state.buffer.is_empty() {
+                        state = self.shared.not_empty.wait(state).unwrap();
                     }
                 }
-            });
-
-            match r {
-                Ok(v) => return Ok(v),
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => return Err(RecvError),
             }
         }
+    }
+}
 
-        // What? The processor is gone? Then use blocking recv
-        match self.inner.as_ref().unwrap().recv() {
-            Ok(t) => {
-                let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                if let Some(coro) = send_wait_list.pop_front() {
-                    Scheduler::ready(coro);
-                }
-                Ok(t)
-            }
-            Err(err) => Err(err),
+/// An iterator over the items of a `SyncReceiver`, see `SyncReceiver::iter()`
+pub struct SyncIter<'a, T: 'a> {
+    rx: &'a SyncReceiver<T>,
+}
+
+impl<'a, T> Iterator for SyncIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// An owning iterator over the items of a `SyncReceiver`
+pub struct SyncIntoIter<T> {
+    rx: SyncReceiver<T>,
+}
+
+impl<T> Iterator for SyncIntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a SyncReceiver<T> {
+    type Item = T;
+    type IntoIter = SyncIter<'a, T>;
+
+    fn into_iter(self) -> SyncIter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for SyncReceiver<T> {
+    type Item = T;
+    type IntoIter = SyncIntoIter<T>;
+
+    fn into_iter(self) -> SyncIntoIter<T> {
+        SyncIntoIter { rx: self }
+    }
+}
+
+impl<T> SelectSource for SyncReceiver<T> {
+    fn add_selector(&self, waiter: &Arc<Waiter>, idx: usize) -> bool {
+        let mut state = self.shared.lock();
+
+        if state.senders == 0 || !state.buffer.is_empty() {
+            waiter.wake(idx, Scheduler::ready);
+            false
+        } else {
+            state.selectors.push((waiter.clone(), idx));
+            true
         }
     }
+
+    fn remove_selector(&self, waiter: &Arc<Waiter>) {
+        let mut state = self.shared.lock();
+        state.selectors.retain(|&(ref w, _)| !w.same(waiter));
+    }
+}
+
+impl<T> SyncReceiver<T> {
+    /// Returns an iterator which blocks on `recv()` and ends once all `SyncSender`s are dropped
+    pub fn iter(&self) -> SyncIter<T> {
+        SyncIter { rx: self }
+    }
 }
 
 impl<T> Drop for SyncReceiver<T> {
     fn drop(&mut self) {
-        // Drop the inner SyncReceiver first
-        {
-            self.inner.take();
-        }
+        let (buffer, send_wait_list) = {
+            let mut state = self.shared.lock();
+            state.receiver_alive = false;
+
+            (mem::replace(&mut state.buffer, VecDeque::new()),
+             mem::replace(&mut state.send_wait_list, HandleList::new()))
+        };
 
-        // Try to wake up all the pending coroutines if this is the last SyncReceiver.
-        // Because there won't be another one to push items into this queue, so we
-        // have to wake the coroutine up explicitly, who ownes the other end of this channel.
-        let mut send_wait_list = self.send_wait_list.lock().unwrap();
-        while let Some(hdl) = send_wait_list.pop_front() {
+        // Drop the remaining items outside of the lock
+        drop(buffer);
+
+        // No one is going to receive items anymore, so the senders
+        // have to be woken up explicitly, to make them return an error.
+        self.shared.not_full.notify_all();
+
+        for hdl in send_wait_list {
             trace!("{:?} is awaken by dropping SyncReceiver in send_wait_list",
                    hdl);
             Scheduler::ready(hdl);
@@ -333,26 +560,37 @@ impl<T> Drop for SyncReceiver<T> {
 
 /// Create a bounded channel pair
 ///
-/// NOTE: Due to the implementation of sync channel in libstd, you will get `RecvError` from `SyncReceiver::recv`
-/// no matter if there still have data inside the queue after you dropped the `SyncSender`.
+/// Senders are parked while the buffer holds `bound` items and the receiver is parked
+/// while it is empty. Threads outside of a Processor are blocked instead.
+/// Items which are still buffered after all `SyncSender`s have been dropped can be received,
+/// before `recv()` starts returning `RecvError`.
 ///
-/// Tracking issue: https://github.com/zonyitoo/coio-rs/issues/31
+/// # Panics
+///
+/// Panics if `bound` is 0, since rendezvous channels like the ones created by
+/// `std::sync::mpsc::sync_channel(0)` aren't supported.
 pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, SyncReceiver<T>) {
-    let (tx, rx) = mpsc::sync_channel(bound);
-    let send_wait_list = Arc::new(Mutex::new(HandleList::new()));
-    let recv_wait_list = Arc::new(Mutex::new(HandleList::new()));
+    assert!(bound > 0, "sync_channel() requires a bound of at least 1");
 
-    let sender = SyncSender {
-        inner: Some(tx),
-        send_wait_list: send_wait_list.clone(),
-        recv_wait_list: recv_wait_list.clone(),
-    };
+    let shared = Arc::new(SyncShared {
+        state: Mutex::new(SyncState {
+            buffer: VecDeque::with_capacity(bound),
+            bound: bound,
 
-    let receiver = SyncReceiver {
-        inner: Some(rx),
-        send_wait_list: send_wait_list,
-        recv_wait_list: recv_wait_list,
-    };
+            senders: 1,
+            receiver_alive: true,
+
+            send_wait_list: HandleList::new(),
+            recv_wait_list: HandleList::new(),
+            selectors: Vec::new(),
+        }),
+
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+
+    let sender = SyncSender { shared: shared.clone() };
+    let receiver = SyncReceiver { shared: shared };
 
     (sender, receiver)
 }
@@ -428,6 +666,91 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn test_sync_channel_recv_after_sender_dropped() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = sync_channel(2);
+                let tx2 = tx.clone();
+
+                assert_eq!(tx.send(1), Ok(()));
+                assert_eq!(tx2.send(2), Ok(()));
+                drop(tx);
+                drop(tx2);
+
+                // Buffered items are still delivered after the disconnect
+                assert_eq!(rx.recv(), Ok(1));
+                assert_eq!(rx.recv(), Ok(2));
+                assert_eq!(rx.recv(), Err(RecvError));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sync_channel_disconnect_wakes_up() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = sync_channel::<i32>(1);
+
+                let h = Scheduler::spawn(move || rx.recv());
+
+                // Let the receiver park itself
+                Scheduler::sched();
+                drop(tx);
+
+                assert_eq!(h.join().unwrap(), Err(RecvError));
+
+                let (tx, rx) = sync_channel(1);
+                assert_eq!(tx.send(1), Ok(()));
+
+                let h = Scheduler::spawn(move || tx.send(2));
+
+                Scheduler::sched();
+                drop(rx);
+
+                assert_eq!(h.join().unwrap(), Err(SendError(2)));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "a bound of at least 1")]
+    fn test_sync_channel_rejects_zero_bound() {
+        let _ = sync_channel::<i32>(0);
+    }
+
+    #[test]
+    fn test_channel_iter_ends_on_disconnect() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = channel();
+                let (sync_tx, sync_rx) = sync_channel(5);
+
+                let h = Scheduler::spawn(move || {
+                    let items = rx.into_iter().collect::<Vec<_>>();
+                    let mut sync_items = Vec::new();
+
+                    for item in &sync_rx {
+                        sync_items.push(item);
+                    }
+
+                    (items, sync_items)
+                });
+
+                for i in 0..5 {
+                    tx.send(i).unwrap();
+                    sync_tx.send(i).unwrap();
+                }
+
+                drop(tx);
+                drop(sync_tx);
+
+                let expected = (0..5).collect::<Vec<_>>();
+                assert_eq!(h.join().unwrap(), (expected.clone(), expected));
+            })
+            .unwrap();
+    }
+
     #[test]
     fn test_channel_without_processor() {
         let (tx1, rx1) = channel();
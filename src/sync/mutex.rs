@@ -12,10 +12,10 @@ use std::error::Error;
 use std::marker::Reflect;
 use std::ops::{Deref, DerefMut};
 
-use sync::semaphore::Semaphore;
+use sync::semaphore::{Semaphore, SemaphorePermit};
 
 pub type LockResult<G> = Result<G, PoisonError<G>>;
-pub type TryLockResult<G> = Result<G, PoisonError<G>>;
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
 
 /// A mutual exclusion primitive useful for protecting shared data
 pub struct Mutex<T> {
@@ -32,20 +32,32 @@ impl<T> Mutex<T> {
         }
     }
 
-    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    /// Acquires a mutex, parking the current coroutine until it is able to do so.
+    ///
+    /// Waiting coroutines are queued and get the lock in the order they asked for it,
+    /// while the worker thread is free to run other coroutines in the meantime.
     pub fn lock(&self) -> LockResult<Guard<T>> {
-        self.sema.acquire();
-        Ok(Guard::new(unsafe { &mut *self.data.get() }, self))
+        let permit = self.sema.acquire();
+        Ok(Guard::new(self, permit))
     }
 
     /// Try to acquire a mutex, will return immediately
     pub fn try_lock(&self) -> TryLockResult<Guard<T>> {
-        if self.sema.try_acquire() {
-            Err(PoisonError::new(Guard::new(unsafe { &mut *self.data.get() }, self)))
-        } else {
-            Ok(Guard::new(unsafe { &mut *self.data.get() }, self))
+        match self.sema.try_acquire() {
+            Some(permit) => Ok(Guard::new(self, permit)),
+            None => Err(TryLockError::WouldBlock),
         }
     }
+
+    /// Returns a mutable reference to the data, no locking is needed since the borrow is unique.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(unsafe { &mut *self.data.get() })
+    }
+
+    /// Consumes the mutex, returning the data.
+    pub fn into_inner(self) -> LockResult<T> {
+        Ok(self.data.into_inner())
+    }
 }
 
 unsafe impl<T: Send> Send for Mutex<T> {}
@@ -55,22 +67,23 @@ unsafe impl<T: Sync> Sync for Mutex<T> {}
 /// the lock will be unlocked.
 #[must_use]
 pub struct Guard<'a, T: 'a> {
-    data: &'a mut T,
     mutex: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
 }
 
 impl<'a, T: 'a> Guard<'a, T> {
-    fn new(data: &'a mut T, mutex: &'a Mutex<T>) -> Guard<'a, T> {
+    fn new(mutex: &'a Mutex<T>, permit: SemaphorePermit<'a>) -> Guard<'a, T> {
         Guard {
-            data: data,
             mutex: mutex,
+            _permit: permit,
         }
     }
-}
 
-impl<'a, T: 'a> Drop for Guard<'a, T> {
-    fn drop(&mut self) {
-        self.mutex.sema.release();
+    /// Returns the mutex this guard belongs to, so that it can be locked again by `Condvar`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
     }
 }
 
@@ -79,14 +92,14 @@ impl<'a, T: 'a> Deref for Guard<'a, T> {
 
     #[inline]
     fn deref(&self) -> &T {
-        self.data
+        unsafe { &*self.mutex.data.get() }
     }
 }
 
 impl<'a, T: 'a> DerefMut for Guard<'a, T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
-        self.data
+        unsafe { &mut *self.mutex.data.get() }
     }
 }
 
@@ -137,6 +150,47 @@ impl<T: Send + Reflect> Error for PoisonError<T> {
     }
 }
 
+/// An enumeration of possible errors which can occur while calling the `try_lock` method.
+pub enum TryLockError<T> {
+    /// The lock could not be acquired because another coroutine failed while holding it.
+    Poisoned(PoisonError<T>),
+    /// The lock could not be acquired at this time because the operation would otherwise block.
+    WouldBlock,
+}
+
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "poisoned lock: another task failed inside".fmt(f),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block".fmt(f),
+        }
+    }
+}
+
+impl<T: Send + Reflect> Error for TryLockError<T> {
+    fn description(&self) -> &str {
+        match *self {
+            TryLockError::Poisoned(ref p) => p.description(),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block",
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
@@ -175,4 +229,48 @@ mod test {
 
         assert_eq!(*num.lock().unwrap(), 1000);
     }
+
+    #[test]
+    fn test_mutex_try_lock() {
+        let mutex = Mutex::new(0);
+
+        {
+            let _guard = mutex.try_lock().unwrap();
+            assert!(mutex.try_lock().is_err());
+        }
+
+        assert!(mutex.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_mutex_parks_coroutine() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let mutex = Arc::new(Mutex::new(Vec::new()));
+                let guard = mutex.lock().unwrap();
+
+                let waiter = {
+                    let mutex = mutex.clone();
+                    Scheduler::spawn(move || mutex.lock().unwrap().push(1))
+                };
+                let other = {
+                    let mutex = mutex.clone();
+                    Scheduler::spawn(move || {
+                        // Runs although the first coroutine is waiting on the same worker
+                        assert!(mutex.try_lock().is_err());
+                        2
+                    })
+                };
+
+                assert_eq!(other.join().unwrap(), 2);
+                drop(guard);
+                waiter.join().unwrap();
+
+                let mut mutex = Arc::try_unwrap(mutex).ok().unwrap();
+                mutex.get_mut().unwrap().push(3);
+                assert_eq!(mutex.into_inner().unwrap(), vec![1, 3]);
+            })
+            .unwrap();
+    }
 }
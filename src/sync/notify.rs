@@ -0,0 +1,215 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Notification primitive for Coroutines
+
+use std::collections::VecDeque;
+use std::mem;
+
+use runtime::coroutine::Handle;
+use scheduler::RemoteReady;
+use runtime::Processor;
+
+use super::spinlock::Spinlock;
+
+struct NotifyInner {
+    permit: bool,
+    waiters: VecDeque<(Handle, RemoteReady)>,
+}
+
+/// Wakes up coroutines without passing any value
+///
+/// If `notify_one()` is called while no coroutine is waiting, a single permit is stored and
+/// the next call to `notified()` returns immediately. Thus a notification can't get lost, even if
+/// it races with the consumer starting to wait. Multiple notifications still result in a single
+/// permit, so the consumer should check the shared state for everything which is ready.
+///
+/// Only coroutines can wait, but any thread is able to notify them.
+pub struct Notify(Spinlock<NotifyInner>);
+
+impl Notify {
+    /// Create a `Notify` without a stored permit
+    pub fn new() -> Notify {
+        Notify(Spinlock::new(NotifyInner {
+            permit: false,
+            waiters: VecDeque::new(),
+        }))
+    }
+
+    /// Blocks the current coroutine until it's notified
+    ///
+    /// Returns immediately if a permit has been stored by `notify_one()`.
+    pub fn notified(&self) {
+        let mut inner = self.0.lock();
+
+        if inner.permit {
+            inner.permit = false;
+            return;
+        }
+
+        match Processor::current() {
+            Some(p) => {
+                let remote = RemoteReady::current();
+
+                p.park_with(|_, coro| {
+                    inner.waiters.push_back((coro, remote));
+                    drop(inner); // We _must_ to hold the lock until here
+                });
+            }
+            None => panic!("Notify will not work in thread environment"),
+        }
+    }
+
+    /// Wakes up the coroutine which has been waiting the longest
+    ///
+    /// If no coroutine is waiting, a permit is stored for the next call to `notified()` instead.
+    pub fn notify_one(&self) {
+        let waiter = {
+            let mut inner = self.0.lock();
+            let waiter = inner.waiters.pop_front();
+
+            if waiter.is_none() {
+                inner.permit = true;
+            }
+
+            waiter
+        };
+
+        if let Some((coro, remote)) = waiter {
+            remote.ready(coro);
+        }
+    }
+
+    /// Wakes up all coroutines which are currently waiting
+    ///
+    /// Unlike `notify_one()` no permit is stored if no coroutine is waiting.
+    pub fn notify_waiters(&self) {
+        let waiters = {
+            let mut inner = self.0.lock();
+            mem::replace(&mut inner.waiters, VecDeque::new())
+        };
+
+        for (coro, remote) in waiters {
+            remote.ready(coro);
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Notify {
+        Notify::new()
+    }
+}
+
+unsafe impl Send for Notify {}
+unsafe impl Sync for Notify {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn notify_before_notified() {
+        Scheduler::new()
+            .run(|| {
+                let notify = Notify::new();
+
+                // The permit is stored and consumed right away
+                notify.notify_one();
+                notify.notify_one();
+                notify.notified();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn notify_one_wakes_waiter() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let notify = Arc::new(Notify::new());
+                let counter = Arc::new(AtomicUsize::new(0));
+
+                for _ in 0..100 {
+                    let consumer = {
+                        let notify = notify.clone();
+                        let counter = counter.clone();
+
+                        Scheduler::spawn(move || {
+                            notify.notified();
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        })
+                    };
+
+                    // Races with the consumer starting to wait
+                    notify.notify_one();
+                    consumer.join().unwrap();
+                }
+
+                assert_eq!(counter.load(Ordering::SeqCst), 100);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn notify_waiters_wakes_all() {
+        Scheduler::new()
+            .run(|| {
+                let notify = Arc::new(Notify::new());
+
+                let handles = (0..10)
+                                  .map(|_| {
+                                      let notify = notify.clone();
+                                      Scheduler::spawn(move || notify.notified())
+                                  })
+                                  .collect::<Vec<_>>();
+
+                // Let all coroutines park
+                Scheduler::sched();
+
+                notify.notify_waiters();
+
+                for h in handles {
+                    h.join().unwrap();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn notify_from_foreign_thread() {
+        Scheduler::new()
+            .run(|| {
+                let notify = Arc::new(Notify::new());
+
+                let thread = {
+                    let notify = notify.clone();
+
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(10));
+                        notify.notify_waiters();
+                        notify.notify_one();
+                    })
+                };
+
+                // Must be resumed by a worker and not on the notifying thread
+                notify.notified();
+                assert!(Scheduler::instance().is_some());
+
+                thread.join().unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Single value handoff between two coroutines
+
+pub use std::sync::mpsc::{RecvError, TryRecvError};
+
+use std::sync::Arc;
+
+use runtime::coroutine::Handle;
+use runtime::Processor;
+use scheduler::Scheduler;
+
+use super::spinlock::Spinlock;
+
+struct State<T> {
+    value: Option<T>,
+    receiver: Option<Handle>,
+
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+/// The sending half of a oneshot channel, see `channel()`
+pub struct Sender<T>(Arc<Spinlock<State<T>>>);
+
+unsafe impl<T: Send> Send for Sender<T> {}
+
+impl<T> Sender<T> {
+    /// Hands `t` over to the `Receiver`, waking it up if it's waiting
+    ///
+    /// If the `Receiver` has already been dropped `t` is given back.
+    pub fn send(self, t: T) -> Result<(), T> {
+        let coro = {
+            let mut state = self.0.lock();
+
+            if !state.receiver_alive {
+                return Err(t);
+            }
+
+            state.value = Some(t);
+            state.receiver.take()
+        };
+
+        if let Some(coro) = coro {
+            Scheduler::ready(coro);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the `Receiver` has been dropped, in which case sending is pointless
+    pub fn is_canceled(&self) -> bool {
+        !self.0.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let coro = {
+            let mut state = self.0.lock();
+            state.sender_alive = false;
+            state.receiver.take()
+        };
+
+        // The Sender has been dropped without sending, so the Receiver has to observe the disconnect
+        if let Some(coro) = coro {
+            trace!("{:?} is awaken by dropping oneshot Sender", coro);
+            Scheduler::ready(coro);
+        }
+    }
+}
+
+/// The receiving half of a oneshot channel, see `channel()`
+pub struct Receiver<T>(Arc<Spinlock<State<T>>>);
+
+unsafe impl<T: Send> Send for Receiver<T> {}
+
+impl<T> Receiver<T> {
+    /// Returns the value if it has already been sent
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.0.lock();
+
+        match state.value.take() {
+            Some(t) => Ok(t),
+            None if !state.sender_alive => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Blocks the current coroutine until the value has been sent
+    ///
+    /// Returns `RecvError` if the `Sender` has been dropped without sending anything.
+    pub fn recv(self) -> Result<T, RecvError> {
+        {
+            let mut state = self.0.lock();
+
+            if let Some(t) = state.value.take() {
+                return Ok(t);
+            }
+
+            if !state.sender_alive {
+                return Err(RecvError);
+            }
+
+            match Processor::current() {
+                Some(p) => {
+                    p.park_with(|_, coro| {
+                        state.receiver = Some(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("oneshot::Receiver will not work in thread environment"),
+            }
+        }
+
+        self.0.lock().value.take().ok_or(RecvError)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = {
+            let mut state = self.0.lock();
+            state.receiver_alive = false;
+            state.value.take()
+        };
+
+        // Drop a value which has never been received outside of the lock
+        drop(value);
+    }
+}
+
+/// Create a oneshot channel pair
+///
+/// Exactly one value can be sent from the `Sender` to the `Receiver`, which makes it a good fit
+/// for handing back the response to a request. Both halves share a single allocation.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Spinlock::new(State {
+        value: None,
+        receiver: None,
+
+        sender_alive: true,
+        receiver_alive: true,
+    }));
+
+    (Sender(state.clone()), Receiver(state))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn oneshot_send_recv() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let (tx, rx) = channel();
+
+                let h = Scheduler::spawn(move || rx.recv().unwrap());
+
+                Scheduler::sched();
+                tx.send(42).unwrap();
+
+                assert_eq!(h.join().unwrap(), 42);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn oneshot_sender_dropped() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = channel::<i32>();
+
+                let h = Scheduler::spawn(move || rx.recv());
+
+                // Let the receiver park before dropping the sender
+                Scheduler::sched();
+                drop(tx);
+
+                assert_eq!(h.join().unwrap(), Err(RecvError));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn oneshot_receiver_dropped() {
+        let (tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        drop(rx);
+        assert!(tx.is_canceled());
+        assert_eq!(tx.send(1), Err(1));
+    }
+}
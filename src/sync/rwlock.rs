@@ -0,0 +1,509 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reader-writer lock for Coroutines
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+use runtime::coroutine::{Handle, HandleList};
+use runtime::Processor;
+use scheduler::Scheduler;
+
+use super::mutex::{LockResult, TryLockError, TryLockResult};
+use super::spinlock::Spinlock;
+
+struct RwState {
+    // Includes the holder of the upgradeable read access
+    readers: usize,
+    writer: bool,
+    upgradeable: bool,
+    read_waiters: HandleList,
+    write_waiters: HandleList,
+    upgradeable_waiters: HandleList,
+
+    // The upgradeable reader waiting for the other readers to leave
+    upgrading: Option<Handle>,
+}
+
+impl RwState {
+    #[inline]
+    fn can_read(&self) -> bool {
+        !self.writer && self.write_waiters.is_empty() && self.upgrading.is_none()
+    }
+}
+
+/// A reader-writer lock which parks coroutines instead of blocking the thread
+///
+/// Any number of readers or a single writer may hold the lock at the same time.
+///
+/// # Fairness
+///
+/// Neither readers nor writers can starve: As soon as a writer is waiting, newly arriving readers
+/// queue up behind it, instead of joining the readers which currently hold the lock. When the
+/// last of those readers leaves, the lock is handed over to the longest waiting writer. When a
+/// writer leaves, the lock is handed over to all readers which are waiting at that point,
+/// or to the next writer if there are none. Readers and writers thus take turns under contention.
+///
+/// # Upgradeable reads
+///
+/// A single coroutine at a time may hold an upgradeable read access using `upgradeable_read()`,
+/// next to any number of ordinary readers. It can later be turned into write access without
+/// releasing the lock in between, so that no other writer can change the data after it has
+/// been inspected. While an upgrade is waiting for the other readers to leave, new readers
+/// queue up behind it.
+pub struct RwLock<T> {
+    data: UnsafeCell<T>,
+    state: Spinlock<RwState>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new reader-writer lock in an unlocked state ready for use.
+    pub fn new(data: T) -> RwLock<T> {
+        RwLock {
+            data: UnsafeCell::new(data),
+            state: Spinlock::new(RwState {
+                readers: 0,
+                writer: false,
+                upgradeable: false,
+                read_waiters: HandleList::new(),
+                write_waiters: HandleList::new(),
+                upgradeable_waiters: HandleList::new(),
+                upgrading: None,
+            }),
+        }
+    }
+
+    /// Acquires shared read access, blocking the current coroutine until it is able to do so.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<T>> {
+        let mut state = self.state.lock();
+
+        if state.can_read() {
+            state.readers += 1;
+        } else {
+            match Processor::current() {
+                Some(p) => {
+                    // The read access is transferred to us by the unlocking writer
+                    p.park_with(|_, coro| {
+                        state.read_waiters.push_back(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("RwLock will not work in thread environment"),
+            }
+        }
+
+        Ok(RwLockReadGuard { lock: self })
+    }
+
+    /// Try to acquire shared read access, will return immediately
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T>> {
+        let mut state = self.state.lock();
+
+        if state.can_read() {
+            state.readers += 1;
+            Ok(RwLockReadGuard { lock: self })
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Acquires upgradeable read access, blocking the current coroutine until it is able to do so.
+    ///
+    /// Only one coroutine at a time can hold it, but ordinary readers are not excluded.
+    pub fn upgradeable_read(&self) -> LockResult<RwLockUpgradeableGuard<T>> {
+        let mut state = self.state.lock();
+
+        if state.can_read() && !state.upgradeable {
+            state.upgradeable = true;
+            state.readers += 1;
+        } else {
+            match Processor::current() {
+                Some(p) => {
+                    // The access is transferred to us by the unlocking writer or upgradeable reader
+                    p.park_with(|_, coro| {
+                        state.upgradeable_waiters.push_back(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("RwLock will not work in thread environment"),
+            }
+        }
+
+        Ok(RwLockUpgradeableGuard { lock: self })
+    }
+
+    /// Acquires exclusive write access, blocking the current coroutine until it is able to do so.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<T>> {
+        let mut state = self.state.lock();
+
+        if !state.writer && state.readers == 0 {
+            state.writer = true;
+        } else {
+            match Processor::current() {
+                Some(p) => {
+                    // The write access is transferred to us by the unlocking reader or writer
+                    p.park_with(|_, coro| {
+                        state.write_waiters.push_back(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("RwLock will not work in thread environment"),
+            }
+        }
+
+        Ok(RwLockWriteGuard { lock: self })
+    }
+
+    /// Try to acquire exclusive write access, will return immediately
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<T>> {
+        let mut state = self.state.lock();
+
+        if !state.writer && state.readers == 0 {
+            state.writer = true;
+            Ok(RwLockWriteGuard { lock: self })
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    fn read_unlock(&self) {
+        let mut state = self.state.lock();
+
+        state.readers -= 1;
+
+        if state.readers == 1 && state.upgrading.is_some() {
+            // Only the upgrading coroutine is left
+            let coro = state.upgrading.take().unwrap();
+            state.readers = 0;
+            state.upgradeable = false;
+            state.writer = true;
+            Scheduler::ready(coro);
+        } else if state.readers == 0 {
+            if let Some(coro) = state.write_waiters.pop_front() {
+                state.writer = true;
+                Scheduler::ready(coro);
+            }
+        }
+    }
+
+    fn upgradeable_unlock(&self) {
+        let mut state = self.state.lock();
+
+        state.readers -= 1;
+        state.upgradeable = false;
+
+        if state.readers == 0 && !state.write_waiters.is_empty() {
+            let coro = state.write_waiters.pop_front().unwrap();
+            state.writer = true;
+            Scheduler::ready(coro);
+        } else if state.write_waiters.is_empty() {
+            if let Some(coro) = state.upgradeable_waiters.pop_front() {
+                state.upgradeable = true;
+                state.readers += 1;
+                Scheduler::ready(coro);
+            }
+        }
+    }
+
+    fn upgrade(&self) {
+        let mut state = self.state.lock();
+
+        if state.readers == 1 {
+            state.readers = 0;
+            state.upgradeable = false;
+            state.writer = true;
+        } else {
+            match Processor::current() {
+                Some(p) => {
+                    // The write access is transferred to us by the last leaving reader
+                    p.park_with(|_, coro| {
+                        state.upgrading = Some(coro);
+                        drop(state); // We _must_ to hold the lock until here
+                    });
+                }
+                None => panic!("RwLock will not work in thread environment"),
+            }
+        }
+    }
+
+    fn write_unlock(&self) {
+        let mut state = self.state.lock();
+
+        if state.read_waiters.is_empty() && state.upgradeable_waiters.is_empty() {
+            match state.write_waiters.pop_front() {
+                Some(coro) => Scheduler::ready(coro),
+                None => state.writer = false,
+            }
+        } else {
+            state.writer = false;
+
+            while let Some(coro) = state.read_waiters.pop_front() {
+                state.readers += 1;
+                Scheduler::ready(coro);
+            }
+
+            if let Some(coro) = state.upgradeable_waiters.pop_front() {
+                state.upgradeable = true;
+                state.readers += 1;
+                Scheduler::ready(coro);
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// An RAII guard for shared read access to a `RwLock`. When this structure is dropped,
+/// the read access is given back.
+#[must_use]
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: 'a> !Send for RwLockReadGuard<'a, T> {}
+
+impl<'a, T: 'a> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+/// An RAII guard for upgradeable read access to a `RwLock`. When this structure is dropped,
+/// the read access is given back.
+#[must_use]
+pub struct RwLockUpgradeableGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: 'a> RwLockUpgradeableGuard<'a, T> {
+    /// Turns the read access into write access, waiting for all other readers to leave.
+    ///
+    /// No writer is able to acquire the lock in between.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let lock = self.lock;
+        mem::forget(self);
+
+        lock.upgrade();
+        RwLockWriteGuard { lock: lock }
+    }
+}
+
+impl<'a, T: 'a> !Send for RwLockUpgradeableGuard<'a, T> {}
+
+impl<'a, T: 'a> Drop for RwLockUpgradeableGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.upgradeable_unlock();
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockUpgradeableGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+/// An RAII guard for exclusive write access to a `RwLock`. When this structure is dropped,
+/// the write access is given back.
+#[must_use]
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: 'a> !Send for RwLockWriteGuard<'a, T> {}
+
+impl<'a, T: 'a> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn rwlock_concurrent_readers() {
+        Scheduler::new()
+            .run(|| {
+                let lock = Arc::new(RwLock::new(0));
+                let active = Arc::new(AtomicUsize::new(0));
+                let max_active = Arc::new(AtomicUsize::new(0));
+
+                let handles = (0..10)
+                                  .map(|_| {
+                                      let lock = lock.clone();
+                                      let active = active.clone();
+                                      let max_active = max_active.clone();
+
+                                      Scheduler::spawn(move || {
+                                          let guard = lock.read().unwrap();
+                                          let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+
+                                          if n > max_active.load(Ordering::SeqCst) {
+                                              max_active.store(n, Ordering::SeqCst);
+                                          }
+
+                                          Scheduler::sched();
+                                          active.fetch_sub(1, Ordering::SeqCst);
+                                          *guard
+                                      })
+                                  })
+                                  .collect::<Vec<_>>();
+
+                for h in handles {
+                    assert_eq!(h.join().unwrap(), 0);
+                }
+
+                // Readers don't exclude each other
+                assert!(max_active.load(Ordering::SeqCst) > 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rwlock_exclusive_writers() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let lock = Arc::new(RwLock::new(0));
+
+                let handles = (0..100)
+                                  .map(|_| {
+                                      let lock = lock.clone();
+
+                                      Scheduler::spawn(move || {
+                                          let mut guard = lock.write().unwrap();
+                                          let value = *guard;
+                                          Scheduler::sched();
+                                          *guard = value + 1;
+                                      })
+                                  })
+                                  .collect::<Vec<_>>();
+
+                for h in handles {
+                    h.join().unwrap();
+                }
+
+                assert_eq!(*lock.read().unwrap(), 100);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rwlock_writer_not_starved() {
+        Scheduler::new()
+            .run(|| {
+                let lock = Arc::new(RwLock::new(false));
+
+                let reader = lock.read().unwrap();
+
+                let writer = {
+                    let lock = lock.clone();
+                    Scheduler::spawn(move || *lock.write().unwrap() = true)
+                };
+
+                // Let the writer queue up behind the active reader
+                Scheduler::sched();
+
+                // New readers must not overtake the waiting writer
+                assert!(lock.try_read().is_err());
+
+                let late_reader = {
+                    let lock = lock.clone();
+                    Scheduler::spawn(move || *lock.read().unwrap())
+                };
+
+                drop(reader);
+
+                writer.join().unwrap();
+                assert!(late_reader.join().unwrap());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rwlock_upgradeable_read() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let lock = Arc::new(RwLock::new(0));
+
+                let upgradeable = lock.upgradeable_read().unwrap();
+
+                // Ordinary readers are not excluded, but a second upgradeable reader and writers are
+                let reader = {
+                    let lock = lock.clone();
+                    Scheduler::spawn(move || {
+                        let guard = lock.read().unwrap();
+                        Scheduler::sched();
+                        *guard
+                    })
+                };
+                let other = {
+                    let lock = lock.clone();
+                    Scheduler::spawn(move || *lock.upgradeable_read().unwrap())
+                };
+                let writer = {
+                    let lock = lock.clone();
+                    Scheduler::spawn(move || *lock.write().unwrap() = 10)
+                };
+
+                Scheduler::sched();
+                assert!(lock.try_write().is_err());
+
+                {
+                    // Waits for the reader, but the waiting writer must not get in between
+                    let mut guard = upgradeable.upgrade();
+                    assert_eq!(*guard, 0);
+                    *guard += 1;
+                }
+
+                assert_eq!(reader.join().unwrap(), 0);
+                assert_eq!(other.join().unwrap(), 1);
+                writer.join().unwrap();
+                assert_eq!(*lock.read().unwrap(), 10);
+            })
+            .unwrap();
+    }
+}
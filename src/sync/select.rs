@@ -0,0 +1,227 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Waiting on multiple channels at once
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use runtime::Processor;
+use runtime::waiter::Waiter;
+use scheduler::Scheduler;
+
+#[doc(hidden)]
+pub type SelectWaiters = Vec<(Arc<Waiter>, usize)>;
+
+// Wakes up all `Select`s which have been waiting on a source. Any of them might win
+// the race for its own `Waiter`, so all of them have to be woken up and not just the first.
+#[doc(hidden)]
+pub fn wake_all(waiters: SelectWaiters) {
+    for (waiter, idx) in waiters {
+        waiter.wake(idx, Scheduler::ready);
+    }
+}
+
+/// A receiving channel end which can be waited on by `Select`
+pub trait SelectSource {
+    /// Registers `waiter` to be woken up with `idx` as soon as the source is ready.
+    ///
+    /// If the source is already ready, `waiter` is woken up right away and false is returned.
+    #[doc(hidden)]
+    fn add_selector(&self, waiter: &Arc<Waiter>, idx: usize) -> bool;
+
+    /// Removes a `waiter` which has been registered by `add_selector()`.
+    #[doc(hidden)]
+    fn remove_selector(&self, waiter: &Arc<Waiter>);
+}
+
+/// Waits until one of several channels is ready to be received from
+///
+/// A channel is ready if `try_recv()` is not going to return `TryRecvError::Empty`, i.e. it
+/// either holds an item or it has been disconnected. `Select` doesn't receive the item itself,
+/// which is left to the caller after `wait()` returned the index of the ready channel.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use coio::Scheduler;
+/// use coio::sync::Select;
+/// use coio::sync::mpsc::{channel, sync_channel};
+///
+/// Scheduler::new().run(|| {
+///     let (_tx1, rx1) = channel::<u32>();
+///     let (_tx2, rx2) = sync_channel::<String>(16);
+///
+///     let mut select = Select::new();
+///     let numbers = select.recv(&rx1);
+///     let strings = select.recv(&rx2);
+///
+///     match select.wait_timeout(Duration::from_secs(1)) {
+///         Some(idx) if idx == numbers => println!("{:?}", rx1.try_recv()),
+///         Some(idx) if idx == strings => println!("{:?}", rx2.try_recv()),
+///         _ => println!("timed out"),
+///     }
+/// }).unwrap();
+/// ```
+pub struct Select<'a> {
+    sources: Vec<&'a SelectSource>,
+}
+
+impl<'a> Select<'a> {
+    /// Creates a `Select` without any sources
+    pub fn new() -> Select<'a> {
+        Select { sources: Vec::new() }
+    }
+
+    /// Adds a receiving channel end and returns the index `wait()` will return for it
+    pub fn recv(&mut self, source: &'a SelectSource) -> usize {
+        self.sources.push(source);
+        self.sources.len() - 1
+    }
+
+    /// Blocks the current coroutine until one of the sources is ready and returns its index
+    ///
+    /// If several sources are ready at once, the one which has been added first wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no source has been added or if called outside of a coroutine.
+    pub fn wait(&self) -> usize {
+        self.wait_inner(None).expect("Select resumed without a ready source")
+    }
+
+    /// Like `wait()`, but returns `None` once `timeout` has elapsed without any source being ready
+    ///
+    /// `None` is returned as well if the coroutine is cancelled while waiting.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<usize> {
+        self.wait_inner(Some(timeout))
+    }
+
+    fn wait_inner(&self, timeout: Option<Duration>) -> Option<usize> {
+        assert!(!self.sources.is_empty(), "cannot select without any source");
+
+        let waiter = match timeout {
+            None => {
+                let waiter = Arc::new(Waiter::new());
+                let p = Processor::current().expect("cannot select without processor");
+
+                p.park_with(|p, coro| {
+                    self.register(&waiter);
+
+                    if let Some(coro) = waiter.arm(coro) {
+                        p.ready(coro);
+                    }
+                });
+
+                waiter
+            }
+            Some(timeout) => {
+                let mut own_waiter = None;
+
+                Scheduler::park_with_timeout(timeout, |_, waiter| {
+                    self.register(&waiter);
+                    own_waiter = Some(waiter);
+                });
+
+                own_waiter.expect("park_with_timeout() didn't pass a Waiter")
+            }
+        };
+
+        // Sources which didn't win still hold our Waiter
+        for source in &self.sources {
+            source.remove_selector(&waiter);
+        }
+
+        waiter.fired().and_then(|idx| if idx < self.sources.len() { Some(idx) } else { None })
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        for (idx, source) in self.sources.iter().enumerate() {
+            if !source.add_selector(waiter, idx) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use scheduler::Scheduler;
+    use sync::mpsc::{channel, sync_channel, TryRecvError};
+
+    #[test]
+    fn select_picks_ready_channel() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let (tx1, rx1) = sync_channel::<i32>(1);
+                let (tx2, rx2) = channel();
+
+                let h = Scheduler::spawn(move || {
+                    tx2.send("hello").unwrap();
+                    tx1
+                });
+
+                let idx = {
+                    let mut select = Select::new();
+                    select.recv(&rx1);
+                    select.recv(&rx2);
+                    select.wait()
+                };
+
+                assert_eq!(idx, 1);
+                assert_eq!(rx2.try_recv(), Ok("hello"));
+                assert_eq!(rx1.try_recv(), Err(TryRecvError::Empty));
+
+                drop(h.join().unwrap());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn select_disconnect_is_ready() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = sync_channel::<i32>(1);
+
+                let h = Scheduler::spawn(move || drop(tx));
+
+                let mut select = Select::new();
+                select.recv(&rx);
+                assert_eq!(select.wait(), 0);
+                assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+                h.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn select_wait_timeout() {
+        Scheduler::new()
+            .run(|| {
+                let (_tx1, rx1) = sync_channel::<i32>(1);
+                let (tx2, rx2) = channel::<i32>();
+
+                let mut select = Select::new();
+                select.recv(&rx1);
+                select.recv(&rx2);
+                assert_eq!(select.wait_timeout(Duration::from_millis(50)), None);
+
+                // The timed out Select must not have left anything behind
+                tx2.send(1).unwrap();
+                assert_eq!(select.wait_timeout(Duration::from_secs(10)), Some(1));
+                assert_eq!(rx2.recv(), Ok(1));
+            })
+            .unwrap();
+    }
+}
@@ -8,23 +8,31 @@
 
 //! Semaphore for Coroutines
 
-use coroutine::HandleList;
+use std::mem;
+
+use runtime::coroutine::HandleList;
 use scheduler::Scheduler;
 use runtime::Processor;
 
 use super::spinlock::Spinlock;
 
-/// Semaphore
+/// A counting semaphore for coroutines
+///
+/// Waiting coroutines are served in FIFO order: A released permit is handed over directly
+/// to the coroutine which has been waiting the longest, so that newly arriving ones
+/// can't overtake it and no coroutine starves under steady contention.
 pub struct Semaphore(Spinlock<(usize, HandleList)>);
 
 impl Semaphore {
-    /// Create a semaphore by providing an initial count.
-    pub fn new(count: usize) -> Semaphore {
-        Semaphore(Spinlock::new((count, HandleList::new())))
+    /// Create a semaphore by providing the initial number of permits.
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore(Spinlock::new((permits, HandleList::new())))
     }
 
-    /// Semaphore acquire (or down, P). If the counter is 0, block the current coroutine.
-    pub fn acquire(&self) {
+    /// Semaphore acquire (or down, P). If no permits are left, block the current coroutine.
+    ///
+    /// The permit is given back as soon as the returned `SemaphorePermit` is dropped.
+    pub fn acquire(&self) -> SemaphorePermit {
         let mut inner = self.0.lock();
 
         if inner.0 > 0 {
@@ -32,6 +40,7 @@ impl Semaphore {
         } else {
             match Processor::current() {
                 Some(p) => {
+                    // The permit is transferred to us by release()
                     p.park_with(|_, coro| {
                         inner.1.push_back(coro);
                         drop(inner); // We _must_ to hold the lock until here
@@ -44,24 +53,25 @@ impl Semaphore {
                 }
             }
         }
+
+        SemaphorePermit { sema: self }
     }
 
     /// Semaphore acquire (or down, P). Return immediately no matter success or not.
-    pub fn try_acquire(&self) -> bool {
-        let mut inner = match self.0.try_lock() {
-            Some(inner) => inner,
-            None => return false,
-        };
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        let mut inner = self.0.lock();
 
         if inner.0 > 0 {
             inner.0 -= 1;
-            true
+            Some(SemaphorePermit { sema: self })
         } else {
-            false
+            None
         }
     }
 
-    /// Semaphore release (or up, V).
+    /// Semaphore release (or up, V). Adds a permit to the semaphore.
+    ///
+    /// This is done automatically when a `SemaphorePermit` is dropped.
     pub fn release(&self) {
         let mut inner = self.0.lock();
 
@@ -76,6 +86,26 @@ impl Semaphore {
 unsafe impl Send for Semaphore {}
 unsafe impl Sync for Semaphore {}
 
+/// An RAII guard for a permit acquired from a `Semaphore`.
+/// When this structure is dropped, the permit is released and the next waiter is readied.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    sema: &'a Semaphore,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Consumes the permit without giving it back to the semaphore.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        self.sema.release();
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -95,10 +125,10 @@ mod test {
                 for id in 0..10 {
                     let sema = sema.clone();
                     let h = Scheduler::spawn(move || {
-                        sema.acquire();
+                        let permit = sema.acquire();
                         trace!("{} Acquired", id);
                         Scheduler::sched();
-                        sema.release();
+                        drop(permit);
                         trace!("{} Released", id);
                     });
                     hlist.push(h);
@@ -122,10 +152,10 @@ mod test {
                 for id in 0..10 {
                     let sema = sema.clone();
                     let h = Scheduler::spawn(move || {
-                        sema.acquire();
+                        let permit = sema.acquire();
                         trace!("{} Acquired", id);
                         Scheduler::sched();
-                        sema.release();
+                        drop(permit);
                         trace!("{} Released", id);
                     });
                     hlist.push(h);
@@ -137,4 +167,51 @@ mod test {
             })
             .unwrap();
     }
+
+    #[test]
+    fn semaphore_try_acquire() {
+        let sema = Semaphore::new(1);
+
+        {
+            let _permit = sema.try_acquire().unwrap();
+            assert!(sema.try_acquire().is_none());
+        }
+
+        assert!(sema.try_acquire().is_some());
+    }
+
+    #[test]
+    fn semaphore_fifo_order() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let sema = Arc::new(Semaphore::new(1));
+                let order = Arc::new(::std::sync::Mutex::new(Vec::new()));
+
+                let permit = sema.acquire();
+                let mut hlist = Vec::new();
+
+                for id in 0..5 {
+                    let sema = sema.clone();
+                    let order = order.clone();
+                    let h = Scheduler::spawn(move || {
+                        let _permit = sema.acquire();
+                        order.lock().unwrap().push(id);
+                    });
+                    hlist.push(h);
+
+                    // Let the new coroutine run until it parks in acquire()
+                    Scheduler::sched();
+                }
+
+                drop(permit);
+
+                for h in hlist {
+                    h.join().unwrap();
+                }
+
+                assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+            })
+            .unwrap();
+    }
 }
@@ -0,0 +1,140 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sequence lock for read-mostly data
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+
+use super::spinlock::{cpu_relax, Spinlock};
+
+/// A sequence lock for small `Copy` values which are read far more often than written
+///
+/// Readers never take a lock and never make writers wait. Instead they copy the value and
+/// retry if a writer changed it in the meantime, which is detected using a sequence counter
+/// that is odd while a write is in progress. Writers are serialized by a spinlock.
+///
+/// Since readers spin while a write is in progress, writes should be short and rare.
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+    writer: Spinlock<()>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Creates a new `SeqLock` holding `data`
+    pub fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+            writer: Spinlock::new(()),
+        }
+    }
+
+    /// Returns a copy of the current value
+    pub fn read(&self) -> T {
+        loop {
+            let seq1 = self.seq.load(Ordering::Acquire);
+
+            if seq1 & 1 != 0 {
+                cpu_relax();
+                continue;
+            }
+
+            // The copy might be torn, but it's thrown away in that case
+            let data = unsafe { ptr::read_volatile(self.data.get()) };
+            atomic::fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == seq1 {
+                return data;
+            }
+        }
+    }
+
+    /// Replaces the current value with `data`
+    pub fn write(&self, data: T) {
+        self.update(|value| *value = data);
+    }
+
+    /// Modifies the current value in place using `f`
+    ///
+    /// `f` should be short, since all readers spin while it runs.
+    pub fn update<F>(&self, f: F)
+        where F: FnOnce(&mut T)
+    {
+        let _guard = self.writer.lock();
+
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+
+        f(unsafe { &mut *self.data.get() });
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> SeqLock<T> {
+        SeqLock::new(Default::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SeqLock {{ data: {:?} }}", self.read())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn seqlock_reads_are_consistent() {
+        let lock = Arc::new(SeqLock::new((0usize, 0usize)));
+
+        let readers = (0..4)
+                          .map(|_| {
+                              let lock = lock.clone();
+
+                              thread::spawn(move || {
+                                  loop {
+                                      let (a, b) = lock.read();
+                                      assert_eq!(a, b);
+
+                                      if a == 10000 {
+                                          break;
+                                      }
+                                  }
+                              })
+                          })
+                          .collect::<Vec<_>>();
+
+        for i in 1..10001 {
+            lock.update(|value| {
+                value.0 = i;
+                value.1 = i;
+            });
+        }
+
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        assert_eq!(lock.read(), (10000, 10000));
+    }
+}
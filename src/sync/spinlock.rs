@@ -8,13 +8,16 @@
 
 //! A simple Spinlock
 
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
 use std::fmt;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+#[doc(hidden)]
 #[inline(always)]
-fn cpu_relax() {
+pub fn cpu_relax() {
     if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
         unsafe {
             // "Modern" processors exiting a tight loop (like this one)
@@ -63,7 +66,8 @@ impl<T: ?Sized> Spinlock<T> {
         const SUCCESS: Ordering = Ordering::Acquire;
         const FAILURE: Ordering = Ordering::Relaxed;
 
-        match self.lock.compare_exchange_weak(false, true, SUCCESS, FAILURE) {
+        // A strong exchange, so that an unlocked lock is never reported as being held
+        match self.lock.compare_exchange(false, true, SUCCESS, FAILURE) {
             Ok(_) => Some(SpinlockGuard(&self.lock, unsafe { &mut *self.data.get() })),
             Err(_) => None,
         }
@@ -91,6 +95,50 @@ impl<T: ?Sized> Spinlock<T> {
 
         SpinlockGuard(&self.lock, unsafe { &mut *self.data.get() })
     }
+
+    /// Like `lock()`, but gives up after spinning `spins` times without acquiring the lock.
+    ///
+    /// The lock is tried once more after every spin, so `try_lock_spins(0)` is the same as
+    /// `try_lock()` and `try_lock_spins(n)` tries to acquire the lock `n + 1` times.
+    ///
+    /// This allows coroutines to yield to the scheduler under heavy contention,
+    /// instead of burning the worker thread.
+    pub fn try_lock_spins(&self, spins: usize) -> Option<SpinlockGuard<T>> {
+        self.try_lock_backoff(|failed| failed <= spins)
+    }
+
+    /// Like `lock()`, but gives up once `timeout` has elapsed.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<SpinlockGuard<T>> {
+        let start = Instant::now();
+        self.try_lock_backoff(|_| start.elapsed() < timeout)
+    }
+
+    // Spins with the same backoff as `lock()` for as long as `retry()`,
+    // which is passed the number of failed attempts so far, returns true
+    fn try_lock_backoff<F>(&self, mut retry: F) -> Option<SpinlockGuard<T>>
+        where F: FnMut(usize) -> bool
+    {
+        let mut backoff = BACKOFF_BASE;
+        let mut failed = 0;
+
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+
+            failed += 1;
+
+            if !retry(failed) {
+                return None;
+            }
+
+            for _ in 0..backoff {
+                cpu_relax();
+            }
+
+            backoff <<= (backoff != BACKOFF_CEILING) as usize;
+        }
+    }
 }
 
 impl<T: ?Sized + Default> Default for Spinlock<T> {
@@ -136,9 +184,9 @@ impl<'a, T: ?Sized> DerefMut for SpinlockGuard<'a, T> {
 ///
 /// This lock has a similiar performance to `std::sync::Mutex`, and thus gets slower about 5x
 /// faster than `Spinlock`, but guarantees fairness which a `Mutex` surprisingly does not.
-// TODO:
-//   The CHL or MCS lock would theoretically be much faster the more cores a system has,
-//   but initial tests showed a slow down instead.
+///
+/// All waiters spin on the same counter, which is why it scales poorly with many cores.
+/// Use `QueueSpinlock` in that case.
 pub struct TicketSpinlock<T: ?Sized> {
     tick: AtomicUsize,
     tock: AtomicUsize,
@@ -216,3 +264,538 @@ impl<'a, T: ?Sized> DerefMut for TicketSpinlockGuard<'a, T> {
         self.2
     }
 }
+
+const CACHE_LINE_SIZE: usize = 64;
+
+struct QueueNode {
+    next: AtomicPtr<QueueNode>,
+    locked: AtomicBool,
+}
+
+// A QueueNode placed at the start of a cache line of its own, so that waiters don't spin on
+// shared lines. A Box only guarantees the alignment of the node's fields, which is why
+// the node is put at the first aligned address within a buffer of two cache lines.
+struct AlignedNode {
+    _buf: Box<[u8; 2 * CACHE_LINE_SIZE]>,
+    node: *mut QueueNode,
+}
+
+impl AlignedNode {
+    fn new() -> AlignedNode {
+        let mut buf = Box::new([0u8; 2 * CACHE_LINE_SIZE]);
+
+        let addr = buf.as_mut_ptr() as usize;
+        let offset = (CACHE_LINE_SIZE - addr % CACHE_LINE_SIZE) % CACHE_LINE_SIZE;
+        let node = unsafe { buf.as_mut_ptr().offset(offset as isize) as *mut QueueNode };
+
+        unsafe {
+            ptr::write(node,
+                       QueueNode {
+                           next: AtomicPtr::new(ptr::null_mut()),
+                           locked: AtomicBool::new(false),
+                       });
+        }
+
+        AlignedNode {
+            _buf: buf,
+            node: node,
+        }
+    }
+}
+
+impl Deref for AlignedNode {
+    type Target = QueueNode;
+
+    fn deref(&self) -> &QueueNode {
+        unsafe { &*self.node }
+    }
+}
+
+thread_local! {
+    // Nodes are reused, since allocating one for every acquisition would dominate the cost
+    static QUEUE_NODES: RefCell<Vec<AlignedNode>> = RefCell::new(Vec::new())
+}
+
+fn take_queue_node() -> AlignedNode {
+    let node = QUEUE_NODES.with(|nodes| nodes.borrow_mut().pop());
+    node.unwrap_or_else(AlignedNode::new)
+}
+
+fn return_queue_node(node: AlignedNode) {
+    QUEUE_NODES.with(|nodes| nodes.borrow_mut().push(node));
+}
+
+/// A fair spinlock using the MCS queue lock algorithm.
+///
+/// Waiters form a queue in which every one of them spins on a flag of its own, which is set by
+/// its predecessor when unlocking. Unlike with `TicketSpinlock` an unlock thus only touches the
+/// cache line of the next waiter, which keeps the lock scalable on systems with many cores.
+/// For a low number of threads it is slower than the other spinlocks though.
+pub struct QueueSpinlock<T: ?Sized> {
+    tail: AtomicPtr<QueueNode>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for QueueSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for QueueSpinlock<T> {}
+
+impl<T> QueueSpinlock<T> {
+    pub fn new(data: T) -> QueueSpinlock<T> {
+        QueueSpinlock {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> QueueSpinlock<T> {
+    pub fn try_lock(&self) -> Option<QueueSpinlockGuard<T>> {
+        let node = take_queue_node();
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+
+        let node_ptr = node.node;
+
+        match self.tail.compare_exchange(ptr::null_mut(),
+                                         node_ptr,
+                                         Ordering::Acquire,
+                                         Ordering::Relaxed) {
+            Ok(_) => {
+                Some(QueueSpinlockGuard {
+                    lock: self,
+                    node: Some(node),
+                })
+            }
+            Err(_) => {
+                return_queue_node(node);
+                None
+            }
+        }
+    }
+
+    pub fn lock(&self) -> QueueSpinlockGuard<T> {
+        let node = take_queue_node();
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.locked.store(true, Ordering::Relaxed);
+
+        let node_ptr = node.node;
+        let prev = self.tail.swap(node_ptr, Ordering::AcqRel);
+
+        if !prev.is_null() {
+            unsafe { (*prev).next.store(node_ptr, Ordering::Release) };
+
+            while node.locked.load(Ordering::Acquire) {
+                cpu_relax();
+            }
+        }
+
+        QueueSpinlockGuard {
+            lock: self,
+            node: Some(node),
+        }
+    }
+
+    fn unlock(&self, node: AlignedNode) {
+        let node_ptr = node.node;
+        let mut next = node.next.load(Ordering::Acquire);
+
+        if next.is_null() {
+            // No one has queued up behind us yet
+            if self.tail
+                   .compare_exchange(node_ptr, ptr::null_mut(), Ordering::Release, Ordering::Relaxed)
+                   .is_ok() {
+                return_queue_node(node);
+                return;
+            }
+
+            // Someone swapped the tail, but didn't link itself to our node yet
+            loop {
+                next = node.next.load(Ordering::Acquire);
+
+                if !next.is_null() {
+                    break;
+                }
+
+                cpu_relax();
+            }
+        }
+
+        unsafe { (*next).locked.store(false, Ordering::Release) };
+        return_queue_node(node);
+    }
+}
+
+impl<T: ?Sized + Default> Default for QueueSpinlock<T> {
+    fn default() -> QueueSpinlock<T> {
+        QueueSpinlock::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for QueueSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "QueueSpinlock {{ data: {:?} }}", &*guard),
+            None => write!(f, "QueueSpinlock {{ <locked> }}"),
+        }
+    }
+}
+
+pub struct QueueSpinlockGuard<'a, T: ?Sized + 'a> {
+    lock: &'a QueueSpinlock<T>,
+    node: Option<AlignedNode>,
+}
+
+impl<'a, T: ?Sized> !Send for QueueSpinlockGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Drop for QueueSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        let node = self.node.take().unwrap();
+        self.lock.unlock(node);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for QueueSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for QueueSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+const RW_WRITER: usize = 1;
+const RW_WRITER_WAITING: usize = 1 << 1;
+const RW_READER: usize = 1 << 2;
+
+/// A reader-writer spinlock for short, read-dominated critical sections.
+///
+/// Any number of readers or a single writer may hold the lock at the same time.
+/// As soon as a writer is waiting, newly arriving readers spin until it acquired the lock,
+/// so that a steady stream of readers can't starve the writers.
+pub struct RwSpinlock<T: ?Sized> {
+    // The reader count in the upper bits, followed by RW_WRITER_WAITING and RW_WRITER
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
+
+impl<T> RwSpinlock<T> {
+    pub fn new(data: T) -> RwSpinlock<T> {
+        RwSpinlock {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    pub fn try_read(&self) -> Option<RwSpinlockReadGuard<T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            if state & (RW_WRITER | RW_WRITER_WAITING) != 0 {
+                return None;
+            }
+
+            // Only fails if the state has changed, e.g. because of another reader
+            match self.state.compare_exchange(state,
+                                              state + RW_READER,
+                                              Ordering::Acquire,
+                                              Ordering::Relaxed) {
+                Ok(_) => return Some(RwSpinlockReadGuard(self)),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    pub fn read(&self) -> RwSpinlockReadGuard<T> {
+        let mut backoff = BACKOFF_BASE;
+
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+
+            for _ in 0..backoff {
+                cpu_relax();
+            }
+
+            backoff <<= (backoff != BACKOFF_CEILING) as usize;
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwSpinlockWriteGuard<T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        // Only a waiting writer may be present, whose flag is taken over by us
+        if state & !RW_WRITER_WAITING != 0 {
+            return None;
+        }
+
+        match self.state.compare_exchange(state, RW_WRITER, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(RwSpinlockWriteGuard(self)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn write(&self) -> RwSpinlockWriteGuard<T> {
+        let mut backoff = BACKOFF_BASE;
+
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+
+            // Keeps new readers out, until we got the lock.
+            // Other waiting writers set the flag again after we acquired it.
+            if self.state.load(Ordering::Relaxed) & RW_WRITER_WAITING == 0 {
+                self.state.fetch_or(RW_WRITER_WAITING, Ordering::Relaxed);
+            }
+
+            for _ in 0..backoff {
+                cpu_relax();
+            }
+
+            backoff <<= (backoff != BACKOFF_CEILING) as usize;
+        }
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwSpinlock<T> {
+    fn default() -> RwSpinlock<T> {
+        RwSpinlock::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwSpinlock {{ data: {:?} }}", &*guard),
+            None => write!(f, "RwSpinlock {{ <locked> }}"),
+        }
+    }
+}
+
+pub struct RwSpinlockReadGuard<'a, T: ?Sized + 'a>(&'a RwSpinlock<T>);
+
+impl<'a, T: ?Sized> !Send for RwSpinlockReadGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Drop for RwSpinlockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.0.state.fetch_sub(RW_READER, Ordering::Release);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinlockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.0.data.get() }
+    }
+}
+
+pub struct RwSpinlockWriteGuard<'a, T: ?Sized + 'a>(&'a RwSpinlock<T>);
+
+impl<'a, T: ?Sized> !Send for RwSpinlockWriteGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Drop for RwSpinlockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.0.state.fetch_and(!RW_WRITER, Ordering::Release);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinlockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.0.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwSpinlockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.0.data.get() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    const THREADS: usize = 4;
+    const ITERS: usize = 10_000;
+
+    // Every thread increments the counter with a non-atomic read-modify-write,
+    // so that a lock which lets two of them in at once loses updates.
+    fn contend<L, F>(lock: Arc<L>, increment: F) -> usize
+        where L: Send + Sync + 'static,
+              F: Fn(&L) -> usize + Send + Sync + Copy + 'static
+    {
+        let threads = (0..THREADS)
+                          .map(|_| {
+                              let lock = lock.clone();
+
+                              thread::spawn(move || {
+                                  for _ in 0..ITERS {
+                                      increment(&lock);
+                                  }
+                              })
+                          })
+                          .collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        increment(&lock)
+    }
+
+    #[test]
+    fn spinlock_contention() {
+        let lock = Arc::new(Spinlock::new(0));
+        let n = contend(lock, |lock| {
+            let mut guard = lock.lock();
+            let n = *guard;
+            *guard = n + 1;
+            n
+        });
+        assert_eq!(n, THREADS * ITERS);
+    }
+
+    #[test]
+    fn spinlock_try_lock_spins() {
+        let lock = Spinlock::new(0);
+
+        *lock.try_lock_spins(0).unwrap() += 1;
+        *lock.try_lock_spins(3).unwrap() += 1;
+
+        {
+            let _guard = lock.lock();
+            assert!(lock.try_lock_spins(0).is_none());
+            assert!(lock.try_lock_spins(3).is_none());
+            assert!(lock.try_lock_for(Duration::from_millis(1)).is_none());
+        }
+
+        assert_eq!(*lock.try_lock_for(Duration::from_millis(1)).unwrap(), 2);
+    }
+
+    #[test]
+    fn spinlock_try_lock_for_waits_for_release() {
+        let lock = Arc::new(Spinlock::new(0));
+        let guard = lock.lock();
+
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.try_lock_for(Duration::from_secs(10)).unwrap())
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+
+        assert_eq!(t.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn ticket_spinlock_contention() {
+        let lock = Arc::new(TicketSpinlock::new(0));
+        let n = contend(lock, |lock| {
+            let mut guard = lock.lock();
+            let n = *guard;
+            *guard = n + 1;
+            n
+        });
+        assert_eq!(n, THREADS * ITERS);
+    }
+
+    #[test]
+    fn queue_spinlock_contention() {
+        let lock = Arc::new(QueueSpinlock::new(0));
+        let n = contend(lock, |lock| {
+            let mut guard = lock.lock();
+            let n = *guard;
+            *guard = n + 1;
+            n
+        });
+        assert_eq!(n, THREADS * ITERS);
+    }
+
+    #[test]
+    fn queue_spinlock_try_lock() {
+        let lock = QueueSpinlock::new(0);
+
+        {
+            let _guard = lock.lock();
+            assert!(lock.try_lock().is_none());
+        }
+
+        *lock.try_lock().unwrap() += 1;
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test]
+    fn queue_spinlock_nodes_are_aligned() {
+        let node = take_queue_node();
+        assert_eq!(node.node as usize % CACHE_LINE_SIZE, 0);
+        return_queue_node(node);
+    }
+
+    #[test]
+    fn rw_spinlock_exclusion() {
+        let lock = RwSpinlock::new(0);
+
+        {
+            let _read = lock.read();
+            assert!(lock.try_read().is_some());
+            assert!(lock.try_write().is_none());
+        }
+
+        {
+            let _write = lock.write();
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+        }
+
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn rw_spinlock_contention() {
+        // Writers keep both values equal, which readers would see violated without exclusion
+        let lock = Arc::new(RwSpinlock::new((0usize, 0usize)));
+
+        let threads = (0..THREADS)
+                          .map(|i| {
+                              let lock = lock.clone();
+
+                              thread::spawn(move || {
+                                  for _ in 0..ITERS {
+                                      if i % 2 == 0 {
+                                          let mut guard = lock.write();
+                                          let pair = &mut *guard;
+                                          pair.0 += 1;
+                                          pair.1 += 1;
+                                      } else {
+                                          let (a, b) = *lock.read();
+                                          assert_eq!(a, b);
+                                      }
+                                  }
+                              })
+                          })
+                          .collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(*lock.read(), (THREADS / 2 * ITERS, THREADS / 2 * ITERS));
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Go-style wait group for Coroutines
+
+use std::mem;
+
+use runtime::coroutine::HandleList;
+use scheduler::Scheduler;
+use runtime::Processor;
+
+use super::spinlock::Spinlock;
+
+struct WaitGroupInner {
+    count: usize,
+    waiters: HandleList,
+}
+
+/// Waits for a dynamically growing set of coroutines to finish
+///
+/// Call `add()` before spawning a coroutine and let the coroutine call `done()` when it's
+/// finished. `wait()` blocks until the counter drops to zero, no matter how many coroutines
+/// have been counted in the meantime.
+pub struct WaitGroup(Spinlock<WaitGroupInner>);
+
+impl WaitGroup {
+    /// Create a `WaitGroup` with a counter of zero
+    pub fn new() -> WaitGroup {
+        WaitGroup(Spinlock::new(WaitGroupInner {
+            count: 0,
+            waiters: HandleList::new(),
+        }))
+    }
+
+    /// Increments the counter by `n`
+    pub fn add(&self, n: usize) {
+        self.0.lock().count += n;
+    }
+
+    /// Decrements the counter and wakes up all waiting coroutines if it drops to zero
+    ///
+    /// # Panics
+    ///
+    /// Panics if the counter is already zero.
+    pub fn done(&self) {
+        let mut waiters = {
+            let mut inner = self.0.lock();
+
+            assert!(inner.count > 0, "WaitGroup::done() called more often than add()");
+            inner.count -= 1;
+
+            if inner.count > 0 {
+                return;
+            }
+
+            mem::replace(&mut inner.waiters, HandleList::new())
+        };
+
+        while let Some(coro) = waiters.pop_front() {
+            Scheduler::ready(coro);
+        }
+    }
+
+    /// Blocks the current coroutine until the counter is zero
+    pub fn wait(&self) {
+        let mut inner = self.0.lock();
+
+        if inner.count == 0 {
+            return;
+        }
+
+        match Processor::current() {
+            Some(p) => {
+                p.park_with(|_, coro| {
+                    inner.waiters.push_back(coro);
+                    drop(inner); // We _must_ to hold the lock until here
+                });
+            }
+            None => panic!("WaitGroup will not work in thread environment"),
+        }
+    }
+
+    /// Returns the current value of the counter
+    pub fn count(&self) -> usize {
+        self.0.lock().count
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+unsafe impl Send for WaitGroup {}
+unsafe impl Sync for WaitGroup {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn wait_group_waits_for_nested_children() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let wg = Arc::new(WaitGroup::new());
+                let counter = Arc::new(AtomicUsize::new(0));
+
+                for _ in 0..10 {
+                    let wg = wg.clone();
+                    let counter = counter.clone();
+
+                    wg.add(1);
+                    Scheduler::spawn(move || {
+                        // Children may spawn further children before being done
+                        for _ in 0..10 {
+                            let wg_inner = wg.clone();
+                            let counter = counter.clone();
+
+                            wg.add(1);
+                            Scheduler::spawn(move || {
+                                counter.fetch_add(1, Ordering::SeqCst);
+                                wg_inner.done();
+                            });
+                        }
+
+                        wg.done();
+                    });
+                }
+
+                wg.wait();
+                assert_eq!(counter.load(Ordering::SeqCst), 100);
+                assert_eq!(wg.count(), 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn wait_group_zero_returns_immediately() {
+        let wg = WaitGroup::new();
+        wg.wait();
+    }
+}
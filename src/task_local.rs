@@ -0,0 +1,169 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Coroutine local storage
+//!
+//! `thread_local!` values are shared by all coroutines running on the same worker thread.
+//! Values declared with `task_local!` are instead stored in the current coroutine and each
+//! coroutine lazily initializes its own copy on first access. The values are dropped as soon as
+//! the coroutine has finished.
+//!
+//! ```ignore
+//! task_local!(static REQUEST_ID: Cell<usize> = Cell::new(0));
+//!
+//! REQUEST_ID.with(|id| id.set(42));
+//! ```
+
+use std::any::Any;
+
+use runtime::coroutine::Coroutine;
+use runtime::Processor;
+
+/// Declares a new task-local key of type `coio::task_local::LocalKey`
+#[macro_export]
+macro_rules! task_local {
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr) => (
+        $(#[$attr])*
+        static $name: $crate::task_local::LocalKey<$t> = {
+            fn __init() -> $t {
+                $init
+            }
+
+            $crate::task_local::LocalKey { init: __init }
+        };
+    );
+    ($(#[$attr:meta])* pub static $name:ident: $t:ty = $init:expr) => (
+        $(#[$attr])*
+        pub static $name: $crate::task_local::LocalKey<$t> = {
+            fn __init() -> $t {
+                $init
+            }
+
+            $crate::task_local::LocalKey { init: __init }
+        };
+    );
+}
+
+/// A key for a coroutine local value, declared using `task_local!`
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub init: fn() -> T,
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    /// Calls `f` with a reference to the value of the current coroutine
+    ///
+    /// The value is initialized the first time it is accessed by a coroutine.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it's not called from within a coroutine.
+    pub fn with<F, R>(&'static self, f: F) -> R
+        where F: FnOnce(&T) -> R
+    {
+        let key = self as *const LocalKey<T> as usize;
+
+        let coro = {
+            let mut p = Processor::current()
+                            .expect("cannot access a task-local value outside of a coroutine");
+            let coro = p.current()
+                        .expect("cannot access a task-local value outside of a coroutine");
+            &mut **coro as *mut Coroutine
+        };
+
+        // NOTE:
+        //   The values are boxed and never removed before the coroutine finishes,
+        //   which is why the reference stays valid even if `init` or `f` access other keys.
+        let value = unsafe {
+            if (*coro).task_local(key).is_none() {
+                let value: Box<Any> = Box::new((self.init)());
+                (*coro).insert_task_local(key, value);
+            }
+
+            let value = (*coro).task_local(key).expect("task-local value must be initialized");
+            value.downcast_ref::<T>().expect("task-local value has a wrong type") as *const T
+        };
+
+        f(unsafe { &*value })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    task_local!(static COUNTER: Cell<usize> = Cell::new(0));
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    task_local!(static DROP_COUNTER: Cell<Option<DropCounter>> = Cell::new(None));
+
+    #[test]
+    fn task_local_per_coroutine() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let handles = (0..10)
+                                  .map(|i| {
+                                      Scheduler::spawn(move || {
+                                          for _ in 0..i {
+                                              COUNTER.with(|c| c.set(c.get() + 1));
+                                              Scheduler::sched();
+                                          }
+
+                                          COUNTER.with(|c| c.get())
+                                      })
+                                  })
+                                  .collect::<Vec<_>>();
+
+                for (i, h) in handles.into_iter().enumerate() {
+                    assert_eq!(h.join().unwrap(), i);
+                }
+
+                COUNTER.with(|c| assert_eq!(c.get(), 0));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn task_local_dropped_on_finish() {
+        Scheduler::new()
+            .run(|| {
+                let dropped = Arc::new(AtomicUsize::new(0));
+                let cloned = dropped.clone();
+
+                // With a single worker the spawned coroutine is finished (and its values are
+                // dropped) before the joining coroutine is resumed.
+
+                Scheduler::spawn(move || {
+                    DROP_COUNTER.with(|d| d.set(Some(DropCounter(cloned))));
+                })
+                    .join()
+                    .unwrap();
+
+                assert_eq!(dropped.load(Ordering::SeqCst), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a coroutine")]
+    fn task_local_without_coroutine() {
+        COUNTER.with(|_| {});
+    }
+}
@@ -53,6 +53,51 @@ fn test_tcp_echo() {
         .unwrap();
 }
 
+#[test]
+fn test_tcp_split() {
+    use coio::net::tcp;
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            // Echo back everything until EOF and close the connection afterwards
+            let listen_fut = Scheduler::spawn(move || {
+                let (stream, _) = acceptor.accept().unwrap();
+                let (mut reader, mut writer) = stream.split();
+
+                let mut buf = [0u8; 1024];
+                while let Ok(len) = reader.read(&mut buf) {
+                    if len == 0 {
+                        break;
+                    }
+
+                    writer.write_all(&buf[..len]).unwrap();
+                }
+            });
+
+            let stream = TcpStream::connect(addr).unwrap();
+            let (mut reader, mut writer) = stream.split();
+
+            let writer_fut = Scheduler::spawn(move || {
+                writer.write_all(b"abcdefg").unwrap();
+                writer.shutdown_write().unwrap();
+                writer
+            });
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf[..], b"abcdefg");
+
+            let writer = writer_fut.join().unwrap();
+            tcp::reunite(reader, writer).unwrap();
+
+            listen_fut.join().unwrap();
+        })
+        .unwrap();
+}
+
 #[test]
 fn test_udp_echo() {
     Scheduler::new()
@@ -125,3 +170,455 @@ fn test_unix_socket_echo() {
         })
         .unwrap();
 }
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_socket_options() {
+    use std::time::Duration;
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            acceptor.set_ttl(42).unwrap();
+            assert_eq!(acceptor.ttl().unwrap(), 42);
+
+            let stream = TcpStream::connect(addr).unwrap();
+
+            stream.set_nodelay(true).unwrap();
+            assert_eq!(stream.nodelay().unwrap(), true);
+            stream.set_nodelay(false).unwrap();
+            assert_eq!(stream.nodelay().unwrap(), false);
+
+            stream.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+            stream.set_keepalive(None).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), None);
+
+            stream.set_linger(Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(stream.linger().unwrap(), Some(Duration::from_secs(1)));
+            stream.set_linger(None).unwrap();
+            assert_eq!(stream.linger().unwrap(), None);
+
+            stream.set_ttl(23).unwrap();
+            assert_eq!(stream.ttl().unwrap(), 23);
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_ttl_v6() {
+    Scheduler::new()
+        .run(move || {
+            let acceptor = match TcpListener::bind("[::1]:0") {
+                Ok(acceptor) => acceptor,
+                // IPv6 isn't available on every test machine
+                Err(..) => return,
+            };
+            let addr = acceptor.local_addr().unwrap();
+
+            acceptor.set_ttl(42).unwrap();
+            assert_eq!(acceptor.ttl().unwrap(), 42);
+
+            let stream = TcpStream::connect(addr).unwrap();
+
+            stream.set_ttl(23).unwrap();
+            assert_eq!(stream.ttl().unwrap(), 23);
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_tcp_write_to_closed_peer() {
+    use std::io::ErrorKind;
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+
+            // The peer closes the connection right away
+            drop(acceptor.accept().unwrap());
+
+            // The first writes might still succeed, until the peer has reset the connection
+            let buf = [0u8; 1024];
+            let mut result = stream.write(&buf);
+
+            while result.is_ok() {
+                coio::sleep_ms(10);
+                result = stream.write(&buf);
+            }
+
+            let err = result.unwrap_err();
+
+            assert!(err.kind() == ErrorKind::BrokenPipe || err.kind() == ErrorKind::ConnectionReset,
+                    "unexpected error: {:?}",
+                    err);
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_tcp_read_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let (mut peer, _) = acceptor.accept().unwrap();
+
+            assert!(stream.set_read_timeout(Some(Duration::new(0, 0))).is_err());
+            stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_millis(50)));
+
+            // The peer stays silent
+            let start = Instant::now();
+            let mut buf = [0u8; 16];
+            let err = stream.read(&mut buf).unwrap_err();
+
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            // The stream is still usable after a timeout
+            peer.write_all(b"abc").unwrap();
+            assert_eq!(stream.read(&mut buf).unwrap(), 3);
+
+            stream.set_read_timeout(None).unwrap();
+            assert_eq!(stream.read_timeout().unwrap(), None);
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_tcp_read_exact_write_all_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let (mut peer, _) = acceptor.accept().unwrap();
+
+            peer.write_all_timeout(b"abc", Duration::from_secs(1)).unwrap();
+
+            // Only half of the message arrives
+            let start = Instant::now();
+            let mut buf = [0u8; 6];
+            let err = stream.read_exact_timeout(&mut buf, Duration::from_millis(50)).unwrap_err();
+
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            peer.write_all_timeout(b"defghi", Duration::from_secs(1)).unwrap();
+            stream.read_exact_timeout(&mut buf, Duration::from_secs(1)).unwrap();
+            assert_eq!(&buf, b"defghi");
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_peek() {
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let writer = Scheduler::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                coio::sleep_ms(20);
+                stream.write_all(b"GET / HTTP/1.1").unwrap();
+            });
+
+            let (mut stream, _) = acceptor.accept().unwrap();
+
+            // Parks until the data arrives, but leaves it in the socket
+            let mut buf = [0u8; 3];
+            assert_eq!(stream.peek(&mut buf).unwrap(), 3);
+            assert_eq!(&buf, b"GET");
+
+            writer.join().unwrap();
+
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            assert_eq!(&data[..], b"GET / HTTP/1.1");
+        })
+        .unwrap();
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn test_tcp_builder_reuse_port() {
+    use coio::net::TcpBuilder;
+
+    Scheduler::new()
+        .run(move || {
+            let first = TcpBuilder::new_v4().unwrap();
+            first.reuse_address(true).unwrap().reuse_port(true).unwrap();
+            let first = first.bind("127.0.0.1:0").unwrap().listen(128).unwrap();
+            let addr = first.local_addr().unwrap();
+
+            // A second listener may share the port
+            let second = TcpBuilder::new_v4().unwrap();
+            second.reuse_address(true).unwrap().reuse_port(true).unwrap();
+            let second = second.bind(addr).unwrap().listen(128).unwrap();
+            assert_eq!(second.local_addr().unwrap(), addr);
+
+            // Connections would be distributed among both listeners otherwise
+            drop(second);
+
+            let builder = TcpBuilder::new_v4().unwrap();
+            let mut stream = builder.connect(addr).unwrap();
+            assert!(builder.connect(addr).is_err());
+
+            stream.write_all(b"abc").unwrap();
+
+            let (mut conn, _) = first.accept().unwrap();
+            let mut buf = [0u8; 3];
+            conn.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"abc");
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_builder_listener_options() {
+    use coio::net::TcpBuilder;
+
+    Scheduler::new()
+        .run(move || {
+            let builder = TcpBuilder::new_v4().unwrap();
+            builder.reuse_address(true)
+                   .unwrap()
+                   .recv_buffer_size(256 * 1024)
+                   .unwrap()
+                   .send_buffer_size(256 * 1024)
+                   .unwrap();
+            let listener = builder.bind("127.0.0.1:0").unwrap().listen(16).unwrap();
+
+            // The builder has been consumed by listen()
+            assert_eq!(builder.recv_buffer_size(1024).unwrap_err().kind(),
+                       ::std::io::ErrorKind::InvalidInput);
+
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut conn, _) = listener.accept().unwrap();
+
+            stream.write_all(b"abc").unwrap();
+            let mut buf = [0u8; 3];
+            conn.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"abc");
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_bind_sharded() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    Scheduler::new()
+        .with_workers(2)
+        .run(move || {
+            let shards = Scheduler::instance().unwrap().worker_count();
+            let listeners = TcpListener::bind_sharded("127.0.0.1:0", shards).unwrap();
+            assert_eq!(listeners.len(), 2);
+
+            let addr = listeners[0].local_addr().unwrap();
+            assert_eq!(listeners[1].local_addr().unwrap(), addr);
+
+            let accepted = Arc::new(AtomicUsize::new(0));
+
+            for listener in listeners {
+                let accepted = accepted.clone();
+
+                Scheduler::spawn(move || {
+                    for stream in listener.incoming() {
+                        let mut stream = stream.unwrap();
+                        accepted.fetch_add(1, Ordering::SeqCst);
+                        stream.write_all(b"x").unwrap();
+                    }
+                });
+            }
+
+            for _ in 0..16 {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut buf = [0u8; 1];
+                stream.read_exact(&mut buf).unwrap();
+            }
+
+            assert_eq!(accepted.load(Ordering::SeqCst), 16);
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_vectored_io() {
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let (mut peer, _) = acceptor.accept().unwrap();
+
+            let len = stream.write_vectored(&[&b"head"[..], &b""[..], &b"body"[..]]).unwrap();
+            assert_eq!(len, 8);
+
+            // Both segments are sent at once, so that they arrive together on the loopback device
+            let mut head = [0u8; 4];
+            let mut body = [0u8; 4];
+            let len = peer.read_vectored(&mut [&mut head[..], &mut body[..]]).unwrap();
+            assert_eq!(len, 8);
+
+            assert_eq!(&head, b"head");
+            assert_eq!(&body, b"body");
+        })
+        .unwrap();
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn test_tcp_send_file() {
+    use std::env;
+    use std::fs::{self, File};
+
+    Scheduler::new()
+        .run(move || {
+            let path = env::temp_dir().join("coio-test-send-file");
+            File::create(&path).unwrap().write_all(b"hello sendfile").unwrap();
+            let file = File::open(&path).unwrap();
+
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let (mut peer, _) = acceptor.accept().unwrap();
+
+            // Skip "hello "
+            let mut sent = 0;
+            while sent < 8 {
+                sent += stream.send_file(&file, 6 + sent as u64, 8 - sent).unwrap();
+            }
+            drop(stream);
+
+            let mut data = Vec::new();
+            peer.read_to_end(&mut data).unwrap();
+            assert_eq!(&data[..], b"sendfile");
+
+            fs::remove_file(&path).unwrap();
+        })
+        .unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_tcp_send_file_to_closed_peer() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::ErrorKind;
+
+    Scheduler::new()
+        .run(move || {
+            let path = env::temp_dir().join("coio-test-send-file-closed");
+            File::create(&path).unwrap().write_all(&[0u8; 1024]).unwrap();
+            let file = File::open(&path).unwrap();
+
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            drop(acceptor.accept().unwrap());
+
+            let mut result = stream.send_file(&file, 0, 1024);
+
+            while result.is_ok() {
+                coio::sleep_ms(10);
+                result = stream.send_file(&file, 0, 1024);
+            }
+
+            let err = result.unwrap_err();
+            assert!(err.kind() == ErrorKind::BrokenPipe || err.kind() == ErrorKind::ConnectionReset,
+                    "unexpected error: {:?}",
+                    err);
+
+            fs::remove_file(&path).unwrap();
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_from_std() {
+    use std::net;
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::from_std(net::TcpListener::bind("127.0.0.1:0").unwrap())
+                               .unwrap();
+            let addr = acceptor.local_addr().unwrap();
+
+            // A blocking std stream would block the whole worker thread
+            let mut stream = TcpStream::from_std(net::TcpStream::connect(addr).unwrap()).unwrap();
+            let (mut peer, _) = acceptor.accept().unwrap();
+
+            let reader = Scheduler::spawn(move || {
+                let mut buf = [0u8; 3];
+                peer.read_exact(&mut buf).unwrap();
+                buf
+            });
+
+            stream.write_all(b"abc").unwrap();
+            assert_eq!(&reader.join().unwrap(), b"abc");
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tcp_connect_happy_eyeballs() {
+    use std::net::SocketAddr;
+
+    Scheduler::new()
+        .run(move || {
+            let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = acceptor.local_addr().unwrap().port();
+
+            // Nothing listens on the IPv6 address, which is tried first
+            let addrs: Vec<SocketAddr> = vec![format!("[::1]:{}", port).parse().unwrap(),
+                                              format!("127.0.0.1:{}", port).parse().unwrap()];
+
+            let mut stream = TcpStream::connect_happy_eyeballs_addrs(&addrs).unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+
+            let (mut peer, _) = acceptor.accept().unwrap();
+            stream.write_all(b"abc").unwrap();
+
+            let mut buf = [0u8; 3];
+            peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"abc");
+
+            // Host names are resolved without blocking the worker
+            let stream = TcpStream::connect_happy_eyeballs("localhost", port).unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+            acceptor.accept().unwrap();
+
+            // Every attempt fails
+            drop(acceptor);
+            assert!(TcpStream::connect_happy_eyeballs_addrs(&addrs).is_err());
+        })
+        .unwrap();
+}
@@ -0,0 +1,101 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate coio;
+
+use std::io;
+use std::time::Duration;
+
+use coio::Scheduler;
+use coio::net::{self, ReadyType, UdpSocket};
+
+#[test]
+fn test_select_udp() {
+    Scheduler::new()
+        .run(move || {
+            let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let second_addr = second.local_addr().unwrap();
+
+            let sender_fut = Scheduler::spawn(move || {
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                sender.send_to(b"abcdefg", &second_addr).unwrap();
+            });
+
+            let idx = net::select(&[(&first, ReadyType::Readable), (&second, ReadyType::Readable)])
+                          .unwrap();
+            assert_eq!(idx, 1);
+
+            let mut buf = [0u8; 1024];
+            let (len, _) = second.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"abcdefg");
+
+            sender_fut.join().unwrap();
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_select_already_ready() {
+    Scheduler::new()
+        .run(move || {
+            let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            // A fresh socket is writable right away
+            let idx = net::select(&[(&first, ReadyType::Readable), (&second, ReadyType::Writable)])
+                          .unwrap();
+            assert_eq!(idx, 1);
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_poll_returns_all_ready() {
+    Scheduler::new()
+        .run(move || {
+            let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let third = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sender.send_to(b"first", &first.local_addr().unwrap()).unwrap();
+            sender.send_to(b"third", &third.local_addr().unwrap()).unwrap();
+
+            // Give the event loop the chance to deliver both events
+            Scheduler::instance().unwrap().sleep_ms(50).unwrap();
+
+            let ready = net::poll(&[(&first, ReadyType::Readable),
+                                    (&second, ReadyType::Readable),
+                                    (&third, ReadyType::Readable)],
+                                  None)
+                            .unwrap();
+            assert_eq!(ready, vec![0, 2]);
+
+            let mut buf = [0u8; 16];
+            let (len, _) = first.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"first");
+            let (len, _) = third.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"third");
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_poll_timeout() {
+    Scheduler::new()
+        .run(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            let err = net::poll(&[(&socket, ReadyType::Readable)],
+                                Some(Duration::from_millis(50)))
+                          .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        })
+        .unwrap();
+}
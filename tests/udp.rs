@@ -0,0 +1,204 @@
+// Copyright 2015 The coio Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate coio;
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+use coio::Scheduler;
+use coio::net::UdpSocket;
+
+#[test]
+fn test_udp_shared_receivers() {
+    const RECEIVERS: usize = 4;
+    const DATAGRAMS: usize = 16;
+
+    Scheduler::new()
+        .with_workers(2)
+        .run(move || {
+            let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+            let receiver_addr = receiver.local_addr().unwrap();
+
+            // Every receiver counts the datagrams it got until it receives an empty one
+            let handles = (0..RECEIVERS)
+                              .map(|_| {
+                                  let receiver = receiver.clone();
+
+                                  Scheduler::spawn(move || {
+                                      let mut buf = [0u8; 1024];
+                                      let mut count = 0;
+
+                                      loop {
+                                          let (len, _) = receiver.recv_from(&mut buf).unwrap();
+
+                                          if len == 0 {
+                                              return count;
+                                          }
+
+                                          count += 1;
+                                      }
+                                  })
+                              })
+                              .collect::<Vec<_>>();
+
+            let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            for _ in 0..DATAGRAMS {
+                sender.send_to(b"abcdefg", &receiver_addr).unwrap();
+            }
+
+            for _ in 0..RECEIVERS {
+                sender.send_to(b"", &receiver_addr).unwrap();
+            }
+
+            let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+            assert_eq!(total, DATAGRAMS);
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_udp_multicast_v4() {
+    Scheduler::new()
+        .run(move || {
+            let group = Ipv4Addr::new(239, 255, 42, 98);
+
+            let receiver = UdpSocket::bind("0.0.0.0:0").unwrap();
+            receiver.join_multicast_v4(&group).unwrap();
+            let port = receiver.local_addr().unwrap().port();
+
+            let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+            sender.set_multicast_loop_v4(true).unwrap();
+            sender.set_multicast_ttl_v4(1).unwrap();
+
+            let group_addr = SocketAddr::V4(SocketAddrV4::new(group, port));
+            sender.send_to(b"abcdefg", &group_addr).unwrap();
+
+            let mut buf = [0u8; 1024];
+            let (len, _) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"abcdefg");
+
+            receiver.leave_multicast_v4(&group).unwrap();
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_udp_recv_cancelled() {
+    use std::error::Error;
+    use coio::Cancelled;
+
+    Scheduler::new()
+        .run(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            let handle = Scheduler::spawn(move || {
+                let mut buf = [0u8; 1024];
+                socket.recv_from(&mut buf).unwrap_err()
+            });
+
+            // Let the receiver wait for a datagram which never arrives
+            Scheduler::sched();
+            handle.cancel();
+
+            let err = handle.join().unwrap();
+            assert!(err.get_ref().map_or(false, |e| e.is::<Cancelled>()));
+            assert_eq!(err.description(), "coroutine has been cancelled");
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_udp_recv_aborted() {
+    use coio::Cancelled;
+
+    Scheduler::new()
+        .run(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            let receiver = Scheduler::spawn(move || {
+                let mut buf = [0u8; 1024];
+                let _ = socket.recv_from(&mut buf);
+                unreachable!("the receiver must have been unwound");
+            });
+
+            // Never finishes on its own
+            let spinner = Scheduler::spawn(|| {
+                loop {
+                    coio::sched();
+                }
+            });
+
+            Scheduler::sched();
+            receiver.abort();
+            spinner.abort();
+
+            assert!(receiver.join().unwrap_err().is::<Cancelled>());
+            assert!(spinner.join().unwrap_err().is::<Cancelled>());
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_udp_recv_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    Scheduler::new()
+        .run(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = socket.local_addr().unwrap();
+
+            socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_millis(50)));
+
+            // The reply is dropped
+            let start = Instant::now();
+            let mut buf = [0u8; 1024];
+            let err = socket.recv_from(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            // A datagram arriving in time is received as usual
+            let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sender.send_to(b"pong", &addr).unwrap();
+
+            let (len, _) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"pong");
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_udp_connected() {
+    Scheduler::new()
+        .run(move || {
+            let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_addr = server.local_addr().unwrap();
+
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.connect(server_addr).unwrap();
+            client.send(b"ping").unwrap();
+
+            let mut buf = [0u8; 1024];
+            let (len, client_addr) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+
+            server.send_to(b"pong", &client_addr).unwrap();
+            let len = client.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"pong");
+
+            // The peer going away is reported through ICMP
+            drop(server);
+            client.send(b"ping").unwrap();
+            assert!(client.recv(&mut buf).is_err());
+        })
+        .unwrap();
+}